@@ -7,10 +7,27 @@ extern crate neon;
 extern crate log;
 
 extern crate env_logger;
+extern crate futures_cpupool;
+extern crate protobuf;
 
-use bcrust_core::funcs;
+use std::collections::HashMap;
+use std::sync::Arc;
 
+use bcrust_core::coin;
+use bcrust_core::crypto::sig;
+use bcrust_core::funcs;
+use bcrust_core::mempool::{Mempool, DEFAULT_MAX_MEMPOOL_SIZE};
+use bcrust_core::protos::core::{BcBlock, OutPoint, Transaction, TransactionOutput};
+use bcrust_core::tx::{coinbase, tx_hash};
+use bcrust_core::tx::validation::{UnspentOutput, UtxoLookup};
+use bcrust_core::types::Hash256;
+use bcrust_core::utxo::Utxo;
+use bcrust_core::validation::{self, ValidationError, ValidationOptions};
+
+use futures_cpupool::CpuPool;
 use neon::prelude::*;
+use neon::result::Throw;
+use protobuf::{parse_from_bytes, Message};
 
 fn hello(mut cx: FunctionContext) -> JsResult<JsString> {
     Ok(cx.string("Hello from native world!"))
@@ -127,6 +144,352 @@ fn distance(mut cx: FunctionContext) -> JsResult<JsNumber> {
     Ok(cx.number(res as f64))
 }
 
+fn overline_distance(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let arg0: Handle<JsString> = cx.argument(0)?;
+    let arg1: Handle<JsString> = cx.argument(1)?;
+
+    let a: String = arg0.value();
+    let b: String = arg1.value();
+
+    match funcs::overline_distance(&a.as_bytes(), &b.as_bytes()) {
+        Some(res) => Ok(cx.number(res as f64)),
+        None => cx.throw_error("overline_distance: input strings must have the same length")
+    }
+}
+
+fn buffer_to_vec(cx: &mut FunctionContext, buffer: Handle<JsBuffer>) -> Vec<u8> {
+    cx.borrow(&buffer, |data| data.as_slice::<u8>().to_vec())
+}
+
+fn vec_to_buffer<'a, C: Context<'a>>(cx: &mut C, bytes: &[u8]) -> JsResult<'a, JsBuffer> {
+    let mut buffer = JsBuffer::new(cx, bytes.len() as u32)?;
+    cx.borrow_mut(&mut buffer, |data| data.as_mut_slice::<u8>().copy_from_slice(bytes));
+    Ok(buffer)
+}
+
+/// sign_data(data: string|Buffer, privateKey: Buffer): Buffer
+fn sign_data(mut cx: FunctionContext) -> JsResult<JsBuffer> {
+    let arg0: Handle<JsValue> = cx.argument(0)?;
+    let arg1: Handle<JsBuffer> = cx.argument(1)?;
+
+    let data = match arg0.downcast::<JsString>() {
+        Ok(string) => string.value().into_bytes(),
+        Err(_) => {
+            let buffer = arg0.downcast::<JsBuffer>().or_throw(&mut cx)?;
+            buffer_to_vec(&mut cx, buffer)
+        }
+    };
+    let private_key = buffer_to_vec(&mut cx, arg1);
+
+    match sig::sign_data(&data, &private_key) {
+        Ok(signature) => vec_to_buffer(&mut cx, &signature),
+        Err(err) => cx.throw_error(&format!("sign_data: {}", err))
+    }
+}
+
+/// pub_key_recover(signedData: string, signature: Buffer): Buffer
+fn pub_key_recover(mut cx: FunctionContext) -> JsResult<JsBuffer> {
+    let arg0: Handle<JsString> = cx.argument(0)?;
+    let arg1: Handle<JsBuffer> = cx.argument(1)?;
+
+    let signed_data: Hash256 = match arg0.value().parse() {
+        Ok(hash) => hash,
+        Err(err) => return cx.throw_error(&format!("pub_key_recover: {}", err))
+    };
+    let signature = buffer_to_vec(&mut cx, arg1);
+
+    match sig::pub_key_recover(&signed_data, &signature) {
+        Ok(public_key) => vec_to_buffer(&mut cx, &public_key),
+        Err(err) => cx.throw_error(&format!("pub_key_recover: {}", err))
+    }
+}
+
+thread_local! {
+    // workers of validate_batch, shared by all calls
+    static VALIDATION_POOL: CpuPool = CpuPool::new_num_cpus();
+}
+
+struct ValidateBatchTask {
+    pool: CpuPool,
+    blocks: Arc<[BcBlock]>,
+}
+
+impl Task for ValidateBatchTask {
+    type Output = Vec<Result<(), ValidationError>>;
+    type Error = String;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        validation::validate_batch(&self.pool, self.blocks.clone(), ValidationOptions::default())
+            .map_err(|err| err.to_string())
+    }
+
+    fn complete(self, mut cx: TaskContext, result: Result<Self::Output, Self::Error>) -> JsResult<Self::JsEvent> {
+        let results = match result {
+            Ok(results) => results,
+            Err(err) => return cx.throw_error(&err)
+        };
+
+        // null for a valid block, reason of the failure otherwise
+        let array = JsArray::new(&mut cx, results.len() as u32);
+        for (i, res) in results.iter().enumerate() {
+            let value: Handle<JsValue> = match *res {
+                Ok(()) => cx.null().upcast(),
+                Err(ref err) => cx.string(err.to_string()).upcast()
+            };
+            array.set(&mut cx, i as u32, value)?;
+        }
+
+        Ok(array)
+    }
+}
+
+/// validate_batch(blocks: Buffer[], callback: (err, results: Array<?string>) => void)
+///
+/// Blocks are serialized `BcBlock`s ordered from the oldest to the newest
+fn validate_batch(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let arg0: Handle<JsArray> = cx.argument(0)?;
+    let callback: Handle<JsFunction> = cx.argument(1)?;
+
+    let mut blocks = Vec::new();
+    for item in arg0.to_vec(&mut cx)? {
+        let buffer = item.downcast::<JsBuffer>().or_throw(&mut cx)?;
+        let block = cx.borrow(&buffer, |data| parse_from_bytes::<BcBlock>(data.as_slice::<u8>()));
+        match block {
+            Ok(block) => blocks.push(block),
+            Err(err) => return cx.throw_error(&format!("validate_batch: could not parse block, {}", err))
+        }
+    }
+
+    let pool = VALIDATION_POOL.with(|pool| pool.clone());
+    ValidateBatchTask { pool, blocks: blocks.into() }.schedule(callback);
+
+    Ok(cx.undefined())
+}
+
+/// Count or size passed as JS number, `None` for NaN, negative, fractional and too large numbers
+fn number_to_usize(value: f64) -> Option<usize> {
+    if value.is_finite() && value >= 0.0 && value.fract() == 0.0 && value <= usize::max_value() as f64 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+/// tx_create_coinbase(currentBlockHeight: number, blockTxs: Buffer[], minerAddress: string, emblemBalance: Buffer, mintedNrg: number): Buffer
+///
+/// Serialized coinbase of a block template, `emblemBalance` is the big endian emblem balance of
+/// the miner and `mintedNrg` the NRG minted so far as JS reads them from the persistence
+fn tx_create_coinbase(mut cx: FunctionContext) -> JsResult<JsBuffer> {
+    let height = cx.argument::<JsNumber>(0)?.value();
+    let height = match number_to_usize(height) {
+        Some(height) => height as u64,
+        None => return cx.throw_error(&format!("tx_create_coinbase: invalid block height {}", height))
+    };
+    let arg1: Handle<JsArray> = cx.argument(1)?;
+    let mut txs = Vec::new();
+    for item in arg1.to_vec(&mut cx)? {
+        let buffer = item.downcast::<JsBuffer>().or_throw(&mut cx)?;
+        match cx.borrow(&buffer, |data| parse_from_bytes::<Transaction>(data.as_slice::<u8>())) {
+            Ok(tx) => txs.push(tx),
+            Err(err) => return cx.throw_error(&format!("tx_create_coinbase: could not parse transaction, {}", err))
+        }
+    }
+    let miner_address = cx.argument::<JsString>(2)?.value();
+    let arg3: Handle<JsBuffer> = cx.argument(3)?;
+    let emblem_balance = coin::internal_to_biguint(&buffer_to_vec(&mut cx, arg3));
+    let minted_nrg = cx.argument::<JsNumber>(4)?.value();
+    let minted_nrg = match number_to_usize(minted_nrg) {
+        Some(minted_nrg) => minted_nrg as u64,
+        None => return cx.throw_error(&format!("tx_create_coinbase: invalid minted NRG {}", minted_nrg))
+    };
+
+    let coinbase = match coinbase::tx_create_coinbase(height, &txs, &miner_address, &emblem_balance, minted_nrg) {
+        Ok(coinbase) => coinbase,
+        Err(err) => return cx.throw_error(&format!("tx_create_coinbase: {}", err))
+    };
+    match coinbase.write_to_bytes() {
+        Ok(bytes) => vec_to_buffer(&mut cx, &bytes),
+        Err(err) => cx.throw_error(&format!("tx_create_coinbase: could not serialize transaction, {}", err))
+    }
+}
+
+/// Outputs spent by pending transactions as JS read them from the persistence
+struct SpentOutputs(HashMap<Utxo, UnspentOutput>);
+
+impl UtxoLookup for SpentOutputs {
+    fn get_unspent_output(&self, out_point: &OutPoint) -> Option<UnspentOutput> {
+        self.0.get(&Utxo::from_out_point(out_point)).cloned()
+    }
+}
+
+/// Reads the outputs spent by the inputs of `txs` from `array`, one serialized `TransactionOutput`
+/// for each input in order or null for outputs of pending transactions
+///
+/// Only the value of the outputs is used by the pool, their height is not known.
+fn spent_outputs<'a, C: Context<'a>>(cx: &mut C, txs: &[&Transaction], array: Handle<JsArray>) -> Result<SpentOutputs, Throw> {
+    let items = array.to_vec(cx)?;
+    let inputs: Vec<&OutPoint> = txs.iter().flat_map(|tx| tx.get_inputs().iter().map(|input| input.get_out_point())).collect();
+    if items.len() != inputs.len() {
+        return cx.throw_error(&format!("expected {} spent outputs, got {}", inputs.len(), items.len()));
+    }
+
+    let mut outputs = HashMap::new();
+    for (out_point, item) in inputs.into_iter().zip(items) {
+        if item.is_a::<JsNull>() {
+            continue;
+        }
+        let buffer = item.downcast::<JsBuffer>().or_throw(cx)?;
+        let output = match cx.borrow(&buffer, |data| parse_from_bytes::<TransactionOutput>(data.as_slice::<u8>())) {
+            Ok(output) => output,
+            Err(err) => return cx.throw_error(&format!("could not parse spent output, {}", err))
+        };
+        outputs.insert(Utxo::from_out_point(out_point), UnspentOutput { output, height: 0, is_coinbase: false });
+    }
+    Ok(SpentOutputs(outputs))
+}
+
+declare_types! {
+    // new TxPool(maxSize?: number), pending transactions to fill block templates from
+    pub class JsTxPool for Mempool {
+        init(mut cx) {
+            let max_size = match cx.argument_opt(0) {
+                Some(arg) => {
+                    let value = arg.downcast::<JsNumber>().or_throw(&mut cx)?.value();
+                    match number_to_usize(value) {
+                        Some(max_size) => max_size,
+                        None => return cx.throw_error(&format!("TxPool: invalid max size {}", value))
+                    }
+                }
+                None => DEFAULT_MAX_MEMPOOL_SIZE
+            };
+
+            Ok(Mempool::new(max_size))
+        }
+
+        // add(tx: Buffer, spentOutputs: Array<Buffer|null>): string, throws if the transaction can not be added
+        method add(mut cx) {
+            let buffer: Handle<JsBuffer> = cx.argument(0)?;
+            let tx = match cx.borrow(&buffer, |data| parse_from_bytes::<Transaction>(data.as_slice::<u8>())) {
+                Ok(tx) => tx,
+                Err(err) => return cx.throw_error(&format!("add: could not parse transaction, {}", err))
+            };
+            let array: Handle<JsArray> = cx.argument(1)?;
+            let utxos = spent_outputs(&mut cx, &[&tx], array)?;
+
+            let mut this = cx.this();
+            match cx.borrow_mut(&mut this, |mut pool| pool.add(tx, &utxos)) {
+                Ok(hash) => Ok(cx.string(hash).upcast()),
+                Err(err) => cx.throw_error(&format!("add: {}", err))
+            }
+        }
+
+        // remove(txHash: string): number, removes also transactions spending its outputs
+        method remove(mut cx) {
+            let hash: String = cx.argument::<JsString>(0)?.value();
+
+            let mut this = cx.this();
+            let removed = cx.borrow_mut(&mut this, |mut pool| pool.remove(&hash).len());
+            Ok(cx.number(removed as f64).upcast())
+        }
+
+        // applyBlock(block: Buffer): number, returns count of dropped conflicting transactions
+        method applyBlock(mut cx) {
+            let buffer: Handle<JsBuffer> = cx.argument(0)?;
+            let block = match cx.borrow(&buffer, |data| parse_from_bytes::<BcBlock>(data.as_slice::<u8>())) {
+                Ok(block) => block,
+                Err(err) => return cx.throw_error(&format!("applyBlock: could not parse block, {}", err))
+            };
+
+            let mut this = cx.this();
+            let conflicts = cx.borrow_mut(&mut this, |mut pool| pool.apply_block(&block).len());
+            Ok(cx.number(conflicts as f64).upcast())
+        }
+
+        // revertBlock(block: Buffer, spentOutputs: Array<Buffer|null>): string[], returns hashes of
+        // the transactions of the block pending after the revert, spent outputs are given for the
+        // inputs of all transactions
+        method revertBlock(mut cx) {
+            let buffer: Handle<JsBuffer> = cx.argument(0)?;
+            let block = match cx.borrow(&buffer, |data| parse_from_bytes::<BcBlock>(data.as_slice::<u8>())) {
+                Ok(block) => block,
+                Err(err) => return cx.throw_error(&format!("revertBlock: could not parse block, {}", err))
+            };
+            let array: Handle<JsArray> = cx.argument(1)?;
+            let utxos = {
+                let txs: Vec<&Transaction> = block.get_txs().iter().collect();
+                spent_outputs(&mut cx, &txs, array)?
+            };
+
+            let mut this = cx.this();
+            let pending: Vec<String> = cx.borrow_mut(&mut this, |mut pool| {
+                pool.revert_block(&block, &utxos);
+                block.get_txs().iter().map(|tx| tx_hash(tx).to_hex()).filter(|hash| pool.contains(hash)).collect()
+            });
+
+            let array = JsArray::new(&mut cx, pending.len() as u32);
+            for (i, hash) in pending.iter().enumerate() {
+                let hash = cx.string(hash);
+                array.set(&mut cx, i as u32, hash)?;
+            }
+            Ok(array.upcast())
+        }
+
+        // takeEvicted(): string[], hashes of transactions evicted from the full pool since the last call
+        method takeEvicted(mut cx) {
+            let mut this = cx.this();
+            let evicted = cx.borrow_mut(&mut this, |mut pool| pool.take_evicted());
+
+            let array = JsArray::new(&mut cx, evicted.len() as u32);
+            for (i, hash) in evicted.iter().enumerate() {
+                let hash = cx.string(hash);
+                array.set(&mut cx, i as u32, hash)?;
+            }
+            Ok(array.upcast())
+        }
+
+        // loadBestPendingTxs(maxCount: number, maxSize: number): Buffer[]
+        method loadBestPendingTxs(mut cx) {
+            let max_count = cx.argument::<JsNumber>(0)?.value();
+            let max_count = match number_to_usize(max_count) {
+                Some(max_count) => max_count,
+                None => return cx.throw_error(&format!("loadBestPendingTxs: invalid max count {}", max_count))
+            };
+            let max_size = cx.argument::<JsNumber>(1)?.value();
+            let max_size = match number_to_usize(max_size) {
+                Some(max_size) => max_size,
+                None => return cx.throw_error(&format!("loadBestPendingTxs: invalid max size {}", max_size))
+            };
+
+            let this = cx.this();
+            let txs = cx.borrow(&this, |pool| {
+                pool.load_best_pending_txs(max_count, max_size)
+                    .into_iter()
+                    .map(|tx| tx.write_to_bytes())
+                    .collect::<Result<Vec<Vec<u8>>, _>>()
+            });
+            let txs = match txs {
+                Ok(txs) => txs,
+                Err(err) => return cx.throw_error(&format!("loadBestPendingTxs: could not serialize transaction, {}", err))
+            };
+
+            let array = JsArray::new(&mut cx, txs.len() as u32);
+            for (i, tx) in txs.iter().enumerate() {
+                let buffer = vec_to_buffer(&mut cx, tx)?;
+                array.set(&mut cx, i as u32, buffer)?;
+            }
+            Ok(array.upcast())
+        }
+
+        // size(): number, count of pending transactions
+        method size(mut cx) {
+            let this = cx.this();
+            let len = cx.borrow(&this, |pool| pool.len());
+            Ok(cx.number(len as f64).upcast())
+        }
+    }
+}
+
 register_module!(mut m, {
     m.export_function("hello", hello)?;
     m.export_function("initLogger", init_logger)?;
@@ -143,6 +506,22 @@ register_module!(mut m, {
     // Miner functions
     m.export_function("distance", distance)?;
 
+    // DEX functions
+    m.export_function("overline_distance", overline_distance)?;
+
+    // Signature functions
+    m.export_function("sign_data", sign_data)?;
+    m.export_function("pub_key_recover", pub_key_recover)?;
+
+    // Validation functions
+    m.export_function("validate_batch", validate_batch)?;
+
+    // Block template functions
+    m.export_function("tx_create_coinbase", tx_create_coinbase)?;
+
+    // Transaction pool
+    m.export_class::<JsTxPool>("TxPool")?;
+
     Ok(())
 });
 
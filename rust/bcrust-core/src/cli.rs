@@ -13,18 +13,19 @@ extern crate num_traits;
 extern crate blake2_rfc;
 extern crate rand;
 extern crate rustc_serialize;
+extern crate bcrust_core;
 
-use clap::{Arg, App, SubCommand};
+use clap::{Arg, App, ArgMatches, SubCommand};
 use std::sync::mpsc::channel;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::{SystemTime, UNIX_EPOCH};
-use blake2_rfc::blake2b::{blake2b};
-use rustc_serialize::hex::{ToHex};
-use num_bigint::{BigInt};
-use std::str::FromStr;
 use std::sync::{Mutex, Arc};
 use std::env;
+use std::process;
+
+use bcrust_core::store::{KeyValueDb, Store};
+use bcrust_core::store::rocks::RocksDb;
 
 use self::protos::miner::{MinerRequest, MinerResponse, MinerResponseResult};
 use self::protos::miner_grpc::{Miner, MinerServer};
@@ -33,9 +34,14 @@ pub mod data;
 pub mod funcs;
 pub mod mining;
 pub mod protos;
+pub mod types;
+
+#[cfg(test)]
+mod templates;
 
 use self::funcs::distance;
 use self::mining::primitives::{get_new_block_count, get_new_pre_exp_diff, get_exp_factor_diff};
+use self::types::{Distance, Hash256};
 
 
 const AUTHOR: &'static str = env!("CARGO_PKG_AUTHORS");
@@ -59,6 +65,17 @@ impl Miner for MinerImpl {
             *work_id = p_in.clone().get_work_id().to_string();
         }
 
+        // work is the only hash the miner reads from the request, reject malformed ones upfront
+        let work: Hash256 = match p_in.get_work().parse() {
+            Ok(work) => work,
+            Err(err) => {
+                return grpc::SingleResponse::err(grpc::Error::GrpcMessage(grpc::GrpcMessageError {
+                    grpc_status: 3,
+                    grpc_message: format!("invalid work: {}", err)
+                }))
+            }
+        };
+
         let mut threads: Vec<JoinHandle<()>> = Vec::new();
         let (tx, rx) = channel();
 
@@ -66,16 +83,16 @@ impl Miner for MinerImpl {
         let request_exit = Arc::new(Mutex::new(false));
 
         // Log just start of the work
-        let work = p_in.get_work();
         let ts = p_in.get_current_timestamp();
         let diff = p_in.get_difficulty();
 
         if  ts % 5 == 0 {
+            let work_hex = work.to_hex();
             println!(
                 "{} mining: work {}..{} last block diff {}",
                 &ts,
-                &work[0..4],
-                &work[work.len()-4..work.len()],
+                &work_hex[0..4],
+                &work_hex[work_hex.len()-4..work_hex.len()],
                 &diff
             );
         }
@@ -95,8 +112,8 @@ impl Miner for MinerImpl {
                 let miner = p.get_miner_key().clone();
                 let merkle_root = p.get_merkle_root();
                 let current_timestamp = p.get_current_timestamp();
-                // let difficulty = BigInt::from_str(p.get_difficulty()).unwrap();
-                let work = p.get_work();
+                // distance is computed over the hex representations, work has to be lower case
+                let work_hex = work.to_hex();
                 let work_id = p.get_work_id();
                 let last_previous_block = p.get_last_previous_block();
 
@@ -113,33 +130,30 @@ impl Miner for MinerImpl {
                     let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
                     let nonce = get_random_string();
                     let new_block_count = get_new_block_count(p.get_last_previous_block().get_blockchain_headers(), p.get_new_block_headers());
-                    let new_pre_exp_diff = get_new_pre_exp_diff(
-                        ts,
-                        last_previous_block,
-                        new_block_count
-                    );
+                    let new_pre_exp_diff = match get_new_pre_exp_diff(ts, last_previous_block, new_block_count) {
+                        Ok(diff) => diff,
+                        Err(err) => {
+                            error!("could not compute difficulty of work {}: {}", work_id, err);
+                            let mut response = MinerResponse::new();
+                            response.set_result(MinerResponseResult::Error);
+                            let _ = tx.send(response);
+                            break;
+                        }
+                    };
 
                     let threshold = get_exp_factor_diff(
                         new_pre_exp_diff,
                         last_previous_block.get_height()
                     );
 
-                    let nonce_hash = blake2b(64, &[], nonce.to_string().as_bytes())
-                        .as_bytes()[32..64]
-                        .to_hex();
+                    let nonce_hash = Hash256::blake2bl(nonce.to_string().as_bytes());
 
                     let in_str = format!("{}{}{}{}", miner, merkle_root, nonce_hash, ts);
 
-                    let result_candidate = blake2b(64, &[], in_str.as_bytes())
-                        .as_bytes()[32..64]
-                        .to_hex();
-
-                    let threshold = BigInt::from_str(&threshold[..]).unwrap();
-                    let s = String::from(work);
-                    let r = result_candidate.clone();
+                    let result_candidate = Hash256::blake2bl(in_str.as_bytes());
 
-                    let similarity = distance(s.as_bytes(), r.as_bytes());
-                    let distance = BigInt::from(similarity);
+                    let similarity = distance(work_hex.as_bytes(), result_candidate.to_hex().as_bytes());
+                    let distance = Distance::from(similarity);
 
                     let solution_found = distance > threshold;
 
@@ -214,12 +228,63 @@ impl Miner for MinerImpl {
     }
 }
 
+/// Checks the node database at PATH, exits with 1 if it is not consistent
+fn db_check(matches: &ArgMatches) {
+    let path = matches.value_of("PATH").expect("PATH is required");
+    let db = if matches.is_present("apply") { RocksDb::open(path) } else { RocksDb::open_read_only(path) };
+    let store = match db {
+        Ok(db) => Store::new(db),
+        Err(err) => {
+            eprintln!("could not open {}: {}", path, err);
+            process::exit(2);
+        }
+    };
+    let report = match store.check() {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("could not check {}: {}", path, err);
+            process::exit(2);
+        }
+    };
+    println!("{}", report.to_json(matches.is_present("repair")).pretty());
+
+    if matches.is_present("apply") {
+        if let Err(err) = store.db().write(report.repairs.clone()) {
+            eprintln!("could not apply repairs: {}", err);
+            process::exit(2);
+        }
+    }
+    process::exit(if report.is_consistent() { 0 } else { 1 });
+}
+
 fn main() {
     let matches = App::new(DESCRIPTION)
         .version(VERSION)
         .author(AUTHOR)
+        .subcommand(SubCommand::with_name("db")
+            .about("Node database tools")
+            .subcommand(SubCommand::with_name("check")
+                .about("Checks the indices of a node database, prints a JSON report")
+                .arg(Arg::with_name("PATH")
+                    .help("Data directory of the node database, stop the node before applying repairs")
+                    .required(true))
+                .arg(Arg::with_name("repair")
+                    .long("repair")
+                    .help("Lists the writes repairing the issues which can be fixed"))
+                .arg(Arg::with_name("apply")
+                    .long("apply")
+                    .requires("repair")
+                    .help("Writes the repairs to the database"))))
         .get_matches();
 
+    if let Some(db) = matches.subcommand_matches("db") {
+        if let Some(check) = db.subcommand_matches("check") {
+            db_check(check);
+        }
+        eprintln!("{}", db.usage());
+        process::exit(2);
+    }
+
     let mut conf = httpbis::ServerConf::default();
     conf.reuse_port = Some(true);
 
@@ -0,0 +1,129 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! NRG amounts, port of src/core/coin.es6
+//!
+//! Amounts are stored in transactions as big endian unsigned integers in the smallest unit (boson).
+use num_bigint::BigUint;
+use num_traits::pow;
+
+/// 9.8 billion NRG
+pub const MAX_NRG_VALUE: u64 = 9800000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoinFrac {
+    Boson,
+    Weibetter,
+    Nrg,
+}
+
+impl CoinFrac {
+    /// Power of ten the unit is of boson
+    pub fn divisor(&self) -> usize {
+        match *self {
+            CoinFrac::Boson => 0,
+            CoinFrac::Weibetter => 1,
+            CoinFrac::Nrg => 18,
+        }
+    }
+}
+
+/// Amount of boson in `internal` representation, `new BN(internal)` in JS
+pub fn internal_to_biguint(internal: &[u8]) -> BigUint {
+    BigUint::from_bytes_be(internal)
+}
+
+/// Internal representation of `amount` of boson, `amount.toBuffer()` in JS
+pub fn biguint_to_internal(amount: &BigUint) -> Vec<u8> {
+    amount.to_bytes_be()
+}
+
+/// Amount of boson in `human` amount of `unit`, `humanToBN` in JS
+///
+/// Decimals below boson are truncated. Returns `None` for malformed amounts and amounts out of
+/// the range of 1 boson to `MAX_NRG_VALUE` NRG.
+pub fn human_to_biguint(human: &str, unit: CoinFrac) -> Option<BigUint> {
+    let divisor = unit.divisor();
+    let mut parts = human.splitn(2, '.');
+    let int_part = parts.next().unwrap_or("");
+    let float_part = parts.next().unwrap_or("");
+    if unit == CoinFrac::Boson && human.contains('.') {
+        return None;
+    }
+    if !int_part.bytes().chain(float_part.bytes()).all(|c| c.is_ascii_digit()) || int_part.len() + float_part.len() == 0 {
+        return None;
+    }
+
+    let float_part = if float_part.len() > divisor { &float_part[..divisor] } else { float_part };
+    let digits = format!("{}{}{}", int_part, float_part, "0".repeat(divisor - float_part.len()));
+    let amount = BigUint::parse_bytes(digits.as_bytes(), 10)?;
+
+    let max = BigUint::from(MAX_NRG_VALUE) * pow(BigUint::from(10u32), CoinFrac::Nrg.divisor());
+    if amount < BigUint::from(1u32) || amount > max {
+        return None;
+    }
+    Some(amount)
+}
+
+/// Formats `internal` amount of boson in `unit` without trailing zeros, `internalToHuman` in JS
+pub fn internal_to_human(internal: &[u8], unit: CoinFrac) -> String {
+    let divisor = unit.divisor();
+    let value = internal_to_biguint(internal).to_string();
+    if divisor == 0 {
+        return value;
+    }
+
+    let padded = if value.len() > divisor {
+        value
+    } else {
+        format!("{}{}", "0".repeat(divisor + 1 - value.len()), value)
+    };
+
+    let (int_part, float_part) = padded.split_at(padded.len() - divisor);
+    let float_part = float_part.trim_end_matches('0');
+    if float_part.is_empty() {
+        int_part.to_string()
+    } else {
+        format!("{}.{}", int_part, float_part)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_to_human_test() {
+        let amount = |value: u64| biguint_to_internal(&BigUint::from(value));
+
+        // parity with internalToHuman in JS
+        assert_eq!(internal_to_human(&amount(1500000000000000000), CoinFrac::Nrg), "1.5");
+        assert_eq!(internal_to_human(&amount(3000000000000000000), CoinFrac::Nrg), "3");
+        assert_eq!(internal_to_human(&amount(25), CoinFrac::Nrg), "0.000000000000000025");
+        assert_eq!(internal_to_human(&amount(0), CoinFrac::Nrg), "0");
+        assert_eq!(internal_to_human(&[], CoinFrac::Nrg), "0");
+        assert_eq!(internal_to_human(&amount(25), CoinFrac::Weibetter), "2.5");
+        assert_eq!(internal_to_human(&amount(25), CoinFrac::Boson), "25");
+    }
+
+    #[test]
+    fn human_to_biguint_test() {
+        let amount = |value: u64| Some(BigUint::from(value));
+
+        // parity with humanToBN in JS
+        assert_eq!(human_to_biguint("1.5", CoinFrac::Nrg), amount(1500000000000000000));
+        assert_eq!(human_to_biguint("0.001", CoinFrac::Nrg), amount(1000000000000000));
+        assert_eq!(human_to_biguint("3", CoinFrac::Nrg), amount(3000000000000000000));
+        assert_eq!(human_to_biguint("0.0000000000000000251", CoinFrac::Nrg), amount(25));
+        assert_eq!(human_to_biguint(".5", CoinFrac::Weibetter), amount(5));
+        assert_eq!(human_to_biguint("25", CoinFrac::Boson), amount(25));
+        assert_eq!(human_to_biguint("2.5", CoinFrac::Boson), None);
+        assert_eq!(human_to_biguint("0", CoinFrac::Nrg), None);
+        assert_eq!(human_to_biguint("9800000001", CoinFrac::Nrg), None);
+        assert_eq!(human_to_biguint("1.2.3", CoinFrac::Nrg), None);
+        assert_eq!(human_to_biguint("-1", CoinFrac::Nrg), None);
+        assert_eq!(human_to_biguint("", CoinFrac::Nrg), None);
+    }
+}
@@ -0,0 +1,5 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+pub mod sig;
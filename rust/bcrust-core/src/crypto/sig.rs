@@ -0,0 +1,191 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Recoverable secp256k1 signatures, port of the signing functions from src/core/txUtils.es6
+//!
+//! Signatures are 65 bytes long, 64 bytes of compact signature followed by the recovery id,
+//! public keys are 64 bytes long (uncompressed without the 0x04 prefix) unless compressed.
+use std::error::Error;
+use std::fmt;
+
+use secp256k1::{self, Message, PublicKey, RecoveryId, SecretKey, Signature};
+
+use protos::core::{OutPoint, Transaction};
+use tx::out_point_output_hash;
+use types::Hash256;
+
+pub const SIGNATURE_LENGTH: usize = 65;
+pub const PUBLIC_KEY_LENGTH: usize = 64;
+pub const COMPRESSED_PUBLIC_KEY_LENGTH: usize = 33;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigError {
+    InvalidPrivateKey,
+    InvalidSignatureLength(usize),
+    InvalidRecoveryId(u8),
+    RecoveryFailed,
+    InvalidPublicKey,
+}
+
+impl fmt::Display for SigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SigError::InvalidPrivateKey => write!(f, "invalid private key"),
+            SigError::InvalidSignatureLength(len) =>
+                write!(f, "invalid signature length {}, expected {} bytes", len, SIGNATURE_LENGTH),
+            SigError::InvalidRecoveryId(id) => write!(f, "invalid recovery id {}", id),
+            SigError::RecoveryFailed => write!(f, "public key could not be recovered from signature"),
+            SigError::InvalidPublicKey => write!(f, "invalid public key"),
+        }
+    }
+}
+
+impl Error for SigError {
+    fn description(&self) -> &str {
+        "signature error"
+    }
+}
+
+/// Signs blake2bl hash of `data`, `signData` in JS
+pub fn sign_data(data: &[u8], private_key: &[u8]) -> Result<Vec<u8>, SigError> {
+    let secret_key = SecretKey::parse_slice(private_key).map_err(|_| SigError::InvalidPrivateKey)?;
+    let message = Message::parse(Hash256::blake2bl(data).as_bytes());
+    let (signature, recovery_id) = secp256k1::sign(&message, &secret_key);
+
+    let mut signature_with_recovery = signature.serialize().to_vec();
+    signature_with_recovery.push(recovery_id.serialize());
+    Ok(signature_with_recovery)
+}
+
+/// Recovers public key which created `raw_signature` of `signed_data`, `pubKeyRecover` in JS
+///
+/// `signed_data` is the hash which was signed, e.g. `blake2bl(data)` for signatures of `sign_data`.
+pub fn pub_key_recover(signed_data: &Hash256, raw_signature: &[u8]) -> Result<Vec<u8>, SigError> {
+    if raw_signature.len() != SIGNATURE_LENGTH {
+        return Err(SigError::InvalidSignatureLength(raw_signature.len()));
+    }
+
+    let mut compact = [0u8; 64];
+    compact.copy_from_slice(&raw_signature[..64]);
+    let recovery_byte = raw_signature[64];
+    let recovery_id = RecoveryId::parse(recovery_byte).map_err(|_| SigError::InvalidRecoveryId(recovery_byte))?;
+
+    let message = Message::parse(signed_data.as_bytes());
+    let public_key: PublicKey = secp256k1::recover(&message, &Signature::parse(&compact), &recovery_id)
+        .map_err(|_| SigError::RecoveryFailed)?;
+
+    Ok(public_key.serialize()[1..].to_vec())
+}
+
+/// Compressed public key of `private_key`, `secp256k1.publicKeyCreate(privateKey, true)` in JS
+pub fn public_key_create(private_key: &[u8]) -> Result<Vec<u8>, SigError> {
+    let secret_key = SecretKey::parse_slice(private_key).map_err(|_| SigError::InvalidPrivateKey)?;
+    Ok(PublicKey::from_secret_key(&secret_key).serialize_compressed().to_vec())
+}
+
+/// Checks compact 64 bytes `signature` of `signed_data` against compressed or uncompressed `public_key`, `secp256k1.verify` in JS
+///
+/// Fails like JS does when the public key can not be parsed, signatures with a high s are invalid.
+pub fn verify_compact_signature(signed_data: &Hash256, signature: &[u8], public_key: &[u8]) -> Result<bool, SigError> {
+    let signature = Signature::parse_slice(signature).map_err(|_| SigError::InvalidSignatureLength(signature.len()))?;
+    let public_key = PublicKey::parse_slice(public_key, None).map_err(|_| SigError::InvalidPublicKey)?;
+    if signature.s.is_high() {
+        return Ok(false);
+    }
+
+    Ok(secp256k1::verify(&Message::parse(signed_data.as_bytes()), &signature, &public_key))
+}
+
+/// Checks `raw_signature` of `signed_data` against compressed `public_key`, `verifySignature` of the script interpreter
+///
+/// The recovery id of the signature is ignored.
+pub fn verify_signature(signed_data: &Hash256, raw_signature: &[u8], public_key: &[u8]) -> bool {
+    if raw_signature.len() != SIGNATURE_LENGTH || public_key.len() != COMPRESSED_PUBLIC_KEY_LENGTH {
+        return false;
+    }
+
+    verify_compact_signature(signed_data, &raw_signature[..64], public_key).unwrap_or(false)
+}
+
+/// Hash signed by input spending `out_point` in `tx`, `generateDataToSignForSig` in JS
+pub fn generate_data_to_sign_for_sig(out_point: &OutPoint, tx: &Transaction) -> Hash256 {
+    out_point_output_hash(out_point, tx.get_outputs())
+}
+
+/// Signature of input spending `out_point` in `tx`, `txInputSignature` in JS
+///
+/// As in JS the hex form of the data to sign is what gets signed.
+pub fn tx_input_signature(out_point: &OutPoint, tx: &Transaction, private_key: &[u8]) -> Result<Vec<u8>, SigError> {
+    sign_data(generate_data_to_sign_for_sig(out_point, tx).to_hex().as_bytes(), private_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    use templates::{load_bc_tx_cb, BC_TX_CB};
+
+    const PRIVATE_KEY: &str = "e3b7f1c4a2d5960817f3b2a4c6d8e0f1a3b5c7d9e1f30517a9c2b4d6e8f0a1b2";
+    // crypto.createECDH('secp256k1').getPublicKey() in node without the 0x04 prefix
+    const PUBLIC_KEY: &str = "6eedccb30f83716aeb7b9c078266349de560543b9c00ef20848550629041e206\
+                              7257fcc621560b986fa17073fe9244cb3caa1363028030eb988adb32023fdc01";
+    const SIGNATURE_HELLO_WORLD: &str = "0498bc084d27d193e6340f7d6e8292a17f5697a363faa84ac18225345b852f30\
+                                         3d5648d12be86a62081db7cc2bbf6c8cd579690c95bae871a960ed6f2f6013e401";
+
+    #[test]
+    fn sign_data_recover_test() {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let signature = sign_data(b"hello world", &private_key).unwrap();
+        assert_eq!(signature.len(), SIGNATURE_LENGTH);
+
+        // signing is deterministic (RFC 6979) with low s, the same as secp256k1.sign in JS
+        assert_eq!(signature.to_hex(), SIGNATURE_HELLO_WORLD);
+
+        let public_key = pub_key_recover(&Hash256::blake2bl(b"hello world"), &signature).unwrap();
+        assert_eq!(public_key.to_hex(), PUBLIC_KEY);
+
+        let other = pub_key_recover(&Hash256::blake2bl(b"hello worlds"), &signature).unwrap();
+        assert_ne!(other.to_hex(), PUBLIC_KEY);
+    }
+
+    #[test]
+    fn sign_data_errors_test() {
+        assert_eq!(sign_data(b"data", &[0u8; 32]), Err(SigError::InvalidPrivateKey));
+        assert_eq!(sign_data(b"data", &[1u8; 31]), Err(SigError::InvalidPrivateKey));
+
+        let hash = Hash256::blake2bl(b"data");
+        assert_eq!(pub_key_recover(&hash, &[0u8; 64]), Err(SigError::InvalidSignatureLength(64)));
+
+        let mut signature = sign_data(b"data", &PRIVATE_KEY.from_hex().unwrap()).unwrap();
+        signature[64] = 7;
+        assert_eq!(pub_key_recover(&hash, &signature), Err(SigError::InvalidRecoveryId(7)));
+    }
+
+    #[test]
+    fn verify_signature_test() {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let public_key = public_key_create(&private_key).unwrap();
+        assert_eq!(public_key.len(), COMPRESSED_PUBLIC_KEY_LENGTH);
+        assert_eq!(public_key[1..].to_hex(), &PUBLIC_KEY[..64]);
+
+        let hash = Hash256::blake2bl(b"hello world");
+        let signature = SIGNATURE_HELLO_WORLD.from_hex().unwrap();
+        assert!(verify_signature(&hash, &signature, &public_key));
+        assert!(!verify_signature(&Hash256::blake2bl(b"hello worlds"), &signature, &public_key));
+        assert!(!verify_signature(&hash, &signature[..64], &public_key));
+        assert!(!verify_signature(&hash, &signature, &public_key[1..]));
+    }
+
+    #[test]
+    fn tx_input_signature_test() {
+        let tx = load_bc_tx_cb(BC_TX_CB);
+        let out_point = tx.get_inputs()[0].get_out_point();
+        let signature = tx_input_signature(out_point, &tx, &PRIVATE_KEY.from_hex().unwrap()).unwrap();
+
+        let signed_data = Hash256::blake2bl(generate_data_to_sign_for_sig(out_point, &tx).to_hex().as_bytes());
+        assert_eq!(pub_key_recover(&signed_data, &signature).unwrap().to_hex(), PUBLIC_KEY);
+    }
+}
@@ -30,3 +30,17 @@ pub const HASHES_EPHEMERALS: [&str; 7] = [
 ];
 
 // pub const XORED_HASHES: &str = "ed91019abe56ad047b1e9ce3a27b7645aea9b8fadd6fff86659355f102eacef";
+
+/// Child chains in `BlockchainHeaders` field order
+pub const BLOCKCHAINS: [&str; 5] = ["btc", "eth", "lsk", "neo", "wav"];
+
+/// Seconds between blocks of child chains in `BLOCKCHAINS` order, see src/rover/utils.es6
+pub const ROVER_SECONDS_PER_BLOCK: [u64; 5] = [600, 15, 10, 15, 65];
+
+/// Period of child chain history the node keeps in sync, 72h in seconds
+pub const ROVER_RESYNC_PERIOD: u64 = 72 * 60 * 60;
+
+// see src/bc/genesis.raw.es6
+pub const GENESIS_HEIGHT: u64 = 1;
+pub const GENESIS_CHILD_BLOCKCHAIN_COUNT: usize = 5;
+pub const GENESIS_BLOCKCHAIN_FINGERPRINTS_ROOT: &str = "d65ffda8a561b53c09377ef7d3ee9ebbf18a618c603faf2631c1bbb7d66a03ac";
@@ -0,0 +1,617 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Open and matched orders, `getOpenOrders`, `getMatchedOrders` and the taker side of `DexLib` in JS
+//!
+//! JS scans the blocks of the last week on every query, the book collects the orders as blocks
+//! are applied instead. Orders past their deposit window and matches past their settlement window
+//! are pruned, applying a block returns the `BookUndo` record to revert it in a reorg. Settlement
+//! on the other chains is not visible in BC blocks, settled outputs are marked with `mark_settled`.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str;
+
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+
+use coin::{biguint_to_internal, internal_to_biguint, internal_to_human, CoinFrac};
+use protos::bc::{CalculateTakerFeeRequest, MakerOrderInfo, MatchedOpenOrder, TakerOrderInfo};
+use protos::core::{BcBlock, OutPoint, TransactionInput, TransactionOutput};
+use tx::cross_chain::{MakerOrder, TakerInput, TakerOrder};
+use utxo::Utxo;
+
+use super::fees::{calculate_cross_chain_tx_fee, parse_nrg, CrossChainFee, Side};
+use super::DexError;
+
+/// Maker output not matched by a taker yet
+#[derive(Debug, Clone, PartialEq)]
+struct OpenOrder {
+    order: MakerOrder,
+    /// Maker output the order originates from, the remaining part of a partially matched order
+    /// is in a callback output
+    monoid: Utxo,
+    monoid_value: BigUint,
+    monoid_height: u64,
+    value: BigUint,
+    unit: BigUint,
+    block_hash: String,
+    block_height: u64,
+}
+
+impl OpenOrder {
+    /// Height `blocks` after the maker output, windows ending past the last height never end
+    fn window_end(&self, blocks: u64) -> u64 {
+        self.monoid_height.saturating_add(blocks)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Match {
+    maker_utxo: Utxo,
+    maker: OpenOrder,
+    taker_utxo: Utxo,
+    taker: TakerOrderInfo,
+}
+
+/// Input and outputs a taker transaction needs to match an open order
+#[derive(Debug, Clone, PartialEq)]
+pub struct TakerFill {
+    /// Input spending the maker output
+    pub input: TransactionInput,
+    /// Taker output locking collateral of both sides, followed by the callback output with the
+    /// remaining collateral of the maker if the order is matched partially
+    pub outputs: Vec<TransactionOutput>,
+}
+
+/// Maker output referenced by a callback script `<maker tx hash> <output index> OP_CALLBACK`
+pub fn parse_callback(script: &str) -> Option<Utxo> {
+    match *script.split(' ').collect::<Vec<_>>() {
+        [hash, index, "OP_CALLBACK"] => index.parse().ok().map(|index| Utxo::new(hash, index)),
+        _ => None,
+    }
+}
+
+/// Scales unit of a partially matched order, JS keeps the floating point result
+fn scale_unit(unit: &str, ratio: f64) -> String {
+    match unit.parse::<f64>() {
+        Ok(unit) => (unit * ratio).to_string(),
+        Err(_) => unit.to_string(),
+    }
+}
+
+/// Output locking `value` boson with `script`, `createTransactionOutput` in JS
+fn create_output(script: &str, unit: &BigUint, value: &BigUint) -> TransactionOutput {
+    let mut output = TransactionOutput::new();
+    output.set_value(biguint_to_internal(value));
+    output.set_unit(biguint_to_internal(unit));
+    output.set_script_length(script.len() as u32);
+    output.set_output_script(script.as_bytes().to_vec());
+    output
+}
+
+/// Changes of the book done by a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookUndo {
+    pub hash: String,
+    previous_hash: String,
+    previous_height: u64,
+    /// Open orders matched, closed or pruned by the block
+    removed_open: Vec<(Utxo, OpenOrder)>,
+    added_open: Vec<Utxo>,
+    /// Matches pruned by the block
+    removed_matched: Vec<Match>,
+    /// Taker outputs matching orders in the block
+    added_matched: Vec<Utxo>,
+    /// Settled outputs of the pruned matches
+    removed_settled: Vec<Utxo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OrderBook {
+    open: BTreeMap<Utxo, OpenOrder>,
+    /// Matches by taker output
+    matched: BTreeMap<Utxo, Match>,
+    settled: HashSet<Utxo>,
+    best_block_hash: String,
+    height: u64,
+}
+
+impl OrderBook {
+    pub fn new() -> OrderBook {
+        OrderBook::default()
+    }
+
+    /// Height of the last applied block
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Collects maker orders of `block` and matches of its taker transactions
+    ///
+    /// Maker outputs spent without a taker output referencing them are closed. Orders and matches
+    /// which expire at the height of the block are pruned.
+    pub fn apply_block(&mut self, block: &BcBlock) -> BookUndo {
+        let mut undo = BookUndo {
+            hash: block.get_hash().to_string(),
+            previous_hash: self.best_block_hash.clone(),
+            previous_height: self.height,
+            removed_open: Vec::new(),
+            added_open: Vec::new(),
+            removed_matched: Vec::new(),
+            added_matched: Vec::new(),
+            removed_settled: Vec::new(),
+        };
+        self.best_block_hash = block.get_hash().to_string();
+        self.height = block.get_height();
+        for tx in block.get_txs() {
+            let mut taken = HashMap::new();
+            for input in tx.get_inputs() {
+                let utxo = Utxo::from_out_point(input.get_out_point());
+                if let Some(order) = self.open.remove(&utxo) {
+                    undo.removed_open.push((utxo.clone(), order.clone()));
+                    taken.insert(utxo, (order, input));
+                }
+            }
+
+            for (index, output) in tx.get_outputs().iter().enumerate() {
+                let script = match str::from_utf8(output.get_output_script()) {
+                    Ok(script) => script,
+                    Err(_) => continue,
+                };
+                let utxo = Utxo::new(tx.get_hash(), index as u64);
+                let value = internal_to_biguint(output.get_value());
+                let unit = internal_to_biguint(output.get_unit());
+
+                if script.starts_with("OP_MONOID") {
+                    if let Ok(order) = MakerOrder::parse(script) {
+                        let order = OpenOrder {
+                            order,
+                            monoid: utxo.clone(),
+                            monoid_value: value.clone(),
+                            monoid_height: block.get_height(),
+                            value,
+                            unit,
+                            block_hash: block.get_hash().to_string(),
+                            block_height: block.get_height(),
+                        };
+                        self.open.insert(utxo.clone(), order);
+                        undo.added_open.push(utxo);
+                    }
+                } else if let Ok(taker) = TakerOrder::parse(script) {
+                    let maker_utxo = Utxo::new(&taker.maker_tx_hash, u64::from(taker.maker_tx_output_index));
+                    if let Some(&(ref maker, input)) = taken.get(&maker_utxo) {
+                        let input_script = str::from_utf8(input.get_input_script()).unwrap_or("");
+                        let mut info = TakerOrderInfo::new();
+                        if let Ok(taker_input) = TakerInput::parse(input_script) {
+                            info.set_sends_address(taker_input.sends_address);
+                            info.set_wants_address(taker_input.wants_address);
+                        }
+                        info.set_maker_tx_hash(taker.maker_tx_hash);
+                        info.set_maker_tx_output_index(taker.maker_tx_output_index);
+                        info.set_double_hashed_bc_address(taker.double_hashed_bc_address);
+                        info.set_collateralized_nrg(internal_to_human(output.get_value(), CoinFrac::Nrg));
+                        info.set_block_hash(block.get_hash().to_string());
+                        info.set_block_height(block.get_height());
+                        info.set_tx_hash(tx.get_hash().to_string());
+                        let matched = Match { maker_utxo, maker: maker.clone(), taker_utxo: utxo.clone(), taker: info };
+                        self.matched.insert(utxo.clone(), matched);
+                        undo.added_matched.push(utxo);
+                    }
+                } else if let Some(maker_utxo) = parse_callback(script) {
+                    if let Some((maker, _)) = taken.get(&maker_utxo) {
+                        let order = OpenOrder {
+                            value,
+                            unit,
+                            block_hash: block.get_hash().to_string(),
+                            block_height: block.get_height(),
+                            ..maker.clone()
+                        };
+                        self.open.insert(utxo.clone(), order);
+                        undo.added_open.push(utxo);
+                    }
+                }
+            }
+        }
+
+        self.prune(&mut undo);
+        undo
+    }
+
+    /// Drops open orders past their deposit window and matches past their settlement window
+    /// together with their settled outputs
+    fn prune(&mut self, undo: &mut BookUndo) {
+        let height = self.height;
+        let expired: Vec<Utxo> = self
+            .open
+            .iter()
+            .filter(|&(_, order)| height > order.window_end(order.order.deposit_ends_at))
+            .map(|(utxo, _)| utxo.clone())
+            .collect();
+        for utxo in expired {
+            if let Some(order) = self.open.remove(&utxo) {
+                undo.removed_open.push((utxo, order));
+            }
+        }
+
+        let expired: Vec<Utxo> = self
+            .matched
+            .iter()
+            .filter(|&(_, matched)| !self.is_in_settle_window(&matched.maker))
+            .map(|(utxo, _)| utxo.clone())
+            .collect();
+        for utxo in expired {
+            if let Some(matched) = self.matched.remove(&utxo) {
+                for settled in &[&matched.maker_utxo, &matched.taker_utxo] {
+                    if self.settled.remove(*settled) {
+                        undo.removed_settled.push((*settled).clone());
+                    }
+                }
+                undo.removed_matched.push(matched);
+            }
+        }
+    }
+
+    /// Disconnects the last applied block using the `undo` record returned when it was applied
+    pub fn revert_block(&mut self, undo: &BookUndo) -> Result<(), DexError> {
+        if undo.hash != self.best_block_hash {
+            return Err(DexError::UndoMismatch { best_block_hash: self.best_block_hash.clone(), undo_hash: undo.hash.clone() });
+        }
+
+        // restored first as callback outputs matched in the same block are in `added_open` as well
+        for (utxo, order) in undo.removed_open.iter().rev() {
+            self.open.insert(utxo.clone(), order.clone());
+        }
+        for utxo in &undo.added_open {
+            self.open.remove(utxo);
+        }
+        for matched in &undo.removed_matched {
+            self.matched.insert(matched.taker_utxo.clone(), matched.clone());
+        }
+        for utxo in &undo.added_matched {
+            self.matched.remove(utxo);
+        }
+        self.settled.extend(undo.removed_settled.iter().cloned());
+
+        self.best_block_hash = undo.previous_hash.clone();
+        self.height = undo.previous_height;
+        Ok(())
+    }
+
+    /// Marks output `utxo` of a maker or taker as settled on the other chain
+    pub fn mark_settled(&mut self, utxo: Utxo) {
+        self.settled.insert(utxo);
+    }
+
+    fn is_in_deposit_window(&self, order: &OpenOrder) -> bool {
+        order.window_end(order.order.shift_starts_at) <= self.height &&
+            self.height <= order.window_end(order.order.deposit_ends_at)
+    }
+
+    fn is_in_settle_window(&self, order: &OpenOrder) -> bool {
+        self.height <= order.window_end(order.order.settle_ends_at)
+    }
+
+    /// Order info of `order` in maker output `utxo`, `formatTradeInfoForOpenOrders` in JS
+    fn maker_info(&self, utxo: &Utxo, order: &OpenOrder) -> MakerOrderInfo {
+        let (wants_unit, pays_unit) = if *utxo == order.monoid {
+            (order.order.wants_unit.clone(), order.order.pays_unit.clone())
+        } else {
+            let ratio = order.value.to_f64().unwrap_or(0.0) / order.monoid_value.to_f64().unwrap_or(0.0);
+            (scale_unit(&order.order.wants_unit, ratio), scale_unit(&order.order.pays_unit, ratio))
+        };
+
+        let mut info = MakerOrderInfo::new();
+        info.set_shift_starts_at(order.order.shift_starts_at);
+        info.set_deposit_ends_at(order.order.deposit_ends_at);
+        info.set_settle_ends_at(order.order.settle_ends_at);
+        info.set_pays_chain_id(order.order.pays_chain_id.clone());
+        info.set_wants_chain_id(order.order.wants_chain_id.clone());
+        info.set_wants_address(order.order.wants_address.clone());
+        info.set_wants_unit(wants_unit);
+        info.set_pays_unit(pays_unit);
+        info.set_double_hashed_bc_address(order.order.double_hashed_bc_address.clone());
+        info.set_collateralized_nrg(internal_to_human(&biguint_to_internal(&order.value), CoinFrac::Nrg));
+        info.set_nrg_unit(internal_to_human(&biguint_to_internal(&order.unit), CoinFrac::Nrg));
+        info.set_tx_hash(utxo.tx_hash.clone());
+        info.set_tx_output_index(utxo.index);
+        info.set_block_hash(order.block_hash.clone());
+        info.set_block_height(order.block_height);
+        info.set_is_settled(self.settled.contains(utxo));
+        info.set_block_height_has_original_maker_tx(order.monoid_height);
+        info
+    }
+
+    /// Orders which can be matched at the current height, `getOpenOrders` in JS
+    pub fn open_orders(&self) -> Vec<MakerOrderInfo> {
+        self.open
+            .iter()
+            .filter(|&(_, order)| self.is_in_deposit_window(order))
+            .map(|(utxo, order)| self.maker_info(utxo, order))
+            .collect()
+    }
+
+    /// Matched orders which can still be settled, `getMatchedOrders` in JS
+    ///
+    /// With `only_settled` only orders settled by at least one side are returned.
+    pub fn matched_orders(&self, only_settled: bool) -> Vec<MatchedOpenOrder> {
+        self.matched
+            .values()
+            .filter(|matched| self.is_in_settle_window(&matched.maker))
+            .filter(|matched| {
+                !only_settled || self.settled.contains(&matched.maker_utxo) || self.settled.contains(&matched.taker_utxo)
+            })
+            .map(|matched| {
+                let mut taker = matched.taker.clone();
+                taker.set_is_settled(self.settled.contains(&matched.taker_utxo));
+                let mut order = MatchedOpenOrder::new();
+                order.set_maker(self.maker_info(&matched.maker_utxo, &matched.maker));
+                order.set_taker(taker);
+                order
+            })
+            .collect()
+    }
+
+    /// Open order `maker` if a taker can match `collateralized` boson of it, see `getMakerData` in JS
+    fn matchable_order(&self, maker: &Utxo, collateralized: &BigUint) -> Result<&OpenOrder, DexError> {
+        let order = self.open.get(maker).ok_or_else(|| DexError::UnknownOrder(maker.clone()))?;
+        if !self.is_in_deposit_window(order) {
+            return Err(DexError::OutsideDepositWindow(maker.clone()));
+        }
+        if order.unit.is_zero() || !(collateralized % &order.unit).is_zero() {
+            return Err(DexError::NotDivisible { collateralized: collateralized.to_string(), unit: order.unit.to_string() });
+        }
+        if *collateralized > order.value {
+            return Err(DexError::ExceedsCollateral {
+                collateralized: collateralized.to_string(),
+                available: order.value.to_string(),
+            });
+        }
+        Ok(order)
+    }
+
+    /// Fee of matching the order of `request` at the current height, `calculateTakerFee` in JS
+    pub fn calculate_taker_fee(&self, request: &CalculateTakerFeeRequest) -> Result<CrossChainFee, DexError> {
+        let collateralized = parse_nrg(request.get_collateralized_nrg())?;
+        let maker = Utxo::new(request.get_maker_tx_hash(), u64::from(request.get_maker_tx_output_index()));
+        let order = self.matchable_order(&maker, &collateralized)?;
+
+        let settle_height = order.window_end(order.order.shift_starts_at).saturating_add(order.order.settle_ends_at);
+        let block_window = settle_height.saturating_sub(self.height);
+        let tx_fee = calculate_cross_chain_tx_fee(&collateralized, block_window, self.height, Side::Taker)?;
+        let total = &tx_fee + collateralized;
+        Ok(CrossChainFee { tx_fee, total })
+    }
+
+    /// Input and outputs matching `collateralized` boson of the open order `maker`, `getMakerInputsAndOutpointForTaker` in JS
+    pub fn fill(
+        &self,
+        maker: &Utxo,
+        collateralized: &BigUint,
+        taker_input: &TakerInput,
+        taker_bc_address: &str,
+    ) -> Result<TakerFill, DexError> {
+        let order = self.matchable_order(maker, collateralized)?;
+
+        let taker = TakerOrder::new(&maker.tx_hash, maker.index as u32, taker_bc_address);
+        let mut outputs = vec![create_output(&taker.to_script(), &order.unit, &(collateralized * BigUint::from(2u32)))];
+        let remaining = &order.value - collateralized;
+        if !remaining.is_zero() {
+            outputs.push(create_output(&taker.to_callback_script(), &order.unit, &remaining));
+        }
+
+        let mut out_point = OutPoint::new();
+        out_point.set_value(biguint_to_internal(&order.value));
+        out_point.set_hash(maker.tx_hash.clone());
+        out_point.set_index(maker.index);
+        let script = taker_input.to_script();
+        let mut input = TransactionInput::new();
+        input.set_out_point(out_point);
+        input.set_script_length(script.len() as u32);
+        input.set_input_script(script.into_bytes());
+
+        Ok(TakerFill { input, outputs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use protos::core::Transaction;
+    use tx::double_hashed_address;
+
+    const MAKER_ADDRESS: &str = "0x25cc7722a6378e11082f7967c09b98bd26c979d3";
+    const TAKER_ADDRESS: &str = "0xea674fdde714fd979de3edf0f56aa9716b898ec8";
+
+    fn nrg(amount: &str) -> BigUint {
+        parse_nrg(amount).unwrap()
+    }
+
+    fn block(height: u64, txs: Vec<Transaction>) -> BcBlock {
+        let mut block = BcBlock::new();
+        block.set_hash(format!("b{}", height));
+        block.set_height(height);
+        block.set_txs(RepeatedField::from_vec(txs));
+        block
+    }
+
+    fn tx(hash: &str, inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Transaction {
+        let mut tx = Transaction::new();
+        tx.set_hash(hash.to_string());
+        tx.set_nin_count(inputs.len() as u64);
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx.set_nout_count(outputs.len() as u64);
+        tx.set_outputs(RepeatedField::from_vec(outputs));
+        tx
+    }
+
+    fn taker_input() -> TakerInput {
+        TakerInput { wants_address: "0xwants".to_string(), sends_address: "0xsends".to_string() }
+    }
+
+    /// Book with a 10 NRG maker order placed at height 10
+    fn book() -> OrderBook {
+        let script = MakerOrder::new(0, 450, 800, "eth", "btc", "1boat", "0.5", "10", MAKER_ADDRESS).to_script();
+        let mut book = OrderBook::new();
+        book.apply_block(&block(10, vec![tx("m1", vec![], vec![create_output(&script, &nrg("1"), &nrg("10"))])]));
+        book
+    }
+
+    #[test]
+    fn open_orders_test() {
+        let book = book();
+        let orders = book.open_orders();
+        assert_eq!(orders.len(), 1);
+        let order = &orders[0];
+        assert_eq!((order.get_tx_hash(), order.get_tx_output_index()), ("m1", 0));
+        assert_eq!((order.get_pays_chain_id(), order.get_wants_chain_id()), ("eth", "btc"));
+        assert_eq!((order.get_wants_unit(), order.get_pays_unit()), ("0.5", "10"));
+        assert_eq!((order.get_collateralized_nrg(), order.get_nrg_unit()), ("10", "1"));
+        assert_eq!(order.get_double_hashed_bc_address(), double_hashed_address(MAKER_ADDRESS));
+        assert_eq!((order.get_block_hash(), order.get_block_height()), ("b10", 10));
+        assert_eq!(order.get_block_height_has_original_maker_tx(), 10);
+        assert!(!order.get_is_settled());
+    }
+
+    #[test]
+    fn unbounded_window_test() {
+        // windows of peer scripts ending past the last height never end instead of overflowing
+        let max = u64::max_value();
+        let script = MakerOrder::new(0, max, max, "eth", "btc", "1boat", "0.5", "10", MAKER_ADDRESS).to_script();
+        let mut book = OrderBook::new();
+        book.apply_block(&block(10, vec![tx("m1", vec![], vec![create_output(&script, &nrg("1"), &nrg("10"))])]));
+        book.apply_block(&block(3000000, vec![]));
+        assert_eq!(book.open_orders().len(), 1);
+
+        let mut request = CalculateTakerFeeRequest::new();
+        request.set_maker_tx_hash("m1".to_string());
+        request.set_maker_tx_output_index(0);
+        request.set_collateralized_nrg("4".to_string());
+        assert_eq!(book.calculate_taker_fee(&request), Err(DexError::BlockWindowTooLarge(max - 3000000)));
+    }
+
+    #[test]
+    fn fill_test() {
+        let book = book();
+        let maker = Utxo::new("m1", 0);
+        let fill = book.fill(&maker, &nrg("4"), &taker_input(), TAKER_ADDRESS).unwrap();
+
+        assert_eq!(fill.input.get_out_point().get_hash(), "m1");
+        assert_eq!(fill.input.get_out_point().get_value(), &biguint_to_internal(&nrg("10"))[..]);
+        assert_eq!(fill.input.get_input_script(), b"0xwants 0xsends");
+        assert_eq!(fill.outputs.len(), 2);
+        let taker = TakerOrder::new("m1", 0, TAKER_ADDRESS);
+        assert_eq!(fill.outputs[0].get_output_script(), taker.to_script().as_bytes());
+        assert_eq!(internal_to_biguint(fill.outputs[0].get_value()), nrg("8"));
+        assert_eq!(fill.outputs[1].get_output_script(), b"m1 0 OP_CALLBACK");
+        assert_eq!(internal_to_biguint(fill.outputs[1].get_value()), nrg("6"));
+        assert_eq!(internal_to_biguint(fill.outputs[1].get_unit()), nrg("1"));
+
+        let fill = book.fill(&maker, &nrg("10"), &taker_input(), TAKER_ADDRESS).unwrap();
+        assert_eq!(fill.outputs.len(), 1);
+
+        assert_eq!(
+            book.fill(&maker, &nrg("4.5"), &taker_input(), TAKER_ADDRESS),
+            Err(DexError::NotDivisible { collateralized: nrg("4.5").to_string(), unit: nrg("1").to_string() })
+        );
+        assert_eq!(
+            book.fill(&maker, &nrg("11"), &taker_input(), TAKER_ADDRESS),
+            Err(DexError::ExceedsCollateral { collateralized: nrg("11").to_string(), available: nrg("10").to_string() })
+        );
+        let unknown = Utxo::new("m1", 1);
+        assert_eq!(book.fill(&unknown, &nrg("4"), &taker_input(), TAKER_ADDRESS), Err(DexError::UnknownOrder(unknown)));
+    }
+
+    #[test]
+    fn calculate_taker_fee_test() {
+        let book = book();
+        let mut request = CalculateTakerFeeRequest::new();
+        request.set_maker_tx_hash("m1".to_string());
+        request.set_maker_tx_output_index(0);
+        request.set_collateralized_nrg("4".to_string());
+        assert_eq!(book.calculate_taker_fee(&request), Ok(CrossChainFee { tx_fee: nrg("0.01"), total: nrg("4.01") }));
+    }
+
+    #[test]
+    fn match_orders_test() {
+        let mut book = book();
+        let maker = Utxo::new("m1", 0);
+        let fill = book.fill(&maker, &nrg("4"), &taker_input(), TAKER_ADDRESS).unwrap();
+        book.apply_block(&block(11, vec![tx("t1", vec![fill.input], fill.outputs)]));
+
+        let orders = book.open_orders();
+        assert_eq!(orders.len(), 1);
+        let order = &orders[0];
+        assert_eq!((order.get_tx_hash(), order.get_tx_output_index()), ("t1", 1));
+        assert_eq!((order.get_wants_unit(), order.get_pays_unit()), ("0.3", "6"));
+        assert_eq!(order.get_collateralized_nrg(), "6");
+        assert_eq!((order.get_block_height(), order.get_block_height_has_original_maker_tx()), (11, 10));
+
+        let matched = book.matched_orders(false);
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].get_maker().get_tx_hash(), "m1");
+        assert_eq!(matched[0].get_maker().get_collateralized_nrg(), "10");
+        let taker = matched[0].get_taker();
+        assert_eq!((taker.get_wants_address(), taker.get_sends_address()), ("0xwants", "0xsends"));
+        assert_eq!((taker.get_maker_tx_hash(), taker.get_maker_tx_output_index()), ("m1", 0));
+        assert_eq!(taker.get_double_hashed_bc_address(), double_hashed_address(TAKER_ADDRESS));
+        assert_eq!((taker.get_tx_hash(), taker.get_collateralized_nrg()), ("t1", "8"));
+        assert!(!taker.get_is_settled());
+
+        assert!(book.matched_orders(true).is_empty());
+        book.mark_settled(Utxo::new("t1", 0));
+        let matched = book.matched_orders(true);
+        assert_eq!(matched.len(), 1);
+        assert!(matched[0].get_taker().get_is_settled());
+        assert!(!matched[0].get_maker().get_is_settled());
+
+        book.apply_block(&block(461, vec![]));
+        assert!(book.open_orders().is_empty());
+        assert_eq!(
+            book.fill(&Utxo::new("t1", 1), &nrg("1"), &taker_input(), TAKER_ADDRESS),
+            Err(DexError::UnknownOrder(Utxo::new("t1", 1)))
+        );
+        assert_eq!(book.matched_orders(false).len(), 1);
+        book.apply_block(&block(811, vec![]));
+        assert!(book.matched_orders(false).is_empty());
+        assert!(book.matched.is_empty() && book.settled.is_empty());
+    }
+
+    #[test]
+    fn revert_block_test() {
+        let mut book = book();
+        let after_maker = book.clone();
+        let maker = Utxo::new("m1", 0);
+        let fill = book.fill(&maker, &nrg("4"), &taker_input(), TAKER_ADDRESS).unwrap();
+        let undo = book.apply_block(&block(11, vec![tx("t1", vec![fill.input], fill.outputs)]));
+        assert_eq!(book.open_orders()[0].get_tx_hash(), "t1");
+
+        // reorg replacing the partial match with a full one
+        let mut fork = block(11, vec![]);
+        fork.set_hash("c11".to_string());
+        assert_eq!(
+            book.revert_block(&BookUndo { hash: "c11".to_string(), ..undo.clone() }),
+            Err(DexError::UndoMismatch { best_block_hash: "b11".to_string(), undo_hash: "c11".to_string() })
+        );
+        book.revert_block(&undo).unwrap();
+        assert_eq!(book, after_maker);
+        let fill = book.fill(&maker, &nrg("10"), &taker_input(), TAKER_ADDRESS).unwrap();
+        fork.set_txs(RepeatedField::from_vec(vec![tx("t2", vec![fill.input], fill.outputs)]));
+        book.apply_block(&fork);
+        assert!(book.open_orders().is_empty());
+        let matched = book.matched_orders(false);
+        assert_eq!(matched.len(), 1);
+        assert_eq!((matched[0].get_taker().get_tx_hash(), matched[0].get_taker().get_collateralized_nrg()), ("t2", "20"));
+
+        // pruned matches and their settled outputs come back with the block pruning them
+        book.mark_settled(Utxo::new("t2", 0));
+        let before_expiry = book.clone();
+        let undo = book.apply_block(&block(811, vec![]));
+        assert!(book.matched.is_empty() && book.settled.is_empty());
+        book.revert_block(&undo).unwrap();
+        assert_eq!(book, before_expiry);
+        assert_eq!(book.matched_orders(true).len(), 1);
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Fees of maker and taker transactions, `calculateMakerFee` and `calculateCrossChainTxFee` in JS
+use num_bigint::BigUint;
+use num_traits::Zero;
+
+use coin::{human_to_biguint, CoinFrac};
+use protos::bc::CalculateMakerFeeRequest;
+
+use super::{DexError, LOOK_BACK_BC_HEIGHT_FOR_OPEN_ORDER};
+
+/// Fees are flat until this height
+const FLAT_FEE_HEIGHT: u64 = 2000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Maker,
+    Taker,
+}
+
+/// Fee of a cross chain transaction and the NRG it spends in total, `{ txFee, totalNRG }` in JS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossChainFee {
+    pub tx_fee: BigUint,
+    /// Fee together with the collateralized NRG
+    pub total: BigUint,
+}
+
+/// Boson in `amount` of NRG given by the user, `humanToBN(amount, NRG)` in JS
+pub fn parse_nrg(amount: &str) -> Result<BigUint, DexError> {
+    human_to_biguint(amount, CoinFrac::Nrg).ok_or_else(|| DexError::InvalidAmount(amount.to_string()))
+}
+
+fn nrg(amount: &str) -> BigUint {
+    human_to_biguint(amount, CoinFrac::Nrg).expect("fee constants are valid amounts")
+}
+
+/// Fee of a transaction locking `collateralized` boson for `block_window` blocks, `calculateCrossChainTxFee` in JS
+///
+/// The taker pays 0.1% of the collateral. JS multiplies the amount of boson as a float and parses
+/// the result as NRG, the fee here stays in boson.
+pub fn calculate_cross_chain_tx_fee(
+    collateralized: &BigUint,
+    block_window: u64,
+    latest_height: u64,
+    side: Side,
+) -> Result<BigUint, DexError> {
+    if latest_height < FLAT_FEE_HEIGHT {
+        // encourage makers
+        return Ok(match side {
+            Side::Maker => nrg("0.001"),
+            Side::Taker => nrg("0.01"),
+        });
+    }
+
+    if block_window > LOOK_BACK_BC_HEIGHT_FOR_OPEN_ORDER {
+        return Err(DexError::BlockWindowTooLarge(block_window));
+    }
+
+    Ok(match side {
+        Side::Maker => nrg("0.002"),
+        Side::Taker => collateralized / BigUint::from(1000u32),
+    })
+}
+
+fn is_negative(unit: &str) -> bool {
+    unit.parse::<f64>().map(|unit| unit < 0.0).unwrap_or(false)
+}
+
+/// Checks parameters of a maker order, `makerParamsCheck` in JS
+pub fn maker_params_check(
+    deposit_ends_at: u64,
+    settle_ends_at: u64,
+    wants_unit: &str,
+    pays_unit: &str,
+    collateralized: &BigUint,
+    nrg_unit: &BigUint,
+) -> Result<(), DexError> {
+    if nrg_unit.is_zero() || !(collateralized % nrg_unit).is_zero() {
+        return Err(DexError::NotDivisible { collateralized: collateralized.to_string(), unit: nrg_unit.to_string() });
+    }
+    if deposit_ends_at >= settle_ends_at {
+        return Err(DexError::DepositAfterSettle { deposit_ends_at, settle_ends_at });
+    }
+    if is_negative(pays_unit) {
+        return Err(DexError::NegativeUnit(pays_unit.to_string()));
+    }
+    if is_negative(wants_unit) {
+        return Err(DexError::NegativeUnit(wants_unit.to_string()));
+    }
+    Ok(())
+}
+
+/// Fee of placing the maker order of `request` at `latest_height`, `calculateMakerFee` in JS
+pub fn calculate_maker_fee(request: &CalculateMakerFeeRequest, latest_height: u64) -> Result<CrossChainFee, DexError> {
+    let collateralized = parse_nrg(request.get_collateralized_nrg())?;
+    let nrg_unit = parse_nrg(request.get_nrg_unit())?;
+    maker_params_check(
+        request.get_deposit_ends_at(),
+        request.get_settle_ends_at(),
+        request.get_wants_unit(),
+        request.get_pays_unit(),
+        &collateralized,
+        &nrg_unit,
+    )?;
+
+    let block_window = request.get_settle_ends_at().saturating_sub(request.get_shift_starts_at());
+    let tx_fee = calculate_cross_chain_tx_fee(&collateralized, block_window, latest_height, Side::Maker)?;
+    let total = &tx_fee + collateralized;
+    Ok(CrossChainFee { tx_fee, total })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> CalculateMakerFeeRequest {
+        let mut request = CalculateMakerFeeRequest::new();
+        request.set_shift_starts_at(0);
+        request.set_deposit_ends_at(450);
+        request.set_settle_ends_at(800);
+        request.set_pays_with_chain_id("eth".to_string());
+        request.set_pays_unit("10".to_string());
+        request.set_wants_chain_id("btc".to_string());
+        request.set_wants_unit("0.5".to_string());
+        request.set_collateralized_nrg("2.5".to_string());
+        request.set_nrg_unit("0.5".to_string());
+        request
+    }
+
+    #[test]
+    fn calculate_cross_chain_tx_fee_test() {
+        let collateralized = nrg("20");
+        assert_eq!(calculate_cross_chain_tx_fee(&collateralized, 800, 1000, Side::Maker), Ok(nrg("0.001")));
+        assert_eq!(calculate_cross_chain_tx_fee(&collateralized, 800, 1000, Side::Taker), Ok(nrg("0.01")));
+        assert_eq!(calculate_cross_chain_tx_fee(&collateralized, 800, FLAT_FEE_HEIGHT, Side::Maker), Ok(nrg("0.002")));
+        assert_eq!(calculate_cross_chain_tx_fee(&collateralized, 800, FLAT_FEE_HEIGHT, Side::Taker), Ok(nrg("0.02")));
+        assert_eq!(
+            calculate_cross_chain_tx_fee(&collateralized, 120961, FLAT_FEE_HEIGHT, Side::Taker),
+            Err(DexError::BlockWindowTooLarge(120961))
+        );
+    }
+
+    #[test]
+    fn calculate_maker_fee_test() {
+        let fee = calculate_maker_fee(&request(), 1000).unwrap();
+        assert_eq!(fee, CrossChainFee { tx_fee: nrg("0.001"), total: nrg("2.501") });
+
+        let mut request = request();
+        request.set_nrg_unit("0.3".to_string());
+        assert_eq!(
+            calculate_maker_fee(&request, 1000),
+            Err(DexError::NotDivisible { collateralized: nrg("2.5").to_string(), unit: nrg("0.3").to_string() })
+        );
+        request.set_nrg_unit("0.5".to_string());
+        request.set_settle_ends_at(450);
+        assert_eq!(
+            calculate_maker_fee(&request, 1000),
+            Err(DexError::DepositAfterSettle { deposit_ends_at: 450, settle_ends_at: 450 })
+        );
+        request.set_settle_ends_at(800);
+        request.set_wants_unit("-1".to_string());
+        assert_eq!(calculate_maker_fee(&request, 1000), Err(DexError::NegativeUnit("-1".to_string())));
+        request.set_collateralized_nrg("all".to_string());
+        assert_eq!(calculate_maker_fee(&request, 1000), Err(DexError::InvalidAmount("all".to_string())));
+    }
+}
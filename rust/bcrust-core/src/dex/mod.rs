@@ -0,0 +1,63 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Exchange of NRG collateral against assets of other chains, port of src/core/dexLib.es6 and
+//! src/core/dexUtils.es6
+//!
+//! Makers lock collateralized NRG in an output with the maker script, takers match an open order
+//! by spending that output and locking the same amount. A taker may match only a part of the
+//! collateral, the rest stays open in a callback output referencing the maker output.
+pub mod book;
+pub mod fees;
+pub mod settlement;
+
+use std::error::Error;
+use std::fmt;
+
+use utxo::Utxo;
+
+/// Block window of open orders, about a week of blocks
+pub const LOOK_BACK_BC_HEIGHT_FOR_OPEN_ORDER: u64 = 7 * 24 * 3600 / 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DexError {
+    InvalidAmount(String),
+    NotDivisible { collateralized: String, unit: String },
+    DepositAfterSettle { deposit_ends_at: u64, settle_ends_at: u64 },
+    NegativeUnit(String),
+    BlockWindowTooLarge(u64),
+    UnknownOrder(Utxo),
+    OutsideDepositWindow(Utxo),
+    ExceedsCollateral { collateralized: String, available: String },
+    UndoMismatch { best_block_hash: String, undo_hash: String },
+    InvalidSettlementEntry(String),
+}
+
+impl fmt::Display for DexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DexError::InvalidAmount(ref amount) => write!(f, "invalid amount '{}'", amount),
+            DexError::NotDivisible { ref collateralized, ref unit } =>
+                write!(f, "collateralized NRG {} is not divisible by NRG unit {}", collateralized, unit),
+            DexError::DepositAfterSettle { deposit_ends_at, settle_ends_at } =>
+                write!(f, "deposit ({}) should happen before settle ({})", deposit_ends_at, settle_ends_at),
+            DexError::NegativeUnit(ref unit) => write!(f, "unit {} is less than 0", unit),
+            DexError::BlockWindowTooLarge(window) => write!(f, "block window {} is too large", window),
+            DexError::UnknownOrder(ref utxo) => write!(f, "output {} is not an open maker order", utxo),
+            DexError::OutsideDepositWindow(ref utxo) => write!(f, "maker order {} is not in deposit window", utxo),
+            DexError::ExceedsCollateral { ref collateralized, ref available } =>
+                write!(f, "taker collateralized NRG {} is greater than the amount of maker {}", collateralized, available),
+            DexError::UndoMismatch { ref best_block_hash, ref undo_hash } =>
+                write!(f, "undo record of block {} does not revert best block {}", undo_hash, best_block_hash),
+            DexError::InvalidSettlementEntry(ref key) => write!(f, "invalid settlement entry {}", key),
+        }
+    }
+}
+
+impl Error for DexError {
+    fn description(&self) -> &str {
+        "dex error"
+    }
+}
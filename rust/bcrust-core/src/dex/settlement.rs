@@ -0,0 +1,936 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Settlement of matched orders on the bridged chains, port of src/bc/unsettledTxManager.es6
+//!
+//! Both sides of a matched order pay on the chain the other side wants. The payments show up as
+//! marked transactions in the headers of BC blocks, a payment from the address the taker promised
+//! to send from to the address the maker wants settles the taker output and the other way round.
+//! JS keeps the state in rocksdb under `maker.wants.*`, `taker.wants.*` and `settle.tx.*` keys.
+//! The store persists the entries of the tracker with the changes of every block to revert it, see
+//! `store::settlement`.
+use std::collections::{HashMap, HashSet};
+use std::str;
+
+use rustc_serialize::json::{Json, Object};
+
+use data::BLOCKCHAINS;
+use mining::primitives::blockchain_headers_to_lists;
+use protos::core::{BcBlock, MarkedTransaction};
+use protos::rover::{SettleTxCheckReq, SettleTxCheckResponse};
+use tx::cross_chain::{MakerOrder, TakerInput, TakerOrder};
+use utxo::{SettleLookup, Utxo};
+
+use super::book::parse_callback;
+use super::DexError;
+
+/// Maker order the output of a maker or callback transaction originates from
+#[derive(Debug, Clone, PartialEq)]
+struct Origin {
+    order: MakerOrder,
+    /// Output of the maker transaction placing the order
+    maker_utxo: Utxo,
+    settle_ends_at: u64,
+}
+
+/// Taker which has to pay the maker, `takers` of `maker.wants.*` in JS
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TakerToSettle {
+    taker_utxo: Utxo,
+    sends_address: String,
+    settled: bool,
+}
+
+/// Payments expected by a maker order wanting the assets at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MakerWatch {
+    maker_utxo: Utxo,
+    settle_ends_at: u64,
+    takers: Vec<TakerToSettle>,
+}
+
+/// Payment expected by the taker wanting the assets at an address, `taker.wants.*` in JS
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TakerWatch {
+    settle_ends_at: u64,
+    maker_utxo: Utxo,
+    settled: bool,
+}
+
+/// Addresses of a bridged chain watched for marked transactions, `setMarkedWatch` in JS
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MarkedWatch {
+    pub callback: Utxo,
+    pub bridged_chain: String,
+    /// Height the watch is dropped at
+    pub bc_settle_window: u64,
+    pub to_addr: String,
+    pub bind_from_addr: Option<String>,
+}
+
+/// Entry of the tracker the store persists under its own key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SettlementKey {
+    /// Maker order of a maker or callback output
+    Origin(Utxo),
+    /// Payments expected by the maker orders wanting assets of a chain at an address
+    Maker(String, String),
+    /// Payment expected by the taker wanting assets of a chain at an address
+    Taker(String, String),
+    /// Watches of an address of a chain
+    Watches(String, String),
+    /// Settled maker or taker output
+    Settled(Utxo),
+}
+
+impl SettlementKey {
+    /// Key relative to the prefix of the store, `maker.<chain>.<address>` for a maker
+    pub fn to_key(&self) -> String {
+        match *self {
+            SettlementKey::Origin(ref utxo) => format!("origin.{}", utxo),
+            SettlementKey::Maker(ref chain, ref address) => format!("maker.{}.{}", chain, address),
+            SettlementKey::Taker(ref chain, ref address) => format!("taker.{}.{}", chain, address),
+            SettlementKey::Watches(ref chain, ref address) => format!("watch.{}.{}", chain, address),
+            SettlementKey::Settled(ref utxo) => format!("tx.{}", utxo),
+        }
+    }
+
+    pub fn parse(key: &str) -> Option<SettlementKey> {
+        let mut parts = key.splitn(2, '.');
+        let (kind, rest) = (parts.next()?, parts.next()?);
+        let utxo = || {
+            let mut parts = rest.rsplitn(2, '.');
+            let index = parts.next()?.parse().ok()?;
+            Some(Utxo::new(parts.next()?, index))
+        };
+        let chain_address = || {
+            let mut parts = rest.splitn(2, '.');
+            Some((parts.next()?.to_string(), parts.next()?.to_string()))
+        };
+        match kind {
+            "origin" => utxo().map(SettlementKey::Origin),
+            "maker" => chain_address().map(|(chain, address)| SettlementKey::Maker(chain, address)),
+            "taker" => chain_address().map(|(chain, address)| SettlementKey::Taker(chain, address)),
+            "watch" => chain_address().map(|(chain, address)| SettlementKey::Watches(chain, address)),
+            "tx" => utxo().map(SettlementKey::Settled),
+            _ => None,
+        }
+    }
+}
+
+/// Changes of the tracker done by a block, the values of the entries it changed before the block
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettlementUndo {
+    pub hash: String,
+    pub previous_hash: String,
+    pub previous_height: u64,
+    /// Entries in order of their first change, `None` for entries the block added
+    pub entries: Vec<(SettlementKey, Option<Json>)>,
+}
+
+/// Entries changed while a block is applied
+#[derive(Default)]
+struct Changes {
+    touched: HashSet<SettlementKey>,
+    entries: Vec<(SettlementKey, Option<Json>)>,
+}
+
+fn utxo_to_json(utxo: &Utxo, object: &mut Object, hash: &str, index: &str) {
+    object.insert(hash.to_string(), Json::String(utxo.tx_hash.clone()));
+    object.insert(index.to_string(), Json::U64(utxo.index));
+}
+
+fn field<'a>(json: &'a Json, name: &str) -> Option<&'a Json> {
+    json.as_object().and_then(|object| object.get(name))
+}
+
+fn string_field(json: &Json, name: &str) -> Option<String> {
+    field(json, name).and_then(Json::as_string).map(|value| value.to_string())
+}
+
+fn utxo_field(json: &Json, hash: &str, index: &str) -> Option<Utxo> {
+    Some(Utxo::new(field(json, hash)?.as_string()?, field(json, index)?.as_u64()?))
+}
+
+fn origin_to_json(origin: &Origin) -> Json {
+    let mut object = Object::new();
+    object.insert("script".to_string(), Json::String(origin.order.to_script()));
+    utxo_to_json(&origin.maker_utxo, &mut object, "makerHash", "makerOutputIndex");
+    object.insert("settleEndsAt".to_string(), Json::U64(origin.settle_ends_at));
+    Json::Object(object)
+}
+
+fn origin_from_json(json: &Json) -> Option<Origin> {
+    let order = MakerOrder::parse(&string_field(json, "script")?).ok()?;
+    Some(Origin {
+        order,
+        maker_utxo: utxo_field(json, "makerHash", "makerOutputIndex")?,
+        settle_ends_at: field(json, "settleEndsAt")?.as_u64()?,
+    })
+}
+
+fn makers_to_json(makers: &[MakerWatch]) -> Json {
+    let makers = makers
+        .iter()
+        .map(|maker| {
+            let takers = maker
+                .takers
+                .iter()
+                .map(|taker| {
+                    let mut object = Object::new();
+                    utxo_to_json(&taker.taker_utxo, &mut object, "hash", "outputIndex");
+                    object.insert("sendsAddress".to_string(), Json::String(taker.sends_address.clone()));
+                    object.insert("settled".to_string(), Json::Boolean(taker.settled));
+                    Json::Object(object)
+                })
+                .collect();
+            let mut object = Object::new();
+            utxo_to_json(&maker.maker_utxo, &mut object, "makerHash", "makerOutputIndex");
+            object.insert("settleEndsAt".to_string(), Json::U64(maker.settle_ends_at));
+            object.insert("takers".to_string(), Json::Array(takers));
+            Json::Object(object)
+        })
+        .collect();
+    Json::Array(makers)
+}
+
+fn makers_from_json(json: &Json) -> Option<Vec<MakerWatch>> {
+    json.as_array()?
+        .iter()
+        .map(|maker| {
+            let takers = field(maker, "takers")?
+                .as_array()?
+                .iter()
+                .map(|taker| {
+                    Some(TakerToSettle {
+                        taker_utxo: utxo_field(taker, "hash", "outputIndex")?,
+                        sends_address: string_field(taker, "sendsAddress")?,
+                        settled: field(taker, "settled")?.as_boolean()?,
+                    })
+                })
+                .collect::<Option<_>>()?;
+            Some(MakerWatch {
+                maker_utxo: utxo_field(maker, "makerHash", "makerOutputIndex")?,
+                settle_ends_at: field(maker, "settleEndsAt")?.as_u64()?,
+                takers,
+            })
+        })
+        .collect()
+}
+
+fn taker_to_json(taker: &TakerWatch) -> Json {
+    let mut object = Object::new();
+    object.insert("settleEndsAt".to_string(), Json::U64(taker.settle_ends_at));
+    utxo_to_json(&taker.maker_utxo, &mut object, "makerHash", "makerOutputIndex");
+    object.insert("settled".to_string(), Json::Boolean(taker.settled));
+    Json::Object(object)
+}
+
+fn taker_from_json(json: &Json) -> Option<TakerWatch> {
+    Some(TakerWatch {
+        settle_ends_at: field(json, "settleEndsAt")?.as_u64()?,
+        maker_utxo: utxo_field(json, "makerHash", "makerOutputIndex")?,
+        settled: field(json, "settled")?.as_boolean()?,
+    })
+}
+
+fn watches_to_json(watches: &[MarkedWatch]) -> Json {
+    let watches = watches
+        .iter()
+        .map(|watch| {
+            let mut object = Object::new();
+            utxo_to_json(&watch.callback, &mut object, "callbackHash", "callbackIndex");
+            object.insert("bridgedChain".to_string(), Json::String(watch.bridged_chain.clone()));
+            object.insert("bcSettleWindow".to_string(), Json::U64(watch.bc_settle_window));
+            object.insert("toAddr".to_string(), Json::String(watch.to_addr.clone()));
+            let bind_from_addr = watch.bind_from_addr.clone().map_or(Json::Null, Json::String);
+            object.insert("bindFromAddr".to_string(), bind_from_addr);
+            Json::Object(object)
+        })
+        .collect();
+    Json::Array(watches)
+}
+
+fn watches_from_json(json: &Json) -> Option<Vec<MarkedWatch>> {
+    json.as_array()?
+        .iter()
+        .map(|watch| {
+            let bind_from_addr = match *field(watch, "bindFromAddr")? {
+                Json::Null => None,
+                Json::String(ref address) => Some(address.clone()),
+                _ => return None,
+            };
+            Some(MarkedWatch {
+                callback: utxo_field(watch, "callbackHash", "callbackIndex")?,
+                bridged_chain: string_field(watch, "bridgedChain")?,
+                bc_settle_window: field(watch, "bcSettleWindow")?.as_u64()?,
+                to_addr: string_field(watch, "toAddr")?,
+                bind_from_addr,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SettlementTracker {
+    origins: HashMap<Utxo, Origin>,
+    makers: HashMap<(String, String), Vec<MakerWatch>>,
+    takers: HashMap<(String, String), TakerWatch>,
+    watches: HashMap<(String, String), Vec<MarkedWatch>>,
+    settled: HashSet<Utxo>,
+    best_block_hash: String,
+    height: u64,
+}
+
+impl SettlementTracker {
+    pub fn new() -> SettlementTracker {
+        SettlementTracker::default()
+    }
+
+    /// Tracker of the stored `entries` with `best_block_hash` at `height` as last applied block
+    pub fn load(best_block_hash: &str, height: u64, entries: &[(SettlementKey, Json)]) -> Result<SettlementTracker, DexError> {
+        let mut tracker = SettlementTracker { best_block_hash: best_block_hash.to_string(), height, ..Default::default() };
+        for (key, value) in entries {
+            tracker.set_entry(key, Some(value))?;
+        }
+        Ok(tracker)
+    }
+
+    /// Hash of the last applied block, empty before any block was applied
+    pub fn best_block_hash(&self) -> &str {
+        &self.best_block_hash
+    }
+
+    /// Height of the last applied block
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Value of the entry `key`, `None` if the tracker does not have it
+    pub fn get_entry(&self, key: &SettlementKey) -> Option<Json> {
+        match *key {
+            SettlementKey::Origin(ref utxo) => self.origins.get(utxo).map(origin_to_json),
+            SettlementKey::Maker(ref chain, ref address) => {
+                self.makers.get(&(chain.clone(), address.clone())).map(|makers| makers_to_json(makers))
+            }
+            SettlementKey::Taker(ref chain, ref address) => {
+                self.takers.get(&(chain.clone(), address.clone())).map(taker_to_json)
+            }
+            SettlementKey::Watches(ref chain, ref address) => {
+                self.watches.get(&(chain.clone(), address.clone())).map(|watches| watches_to_json(watches))
+            }
+            SettlementKey::Settled(ref utxo) => {
+                if self.settled.contains(utxo) {
+                    Some(Json::Boolean(true))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Sets the entry `key` to `value`, removes it without value
+    pub fn set_entry(&mut self, key: &SettlementKey, value: Option<&Json>) -> Result<(), DexError> {
+        let invalid = || DexError::InvalidSettlementEntry(key.to_key());
+        match *key {
+            SettlementKey::Origin(ref utxo) => match value {
+                Some(value) => {
+                    self.origins.insert(utxo.clone(), origin_from_json(value).ok_or_else(invalid)?);
+                }
+                None => {
+                    self.origins.remove(utxo);
+                }
+            },
+            SettlementKey::Maker(ref chain, ref address) => {
+                let key = (chain.clone(), address.clone());
+                match value {
+                    Some(value) => {
+                        self.makers.insert(key, makers_from_json(value).ok_or_else(invalid)?);
+                    }
+                    None => {
+                        self.makers.remove(&key);
+                    }
+                }
+            }
+            SettlementKey::Taker(ref chain, ref address) => {
+                let key = (chain.clone(), address.clone());
+                match value {
+                    Some(value) => {
+                        self.takers.insert(key, taker_from_json(value).ok_or_else(invalid)?);
+                    }
+                    None => {
+                        self.takers.remove(&key);
+                    }
+                }
+            }
+            SettlementKey::Watches(ref chain, ref address) => {
+                let key = (chain.clone(), address.clone());
+                match value {
+                    Some(value) => {
+                        self.watches.insert(key, watches_from_json(value).ok_or_else(invalid)?);
+                    }
+                    None => {
+                        self.watches.remove(&key);
+                    }
+                }
+            }
+            SettlementKey::Settled(ref utxo) => match value {
+                Some(&Json::Boolean(true)) => {
+                    self.settled.insert(utxo.clone());
+                }
+                Some(_) => return Err(invalid()),
+                None => {
+                    self.settled.remove(utxo);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// All entries of the tracker
+    pub fn entries(&self) -> Vec<(SettlementKey, Json)> {
+        let mut keys: Vec<SettlementKey> = self.origins.keys().cloned().map(SettlementKey::Origin).collect();
+        keys.extend(self.makers.keys().map(|(chain, address)| SettlementKey::Maker(chain.clone(), address.clone())));
+        keys.extend(self.takers.keys().map(|(chain, address)| SettlementKey::Taker(chain.clone(), address.clone())));
+        keys.extend(self.watches.keys().map(|(chain, address)| SettlementKey::Watches(chain.clone(), address.clone())));
+        keys.extend(self.settled.iter().cloned().map(SettlementKey::Settled));
+        keys.into_iter().filter_map(|key| self.get_entry(&key).map(|value| (key, value))).collect()
+    }
+
+    /// Records the value of `key` before the block changes it for the first time
+    fn touch(&self, changes: &mut Changes, key: SettlementKey) {
+        if changes.touched.insert(key.clone()) {
+            let previous = self.get_entry(&key);
+            changes.entries.push((key, previous));
+        }
+    }
+
+    /// Watches trades of `block`, settles them with its marked transactions and drops expired state,
+    /// `watchCrossChainTx` and `markTxAsSettledViaNewBlock` in JS
+    pub fn apply_block(&mut self, block: &BcBlock) -> SettlementUndo {
+        let mut changes = Changes::default();
+        let previous_hash = self.best_block_hash.clone();
+        let previous_height = self.height;
+        self.best_block_hash = block.get_hash().to_string();
+        self.height = block.get_height();
+        self.watch_cross_chain_txs(block, &mut changes);
+
+        let lists = blockchain_headers_to_lists(block.get_blockchain_headers());
+        for (chain, headers) in BLOCKCHAINS.iter().zip(lists.iter()) {
+            for header in headers.iter() {
+                for marked in header.get_marked_txs().iter().filter(|marked| marked.get_token() == *chain) {
+                    self.mark_settled(marked, &mut changes);
+                }
+            }
+        }
+
+        self.expire(&mut changes);
+        SettlementUndo { hash: block.get_hash().to_string(), previous_hash, previous_height, entries: changes.entries }
+    }
+
+    /// Disconnects the last applied block using the `undo` record returned when it was applied
+    pub fn revert_block(&mut self, undo: &SettlementUndo) -> Result<(), DexError> {
+        if undo.hash != self.best_block_hash {
+            return Err(DexError::UndoMismatch { best_block_hash: self.best_block_hash.clone(), undo_hash: undo.hash.clone() });
+        }
+        for (key, previous) in &undo.entries {
+            self.set_entry(key, previous.as_ref())?;
+        }
+        self.best_block_hash = undo.previous_hash.clone();
+        self.height = undo.previous_height;
+        Ok(())
+    }
+
+    fn watch_cross_chain_txs(&mut self, block: &BcBlock, changes: &mut Changes) {
+        for tx in block.get_txs() {
+            for input in tx.get_inputs() {
+                let utxo = Utxo::from_out_point(input.get_out_point());
+                if self.settled.contains(&utxo) {
+                    self.touch(changes, SettlementKey::Settled(utxo.clone()));
+                    self.settled.remove(&utxo);
+                }
+            }
+
+            for (index, output) in tx.get_outputs().iter().enumerate() {
+                let script = match str::from_utf8(output.get_output_script()) {
+                    Ok(script) => script,
+                    Err(_) => continue,
+                };
+                let utxo = Utxo::new(tx.get_hash(), index as u64);
+
+                if script.starts_with("OP_MONOID") {
+                    if let Ok(order) = MakerOrder::parse(script) {
+                        // as in the order book, windows ending past the last height never end
+                        let settle_ends_at =
+                            block.get_height().saturating_add(order.shift_starts_at).saturating_add(order.settle_ends_at);
+                        let key = (order.wants_chain_id.clone(), order.wants_address.clone());
+                        self.touch(changes, SettlementKey::Maker(key.0.clone(), key.1.clone()));
+                        let maker = MakerWatch { maker_utxo: utxo.clone(), settle_ends_at, takers: vec![] };
+                        self.makers.entry(key).or_default().push(maker);
+                        self.touch(changes, SettlementKey::Origin(utxo.clone()));
+                        self.origins.insert(utxo.clone(), Origin { order, maker_utxo: utxo, settle_ends_at });
+                    }
+                    continue;
+                }
+
+                let maker_utxo = match TakerOrder::parse(script) {
+                    Ok(taker) => Utxo::new(&taker.maker_tx_hash, u64::from(taker.maker_tx_output_index)),
+                    Err(_) => {
+                        // the remaining collateral of a partially matched order
+                        if let Some(maker_utxo) = parse_callback(script) {
+                            if let Some(origin) = self.origins.get(&maker_utxo).cloned() {
+                                self.touch(changes, SettlementKey::Origin(utxo.clone()));
+                                self.origins.insert(utxo, origin);
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let origin = match self.origins.get(&maker_utxo) {
+                    Some(origin) => origin.clone(),
+                    None => continue,
+                };
+                let taker_input = tx
+                    .get_inputs()
+                    .iter()
+                    .find(|input| Utxo::from_out_point(input.get_out_point()) == maker_utxo)
+                    .and_then(|input| str::from_utf8(input.get_input_script()).ok())
+                    .and_then(|script| TakerInput::parse(script).ok());
+                if let Some(taker_input) = taker_input {
+                    self.watch_settle(&origin, maker_utxo, utxo, taker_input, changes);
+                }
+            }
+        }
+    }
+
+    /// Registers the payments both sides of a matched order have to make, `watchSettleMarkedTxs` in JS
+    fn watch_settle(&mut self, origin: &Origin, maker_utxo: Utxo, taker_utxo: Utxo, taker_input: TakerInput, changes: &mut Changes) {
+        let maker_key = (origin.order.wants_chain_id.clone(), origin.order.wants_address.clone());
+        let watched = match self.makers.get(&maker_key) {
+            Some(makers) => makers.iter().any(|maker| maker.maker_utxo == origin.maker_utxo),
+            None => false,
+        };
+        if !watched {
+            return;
+        }
+        self.touch(changes, SettlementKey::Maker(maker_key.0.clone(), maker_key.1.clone()));
+        let maker = self
+            .makers
+            .get_mut(&maker_key)
+            .and_then(|makers| makers.iter_mut().find(|maker| maker.maker_utxo == origin.maker_utxo));
+        let settle_ends_at = match maker {
+            Some(maker) => {
+                if maker.takers.iter().all(|taker| taker.taker_utxo != taker_utxo) {
+                    maker.takers.push(TakerToSettle {
+                        taker_utxo: taker_utxo.clone(),
+                        sends_address: taker_input.sends_address,
+                        settled: false,
+                    });
+                }
+                maker.settle_ends_at
+            }
+            None => return,
+        };
+
+        let taker_key = (origin.order.pays_chain_id.clone(), taker_input.wants_address);
+        self.touch(changes, SettlementKey::Taker(taker_key.0.clone(), taker_key.1.clone()));
+        self.takers.insert(taker_key, TakerWatch { settle_ends_at, maker_utxo, settled: false });
+    }
+
+    /// Settles the trade paid by marked transaction `marked`, `markTxAsSettled` in JS
+    ///
+    /// A payment to the makers settles the taker output of the first unsettled taker sending from
+    /// the address the payment is from, a payment to the taker settles the maker output.
+    fn mark_settled(&mut self, marked: &MarkedTransaction, changes: &mut Changes) {
+        let key = (marked.get_token().to_string(), marked.get_addr_to().to_string());
+        let settled = if let Some(makers) = self.makers.get(&key) {
+            let position = makers
+                .iter()
+                .enumerate()
+                .filter_map(|(maker, watch)| {
+                    let taker =
+                        watch.takers.iter().position(|taker| !taker.settled && taker.sends_address == marked.get_addr_from())?;
+                    Some((maker, taker))
+                })
+                .next();
+            match position {
+                Some((maker, taker)) => {
+                    self.touch(changes, SettlementKey::Maker(key.0.clone(), key.1.clone()));
+                    let taker = &mut self.makers.get_mut(&key).expect("maker is watched")[maker].takers[taker];
+                    taker.settled = true;
+                    taker.taker_utxo.clone()
+                }
+                None => return,
+            }
+        } else if self.takers.contains_key(&key) {
+            self.touch(changes, SettlementKey::Taker(key.0.clone(), key.1.clone()));
+            let taker = self.takers.get_mut(&key).expect("taker is watched");
+            taker.settled = true;
+            taker.maker_utxo.clone()
+        } else {
+            return;
+        };
+        self.touch(changes, SettlementKey::Settled(settled.clone()));
+        self.settled.insert(settled);
+    }
+
+    /// Drops trades past their settle height and watches past their settle window
+    ///
+    /// Settled outputs are kept until they are spent, `getAddressRelatedOutPoints` in JS tells
+    /// the sides of an expired trade apart by them.
+    fn expire(&mut self, changes: &mut Changes) {
+        let height = self.height;
+        let origins: Vec<Utxo> =
+            self.origins.iter().filter(|&(_, origin)| height > origin.settle_ends_at).map(|(utxo, _)| utxo.clone()).collect();
+        for utxo in origins {
+            self.touch(changes, SettlementKey::Origin(utxo.clone()));
+            self.origins.remove(&utxo);
+        }
+        let makers: Vec<(String, String)> = self
+            .makers
+            .iter()
+            .filter(|&(_, makers)| makers.iter().any(|maker| height > maker.settle_ends_at))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in makers {
+            self.touch(changes, SettlementKey::Maker(key.0.clone(), key.1.clone()));
+            let remaining: Vec<MakerWatch> =
+                self.makers[&key].iter().filter(|maker| height <= maker.settle_ends_at).cloned().collect();
+            if remaining.is_empty() {
+                self.makers.remove(&key);
+            } else {
+                self.makers.insert(key, remaining);
+            }
+        }
+        let takers: Vec<(String, String)> =
+            self.takers.iter().filter(|&(_, taker)| height > taker.settle_ends_at).map(|(key, _)| key.clone()).collect();
+        for key in takers {
+            self.touch(changes, SettlementKey::Taker(key.0.clone(), key.1.clone()));
+            self.takers.remove(&key);
+        }
+        let watches: Vec<(String, String)> = self
+            .watches
+            .iter()
+            .filter(|&(_, watches)| watches.iter().any(|watch| height >= watch.bc_settle_window))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in watches {
+            self.touch(changes, SettlementKey::Watches(key.0.clone(), key.1.clone()));
+            let remaining: Vec<MarkedWatch> =
+                self.watches[&key].iter().filter(|watch| height < watch.bc_settle_window).cloned().collect();
+            if remaining.is_empty() {
+                self.watches.remove(&key);
+            } else {
+                self.watches.insert(key, remaining);
+            }
+        }
+    }
+
+    /// Watches payments to `watch.to_addr` until the settle window, `setMarkedWatch` in JS
+    ///
+    /// Returns false if the watch is already registered or binds addresses which can not be of
+    /// the same chain.
+    pub fn set_marked_watch(&mut self, watch: MarkedWatch) -> bool {
+        if let Some(ref from) = watch.bind_from_addr {
+            if *from == watch.to_addr || from.len() != watch.to_addr.len() {
+                return false;
+            }
+        }
+
+        let watches = self.watches.entry((watch.bridged_chain.clone(), watch.to_addr.clone())).or_default();
+        if watches.contains(&watch) {
+            return false;
+        }
+        watches.push(watch);
+        true
+    }
+
+    /// If payments from `bind_from_addr` to `to_addr` are watched, `isMarkedWatch` in JS
+    ///
+    /// Without `bind_from_addr` any watch of `to_addr` matches.
+    pub fn is_marked_watch(&self, bridged_chain: &str, to_addr: &str, bind_from_addr: Option<&str>) -> bool {
+        match self.watches.get(&(bridged_chain.to_string(), to_addr.to_string())) {
+            Some(watches) => {
+                bind_from_addr.is_none() || watches.iter().any(|watch| watch.bind_from_addr.as_ref().map(|from| &from[..]) == bind_from_addr)
+            }
+            None => false,
+        }
+    }
+
+    /// If the maker or taker output `utxo` has been settled on the bridged chain, `getTxSettleInfo` in JS
+    ///
+    /// The flag is dropped once the output is spent.
+    pub fn is_settled(&self, utxo: &Utxo) -> bool {
+        self.settled.contains(utxo)
+    }
+
+    /// If payments to `addr_to` on `bridged_chain` settle a trade at `height`, `isBeforeSettleHeight` in JS
+    pub fn is_before_settle_height(&self, addr_to: &str, bridged_chain: &str, height: u64) -> bool {
+        self.is_settle_payment(None, addr_to, bridged_chain, height)
+    }
+
+    /// If a payment from `addr_from` to `addr_to` on `bridged_chain` settles a trade at `height`
+    ///
+    /// A payment to a maker has to come from the address one of its takers sends from, payments
+    /// to a taker are not bound to an address as the maker order does not name one. Without
+    /// `addr_from` any payment to a watched address matches.
+    fn is_settle_payment(&self, addr_from: Option<&str>, addr_to: &str, bridged_chain: &str, height: u64) -> bool {
+        let key = (bridged_chain.to_string(), addr_to.to_string());
+        match (self.makers.get(&key), self.takers.get(&key)) {
+            (Some(makers), _) => makers.iter().any(|maker| {
+                height <= maker.settle_ends_at && match addr_from {
+                    Some(from) => maker.takers.iter().any(|taker| taker.sends_address == from),
+                    None => true,
+                }
+            }),
+            (None, Some(taker)) => height <= taker.settle_ends_at,
+            (None, None) => false,
+        }
+    }
+
+    /// Answer to the `IsBeforeSettleHeight` RPC at the height of the last applied block
+    ///
+    /// Unlike JS, a payment to a maker only settles from the address one of its takers sends from.
+    pub fn check_settle_tx(&self, request: &SettleTxCheckReq) -> SettleTxCheckResponse {
+        let mut response = SettleTxCheckResponse::new();
+        response.set_is_before_settlement_height(self.is_settle_payment(
+            Some(request.get_addr_from()),
+            request.get_addr_to(),
+            request.get_bridged_chain(),
+            self.height,
+        ));
+        response
+    }
+}
+
+impl SettleLookup for SettlementTracker {
+    fn is_settled(&self, utxo: &Utxo) -> bool {
+        SettlementTracker::is_settled(self, utxo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigUint;
+    use protobuf::RepeatedField;
+
+    use coin::biguint_to_internal;
+    use dex::book::OrderBook;
+    use dex::fees::parse_nrg;
+    use protos::core::{BlockchainHeader, BlockchainHeaders, Transaction, TransactionInput, TransactionOutput};
+
+    const MAKER_ADDRESS: &str = "0x25cc7722a6378e11082f7967c09b98bd26c979d3";
+    const TAKER_ADDRESS: &str = "0xea674fdde714fd979de3edf0f56aa9716b898ec8";
+
+    fn nrg(amount: &str) -> BigUint {
+        parse_nrg(amount).unwrap()
+    }
+
+    fn block(height: u64, txs: Vec<Transaction>, marked: Vec<MarkedTransaction>) -> BcBlock {
+        let mut header = BlockchainHeader::new();
+        header.set_blockchain("btc".to_string());
+        header.set_marked_txs(RepeatedField::from_vec(marked));
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(vec![header]));
+
+        let mut block = BcBlock::new();
+        block.set_hash(format!("b{}", height));
+        block.set_height(height);
+        block.set_txs(RepeatedField::from_vec(txs));
+        block.set_blockchain_headers(headers);
+        block
+    }
+
+    fn tx(hash: &str, inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Transaction {
+        let mut tx = Transaction::new();
+        tx.set_hash(hash.to_string());
+        tx.set_nin_count(inputs.len() as u64);
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx.set_nout_count(outputs.len() as u64);
+        tx.set_outputs(RepeatedField::from_vec(outputs));
+        tx
+    }
+
+    fn marked(token: &str, addr_from: &str, addr_to: &str) -> MarkedTransaction {
+        let mut marked = MarkedTransaction::new();
+        marked.set_token(token.to_string());
+        marked.set_addr_from(addr_from.to_string());
+        marked.set_addr_to(addr_to.to_string());
+        marked
+    }
+
+    /// Tracker with a maker order wanting btc placed at height 10 and matched by a taker at height 11
+    fn tracker() -> SettlementTracker {
+        let script = MakerOrder::new(0, 450, 800, "eth", "btc", "1maker", "0.5", "10", MAKER_ADDRESS).to_script();
+        let mut output = TransactionOutput::new();
+        output.set_value(biguint_to_internal(&nrg("10")));
+        output.set_unit(biguint_to_internal(&nrg("1")));
+        output.set_output_script(script.into_bytes());
+        let maker_block = block(10, vec![tx("m1", vec![], vec![output])], vec![]);
+
+        let mut book = OrderBook::new();
+        book.apply_block(&maker_block);
+        let taker_input = TakerInput { wants_address: "0xtaker".to_string(), sends_address: "1taker".to_string() };
+        let fill = book.fill(&Utxo::new("m1", 0), &nrg("4"), &taker_input, TAKER_ADDRESS).unwrap();
+
+        let mut tracker = SettlementTracker::new();
+        tracker.apply_block(&maker_block);
+        tracker.apply_block(&block(11, vec![tx("t1", vec![fill.input], fill.outputs)], vec![]));
+        tracker
+    }
+
+    #[test]
+    fn settle_test() {
+        let mut tracker = tracker();
+        assert!(tracker.is_before_settle_height("1maker", "btc", 810));
+        assert!(!tracker.is_before_settle_height("1maker", "btc", 811));
+        assert!(tracker.is_before_settle_height("0xtaker", "eth", 810));
+        assert!(!tracker.is_before_settle_height("0xtaker", "btc", 810));
+
+        let mut request = SettleTxCheckReq::new();
+        request.set_addr_from("1other".to_string());
+        request.set_addr_to("1maker".to_string());
+        request.set_bridged_chain("btc".to_string());
+        assert!(!tracker.check_settle_tx(&request).get_is_before_settlement_height());
+        request.set_addr_from("1taker".to_string());
+        assert!(tracker.check_settle_tx(&request).get_is_before_settlement_height());
+
+        // payments from other addresses or marked with other tokens do not settle
+        tracker.apply_block(&block(12, vec![], vec![marked("btc", "1other", "1maker"), marked("eth", "1taker", "1maker")]));
+        assert!(!tracker.is_settled(&Utxo::new("t1", 0)));
+
+        tracker.apply_block(&block(13, vec![], vec![marked("btc", "1taker", "1maker")]));
+        assert!(tracker.is_settled(&Utxo::new("t1", 0)));
+        assert!(!tracker.is_settled(&Utxo::new("m1", 0)));
+
+        tracker.apply_block(&block(811, vec![], vec![]));
+        assert!(!tracker.check_settle_tx(&request).get_is_before_settlement_height());
+        assert!(!tracker.is_before_settle_height("0xtaker", "eth", 811));
+        assert!(tracker.is_settled(&Utxo::new("t1", 0)));
+
+        let mut input = TransactionInput::new();
+        input.mut_out_point().set_hash("t1".to_string());
+        input.mut_out_point().set_index(0);
+        tracker.apply_block(&block(812, vec![tx("s1", vec![input], vec![])], vec![]));
+        assert!(!tracker.is_settled(&Utxo::new("t1", 0)));
+    }
+
+    #[test]
+    fn same_wants_address_test() {
+        // a second order wanting the same address is watched along the first one
+        let script = MakerOrder::new(0, 450, 900, "eth", "btc", "1maker", "0.5", "10", MAKER_ADDRESS).to_script();
+        let mut output = TransactionOutput::new();
+        output.set_value(biguint_to_internal(&nrg("10")));
+        output.set_unit(biguint_to_internal(&nrg("1")));
+        output.set_output_script(script.into_bytes());
+        let maker_block = block(12, vec![tx("m2", vec![], vec![output])], vec![]);
+
+        let mut book = OrderBook::new();
+        book.apply_block(&maker_block);
+        let taker_input = TakerInput { wants_address: "0xtaker2".to_string(), sends_address: "1taker2".to_string() };
+        let fill = book.fill(&Utxo::new("m2", 0), &nrg("4"), &taker_input, TAKER_ADDRESS).unwrap();
+
+        let mut tracker = tracker();
+        tracker.apply_block(&maker_block);
+        tracker.apply_block(&block(13, vec![tx("t2", vec![fill.input], fill.outputs)], vec![]));
+        assert_eq!(SettlementTracker::load("b13", 13, &tracker.entries()), Ok(tracker.clone()));
+
+        tracker.apply_block(&block(14, vec![], vec![marked("btc", "1taker2", "1maker")]));
+        assert!(tracker.is_settled(&Utxo::new("t2", 0)));
+        assert!(!tracker.is_settled(&Utxo::new("t1", 0)));
+        tracker.apply_block(&block(15, vec![], vec![marked("btc", "1taker", "1maker")]));
+        assert!(tracker.is_settled(&Utxo::new("t1", 0)));
+
+        // the first order expires before the second one
+        tracker.apply_block(&block(811, vec![], vec![]));
+        assert!(tracker.is_before_settle_height("1maker", "btc", 811));
+        let mut request = SettleTxCheckReq::new();
+        request.set_addr_from("1taker".to_string());
+        request.set_addr_to("1maker".to_string());
+        request.set_bridged_chain("btc".to_string());
+        assert!(!tracker.check_settle_tx(&request).get_is_before_settlement_height());
+        request.set_addr_from("1taker2".to_string());
+        assert!(tracker.check_settle_tx(&request).get_is_before_settlement_height());
+        tracker.apply_block(&block(913, vec![], vec![]));
+        assert!(!tracker.is_before_settle_height("1maker", "btc", 913));
+    }
+
+    #[test]
+    fn unbounded_settle_window_test() {
+        let script = MakerOrder::new(1, 450, u64::max_value(), "eth", "btc", "1maker", "0.5", "10", MAKER_ADDRESS).to_script();
+        let mut output = TransactionOutput::new();
+        output.set_output_script(script.into_bytes());
+        let mut tracker = SettlementTracker::new();
+        tracker.apply_block(&block(10, vec![tx("m1", vec![], vec![output])], vec![]));
+        assert!(tracker.is_before_settle_height("1maker", "btc", u64::max_value()));
+    }
+
+    #[test]
+    fn marked_watch_test() {
+        let mut tracker = SettlementTracker::new();
+        let watch = MarkedWatch {
+            callback: Utxo::new("t1", 1),
+            bridged_chain: "btc".to_string(),
+            bc_settle_window: 20,
+            to_addr: "1maker".to_string(),
+            bind_from_addr: Some("1taker".to_string()),
+        };
+        assert!(tracker.set_marked_watch(watch.clone()));
+        assert!(!tracker.set_marked_watch(watch.clone()));
+        assert!(!tracker.set_marked_watch(MarkedWatch { bind_from_addr: Some("1maker".to_string()), ..watch.clone() }));
+        assert!(!tracker.set_marked_watch(MarkedWatch { bind_from_addr: Some("0xtaker".to_string()), ..watch }));
+
+        assert!(tracker.is_marked_watch("btc", "1maker", None));
+        assert!(tracker.is_marked_watch("btc", "1maker", Some("1taker")));
+        assert!(!tracker.is_marked_watch("btc", "1maker", Some("1other")));
+        assert!(!tracker.is_marked_watch("eth", "1maker", None));
+
+        tracker.apply_block(&block(19, vec![], vec![]));
+        assert!(tracker.is_marked_watch("btc", "1maker", None));
+        tracker.apply_block(&block(20, vec![], vec![]));
+        assert!(!tracker.is_marked_watch("btc", "1maker", None));
+    }
+
+    #[test]
+    fn revert_block_test() {
+        let mut tracker = tracker();
+        tracker.set_marked_watch(MarkedWatch {
+            callback: Utxo::new("t1", 1),
+            bridged_chain: "btc".to_string(),
+            bc_settle_window: 13,
+            to_addr: "1maker".to_string(),
+            bind_from_addr: None,
+        });
+        let before = tracker.clone();
+
+        // settles the taker and drops the watch
+        let undo = tracker.apply_block(&block(13, vec![], vec![marked("btc", "1taker", "1maker")]));
+        assert!(tracker.is_settled(&Utxo::new("t1", 0)));
+        assert!(!tracker.is_marked_watch("btc", "1maker", None));
+        assert_eq!(SettlementTracker::load("b13", 13, &tracker.entries()), Ok(tracker.clone()));
+
+        let mut input = TransactionInput::new();
+        input.mut_out_point().set_hash("t1".to_string());
+        input.mut_out_point().set_index(0);
+        let spend_undo = tracker.apply_block(&block(811, vec![tx("s1", vec![input], vec![])], vec![]));
+        assert!(!tracker.is_settled(&Utxo::new("t1", 0)));
+        assert!(!tracker.is_before_settle_height("1maker", "btc", 810));
+
+        assert_eq!(
+            tracker.revert_block(&undo),
+            Err(DexError::UndoMismatch { best_block_hash: "b811".to_string(), undo_hash: "b13".to_string() })
+        );
+        tracker.revert_block(&spend_undo).unwrap();
+        assert!(tracker.is_settled(&Utxo::new("t1", 0)));
+        assert!(tracker.is_before_settle_height("1maker", "btc", 810));
+        tracker.revert_block(&undo).unwrap();
+        assert_eq!(tracker, before);
+
+        for (key, _) in tracker.entries() {
+            assert_eq!(SettlementKey::parse(&key.to_key()), Some(key));
+        }
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Consensus economics of transactions and blocks, port of src/core/txUtils.es6
+//!
+//! Fees, transaction distances and NRG grants are big integers, emblem based bonuses keep the
+//! floating point math of JS as they are derived from logarithms.
+use std::error::Error;
+use std::fmt;
+
+use num_bigint::{BigInt, BigUint};
+use num_traits::{ToPrimitive, Zero};
+
+use coin::internal_to_biguint;
+use funcs::overline_distance;
+use protos::core::Transaction;
+use types::Hash256;
+
+/* CONSENSUS TX STATIC VALUES */
+pub const MAX_TXDIST_BLOCK: u64 = 92822807733198;
+pub const HALF_MAX_TXDIST_BLOCK: u64 = 46411403866599;
+pub const BASE_TX_DISTANCE_BLOCK: u64 = 331510027618;
+pub const BASE_BLOCK_SIZE: u64 = 1024 * 1024;
+pub const COINBASE_TX_ESTIMATE_SIZE: u64 = 256;
+pub const COINBASE_MATURITY: u64 = 100;
+pub const MINIMUM_EMBLEM_TO_NRG: u64 = 166;
+pub const NRG_BLOCK_GRANT: u64 = 16;
+pub const TX_DEFAULT_NONCE: &str = "33e9fa317308a1e0002a65d650e27439fc046a8a14ae1862cb91f231bbc6d18f";
+pub const EMBLEM_GOLD_BCI_BLOCK: u64 = 66000000;
+pub const EMBLEM_GOLD_WINDOW: u64 = 260000;
+
+/// Upper limit of the NRG grant of emblem holders
+const MAXIMUM_EMBLEM_TO_NRG: f64 = 166.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EconomicsError {
+    DistanceExceeded { limit: String, consumed: String },
+    NoConsumedDistance,
+    NegativeFees { fees: String },
+    LockTimeOverflow { height: u64 },
+}
+
+impl fmt::Display for EconomicsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EconomicsError::DistanceExceeded { ref limit, ref consumed } =>
+                write!(f, "transactions distance {} exceeds distance with emblems {}", consumed, limit),
+            EconomicsError::NoConsumedDistance =>
+                write!(f, "transactions did not consume any distance"),
+            EconomicsError::NegativeFees { ref fees } =>
+                write!(f, "transactions fees {} are negative", fees),
+            EconomicsError::LockTimeOverflow { height } =>
+                write!(f, "coinbase lock time of block at height {} does not fit 32 bits", height),
+        }
+    }
+}
+
+impl Error for EconomicsError {
+    fn description(&self) -> &str {
+        "economics error"
+    }
+}
+
+/// Maximum transaction distance of a block with the bonus of its miner's emblems
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DistanceWithEmblems {
+    pub total_distance: BigUint,
+    pub emblem_bonus: u64,
+}
+
+/// Value of inputs minus value of outputs of `tx` in boson, `calcTxFee` in JS
+pub fn calc_tx_fee(tx: &Transaction) -> BigInt {
+    let value_in: BigUint = tx.get_inputs()
+        .iter()
+        .map(|input| internal_to_biguint(input.get_out_point().get_value()))
+        .sum();
+    let value_out: BigUint = tx.get_outputs()
+        .iter()
+        .map(|output| internal_to_biguint(output.get_value()))
+        .sum();
+
+    BigInt::from(value_in) - BigInt::from(value_out)
+}
+
+/// Distance of `tx` from its nonce, `getTxDistance` in JS
+///
+/// The hash field and the outpoint of the first input are used, coinbase transactions have no distance.
+pub fn get_tx_distance(tx: &Transaction) -> u64 {
+    let nonce = match tx.get_nonce() {
+        "" | "0" => TX_DEFAULT_NONCE,
+        nonce => nonce,
+    };
+
+    let out_point = match tx.get_inputs().first() {
+        Some(input) if input.has_out_point() => input.get_out_point(),
+        _ => return 0,
+    };
+
+    let checksum = format!("{}{}{}", tx.get_hash(), out_point.get_hash(), out_point.get_index());
+    let nonce_hash = Hash256::blake2bl(nonce.as_bytes()).to_hex();
+    let checksum_hash = Hash256::blake2bl(checksum.as_bytes()).to_hex();
+    overline_distance(nonce_hash.as_bytes(), checksum_hash.as_bytes()).expect("blake2bl hashes have the same length")
+}
+
+/// Sum of distances of `txs`, `getTxsDistanceSum` in JS
+pub fn get_txs_distance_sum(txs: &[Transaction]) -> BigUint {
+    txs.iter().map(|tx| BigUint::from(get_tx_distance(tx))).sum()
+}
+
+/// NRG granted to a block mined with `emblems` balance, `emblemToNrg` in JS
+pub fn emblem_to_nrg(emblems: &BigUint) -> u64 {
+    if *emblems < BigUint::from(MINIMUM_EMBLEM_TO_NRG) {
+        return NRG_BLOCK_GRANT;
+    }
+
+    let emblems = emblems.to_f64().unwrap_or(::std::f64::MAX);
+    let amount = NRG_BLOCK_GRANT as f64 + emblems.ln() * (emblems / 399.0).ln();
+    amount.max(NRG_BLOCK_GRANT as f64).min(MAXIMUM_EMBLEM_TO_NRG).round() as u64
+}
+
+/// Transaction distance available to a block mined with `emblems` balance, `getMaxDistanceWithEmblems` in JS
+///
+/// As in JS the distance per emblem NRG is the integer division of `HALF_MAX_TXDIST_BLOCK` by
+/// 166 NRG in boson, which is zero, so the total distance stays at `MAX_TXDIST_BLOCK`.
+pub fn get_max_distance_with_emblems(emblems: &BigUint) -> DistanceWithEmblems {
+    let nrg_166 = BigUint::from(166u64) * BigUint::from(10u64.pow(18));
+    let distance_as_nrg = BigUint::from(HALF_MAX_TXDIST_BLOCK) / nrg_166;
+    let emblem_bonus = emblem_to_nrg(emblems);
+
+    DistanceWithEmblems {
+        total_distance: BigUint::from(MAX_TXDIST_BLOCK) + BigUint::from(emblem_bonus) * distance_as_nrg,
+        emblem_bonus,
+    }
+}
+
+/// Maximum size in bytes of a block mined with `emblems` balance, `getMaxBlockSize` in JS
+///
+/// JS returns a fractional size, it is floored here which does not change the limit for whole sizes.
+pub fn get_max_block_size(emblems: &BigUint) -> u64 {
+    if *emblems < BigUint::from(MINIMUM_EMBLEM_TO_NRG) {
+        return BASE_BLOCK_SIZE;
+    }
+
+    let emblems = emblems.to_f64().unwrap_or(::std::f64::MAX);
+    let bonus = 256.0 * emblems.ln() * (emblems / MINIMUM_EMBLEM_TO_NRG as f64).ln();
+    BASE_BLOCK_SIZE + bonus.floor() as u64
+}
+
+/// NRG awarded to a block at `block_height`, `getNrgGrant` in JS
+///
+/// `potential_emblem_nrg` is the result of `emblem_to_nrg` and `distance_with_emblems` the total
+/// distance of `get_max_distance_with_emblems` which `txs_consumed_distance` must not exceed.
+pub fn get_nrg_grant(
+    potential_emblem_nrg: u64,
+    distance_with_emblems: &BigUint,
+    txs_consumed_distance: &BigUint,
+    block_height: u64,
+) -> Result<BigUint, EconomicsError> {
+    if potential_emblem_nrg <= NRG_BLOCK_GRANT {
+        return Ok(BigUint::from(NRG_BLOCK_GRANT));
+    }
+
+    if distance_with_emblems < txs_consumed_distance {
+        return Err(EconomicsError::DistanceExceeded {
+            limit: distance_with_emblems.to_string(),
+            consumed: txs_consumed_distance.to_string(),
+        });
+    }
+    // BN throws on division by zero in JS
+    if txs_consumed_distance.is_zero() {
+        return Err(EconomicsError::NoConsumedDistance);
+    }
+
+    // Emblem Gold distribution after BCI_BLOCK height -> all Emblem owners NRG increase until 2036
+    let emblem_gold = if block_height > EMBLEM_GOLD_BCI_BLOCK {
+        (block_height - EMBLEM_GOLD_BCI_BLOCK) / EMBLEM_GOLD_WINDOW
+    } else {
+        0
+    };
+
+    // 20% with 80% penalty, rounded half up
+    let minimum_emblem_grant = BigUint::from((potential_emblem_nrg + 2) / 5);
+    let divisor = distance_with_emblems / txs_consumed_distance;
+    let final_nrg_grant = (divisor * BigUint::from(potential_emblem_nrg)).max(minimum_emblem_grant);
+
+    Ok(final_nrg_grant + BigUint::from(emblem_gold))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use coin::biguint_to_internal;
+    use protos::core::{OutPoint, TransactionInput, TransactionOutput};
+
+    fn tx(nonce: &str, hash: &str, out_points: &[(&str, u64, u64)], outputs: &[u64]) -> Transaction {
+        let inputs = out_points.iter().map(|&(hash, index, value)| {
+            let mut out_point = OutPoint::new();
+            out_point.set_hash(hash.to_string());
+            out_point.set_index(index);
+            out_point.set_value(biguint_to_internal(&BigUint::from(value)));
+            let mut input = TransactionInput::new();
+            input.set_out_point(out_point);
+            input
+        }).collect();
+        let outputs = outputs.iter().map(|&value| {
+            let mut output = TransactionOutput::new();
+            output.set_value(biguint_to_internal(&BigUint::from(value)));
+            output
+        }).collect();
+
+        let mut tx = Transaction::new();
+        tx.set_nonce(nonce.to_string());
+        tx.set_hash(hash.to_string());
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx.set_outputs(RepeatedField::from_vec(outputs));
+        tx
+    }
+
+    #[test]
+    fn calc_tx_fee_test() {
+        assert_eq!(calc_tx_fee(&tx("", "", &[("a", 0, 500), ("a", 1, 700)], &[1000, 150])), BigInt::from(50));
+        assert_eq!(calc_tx_fee(&tx("", "", &[("a", 0, 500)], &[600])), BigInt::from(-100));
+        assert_eq!(calc_tx_fee(&tx("", "", &[], &[])), BigInt::from(0));
+
+        let large = tx("", "", &[("a", 0, 10u64.pow(19)), ("a", 1, 10u64.pow(19))], &[1]);
+        assert_eq!(calc_tx_fee(&large).to_string(), "19999999999999999999");
+    }
+
+    #[test]
+    fn get_tx_distance_test() {
+        // parity with getTxDistance in JS
+        let hash = "a".repeat(64);
+        let out_point_hash = "b".repeat(64);
+        let cases = [
+            (tx("", "txhash", &[("ophash", 0, 0)], &[]), 12920706141668),
+            (tx("12345minerkey", "txhash", &[("ophash", 1, 0)], &[]), 41571918484192),
+            (tx("0", &hash, &[(&out_point_hash, 3, 0)], &[]), 11981856156197),
+            (tx("12345minerkey", "txhash", &[], &[1]), 0),
+        ];
+        for case in cases.iter() {
+            assert_eq!(get_tx_distance(&case.0), case.1);
+        }
+
+        let txs: Vec<Transaction> = cases.iter().map(|case| case.0.clone()).collect();
+        assert_eq!(get_txs_distance_sum(&txs), BigUint::from(12920706141668u64 + 41571918484192 + 11981856156197));
+        assert_eq!(get_txs_distance_sum(&[]), BigUint::from(0u64));
+    }
+
+    #[test]
+    fn emblem_to_nrg_test() {
+        // parity with emblemToNrg and getMaxBlockSize in JS
+        let cases: [(u64, u64, u64); 11] = [
+            (0, 16, 1048576),
+            (165, 16, 1048576),
+            (166, 16, 1048576),
+            (399, 16, 1049920),
+            (400, 16, 1049924),
+            (1000, 22, 1051751),
+            (10000, 46, 1058239),
+            (100000, 80, 1067441),
+            (1000000, 124, 1079358),
+            (1000000000, 166, 1131396),
+            (1000000000000, 166, 1207865),
+        ];
+        for &(emblems, nrg, block_size) in cases.iter() {
+            assert_eq!(emblem_to_nrg(&BigUint::from(emblems)), nrg, "emblems {}", emblems);
+            assert_eq!(get_max_block_size(&BigUint::from(emblems)), block_size, "emblems {}", emblems);
+        }
+    }
+
+    #[test]
+    fn get_max_distance_with_emblems_test() {
+        let distance = get_max_distance_with_emblems(&BigUint::from(10000u64));
+        assert_eq!(distance, DistanceWithEmblems { total_distance: BigUint::from(MAX_TXDIST_BLOCK), emblem_bonus: 46 });
+        assert_eq!(get_max_distance_with_emblems(&BigUint::from(0u64)).emblem_bonus, NRG_BLOCK_GRANT);
+    }
+
+    #[test]
+    fn get_nrg_grant_test() {
+        let max = BigUint::from(MAX_TXDIST_BLOCK);
+        let grant = |potential, consumed: u64, height| get_nrg_grant(potential, &max, &BigUint::from(consumed), height);
+
+        // parity with getNrgGrant in JS
+        assert_eq!(grant(16, 0, 100), Ok(BigUint::from(16u64)));
+        assert_eq!(grant(10, MAX_TXDIST_BLOCK + 1, 100), Ok(BigUint::from(16u64)));
+        assert_eq!(grant(46, HALF_MAX_TXDIST_BLOCK, 100), Ok(BigUint::from(92u64)));
+        assert_eq!(grant(46, MAX_TXDIST_BLOCK, 100), Ok(BigUint::from(46u64)));
+        assert_eq!(grant(46, BASE_TX_DISTANCE_BLOCK, 100), Ok(BigUint::from(12880u64)));
+        assert_eq!(grant(46, MAX_TXDIST_BLOCK, EMBLEM_GOLD_BCI_BLOCK), Ok(BigUint::from(46u64)));
+        assert_eq!(grant(46, MAX_TXDIST_BLOCK, 66520001), Ok(BigUint::from(48u64)));
+
+        assert_eq!(
+            grant(46, MAX_TXDIST_BLOCK + 1, 100),
+            Err(EconomicsError::DistanceExceeded {
+                limit: "92822807733198".to_string(),
+                consumed: "92822807733199".to_string(),
+            })
+        );
+        assert_eq!(grant(46, 0, 100), Err(EconomicsError::NoConsumedDistance));
+    }
+}
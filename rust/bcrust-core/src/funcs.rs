@@ -2,7 +2,8 @@ use std::iter;
 use std::ops::BitXor;
 use std::ops::BitXorAssign;
 use std::vec;
-use rustc_serialize::hex::{FromHex, ToHex};
+
+use types::Hash256;
 
 pub fn l2norm(a: &[f64]) -> f64 {
     let mut r;
@@ -77,6 +78,27 @@ pub fn distance(a: &[u8], b: &[u8]) -> u64 {
     (result * 1e15).floor() as u64
 }
 
+/// Cosine distance of sorted characters used for overline transactions
+///
+/// See src/mining/primitives.es6 func overlineDistance(). JS refuses inputs of different length
+/// (compute-cosine-similarity throws), `None` is returned in that case.
+pub fn overline_distance(a: &[u8], b: &[u8]) -> Option<u64> {
+    if a.len() != b.len() {
+        return None
+    }
+
+    // Array.prototype.sort() without comparator compares the char codes as strings
+    let mut sorted_a = a.to_vec();
+    let mut sorted_b = b.to_vec();
+    sorted_a.sort_by_cached_key(|item| item.to_string());
+    sorted_b.sort_by_cached_key(|item| item.to_string());
+
+    let sorted_a: Vec<f64> = sorted_a.iter().map(|item| { *item as f64 }).collect();
+    let sorted_b: Vec<f64> = sorted_b.iter().map(|item| { *item as f64 }).collect();
+
+    Some((cosine_distance(&sorted_a, &sorted_b) * 1e15).floor() as u64)
+}
+
 pub fn xor<T>(a: &[T], b: &[T]) -> Vec<T>
     where T: BitXor + Copy,
           vec::Vec<T>: iter::FromIterator<<T as BitXor>::Output>
@@ -94,15 +116,10 @@ pub fn xor_in_place<T>(a: &mut [T], b: &[T])
     }
 }
 
-pub fn xor_hashes(input: &Vec<String>) -> String {
-    let mut cloned = input.clone();
-    let init = cloned[0].from_hex().unwrap();
-    cloned.remove(0);
-    let res = cloned.iter().fold(init, |acc, x| {
-        xor(&acc, &x.from_hex().unwrap())
-    });
-
-    res.to_hex()
+pub fn xor_hashes(input: &[Hash256]) -> Hash256 {
+    input.iter().fold(Hash256::default(), |acc, hash| {
+        &acc ^ hash
+    })
 }
 
 #[cfg(test)]
@@ -114,6 +131,8 @@ mod tests {
     const NUM_X: [f64; 5] = [ 5.0, 23.0, 2.0, 5.0, 9.0 ];
     const NUM_Y: [f64; 5] = [ 3.0, 21.0, 2.0, 5.0, 14.0 ];
     const NUM_RES: f64 = 1.0 - 0.024612343796039382;
+    // overlineDistance(a, b) from src/mining/primitives.es6
+    const OVERLINE_DISTANCE_A_B: u64 = 45734665644647;
 
     #[test]
     fn cosine_similarity_test() {
@@ -125,6 +144,17 @@ mod tests {
 //        b.iter(|| cosine_similarity(&NUM_X.to_vec(), &NUM_Y.to_vec()));
 //    }
 
+    #[test]
+    fn overline_distance_test() {
+        // the order of characters does not matter
+        assert_eq!(overline_distance(b"abcd", b"dcba"), overline_distance(b"abcd", b"abcd"));
+        assert_eq!(overline_distance(b"abc", b"abcd"), None);
+
+        let a = b"9b80fc5cba6238801d745ca139ec639924d27ed004c22609d6d9409f1221b8ce";
+        let b = b"781ff33f4d7d36b3f599d8125fd74ed37e2a1564ddc3f06fb22e1b0bf668a4f7";
+        assert_eq!(overline_distance(a, b), Some(OVERLINE_DISTANCE_A_B));
+    }
+
     #[test]
     fn xor_test() {
         // See http://tomeko.net/online_tools/xor.php?lang=en
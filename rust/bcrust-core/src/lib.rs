@@ -7,18 +7,36 @@ extern crate grpc;
 extern crate tls_api;
 extern crate num_traits;
 
-extern crate crypto;
+extern crate crypto as rust_crypto;
+extern crate futures;
+extern crate futures_cpupool;
 #[macro_use]
 extern crate log;
 extern crate num_bigint;
 extern crate num_cpus;
 extern crate protobuf;
 extern crate rand;
+extern crate secp256k1;
 extern crate strsim;
+extern crate rocksdb;
 extern crate rustc_serialize;
 
+pub mod coin;
+pub mod crypto;
 pub mod data;
+pub mod dex;
+pub mod economics;
 pub mod funcs;
+pub mod mempool;
 pub mod miner;
 pub mod protos;
 pub mod mining;
+pub mod script;
+pub mod store;
+pub mod tx;
+pub mod types;
+pub mod utxo;
+pub mod validation;
+
+#[cfg(test)]
+mod templates;
@@ -0,0 +1,541 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Pending transactions, port of `TxPendingPool` from src/bc/txPendingPool.es6
+//!
+//! Each pending transaction claims the outpoints it spends, a transaction spending an outpoint
+//! already claimed by another pending transaction is rejected as a double spend. Spent outputs
+//! are looked up in the unspent outputs or the outputs of pending transactions, the fee is
+//! computed from their values. Once the pool exceeds its maximum size the transactions paying
+//! the lowest fee per byte are evicted together with the pending transactions spending their
+//! outputs.
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+use num_bigint::BigUint;
+use protobuf::Message;
+
+use coin::internal_to_biguint;
+use protos::core::{BcBlock, OutPoint, Transaction};
+use tx::tx_hash;
+use tx::validation::UtxoLookup;
+use utxo::Utxo;
+
+/// Default maximum size of serialized transactions kept in the pool
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    AlreadyPending(String),
+    MissingOutput(Utxo),
+    ValueMismatch(Utxo),
+    NegativeFee(String),
+    DoubleSpend { utxo: Utxo, claimed_by: String },
+    PoolFull(String),
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MempoolError::AlreadyPending(ref hash) => write!(f, "transaction {} is already pending", hash),
+            MempoolError::MissingOutput(ref utxo) => write!(f, "output {} is missing or spent", utxo),
+            MempoolError::ValueMismatch(ref utxo) => write!(f, "value of outpoint {} does not match the output", utxo),
+            MempoolError::NegativeFee(ref hash) => write!(f, "transaction {} spends more than its inputs", hash),
+            MempoolError::DoubleSpend { ref utxo, ref claimed_by } =>
+                write!(f, "outpoint {} is already claimed by transaction {}", utxo, claimed_by),
+            MempoolError::PoolFull(ref hash) => write!(f, "fee of transaction {} is too low to enter the full pool", hash),
+        }
+    }
+}
+
+impl Error for MempoolError {
+    fn description(&self) -> &str {
+        "mempool error"
+    }
+}
+
+/// Transaction waiting to be mined
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingTx {
+    pub hash: String,
+    /// Value of the spent outputs minus value of the outputs in boson
+    pub fee: BigUint,
+    /// Size of the serialized transaction in bytes
+    pub size: usize,
+    pub tx: Transaction,
+}
+
+impl PendingTx {
+    fn fee_rate(&self) -> FeeRate {
+        FeeRate { fee: self.fee.clone(), size: self.size, hash: self.hash.clone() }
+    }
+}
+
+/// Key of the fee rate index, orders by fee per byte and transactions with the same fee rate by hash
+#[derive(Debug, Clone, Eq)]
+struct FeeRate {
+    fee: BigUint,
+    size: usize,
+    hash: String,
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &FeeRate) -> Ordering {
+        (&self.fee * BigUint::from(other.size))
+            .cmp(&(&other.fee * BigUint::from(self.size)))
+            .then_with(|| other.hash.cmp(&self.hash))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &FeeRate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &FeeRate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mempool {
+    txs: HashMap<String, PendingTx>,
+    /// Pending transactions from the lowest to the highest fee rate
+    by_fee_rate: BTreeSet<FeeRate>,
+    /// Pending transaction spending each claimed outpoint
+    claims: HashMap<Utxo, String>,
+    size: usize,
+    max_size: usize,
+    /// Hashes of evicted transactions not taken by `take_evicted` yet
+    evicted: Vec<String>,
+}
+
+impl Default for Mempool {
+    fn default() -> Mempool {
+        Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool {
+    /// Pool keeping at most `max_size` bytes of serialized transactions
+    pub fn new(max_size: usize) -> Mempool {
+        Mempool {
+            txs: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            claims: HashMap::new(),
+            size: 0,
+            max_size,
+            evicted: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Size of all pending transactions in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&PendingTx> {
+        self.txs.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.txs.contains_key(hash)
+    }
+
+    /// Hash of the pending transaction spending `out_point`, see `isAnyOutputClaimed` in JS
+    pub fn claimed_by(&self, out_point: &OutPoint) -> Option<&str> {
+        self.claims.get(&Utxo::from_out_point(out_point)).map(|hash| hash.as_str())
+    }
+
+    /// Value of the output `utxo` of a pending transaction or of the unspent output in `utxos`
+    fn spent_value<U: UtxoLookup>(&self, utxo: &Utxo, out_point: &OutPoint, utxos: &U) -> Option<BigUint> {
+        match self.txs.get(&utxo.tx_hash) {
+            Some(pending) => pending.tx.get_outputs().get(utxo.index as usize).map(|output| internal_to_biguint(output.get_value())),
+            None => utxos.get_unspent_output(out_point).map(|unspent| internal_to_biguint(unspent.output.get_value())),
+        }
+    }
+
+    /// Adds `tx` unless it double spends a pending transaction, `tryAddingNewTx` in JS
+    ///
+    /// Every outpoint has to refer to an output of `utxos` or of a pending transaction with the
+    /// value the outpoint claims. Returns hash of the added transaction. Transactions evicted to
+    /// make room, `tx` included when it fails with `PoolFull`, are kept for `take_evicted`.
+    pub fn add<U: UtxoLookup>(&mut self, tx: Transaction, utxos: &U) -> Result<String, MempoolError> {
+        let hash = tx_hash(&tx).to_hex();
+        if self.txs.contains_key(&hash) {
+            return Err(MempoolError::AlreadyPending(hash));
+        }
+
+        let mut claimed = HashSet::new();
+        let mut value_in = BigUint::from(0u32);
+        for input in tx.get_inputs() {
+            let out_point = input.get_out_point();
+            let utxo = Utxo::from_out_point(out_point);
+            if let Some(claimed_by) = self.claims.get(&utxo) {
+                return Err(MempoolError::DoubleSpend { utxo: utxo.clone(), claimed_by: claimed_by.clone() });
+            }
+            if !claimed.insert(utxo.clone()) {
+                return Err(MempoolError::DoubleSpend { utxo, claimed_by: hash });
+            }
+
+            let value = match self.spent_value(&utxo, out_point, utxos) {
+                Some(value) => value,
+                None => return Err(MempoolError::MissingOutput(utxo)),
+            };
+            if value != internal_to_biguint(out_point.get_value()) {
+                return Err(MempoolError::ValueMismatch(utxo));
+            }
+            value_in += value;
+        }
+
+        let value_out: BigUint = tx.get_outputs().iter().map(|output| internal_to_biguint(output.get_value())).sum();
+        if value_out > value_in {
+            return Err(MempoolError::NegativeFee(hash));
+        }
+        let fee = value_in - value_out;
+
+        for utxo in claimed {
+            self.claims.insert(utxo, hash.clone());
+        }
+        let size = tx.compute_size() as usize;
+        let pending = PendingTx { hash: hash.clone(), fee, size, tx };
+        self.size += size;
+        self.by_fee_rate.insert(pending.fee_rate());
+        self.txs.insert(hash.clone(), pending);
+
+        self.evict();
+        if !self.txs.contains_key(&hash) {
+            return Err(MempoolError::PoolFull(hash));
+        }
+        Ok(hash)
+    }
+
+    /// Evicts transactions with the lowest fee rate until the pool fits its maximum size
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let lowest = match self.by_fee_rate.iter().next() {
+                Some(fee_rate) => fee_rate.hash.clone(),
+                None => return,
+            };
+            let removed = self.remove(&lowest);
+            self.evicted.extend(removed.into_iter().map(|pending| pending.hash));
+        }
+    }
+
+    /// Hashes of the transactions evicted from the full pool since the last call, for the caller
+    /// to drop them from its own storage
+    pub fn take_evicted(&mut self) -> Vec<String> {
+        self.evicted.drain(..).collect()
+    }
+
+    /// Removes the transaction `hash` and pending transactions spending its outputs
+    pub fn remove(&mut self, hash: &str) -> Vec<PendingTx> {
+        let mut removed = Vec::new();
+        let mut queue = vec![hash.to_string()];
+        while let Some(hash) = queue.pop() {
+            let pending = match self.take(&hash) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            for index in 0..pending.tx.get_outputs().len() {
+                if let Some(child) = self.claims.get(&Utxo::new(&hash, index as u64)) {
+                    queue.push(child.clone());
+                }
+            }
+            removed.push(pending);
+        }
+        removed
+    }
+
+    /// Removes only the transaction `hash`, releasing its claims
+    fn take(&mut self, hash: &str) -> Option<PendingTx> {
+        let pending = self.txs.remove(hash)?;
+        self.by_fee_rate.remove(&pending.fee_rate());
+        for input in pending.tx.get_inputs() {
+            self.claims.remove(&Utxo::from_out_point(input.get_out_point()));
+        }
+        self.size -= pending.size;
+        Some(pending)
+    }
+
+    /// Removes transactions mined in `block`, `markTxsAsMined` in JS
+    ///
+    /// Pending transactions spending the same outpoints as mined ones can not be mined anymore,
+    /// they are removed with their descendants and returned.
+    pub fn apply_block(&mut self, block: &BcBlock) -> Vec<PendingTx> {
+        let mut conflicts = Vec::new();
+        for tx in block.get_txs() {
+            self.take(&tx_hash(tx).to_hex());
+            for input in tx.get_inputs() {
+                let conflict = self.claims.get(&Utxo::from_out_point(input.get_out_point())).cloned();
+                if let Some(conflict) = conflict {
+                    conflicts.extend(self.remove(&conflict));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Adds transactions of the disconnected `block` back to the pool
+    ///
+    /// `utxos` are the unspent outputs after the block was disconnected. The coinbase and
+    /// transactions conflicting with pending ones are dropped, returns number of transactions
+    /// added back.
+    pub fn revert_block<U: UtxoLookup>(&mut self, block: &BcBlock, utxos: &U) -> usize {
+        block
+            .get_txs()
+            .iter()
+            .filter(|tx| !tx.get_inputs().is_empty())
+            .filter(|tx| self.add((*tx).clone(), utxos).is_ok())
+            .count()
+    }
+
+    /// Transactions to fill a block template with, `loadBestPendingTxs` in JS
+    ///
+    /// Transactions are taken by fee rate, a transaction spending outputs of another pending
+    /// transaction is taken only after it. Stops at `max_count` transactions or at the first one
+    /// which would exceed `max_size` bytes in total, as the miner does in JS.
+    pub fn load_best_pending_txs(&self, max_count: usize, max_size: usize) -> Vec<&Transaction> {
+        let mut candidates: Vec<&PendingTx> = self.by_fee_rate.iter().rev().map(|fee_rate| &self.txs[&fee_rate.hash]).collect();
+
+        let mut best = Vec::new();
+        let mut included = HashSet::new();
+        let mut size = 0;
+        while !candidates.is_empty() {
+            let selected = best.len();
+            let mut deferred = Vec::new();
+            for pending in candidates {
+                let has_pending_parent = pending.tx.get_inputs().iter().any(|input| {
+                    let parent = input.get_out_point().get_hash();
+                    self.txs.contains_key(parent) && !included.contains(parent)
+                });
+                if has_pending_parent {
+                    deferred.push(pending);
+                    continue;
+                }
+
+                if best.len() == max_count || size + pending.size > max_size {
+                    return best;
+                }
+                size += pending.size;
+                included.insert(pending.hash.as_str());
+                best.push(&pending.tx);
+            }
+
+            if best.len() == selected {
+                break;
+            }
+            candidates = deferred;
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use protos::core::{TransactionInput, TransactionOutput};
+    use tx::create_nrg_output_lock_script;
+    use tx::validation::UnspentOutput;
+
+    const ADDRESS: &str = "0x25cc7722a6378e11082f7967c09b98bd26c979d3";
+
+    /// Unspent outputs with their values
+    struct TestUtxos(HashMap<Utxo, u8>);
+
+    impl UtxoLookup for TestUtxos {
+        fn get_unspent_output(&self, out_point: &OutPoint) -> Option<UnspentOutput> {
+            self.0.get(&Utxo::from_out_point(out_point)).map(|&value| {
+                let mut output = TransactionOutput::new();
+                output.set_value(vec![value]);
+                UnspentOutput { output, height: 1, is_coinbase: false }
+            })
+        }
+    }
+
+    fn utxos(outputs: &[(&str, u64, u8)]) -> TestUtxos {
+        TestUtxos(outputs.iter().map(|&(hash, index, value)| (Utxo::new(hash, index), value)).collect())
+    }
+
+    /// Transaction spending `spends` outpoints of the given values, paying `outputs` to `ADDRESS`
+    fn tx(spends: &[(&str, u64, u8)], outputs: &[u8]) -> Transaction {
+        let inputs: Vec<TransactionInput> = spends
+            .iter()
+            .map(|&(hash, index, value)| {
+                let mut out_point = OutPoint::new();
+                out_point.set_value(vec![value]);
+                out_point.set_hash(hash.to_string());
+                out_point.set_index(index);
+                let mut input = TransactionInput::new();
+                input.set_out_point(out_point);
+                input
+            })
+            .collect();
+        let script = create_nrg_output_lock_script(ADDRESS);
+        let outputs: Vec<TransactionOutput> = outputs
+            .iter()
+            .map(|&value| {
+                let mut output = TransactionOutput::new();
+                output.set_value(vec![value]);
+                output.set_unit(vec![1]);
+                output.set_script_length(script.len() as u32);
+                output.set_output_script(script.as_bytes().to_vec());
+                output
+            })
+            .collect();
+
+        let mut tx = Transaction::new();
+        tx.set_version(1);
+        tx.set_nin_count(inputs.len() as u64);
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx.set_nout_count(outputs.len() as u64);
+        tx.set_outputs(RepeatedField::from_vec(outputs));
+        tx
+    }
+
+    fn hash(tx: &Transaction) -> String {
+        tx_hash(tx).to_hex()
+    }
+
+    fn hashes(txs: &[&Transaction]) -> Vec<String> {
+        txs.iter().map(|tx| hash(tx)).collect()
+    }
+
+    #[test]
+    fn add_test() {
+        let mut mempool = Mempool::default();
+        let utxos = utxos(&[("a", 0, 10), ("a", 1, 10), ("b", 0, 10)]);
+        let first = tx(&[("a", 0, 10), ("a", 1, 10)], &[15]);
+        assert_eq!(mempool.add(first.clone(), &utxos), Ok(hash(&first)));
+        assert_eq!(mempool.get(&hash(&first)).unwrap().fee, BigUint::from(5u32));
+        assert_eq!(mempool.size(), first.compute_size() as usize);
+        assert_eq!(mempool.add(first.clone(), &utxos), Err(MempoolError::AlreadyPending(hash(&first))));
+
+        let double_spend = tx(&[("b", 0, 10), ("a", 1, 10)], &[5]);
+        assert_eq!(
+            mempool.add(double_spend, &utxos),
+            Err(MempoolError::DoubleSpend { utxo: Utxo::new("a", 1), claimed_by: hash(&first) })
+        );
+        let same_outpoint = tx(&[("b", 0, 10), ("b", 0, 10)], &[5]);
+        assert_eq!(
+            mempool.add(same_outpoint.clone(), &utxos),
+            Err(MempoolError::DoubleSpend { utxo: Utxo::new("b", 0), claimed_by: hash(&same_outpoint) })
+        );
+        let negative_fee = tx(&[("b", 0, 10)], &[11]);
+        assert_eq!(mempool.add(negative_fee.clone(), &utxos), Err(MempoolError::NegativeFee(hash(&negative_fee))));
+        let missing = tx(&[("c", 0, 10)], &[5]);
+        assert_eq!(mempool.add(missing, &utxos), Err(MempoolError::MissingOutput(Utxo::new("c", 0))));
+        let overvalued = tx(&[("b", 0, 20)], &[15]);
+        assert_eq!(mempool.add(overvalued, &utxos), Err(MempoolError::ValueMismatch(Utxo::new("b", 0))));
+
+        // outputs of pending transactions can be spent, but only those which exist
+        let child = tx(&[(&hash(&first), 0, 15)], &[12]);
+        assert_eq!(mempool.add(child.clone(), &utxos), Ok(hash(&child)));
+        assert_eq!(mempool.get(&hash(&child)).unwrap().fee, BigUint::from(3u32));
+        let missing = tx(&[(&hash(&first), 1, 15)], &[12]);
+        assert_eq!(mempool.add(missing, &utxos), Err(MempoolError::MissingOutput(Utxo::new(&hash(&first), 1))));
+
+        let mut out_point = OutPoint::new();
+        out_point.set_hash("a".to_string());
+        assert_eq!(mempool.claimed_by(&out_point), Some(hash(&first).as_str()));
+        out_point.set_hash("b".to_string());
+        assert_eq!(mempool.claimed_by(&out_point), None);
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn load_best_pending_txs_test() {
+        let mut mempool = Mempool::default();
+        let parent = tx(&[("a", 0, 100)], &[99]);
+        let child = tx(&[(&hash(&parent), 0, 99)], &[59]);
+        let high = tx(&[("b", 0, 100)], &[80]);
+        let low = tx(&[("c", 0, 100)], &[95]);
+        let utxos = utxos(&[("a", 0, 100), ("b", 0, 100), ("c", 0, 100)]);
+        for tx in &[parent.clone(), child.clone(), low.clone(), high.clone()] {
+            mempool.add(tx.clone(), &utxos).unwrap();
+        }
+
+        let size = high.compute_size() as usize;
+        assert_eq!(hashes(&mempool.load_best_pending_txs(10, usize::max_value())), hashes(&[&high, &low, &parent, &child]));
+        assert_eq!(hashes(&mempool.load_best_pending_txs(2, usize::max_value())), hashes(&[&high, &low]));
+        assert_eq!(hashes(&mempool.load_best_pending_txs(10, size)), hashes(&[&high]));
+    }
+
+    #[test]
+    fn evict_test() {
+        let parent = tx(&[("a", 0, 100)], &[98]);
+        let child = tx(&[(&hash(&parent), 0, 98)], &[88]);
+        let high = tx(&[("b", 0, 100)], &[80]);
+        let low = tx(&[("c", 0, 100)], &[99]);
+        let size = parent.compute_size() + child.compute_size() + high.compute_size();
+
+        let utxos = utxos(&[("a", 0, 100), ("b", 0, 100), ("c", 0, 100), ("d", 0, 100)]);
+        let mut mempool = Mempool::new(size as usize);
+        mempool.add(parent.clone(), &utxos).unwrap();
+        mempool.add(child.clone(), &utxos).unwrap();
+        mempool.add(high.clone(), &utxos).unwrap();
+        assert_eq!(mempool.add(low.clone(), &utxos), Err(MempoolError::PoolFull(hash(&low))));
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.by_fee_rate.len(), 3);
+        assert_eq!(mempool.take_evicted(), vec![hash(&low)]);
+        assert_eq!(mempool.take_evicted(), Vec::<String>::new());
+
+        let higher = tx(&[("d", 0, 100)], &[70]);
+        mempool.add(higher.clone(), &utxos).unwrap();
+        assert!(!mempool.contains(&hash(&parent)));
+        assert!(!mempool.contains(&hash(&child)));
+        let mut evicted = mempool.take_evicted();
+        evicted.sort();
+        let mut expected = vec![hash(&parent), hash(&child)];
+        expected.sort();
+        assert_eq!(evicted, expected);
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.by_fee_rate.iter().map(|fee_rate| &fee_rate.hash).collect::<Vec<_>>(), vec![&hash(&high), &hash(&higher)]);
+        assert_eq!(mempool.size(), (high.compute_size() + higher.compute_size()) as usize);
+    }
+
+    #[test]
+    fn apply_revert_block_test() {
+        let mut mempool = Mempool::default();
+        let mined = tx(&[("a", 0, 100)], &[90]);
+        let pending = tx(&[("b", 0, 100)], &[90]);
+        let pending_child = tx(&[(&hash(&pending), 0, 90)], &[80]);
+        let other = tx(&[("c", 0, 100)], &[90]);
+        let utxos = utxos(&[("a", 0, 100), ("b", 0, 100), ("c", 0, 100)]);
+        for tx in &[mined.clone(), pending.clone(), pending_child.clone(), other.clone()] {
+            mempool.add(tx.clone(), &utxos).unwrap();
+        }
+
+        let conflicting = tx(&[("b", 0, 100)], &[50]);
+        let mut block = BcBlock::new();
+        block.set_txs(RepeatedField::from_vec(vec![tx(&[], &[16]), mined.clone(), conflicting.clone()]));
+        let conflicts = mempool.apply_block(&block);
+        let mut removed: Vec<String> = conflicts.into_iter().map(|pending| pending.hash).collect();
+        removed.sort();
+        let mut expected = vec![hash(&pending), hash(&pending_child)];
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert_eq!(hashes(&mempool.load_best_pending_txs(10, usize::max_value())), hashes(&[&other]));
+
+        assert_eq!(mempool.revert_block(&block, &utxos), 2);
+        assert!(mempool.contains(&hash(&mined)));
+        assert!(mempool.contains(&hash(&conflicting)));
+        assert_eq!(mempool.len(), 3);
+    }
+}
@@ -2,29 +2,83 @@
 //
 // This source code is licensed under the MIT license found in the
 // LICENSE file in the root directory of this source tree.
-use std::str::FromStr;
 use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
 
-use blake2_rfc::blake2b::{blake2b};
-use rustc_serialize::hex::{ToHex};
-use num_bigint::BigInt;
+use num_bigint::{BigInt, BigUint};
 use num_traits::{ToPrimitive, pow};
 
 use funcs::xor_hashes;
+use types::{Difficulty, Hash256};
 
-use protos::core::{BlockchainHeaders, BlockchainHeader, BcBlock};
+use protos::core::{BlockchainHeaders, BlockchainHeader, BcBlock, MarkedTransaction};
 use super::traits::RoveredBlockLike;
 
-const MINIMUM_DIFFICULTY: u64 = 291112262029012;
+pub const MINIMUM_DIFFICULTY: u64 = 291112262029012;
 
-pub fn block_hash (msg: &RoveredBlockLike) -> String {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DifficultyError {
+    MalformedDifficulty(String),
+    MissingNewestChildHeader,
+}
+
+impl fmt::Display for DifficultyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DifficultyError::MalformedDifficulty(ref difficulty) =>
+                write!(f, "previous block difficulty '{}' is not a decimal number", difficulty),
+            DifficultyError::MissingNewestChildHeader =>
+                write!(f, "previous block has no child headers"),
+        }
+    }
+}
+
+impl Error for DifficultyError {
+    fn description(&self) -> &str {
+        "difficulty error"
+    }
+}
+
+/// Hash of a marked transaction, see `markedTransactionHash` in src/mining/primitives.es6
+///
+/// JS interpolates the `value` Uint8Array into the payload which renders it as its bytes in
+/// decimal joined by commas.
+pub fn marked_transaction_hash(tx: &MarkedTransaction) -> Hash256 {
+    let value = tx.get_value().iter().map(|byte| byte.to_string()).collect::<Vec<_>>().join(",");
+    let payload = format!(
+        "{}{}{}{}{}",
+        tx.get_id(),
+        tx.get_token(),
+        tx.get_addr_from(),
+        tx.get_addr_to(),
+        value
+    );
+
+    Hash256::blake2bl(payload.as_bytes())
+}
+
+/// Hash of a rovered block, `blockHash` in JS which hashes the block hash with the merkle root
+pub fn block_hash (msg: &RoveredBlockLike) -> Hash256 {
     let hash = msg.get_hash();
     let merkle_root = msg.get_merkle_root();
-    let payload = format!("{}{}", hash, merkle_root);
+    let mut payload = format!("{}{}", hash, merkle_root);
+    for tx in msg.get_marked_txs() {
+        payload.push_str(&marked_transaction_hash(tx).to_string());
+    }
 
-    blake2b(64, &[], payload.as_bytes())
-        .as_bytes()[32..64]
-        .to_hex()
+    Hash256::blake2bl(payload.as_bytes())
+}
+
+/// Header lists of all child chains in `BlockchainHeaders` field order
+pub fn blockchain_headers_to_lists(blockchain_headers: &BlockchainHeaders) -> [&[BlockchainHeader]; 5] {
+    [
+        blockchain_headers.get_btc(),
+        blockchain_headers.get_eth(),
+        blockchain_headers.get_lsk(),
+        blockchain_headers.get_neo(),
+        blockchain_headers.get_wav()
+    ]
 }
 
 pub fn blockchain_headers_to_list(blockchain_headers: &BlockchainHeaders) -> Vec<BlockchainHeader> {
@@ -39,7 +93,7 @@ pub fn blockchain_headers_to_list(blockchain_headers: &BlockchainHeaders) -> Vec
     headers
 }
 
-pub fn get_children_block_hashes(blockchain_headers: &BlockchainHeaders) -> Vec<String> {
+pub fn get_children_block_hashes(blockchain_headers: &BlockchainHeaders) -> Vec<Hash256> {
     let headers_list = blockchain_headers_to_list(blockchain_headers);
     headers_list
         .iter()
@@ -47,8 +101,32 @@ pub fn get_children_block_hashes(blockchain_headers: &BlockchainHeaders) -> Vec<
         .collect()
 }
 
-pub fn get_children_root_hash(hashes: &[String]) -> String {
-    xor_hashes(&hashes.to_vec())
+pub fn get_children_root_hash(hashes: &[Hash256]) -> Hash256 {
+    xor_hashes(hashes)
+}
+
+/// Chain root stored in `BcBlock`, blake2bl of the decimal value of the children root hash
+pub fn get_chain_root(blockchain_headers: &BlockchainHeaders) -> Hash256 {
+    let children_root = get_children_root_hash(&get_children_block_hashes(blockchain_headers));
+    Hash256::blake2bl(children_root.to_biguint().to_string().as_bytes())
+}
+
+/// Chained merkle root of `list`, `None` for empty list, see `createMerkleRoot` in JS
+pub fn create_merkle_root<T: AsRef<str>>(list: &[T]) -> Option<Hash256> {
+    list.iter().fold(None, |prev, item| {
+        let payload = match prev {
+            Some(prev) => format!("{}{}", prev, item.as_ref()),
+            None => item.as_ref().to_string()
+        };
+
+        Some(Hash256::blake2bl(payload.as_bytes()))
+    })
+}
+
+/// The `work` to which the distance is being guessed while mining, see `prepareWork` in JS
+pub fn prepare_work(previous_hash: &Hash256, blockchain_headers: &BlockchainHeaders) -> Hash256 {
+    let children_root = get_children_root_hash(&get_children_block_hashes(blockchain_headers));
+    Hash256::blake2bl((&children_root ^ previous_hash).to_biguint().to_string().as_bytes())
 }
 
 pub fn get_parent_share_diff(parent_difficulty: u64, child_chain_count: u8) -> u64 {
@@ -59,47 +137,100 @@ pub fn get_minimum_difficulty(child_chain_count: u8) -> u64 {
     MINIMUM_DIFFICULTY / child_chain_count as u64
 }
 
-pub fn get_exp_factor_diff(calculated_difficulty: String, parent_block_height: u64) -> String {
-    let big_2 = BigInt::from(2);
-    let period_count = BigInt::from((parent_block_height + 1) / 66000000); // TODO extract to constant
+pub fn get_exp_factor_diff(calculated_difficulty: Difficulty, parent_block_height: u64) -> Difficulty {
+    let big_2 = BigUint::from(2u8);
+    let period_count = BigUint::from((parent_block_height + 1) / 66000000); // TODO extract to constant
     if period_count > big_2 {
-        let result = BigInt::from_str(calculated_difficulty.as_str()).unwrap()
-            + pow(BigInt::from(2), (period_count - big_2).to_usize().unwrap());
-        return result.to_string()
+        let result = calculated_difficulty.as_biguint()
+            + pow(BigUint::from(2u8), (period_count - big_2).to_usize().unwrap());
+        return Difficulty::from(result)
     }
 
-    format!("{}", calculated_difficulty)
+    calculated_difficulty
 }
 
+/// Number of new child blocks, same as `getNewBlockCount` (which is `getChildBlockDiff`) in JS
 pub fn get_new_block_count(previous_headers: &BlockchainHeaders, current_headers: &BlockchainHeaders) -> u8 {
-    let mut a: HashSet<String> = HashSet::new();
-    let mut b: HashSet<String> = HashSet::new();
+    get_child_block_diff(previous_headers, current_headers) as u8
+}
 
-    for header in blockchain_headers_to_list(previous_headers).iter() {
-        a.insert(header.get_hash().to_string());
-    }
+/// Counts per chain the headers of `previous_headers` whose hash is not in `current_headers`
+///
+/// Port of `getChildBlockDiff`, each chain whose previous headers were replaced contributes
+/// to the difficulty calculation of the next block. Repeated headers are counted each time, as
+/// lodash `difference` keeps them.
+pub fn get_child_block_diff(previous_headers: &BlockchainHeaders, current_headers: &BlockchainHeaders) -> usize {
+    let previous = blockchain_headers_to_lists(previous_headers);
+    let current = blockchain_headers_to_lists(current_headers);
+
+    previous.iter().zip(current.iter()).map(|(previous_chain, current_chain)| {
+        previous_chain
+            .iter()
+            .filter(|previous| current_chain.iter().all(|current| current.get_hash() != previous.get_hash()))
+            .count()
+    }).sum()
+}
 
-    for header in blockchain_headers_to_list(current_headers).iter() {
-        b.insert(header.get_hash().to_string());
-    }
+/// Hashes of `current_headers` not present in `previous_headers`, deduplicated and in order
+///
+/// Port of `getUniqueHashes`. Note that the JS version collects hashes from `previousBlockHeaders`
+/// for both arguments, here the current headers are used as the function contract says.
+pub fn get_unique_hashes(previous_headers: &BlockchainHeaders, current_headers: &BlockchainHeaders) -> Vec<String> {
+    let previous_hashes: HashSet<&str> = blockchain_headers_to_lists(previous_headers)
+        .iter()
+        .flat_map(|chain| chain.iter())
+        .map(|h| h.get_hash())
+        .collect();
 
-    b.difference(&a).count() as u8
+    let mut seen: HashSet<&str> = HashSet::new();
+    blockchain_headers_to_lists(current_headers)
+        .iter()
+        .flat_map(|chain| chain.iter())
+        .map(|h| h.get_hash())
+        .filter(|hash| !previous_hashes.contains(hash) && seen.insert(hash))
+        .map(|hash| hash.to_string())
+        .collect()
 }
 
+/// Rovered blocks from `current_blocks` whose hash is not referenced by `previous_headers`
+///
+/// Port of `getUniqueBlocks` used by the mining officer to find blocks to rebase on. JS takes the
+/// list of current headers and compares hashes of `previousBlockHeaders` with themselves, so it
+/// always returns an empty list; here the blocks the previous headers do not reference are kept.
+pub fn get_unique_blocks<T: RoveredBlockLike + Clone>(previous_headers: &BlockchainHeaders, current_blocks: &[T]) -> Vec<T> {
+    let previous_hashes: HashSet<&str> = blockchain_headers_to_lists(previous_headers)
+        .iter()
+        .flat_map(|chain| chain.iter())
+        .map(|h| h.get_hash())
+        .collect();
+
+    current_blocks
+        .iter()
+        .filter(|block| !previous_hashes.contains(block.get_hash()))
+        .cloned()
+        .collect()
+}
+
+/// Difficulty of the next block, `getDiff` in JS
+///
+/// The elapsed time gets a bonus of `(elapsed_time - 6) * new_block_count` as in JS, the bonus is
+/// zero without new child blocks.
 pub fn get_diff(
     current_block_time: u64,
     previous_block_time: u64,
-    previous_difficulty: &str,
+    previous_difficulty: &Difficulty,
     minimal_difficulty: u64,
     new_block_count: u8,
     newest_child_header: &RoveredBlockLike
-) -> String {
+) -> Difficulty {
     let target_time_window = 8;
     let child_header_time = newest_child_header.get_timestamp() / 1000;
     let child_header_time_bound = child_header_time + target_time_window * 2;
-    let stale_cost = (current_block_time - child_header_time_bound) / target_time_window;
-    let elapsed_time = BigInt::from(current_block_time) - BigInt::from(previous_block_time) - BigInt::from(stale_cost);
-    let elapsed_time_bonus = elapsed_time.clone() + (elapsed_time.clone() - (7 * new_block_count as u64));
+    // child header can be newer than the time bound so stale cost has to be signed
+    let stale_cost = (BigInt::from(current_block_time) - BigInt::from(child_header_time_bound)) / target_time_window;
+    let elapsed_time = BigInt::from(current_block_time) - BigInt::from(previous_block_time) - stale_cost;
+    // elapsed_time + ((elapsed_time - 6) * new_block_count)
+    let elapsed_time_bonus = elapsed_time.clone() + (elapsed_time.clone() - 6) * new_block_count;
     let elapsed = if elapsed_time_bonus > BigInt::from(0) {
         elapsed_time_bonus
     } else {
@@ -111,46 +242,67 @@ pub fn get_diff(
         x = BigInt::from(-99);
     }
 
-    // this guard has to be here because of specific case of block utils/templates/bc.block.93699
-    // which sadly does not have difficulty specified
-    let previous_difficulty_big = match previous_difficulty == "" {
-        true => BigInt::from(MINIMUM_DIFFICULTY),
-        false => BigInt::from_str(previous_difficulty).unwrap()
-    };
+    let previous_difficulty_big = previous_difficulty.to_bigint();
 
     // y = previous_difficulty -> SPECTRUM: 10062600 // AT: 1615520 // BT: ((32 * 16) / 2PI ) * 10 = 815 chain count + hidden chain = 508
     let y = previous_difficulty_big.clone() / BigInt::from(815);
 
     let calculated_difficulty = x * y + previous_difficulty_big;
 
-    if calculated_difficulty < BigInt::from(minimal_difficulty) {
-        return format!("{}", minimal_difficulty);
+    match Difficulty::from_bigint(&calculated_difficulty) {
+        Some(ref difficulty) if *difficulty >= Difficulty::from(minimal_difficulty) => difficulty.clone(),
+        _ => Difficulty::from(minimal_difficulty)
     }
-
-    calculated_difficulty.to_string()
 }
 
-fn get_newest_header(block: &BcBlock) -> BlockchainHeader {
-    let mut headers_list = blockchain_headers_to_list(block.get_blockchain_headers());
-    headers_list.sort_by(|a, b| {
-        let a_ts = a.get_timestamp();
-        let b_ts = b.get_timestamp();
-        a_ts.cmp(&b_ts)
-    });
+/// Returns the child chain header with the highest timestamp
+///
+/// Follows `getNewestHeader` from src/mining/primitives.es6 including its tie-breaking: within
+/// one chain the last of equally timestamped headers wins, across chains the first chain in
+/// `BlockchainHeaders` field order (btc, eth, lsk, neo, wav) wins.
+pub fn get_newest_header(block: &BcBlock) -> Option<BlockchainHeader> {
+    let chains = blockchain_headers_to_lists(block.get_blockchain_headers());
+
+    let mut newest: Option<&BlockchainHeader> = None;
+    for chain in chains.iter() {
+        let chain_newest = chain.iter().fold(None, |acc: Option<&BlockchainHeader>, header| {
+            match acc {
+                Some(current) if current.get_timestamp() > header.get_timestamp() => Some(current),
+                _ => Some(header)
+            }
+        });
+
+        if let Some(candidate) = chain_newest {
+            newest = match newest {
+                Some(current) if current.get_timestamp() >= candidate.get_timestamp() => Some(current),
+                _ => Some(candidate)
+            };
+        }
+    }
 
-    let newest = headers_list[0].clone();
-    newest
+    newest.cloned()
 }
 
-pub fn get_new_pre_exp_diff(current_timestamp: u64, last_previous_block: &BcBlock, new_block_count: u8) -> String {
-    get_diff(
+/// Difficulty of a block mined on top of `last_previous_block`, before the exponential factor
+///
+/// Fails if the previous block, which comes from a peer, has a malformed difficulty or no child headers.
+pub fn get_new_pre_exp_diff(current_timestamp: u64, last_previous_block: &BcBlock, new_block_count: u8) -> Result<Difficulty, DifficultyError> {
+    // this guard has to be here because of specific case of block utils/templates/bc.block.93699
+    // which sadly does not have difficulty specified
+    let previous_difficulty = match last_previous_block.get_difficulty() {
+        "" => Difficulty::from(MINIMUM_DIFFICULTY),
+        difficulty => difficulty.parse().map_err(|_| DifficultyError::MalformedDifficulty(difficulty.to_string()))?
+    };
+    let newest_header = get_newest_header(last_previous_block).ok_or(DifficultyError::MissingNewestChildHeader)?;
+
+    Ok(get_diff(
         current_timestamp,
         last_previous_block.get_timestamp(),
-        last_previous_block.get_difficulty(),
+        &previous_difficulty,
         MINIMUM_DIFFICULTY,
         new_block_count,
-        &get_newest_header(last_previous_block)
-    )
+        &newest_header
+    ))
 }
 
 #[cfg(test)]
@@ -158,17 +310,51 @@ mod tests {
     use super::*;
     use protobuf::RepeatedField;
     use super::super::super::protos::core::Block;
+    use templates::{load_bc_block, BC_BLOCK_93699};
 
     const CORRECT_HASH_EMPTY_STRING_B: &str = "d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce";
 
     #[test]
     fn block_hash_blockchain_header_test() {
-        assert_eq!(block_hash(&BlockchainHeader::new()), CORRECT_HASH_EMPTY_STRING_B.to_string());
+        assert_eq!(block_hash(&BlockchainHeader::new()).to_string(), CORRECT_HASH_EMPTY_STRING_B);
     }
 
     #[test]
     fn block_hash_block_test() {
-        assert_eq!(block_hash(&Block::new()), CORRECT_HASH_EMPTY_STRING_B.to_string());
+        assert_eq!(block_hash(&Block::new()).to_string(), CORRECT_HASH_EMPTY_STRING_B);
+    }
+
+    fn marked_transaction() -> MarkedTransaction {
+        let mut tx = MarkedTransaction::new();
+        tx.set_id(String::from("eth"));
+        tx.set_token(String::from("0xb1aa2b6b3e5b7a1e2d3f4b5f4c4e6c2b9d8c7a6f"));
+        tx.set_addr_from(String::from("0xc67ae72834e36b52a4b76db6f6a44ae175fb2464"));
+        tx.set_addr_to(String::from("0x028d3af888e08aa8380e5866b6ed068bd60e7b19"));
+        // new BN('1000000000000000000').toBuffer()
+        tx.set_value(vec![0x0d, 0xe0, 0xb6, 0xb3, 0xa7, 0x64, 0x00, 0x00]);
+        tx
+    }
+
+    #[test]
+    fn marked_transaction_hash_test() {
+        // markedTransactionHash in JS, the value is interpolated as "13,224,182,179,167,100,0,0"
+        const CORRECT_MARKED_TX_HASH: &str = "cb50739a880508ab9545b06cd5dc0153344014f21edaa145ad15c141f3ba1905";
+        const CORRECT_EMPTY_VALUE_HASH: &str = "fea84ca0bc1968ebd83afa9831b9d7a46dc06ce23208e8efc4228b15ee195be4";
+        let mut tx = marked_transaction();
+        assert_eq!(marked_transaction_hash(&tx).to_string(), CORRECT_MARKED_TX_HASH);
+        tx.clear_value();
+        assert_eq!(marked_transaction_hash(&tx).to_string(), CORRECT_EMPTY_VALUE_HASH);
+    }
+
+    #[test]
+    fn block_hash_with_marked_txs_test() {
+        const CORRECT_BLOCK_HASH: &str = "29e4aff4aed18986b03e1ec7378bb7a2538573e2ad99e0fe03a7a1c2ccd270d1";
+        let mut header = BlockchainHeader::new();
+        header.set_hash(String::from("0xhash"));
+        header.set_merkle_root(String::from("0xmerkle"));
+        header.set_marked_txs(RepeatedField::from_vec(vec![marked_transaction()]));
+
+        assert_eq!(block_hash(&header).to_string(), CORRECT_BLOCK_HASH);
     }
 
     #[test]
@@ -176,27 +362,56 @@ mod tests {
         let mut headers = BlockchainHeaders::new();
         let btcs = vec![BlockchainHeader::new()];
         headers.set_btc(RepeatedField::from_vec(btcs));
-        assert_eq!(get_children_block_hashes(&headers), vec![CORRECT_HASH_EMPTY_STRING_B.to_string()])
+        assert_eq!(get_children_block_hashes(&headers), vec![CORRECT_HASH_EMPTY_STRING_B.parse().unwrap()])
     }
 
     #[test]
     fn get_children_root_hash_test() {
-        let hash_a = blake2b(64, &[], "a".as_bytes())
-            .as_bytes()[32..64]
-            .to_hex();
-        let hash_b = blake2b(64, &[], "b".as_bytes())
-            .as_bytes()[32..64]
-            .to_hex();
+        let hash_a = Hash256::blake2bl("a".as_bytes());
+        let hash_b = Hash256::blake2bl("b".as_bytes());
         let hashes = &[hash_a, hash_b];
 
         const CORRECT_A_B_CHILDREN_ROOT: &str = "930af0a9ddc0507cc4b3b719f434109d2a894286119b98f4ff77ee7ff358735e";
-        assert_eq!(get_children_root_hash(hashes), CORRECT_A_B_CHILDREN_ROOT.to_string())
+        assert_eq!(get_children_root_hash(hashes).to_string(), CORRECT_A_B_CHILDREN_ROOT)
+    }
+
+    #[test]
+    fn create_merkle_root_test() {
+        const CORRECT_MERKLE_ROOT: &str = "fd7bedabd562f78058e81c602f5b32fbde8a91f862aee96c849832b0f2ed75e0";
+        assert_eq!(create_merkle_root(&["hashA", "hashB", "hashC"]).unwrap().to_string(), CORRECT_MERKLE_ROOT);
+        assert_eq!(create_merkle_root::<String>(&[]), None);
+    }
+
+    #[test]
+    fn prepare_work_test() {
+        let mut btc_header = BlockchainHeader::new();
+        btc_header.set_hash(String::from("btcHashA"));
+        btc_header.set_merkle_root(String::from("btcMerkleRootA"));
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(vec![btc_header]));
+
+        let previous_hash = "9b80fc5cba6238801d745ca139ec639924d27ed004c22609d6d9409f1221b8ce".parse().unwrap();
+        const CORRECT_WORK: &str = "fe636d1fcc2d22cc896f8c0c9a48f8c8c19aaa5b945c963c6642683954c05102";
+        assert_eq!(prepare_work(&previous_hash, &headers).to_string(), CORRECT_WORK);
+    }
+
+    #[test]
+    fn prepare_work_empty_previous_hash_test() {
+        let mut btc_header = BlockchainHeader::new();
+        btc_header.set_hash(String::from("a"));
+        btc_header.set_merkle_root(String::from("b"));
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(vec![btc_header]));
+
+        // prepareWork('', headers) in JS
+        const CORRECT_WORK: &str = "8de3817a34dd915841536dbf49a38c18271310465df3bc7cf7e5963c51479d53";
+        assert_eq!(prepare_work(&Hash256::default(), &headers).to_string(), CORRECT_WORK);
     }
 
     #[test]
     fn get_exp_factor_diff_test() {
-        assert_eq!(get_exp_factor_diff(String::from("1024"), 1), "1024".to_string());
-        assert_eq!(get_exp_factor_diff(String::from("290112262029012"), 66000000 * 3), "290112262029014".to_string())
+        assert_eq!(get_exp_factor_diff(Difficulty::from(1024), 1), Difficulty::from(1024));
+        assert_eq!(get_exp_factor_diff(Difficulty::from(290112262029012), 66000000 * 3), Difficulty::from(290112262029014))
     }
 
     #[test]
@@ -207,12 +422,12 @@ mod tests {
         let received_diff = get_diff(
             1534956535000,
             1534956531000,
-            "290112262029015",
+            &Difficulty::from(290112262029015),
             MINIMUM_DIFFICULTY,
             3,
             &newest_header
         );
-        assert_eq!(received_diff, EXPECTED_DIFF.to_string())
+        assert_eq!(received_diff.to_string(), EXPECTED_DIFF)
     }
 
     #[test]
@@ -231,6 +446,175 @@ mod tests {
         let b_btcs = vec![a_header, b_header];
         b_headers.set_btc(RepeatedField::from_vec(b_btcs));
 
-        assert_eq!(get_new_block_count(&a_headers, &b_headers), 1);
+        // values of getNewBlockCount in JS, headers of the previous block missing in the new one
+        assert_eq!(get_new_block_count(&a_headers, &b_headers), 0);
+        assert_eq!(get_new_block_count(&b_headers, &a_headers), 1);
+    }
+
+    #[test]
+    fn get_child_block_diff_test() {
+        let mut previous = BlockchainHeaders::new();
+        previous.set_btc(RepeatedField::from_vec(vec![header("btc", "btc_1", 1000), header("btc", "btc_1", 1000)]));
+        previous.set_eth(RepeatedField::from_vec(vec![header("eth", "eth_1", 1000), header("eth", "eth_2", 2000)]));
+        previous.set_lsk(RepeatedField::from_vec(vec![header("lsk", "lsk_1", 1000)]));
+
+        let mut current = BlockchainHeaders::new();
+        current.set_btc(RepeatedField::from_vec(vec![header("btc", "btc_2", 3000)]));
+        current.set_eth(RepeatedField::from_vec(vec![header("eth", "eth_2", 2000), header("eth", "eth_3", 3000)]));
+        // same hash in a different chain does not count as present
+        current.set_neo(RepeatedField::from_vec(vec![header("neo", "lsk_1", 1000)]));
+
+        // values of getChildBlockDiff in JS: btc_1 twice, eth_1 and lsk_1
+        assert_eq!(get_child_block_diff(&previous, &current), 4);
+        assert_eq!(get_child_block_diff(&current, &previous), 3);
+        assert_eq!(get_child_block_diff(&current, &current), 0);
+
+        let mut repeated = BlockchainHeaders::new();
+        repeated.set_eth(RepeatedField::from_vec(vec![
+            header("eth", "eth_1", 1000),
+            header("eth", "eth_2", 2000),
+            header("eth", "eth_1", 1000),
+            header("eth", "eth_3", 3000),
+        ]));
+        let mut kept = BlockchainHeaders::new();
+        kept.set_eth(RepeatedField::from_vec(vec![header("eth", "eth_2", 2000)]));
+        assert_eq!(get_child_block_diff(&repeated, &kept), 3);
+        assert_eq!(get_child_block_diff(&kept, &repeated), 0);
+    }
+
+    #[test]
+    fn get_unique_hashes_test() {
+        let mut previous = BlockchainHeaders::new();
+        previous.set_btc(RepeatedField::from_vec(vec![header("btc", "btc_1", 1000)]));
+        previous.set_eth(RepeatedField::from_vec(vec![header("eth", "eth_1", 1000)]));
+
+        let mut current = BlockchainHeaders::new();
+        current.set_btc(RepeatedField::from_vec(vec![header("btc", "btc_1", 1000), header("btc", "btc_2", 2000)]));
+        current.set_eth(RepeatedField::from_vec(vec![header("eth", "eth_2", 2000), header("eth", "eth_2", 2000)]));
+
+        assert_eq!(get_unique_hashes(&previous, &current), vec!["btc_2".to_string(), "eth_2".to_string()]);
+        assert_eq!(get_unique_hashes(&current, &previous), vec!["eth_1".to_string()]);
+    }
+
+    #[test]
+    fn get_unique_blocks_test() {
+        let mut previous = BlockchainHeaders::new();
+        previous.set_btc(RepeatedField::from_vec(vec![header("btc", "btc_1", 1000)]));
+
+        let mut known = Block::new();
+        known.set_hash(String::from("btc_1"));
+        let mut new = Block::new();
+        new.set_hash(String::from("eth_1"));
+
+        let unique = get_unique_blocks(&previous, &[known, new.clone()]);
+        assert_eq!(unique, vec![new]);
+    }
+
+    fn header(blockchain: &str, hash: &str, timestamp: u64) -> BlockchainHeader {
+        let mut header = BlockchainHeader::new();
+        header.set_blockchain(String::from(blockchain));
+        header.set_hash(String::from(hash));
+        header.set_timestamp(timestamp);
+        header
+    }
+
+    #[test]
+    fn get_newest_header_test() {
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(vec![header("btc", "btc_2", 2000), header("btc", "btc_1", 1000)]));
+        headers.set_eth(RepeatedField::from_vec(vec![header("eth", "eth_1", 3000)]));
+        headers.set_neo(RepeatedField::from_vec(vec![header("neo", "neo_1", 2500)]));
+        let mut block = BcBlock::new();
+        block.set_blockchain_headers(headers);
+
+        assert_eq!(get_newest_header(&block).unwrap().get_hash(), "eth_1");
+    }
+
+    #[test]
+    fn get_newest_header_tie_break_test() {
+        // within one chain the last header with the highest timestamp wins
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(vec![header("btc", "btc_1", 1000), header("btc", "btc_2", 1000)]));
+        let mut block = BcBlock::new();
+        block.set_blockchain_headers(headers.clone());
+        assert_eq!(get_newest_header(&block).unwrap().get_hash(), "btc_2");
+
+        // across chains the first chain in field order wins
+        headers.set_wav(RepeatedField::from_vec(vec![header("wav", "wav_1", 1000)]));
+        headers.set_lsk(RepeatedField::from_vec(vec![header("lsk", "lsk_1", 1000)]));
+        block.set_blockchain_headers(headers);
+        assert_eq!(get_newest_header(&block).unwrap().get_hash(), "btc_2");
+    }
+
+    #[test]
+    fn get_newest_header_empty_test() {
+        assert_eq!(get_newest_header(&BcBlock::new()), None);
+    }
+
+    #[test]
+    fn get_newest_header_template_test() {
+        // parity with getNewestHeader(bc.block.93699) in JS
+        let block = load_bc_block(BC_BLOCK_93699);
+        let newest = get_newest_header(&block).unwrap();
+        assert_eq!(newest.get_blockchain(), "lsk");
+        assert_eq!(newest.get_hash(), "5966913534974813203");
+        assert_eq!(newest.get_timestamp(), 1534304220000);
+    }
+
+    #[test]
+    fn get_new_pre_exp_diff_malformed_block_test() {
+        let mut block = load_bc_block(BC_BLOCK_93699);
+        assert!(get_new_pre_exp_diff(1534304230, &block, 1).is_ok());
+
+        block.set_difficulty("0x10".to_string());
+        assert_eq!(get_new_pre_exp_diff(1534304230, &block, 1), Err(DifficultyError::MalformedDifficulty("0x10".to_string())));
+
+        assert_eq!(get_new_pre_exp_diff(1534304230, &BcBlock::new(), 1), Err(DifficultyError::MissingNewestChildHeader));
+    }
+
+    #[test]
+    fn get_diff_fresh_child_header_test() {
+        // child header younger than the time bound results in negative stale cost
+        let mut newest_header = BlockchainHeader::new();
+        newest_header.set_timestamp(1534956530000);
+        let received_diff = get_diff(
+            1534956535,
+            1534956531,
+            &Difficulty::from(815000000000000),
+            MINIMUM_DIFFICULTY,
+            1,
+            &newest_header
+        );
+        assert_eq!(received_diff, Difficulty::from(816000000000000))
+    }
+
+    #[test]
+    fn get_diff_time_bonus_test() {
+        // getDiff in JS with the same arguments
+        let expected = [(0, 299264255335166u64), (1, 298528962324516), (3, 297058376303216)];
+        let mut newest_header = BlockchainHeader::new();
+        newest_header.set_timestamp(1540000222000);
+        for &(new_block_count, diff) in expected.iter() {
+            let received_diff = get_diff(
+                1540000230,
+                1540000210,
+                &Difficulty::from(299631901840491),
+                MINIMUM_DIFFICULTY,
+                new_block_count,
+                &newest_header
+            );
+            assert_eq!(received_diff, Difficulty::from(diff))
+        }
+
+        newest_header.set_timestamp(1540000212000);
+        let received_diff = get_diff(
+            1540000215,
+            1540000210,
+            &Difficulty::from(299631901840491),
+            MINIMUM_DIFFICULTY,
+            2,
+            &newest_header
+        );
+        assert_eq!(received_diff, Difficulty::from(299999548345816))
     }
 }
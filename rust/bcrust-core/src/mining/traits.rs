@@ -2,7 +2,7 @@
 //
 // This source code is licensed under the MIT license found in the
 // LICENSE file in the root directory of this source tree.
-use super::super::protos::core::{BlockchainHeader, Block};
+use super::super::protos::core::{BlockchainHeader, Block, MarkedTransaction};
 use std::hash::{Hash, Hasher};
 
 pub trait RoveredBlockLike {
@@ -12,6 +12,7 @@ pub trait RoveredBlockLike {
     fn get_timestamp(&self) -> u64;
     fn get_height(&self) -> u64;
     fn get_merkle_root(&self) -> &str;
+    fn get_marked_txs(&self) -> &[MarkedTransaction];
 }
 
 impl RoveredBlockLike for BlockchainHeader {
@@ -19,10 +20,10 @@ impl RoveredBlockLike for BlockchainHeader {
         &self.blockchain
     }
     fn get_hash(&self) -> &str {
-        &self.merkle_root
+        &self.hash
     }
     fn get_previous_hash(&self) -> &str {
-        &self.merkle_root
+        &self.previous_hash
     }
     fn get_timestamp(&self) -> u64 {
         self.timestamp
@@ -31,6 +32,7 @@ impl RoveredBlockLike for BlockchainHeader {
         self.height
     }
     fn get_merkle_root(&self) -> &str { &self.merkle_root }
+    fn get_marked_txs(&self) -> &[MarkedTransaction] { &self.marked_txs }
 }
 
 impl RoveredBlockLike for Block {
@@ -38,10 +40,10 @@ impl RoveredBlockLike for Block {
         &self.blockchain
     }
     fn get_hash(&self) -> &str {
-        &self.merkle_root
+        &self.hash
     }
     fn get_previous_hash(&self) -> &str {
-        &self.merkle_root
+        &self.previous_hash
     }
     fn get_timestamp(&self) -> u64 {
         self.timestamp
@@ -50,6 +52,7 @@ impl RoveredBlockLike for Block {
         self.height
     }
     fn get_merkle_root(&self) -> &str { &self.merkle_root }
+    fn get_marked_txs(&self) -> &[MarkedTransaction] { &self.marked_txs }
 }
 
 //impl PartialEq for Block {
@@ -0,0 +1,349 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Context of the transaction input a script is evaluated for, port of `getScriptEnv` and
+//! `getScriptMarkedTxs` from src/script/index.js
+//!
+//! Spending an output which calls back, `<hash> <index> OP_CALLBACK <script>`, evaluates the maker
+//! output script of the referenced transaction without its leading `OP_MONOID` followed by the
+//! rest of the output script. The marked transactions are loaded for scripts with `OP_MAKERCOLL`
+//! from the blocks between the shift and the settlement height of their `OP_DEPSET`.
+use std::cmp;
+use std::collections::HashMap;
+use std::str;
+
+use num_bigint::BigInt;
+
+use protos::core::{MarkedTransaction, OutPoint, Transaction, TransactionInput, TransactionOutput};
+
+use super::opcodes::Opcode;
+use super::ScriptError;
+
+/// Source of the transactions and blocks environments are loaded from, the persistence of the JS interpreter
+pub trait ScriptLookup {
+    /// Transaction `hash` together with the height of the block including it, `getTransactionByHash`
+    /// and `bc.txblock.<hash>` in JS
+    fn get_transaction(&self, hash: &str) -> Option<(Transaction, u64)>;
+    /// Height of `bc.block.latest`
+    fn get_latest_height(&self) -> u64;
+    /// Marked transactions of the blocks from height `from` to `to`, both included
+    fn get_marked_txs(&self, from: u64, to: u64) -> Vec<MarkedTransaction>;
+}
+
+/// Maker output the spent output calls back to, `CALLBACK_*` in JS
+#[derive(Debug, Clone, PartialEq)]
+pub struct Callback {
+    pub tx: Transaction,
+    pub index: u64,
+    /// Height of the block including `tx`
+    pub height: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Environment {
+    /// Script to evaluate, `SCRIPT` in JS
+    pub script: String,
+    /// Transaction of the input, `INPUT_TX` in JS
+    pub tx: Transaction,
+    pub out_point: OutPoint,
+    /// Transaction of the spent output, `OUTPOINT_TX` in JS
+    pub out_point_tx: Transaction,
+    /// Height of the block including `out_point_tx`
+    pub out_point_height: u64,
+    pub callback: Option<Callback>,
+    /// Height of the block including `tx`, of the latest block for pending transactions
+    pub height: u64,
+    /// `MARKED_TXS` in JS
+    pub marked_txs: Vec<MarkedTransaction>,
+    /// Values `OP_X` looks up by set and key, `X` in JS
+    ///
+    /// JS loads the MSC2010 names into set 1, their keys are not hex and can not be pushed.
+    pub x: HashMap<BigInt, HashMap<BigInt, BigInt>>,
+    /// Emergency services events `OP_EMERGENCY` looks up by set and key, set 9 of `X` in JS
+    pub emergency: HashMap<(BigInt, BigInt), bool>,
+}
+
+fn script_of(output: &TransactionOutput) -> &str {
+    str::from_utf8(output.get_output_script()).unwrap_or("")
+}
+
+/// Collapses runs of spaces like `replace(/  +/g, ' ')`
+fn collapse_spaces(script: &str) -> String {
+    let mut collapsed = String::with_capacity(script.len());
+    for c in script.chars() {
+        if c != ' ' || !collapsed.ends_with(' ') {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+/// Shift and settlement height of the `OP_DEPSET` arguments, read the way `BeamToJson` does
+fn depset_heights(script: &str) -> Result<(u64, u64), ScriptError> {
+    let invalid = ScriptError::InvalidOperand(Opcode::OP_DEPSET);
+    let mut args: Vec<&str> = Vec::new();
+    for token in script.split_whitespace() {
+        if token == Opcode::OP_DEPSET.name() {
+            return match args[..] {
+                [shift, _, settle] => match (shift.parse(), settle.parse()) {
+                    (Ok(shift), Ok(settle)) => Ok((shift, settle)),
+                    _ => Err(invalid),
+                },
+                _ => Err(invalid),
+            };
+        }
+        if token.contains("OP_") {
+            args.clear();
+        } else {
+            args.push(token);
+        }
+    }
+    Err(invalid)
+}
+
+fn get_output<L: ScriptLookup>(lookup: &L, hash: &str, index: u64) -> Result<(Transaction, u64), ScriptError> {
+    let (tx, height) = lookup.get_transaction(hash).ok_or_else(|| ScriptError::MissingTransaction(hash.to_string()))?;
+    if tx.get_outputs().len() as u64 <= index {
+        return Err(ScriptError::MissingOutput(hash.to_string(), index));
+    }
+    Ok((tx, height))
+}
+
+impl Environment {
+    /// Environment of `input` of `tx` spending an output locked by `output_script`, `getScriptEnv` in JS
+    ///
+    /// `tx_height` is the height of the block including `tx`, `None` for pending transactions.
+    /// Where JS fails to load the environment the error is returned, the input does not unlock then.
+    pub fn load<L: ScriptLookup>(
+        lookup: &L,
+        output_script: &str,
+        input_script: &str,
+        input: &TransactionInput,
+        tx: &Transaction,
+        tx_height: Option<u64>,
+    ) -> Result<Environment, ScriptError> {
+        let mut script = format!("{} {}", input_script, output_script);
+        let mut callback = None;
+        if let Some(at) = output_script.find(Opcode::OP_CALLBACK.name()) {
+            if script.contains(Opcode::OP_MONOID.name()) {
+                return Err(ScriptError::InvalidCallback);
+            }
+
+            let callback_string = output_script[..at].trim_end();
+            let rest = output_script[at + Opcode::OP_CALLBACK.name().len()..].trim_start();
+            let mut parts = callback_string.split(' ');
+            let (hash, index) = match (parts.next(), parts.next().and_then(|index| index.parse().ok())) {
+                (Some(hash), Some(index)) => (hash, index),
+                _ => return Err(ScriptError::InvalidCallback),
+            };
+
+            let (callback_tx, height) = get_output(lookup, hash, index)?;
+            let maker_script = script_of(&callback_tx.get_outputs()[index as usize]);
+            if !maker_script.starts_with(Opcode::OP_MONOID.name()) {
+                return Err(ScriptError::InvalidCallback);
+            }
+
+            let maker_script = maker_script.replacen(Opcode::OP_MONOID.name(), "", 1);
+            script = collapse_spaces(&format!("{} {} OP_CALLBACK {} {}", input_script, callback_string, maker_script, rest));
+            callback = Some(Callback { tx: callback_tx, index, height });
+        }
+
+        let out_point = input.get_out_point();
+        let (out_point_tx, out_point_height) = get_output(lookup, out_point.get_hash(), out_point.get_index())?;
+
+        // exactly one input may spend a monoid
+        if script.contains(Opcode::OP_MONOID.name()) {
+            let mut monoids = 0;
+            for input in tx.get_inputs() {
+                let spent = input.get_out_point();
+                let (spent_tx, _) = get_output(lookup, spent.get_hash(), spent.get_index())?;
+                if script_of(&spent_tx.get_outputs()[spent.get_index() as usize]).contains(Opcode::OP_MONOID.name()) {
+                    monoids += 1;
+                }
+            }
+            if monoids != 1 {
+                return Err(ScriptError::MonoidIsomorphism);
+            }
+        }
+
+        let height = tx_height.unwrap_or_else(|| lookup.get_latest_height());
+        let mut marked_txs = Vec::new();
+        if script.contains(Opcode::OP_MAKERCOLL.name()) {
+            let (shift, settle) = depset_heights(&script)?;
+            let start = callback.as_ref().map_or(out_point_height, |callback| callback.height);
+            let from = start.saturating_add(shift);
+            let to = cmp::min(height, from.saturating_add(settle));
+            if from <= to {
+                marked_txs = lookup.get_marked_txs(from, to);
+            }
+        }
+
+        Ok(Environment {
+            script,
+            tx: tx.clone(),
+            out_point: out_point.clone(),
+            out_point_tx,
+            out_point_height,
+            callback,
+            height,
+            marked_txs,
+            ..Environment::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use tx::cross_chain::{MakerOrder, TakerOrder};
+
+    const ADDRESS: &str = "0x25cc7722a6378e11082f7967c09b98bd26c979d3";
+    const MAKER_TX_HASH: &str = "387a12d284f41d0f7f29f42efd14a232849d4305b6d7b980b448638d8877c129";
+
+    struct TestLookup {
+        txs: HashMap<String, (Transaction, u64)>,
+        marked: Vec<(u64, MarkedTransaction)>,
+    }
+
+    impl ScriptLookup for TestLookup {
+        fn get_transaction(&self, hash: &str) -> Option<(Transaction, u64)> {
+            self.txs.get(hash).cloned()
+        }
+
+        fn get_latest_height(&self) -> u64 {
+            900
+        }
+
+        fn get_marked_txs(&self, from: u64, to: u64) -> Vec<MarkedTransaction> {
+            self.marked
+                .iter()
+                .filter(|&&(height, _)| from <= height && height <= to)
+                .map(|(_, marked)| marked.clone())
+                .collect()
+        }
+    }
+
+    fn output(script: &str) -> TransactionOutput {
+        let mut output = TransactionOutput::new();
+        output.set_value(vec![100]);
+        output.set_unit(vec![1]);
+        output.set_script_length(script.len() as u32);
+        output.set_output_script(script.as_bytes().to_vec());
+        output
+    }
+
+    fn input(hash: &str, index: u64) -> TransactionInput {
+        let mut out_point = OutPoint::new();
+        out_point.set_hash(hash.to_string());
+        out_point.set_index(index);
+        let mut input = TransactionInput::new();
+        input.set_out_point(out_point);
+        input
+    }
+
+    fn tx(hash: &str, inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Transaction {
+        let mut tx = Transaction::new();
+        tx.set_hash(hash.to_string());
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx.set_outputs(RepeatedField::from_vec(outputs));
+        tx
+    }
+
+    fn marked(addr_to: &str) -> MarkedTransaction {
+        let mut marked = MarkedTransaction::new();
+        marked.set_id("eth".to_string());
+        marked.set_addr_to(addr_to.to_string());
+        marked
+    }
+
+    fn lookup() -> TestLookup {
+        let maker_script = MakerOrder::new(0, 450, 800, "eth", "btc", "1BoatSLRHtKNngkdXEeobR76b53LETtpyT", "0.5", "10", ADDRESS)
+            .to_script();
+        let maker_tx = tx(MAKER_TX_HASH, vec![], vec![output("OP_DROP"), output(&maker_script)]);
+        let spent_tx = tx("aa", vec![], vec![output("1 OP_VERIFY"), output("OP_MONOID 1 OP_VERIFY")]);
+
+        let mut txs = HashMap::new();
+        txs.insert(MAKER_TX_HASH.to_string(), (maker_tx, 10));
+        txs.insert("aa".to_string(), (spent_tx, 20));
+        let marked = vec![(9, marked("a")), (10, marked("b")), (800, marked("c")), (811, marked("d"))];
+        TestLookup { txs, marked }
+    }
+
+    #[test]
+    fn load_test() {
+        let lookup = lookup();
+        let spending = tx("bb", vec![input("aa", 0)], vec![]);
+        let env = Environment::load(&lookup, "1 OP_VERIFY", "1", &spending.get_inputs()[0], &spending, Some(30)).unwrap();
+        assert_eq!(env.script, "1 1 OP_VERIFY");
+        assert_eq!(env.out_point_tx.get_hash(), "aa");
+        assert_eq!((env.out_point_height, env.height, env.callback), (20, 30, None));
+        assert_eq!(env.marked_txs, vec![]);
+
+        // pending transactions are evaluated at the latest height
+        let env = Environment::load(&lookup, "1 OP_VERIFY", "1", &spending.get_inputs()[0], &spending, None).unwrap();
+        assert_eq!(env.height, 900);
+
+        let missing = tx("bb", vec![input("aa", 2)], vec![]);
+        assert_eq!(
+            Environment::load(&lookup, "1 OP_VERIFY", "1", &missing.get_inputs()[0], &missing, None),
+            Err(ScriptError::MissingOutput("aa".to_string(), 2))
+        );
+    }
+
+    #[test]
+    fn load_callback_test() {
+        let lookup = lookup();
+        let taker = TakerOrder::new(MAKER_TX_HASH, 1, ADDRESS);
+        let spending = tx("bb", vec![input("aa", 0)], vec![]);
+        let spending_input = &spending.get_inputs()[0];
+
+        let env = Environment::load(&lookup, &taker.to_callback_script(), "ab", spending_input, &spending, Some(30)).unwrap();
+        let maker_script = lookup.txs[MAKER_TX_HASH].0.get_outputs()[1].get_output_script().to_vec();
+        let maker_script = String::from_utf8(maker_script).unwrap();
+        assert_eq!(
+            env.script,
+            format!("ab {} 1 OP_CALLBACK{} ", MAKER_TX_HASH, &maker_script["OP_MONOID".len()..])
+        );
+        let callback = env.callback.unwrap();
+        assert_eq!((callback.tx.get_hash(), callback.index, callback.height), (MAKER_TX_HASH, 1, 10));
+
+        // marked transactions between the shift and the settlement height of the maker order
+        let env = Environment::load(&lookup, &taker.to_script(), "ab", spending_input, &spending, None).unwrap();
+        let addresses: Vec<&str> = env.marked_txs.iter().map(|marked| marked.get_addr_to()).collect();
+        assert_eq!(addresses, vec!["b", "c"]);
+
+        let not_maker = format!("{} 0 OP_CALLBACK", MAKER_TX_HASH);
+        assert_eq!(
+            Environment::load(&lookup, &not_maker, "ab", spending_input, &spending, None),
+            Err(ScriptError::InvalidCallback)
+        );
+        let missing = format!("{} 0 OP_CALLBACK", "cc");
+        assert_eq!(
+            Environment::load(&lookup, &missing, "ab", spending_input, &spending, None),
+            Err(ScriptError::MissingTransaction("cc".to_string()))
+        );
+    }
+
+    #[test]
+    fn load_monoid_test() {
+        let lookup = lookup();
+        let spending = tx("bb", vec![input("aa", 1), input("aa", 0)], vec![]);
+        let env = Environment::load(&lookup, "OP_MONOID 1 OP_VERIFY", "", &spending.get_inputs()[0], &spending, None);
+        assert!(env.is_ok());
+
+        let spending = tx("bb", vec![input("aa", 1), input(MAKER_TX_HASH, 1)], vec![]);
+        assert_eq!(
+            Environment::load(&lookup, "OP_MONOID 1 OP_VERIFY", "", &spending.get_inputs()[0], &spending, None),
+            Err(ScriptError::MonoidIsomorphism)
+        );
+        let spending = tx("bb", vec![input("aa", 0)], vec![]);
+        assert_eq!(
+            Environment::load(&lookup, "1 OP_VERIFY", "OP_MONOID", &spending.get_inputs()[0], &spending, None),
+            Err(ScriptError::MonoidIsomorphism)
+        );
+    }
+}
@@ -0,0 +1,868 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Stack machine evaluating compiled scripts, port of src/script/script-stack.js
+//!
+//! Values are integers, JS keeps them as hex strings of `BN` numbers. Hashing opcodes hash the
+//! hex form of the minimal big endian bytes of the value and push the hex digest back.
+//!
+//! Where `script-stack.js` hands plain numbers to `BN` JS throws, scripts with `OP_1ADD`, `OP_1SUB`,
+//! `OP_2MUL`, `OP_2DIV`, `OP_NEGATE`, `OP_1NEGATE`, `OP_INVERT`, the shifts and the multisig
+//! opcodes are rejected here. `OP_NOT` and `OP_0NOTEQUAL` compare with a plain number which never
+//! equals a `BN`, they push 0 and 1 and fail for negative values as in JS. The stack opcodes keep
+//! the JS behavior which differs for `OP_PICK`, `OP_ROLL` and `OP_TUCK`.
+//!
+//! Opcodes reading the transaction being validated need an `Environment`, see `Interpreter::with_environment`.
+use std::cmp::Ordering;
+
+use blake2_rfc::blake2b::blake2b;
+use num_bigint::{BigInt, Sign};
+use num_traits::{ToPrimitive, Zero};
+use rust_crypto::digest::Digest;
+use rust_crypto::ripemd160::Ripemd160;
+use rust_crypto::sha1::Sha1;
+use rust_crypto::sha2::Sha256;
+use rustc_serialize::hex::ToHex;
+
+use crypto::sig::{pub_key_recover, verify_compact_signature, SIGNATURE_LENGTH};
+use protos::core::TransactionOutput;
+use types::Hash256;
+
+use super::environment::Environment;
+use super::opcodes::Opcode;
+use super::parser::{parse_data, Instruction, Script};
+use super::ScriptError;
+
+/// Hex digits of the shortest address `OP_TAKERPAIR` accepts, `MIN_TAKER_ADDR_LENGTH` in JS
+const MIN_TAKER_ADDRESS_LENGTH: usize = 28;
+
+/// Minimal big endian bytes of `value` ignoring its sign, `toBuffer()` of `BN`
+fn to_bytes(value: &BigInt) -> Vec<u8> {
+    value.to_bytes_be().1
+}
+
+fn from_hex_digest(digest: &str) -> BigInt {
+    BigInt::parse_bytes(digest.as_bytes(), 16).expect("digests are hex")
+}
+
+fn digest_hex<D: Digest>(mut digest: D, data: &str) -> String {
+    digest.input(data.as_bytes());
+    digest.result_str()
+}
+
+fn blake2bl(data: &str) -> String {
+    Hash256::blake2bl(data.as_bytes()).to_hex()
+}
+
+/// Last 20 bytes of blake2b-512, `blake2bls` in src/utils/crypto.es6
+fn blake2bls(data: &str) -> String {
+    blake2b(64, &[], data.as_bytes()).as_bytes()[44..].to_hex()
+}
+
+fn bool_value(value: bool) -> BigInt {
+    BigInt::from(if value { 1 } else { 0 })
+}
+
+/// Unsigned big endian bytes of values and units
+fn bytes_value(bytes: &[u8]) -> BigInt {
+    BigInt::from_bytes_be(Sign::Plus, bytes)
+}
+
+fn total_value(outputs: &[&TransactionOutput]) -> BigInt {
+    outputs.iter().fold(BigInt::zero(), |total, output| total + bytes_value(output.get_value()))
+}
+
+/// Reads the hex digits of a number written in decimal, `replace(/\D/g, '')` in JS
+fn decimal(value: &BigInt, op: Opcode) -> Result<u64, ScriptError> {
+    let digits: String = value.to_str_radix(16).chars().filter(|c| c.is_ascii_digit()).collect();
+    digits.parse().map_err(|_| ScriptError::InvalidOperand(op))
+}
+
+/// Evaluates scripts on its stack, `ScriptStack` in JS
+#[derive(Debug, Default)]
+pub struct Interpreter<'a> {
+    stack: Vec<BigInt>,
+    allow_disabled: bool,
+    env: Option<&'a Environment>,
+    /// Whether the spending outputs were checked, `CALLBACK_LOCAL_OUTPUTS` in JS
+    callback_outputs_checked: bool,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new() -> Interpreter<'a> {
+        Interpreter::default()
+    }
+
+    /// Interpreter accepting the disabled opcodes, `allowDisabled` in JS
+    pub fn allowing_disabled() -> Interpreter<'a> {
+        Interpreter { allow_disabled: true, ..Interpreter::default() }
+    }
+
+    /// Interpreter evaluating the opcodes which read the transaction input of `env`, `ScriptStack(env)` in JS
+    pub fn with_environment(env: &'a Environment) -> Interpreter<'a> {
+        Interpreter { env: Some(env), ..Interpreter::default() }
+    }
+
+    /// Values on the stack, the top is last
+    pub fn stack(&self) -> &[BigInt] {
+        &self.stack
+    }
+
+    /// Runs `script`, returns the result of its terminal opcode
+    ///
+    /// A script without a terminal opcode, one ending with `OP_EQ` or reaching `OP_RETURN` evaluates
+    /// to false. Failed verifications and invalid operations are errors, JS throws for those.
+    pub fn run(&mut self, script: &Script) -> Result<bool, ScriptError> {
+        if !self.allow_disabled {
+            if let Some(op) = script.opcodes().find(|op| op.is_disabled()) {
+                return Err(ScriptError::DisabledOpcode(op));
+            }
+        }
+
+        let instructions = script.instructions();
+        let mut position = 0;
+        while let Some(instruction) = instructions.get(position) {
+            position += 1;
+            match *instruction {
+                Instruction::Push(ref value) => self.stack.push(value.clone()),
+                Instruction::Call(op) => self.call(op)?,
+                Instruction::Return(Opcode::OP_RETURN) => return Ok(false),
+                Instruction::Return(op) => {
+                    self.call(op)?;
+                    return Ok(op != Opcode::OP_EQ);
+                }
+                Instruction::JumpIfZero(target) => {
+                    if self.pop()?.is_zero() {
+                        position = target;
+                    }
+                }
+                Instruction::JumpIfNotZero(target) => {
+                    if !self.pop()?.is_zero() {
+                        position = target;
+                    }
+                }
+                Instruction::JumpIfNotEqual(target) => {
+                    let a = self.pop()?;
+                    let b = self.pop()?;
+                    if a == b {
+                        self.stack.push(b);
+                    } else {
+                        position = target;
+                    }
+                }
+                Instruction::Jump(target) => position = target,
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn pop(&mut self) -> Result<BigInt, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::EmptyStack)
+    }
+
+    /// Pops `n` values, the top value is last
+    fn pop_n(&mut self, n: usize) -> Result<Vec<BigInt>, ScriptError> {
+        if n > self.stack.len() {
+            return Err(ScriptError::EmptyStack);
+        }
+
+        let at = self.stack.len() - n;
+        Ok(self.stack.split_off(at))
+    }
+
+    fn peek(&self) -> Result<BigInt, ScriptError> {
+        self.stack.last().cloned().ok_or(ScriptError::EmptyStack)
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.stack.push(bool_value(value));
+    }
+
+    /// Pops `b` and then `a`, pushes `f(a, b)`
+    fn binary<F: Fn(BigInt, BigInt) -> BigInt>(&mut self, f: F) -> Result<(), ScriptError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.stack.push(f(a, b));
+        Ok(())
+    }
+
+    fn compare<F: Fn(Ordering) -> bool>(&mut self, f: F) -> Result<(), ScriptError> {
+        self.binary(|a, b| bool_value(f(a.cmp(&b))))
+    }
+
+    fn hash<F: Fn(&str) -> String>(&mut self, f: F) -> Result<(), ScriptError> {
+        let value = self.pop()?;
+        self.stack.push(from_hex_digest(&f(&to_bytes(&value).to_hex())));
+        Ok(())
+    }
+
+    /// Duplicates the `n` top values, `OP_DUP(n)` in JS
+    fn dup(&mut self, n: usize) -> Result<(), ScriptError> {
+        let values = self.pop_n(n)?;
+        self.stack.extend(values.iter().cloned());
+        self.stack.extend(values);
+        Ok(())
+    }
+
+    /// Moves (`OP_ROLL`) or copies (`OP_PICK`) the n-th value to the top
+    ///
+    /// As in JS the values above it end up in reverse order and `n` below one selects the top.
+    fn pick(&mut self, remove: bool) -> Result<(), ScriptError> {
+        let n = self.pop()?;
+        let above = if n <= BigInt::from(1) {
+            0
+        } else {
+            (n - BigInt::from(1)).to_usize().unwrap_or(usize::max_value())
+        };
+
+        let mut values = self.pop_n(above)?;
+        let nth = if remove { self.pop()? } else { self.peek()? };
+        values.reverse();
+        self.stack.extend(values);
+        self.stack.push(nth);
+        Ok(())
+    }
+
+    /// Rotates the `n` top values left by `by` and pushes `len` of them, the shared shape of the JS stack opcodes
+    fn reorder(&mut self, n: usize, by: usize, len: usize) -> Result<(), ScriptError> {
+        let values = self.pop_n(n)?;
+        for i in 0..len {
+            self.stack.push(values[(i + by) % n].clone());
+        }
+        Ok(())
+    }
+
+    fn verify(&mut self) -> Result<(), ScriptError> {
+        if self.pop()?.is_zero() {
+            return Err(ScriptError::VerifyFailed);
+        }
+        Ok(())
+    }
+
+    /// `secp256k1.verify` of the message, the first 64 bytes of the signature and the public key
+    fn check_sig(&mut self) -> Result<(), ScriptError> {
+        let public_key = to_bytes(&self.pop()?);
+        let signature = to_bytes(&self.pop()?);
+        let message = to_bytes(&self.pop()?);
+
+        let message = Hash256::from_slice(&message).map_err(|_| ScriptError::InvalidSignatureData(Opcode::OP_CHECKSIG))?;
+        if signature.len() < 64 {
+            return Err(ScriptError::InvalidSignatureData(Opcode::OP_CHECKSIG));
+        }
+
+        let valid = verify_compact_signature(&message, &signature[..64], &public_key)
+            .map_err(|_| ScriptError::InvalidSignatureData(Opcode::OP_CHECKSIG))?;
+        self.push_bool(valid);
+        Ok(())
+    }
+
+    /// Verifies the signature against the public key recovered from it
+    fn check_sig_no_pub_key(&mut self) -> Result<(), ScriptError> {
+        let signature = to_bytes(&self.pop()?);
+        let message = to_bytes(&self.pop()?);
+        let invalid = ScriptError::InvalidSignatureData(Opcode::OP_CHECKSIGNOPUBKEY);
+
+        let message = Hash256::from_slice(&message).map_err(|_| invalid.clone())?;
+        if signature.len() != SIGNATURE_LENGTH {
+            return Err(invalid);
+        }
+
+        let mut public_key = vec![0x04];
+        public_key.extend(pub_key_recover(&message, &signature).map_err(|_| invalid.clone())?);
+        let valid = verify_compact_signature(&message, &signature[..64], &public_key).map_err(|_| invalid)?;
+        self.push_bool(valid);
+        Ok(())
+    }
+
+    /// Outputs of the spending transaction which call back with their first two tokens, `getOutpointsOfCallback` in JS
+    fn callback_outputs<F: Fn(&str, &str) -> bool>(env: &'a Environment, f: F) -> Vec<&'a TransactionOutput> {
+        env.tx
+            .get_outputs()
+            .iter()
+            .filter(|output| {
+                let script = String::from_utf8_lossy(output.get_output_script());
+                let mut tokens = script.split(' ');
+                match (tokens.next(), tokens.next()) {
+                    (Some(hash), Some(index)) => f(hash, index),
+                    _ => false,
+                }
+            })
+            .collect()
+    }
+
+    /// Output `index` of the transaction of the spent output
+    fn out_point_output(env: &'a Environment, index: &BigInt, op: Opcode) -> Result<&'a TransactionOutput, ScriptError> {
+        index
+            .to_usize()
+            .and_then(|index| env.out_point_tx.get_outputs().get(index))
+            .ok_or(ScriptError::InvalidOperand(op))
+    }
+
+    fn call_environment(&mut self, op: Opcode, env: &'a Environment) -> Result<(), ScriptError> {
+        let spent = BigInt::from(env.out_point.get_index());
+        match op {
+            Opcode::OP_ENVOUTPOINTUNIT | Opcode::OP_ENVOUTPOINTVALUE => {
+                let index = self.pop()?;
+                let output = Self::out_point_output(env, &index, op)?;
+                let value = if op == Opcode::OP_ENVOUTPOINTUNIT { output.get_unit() } else { output.get_value() };
+                self.stack.push(bytes_value(value));
+            }
+            Opcode::OP_ENVOUTPOINTHASH | Opcode::OP_ENVOUTPOINTNONCE => {
+                let data = if op == Opcode::OP_ENVOUTPOINTHASH { env.out_point_tx.get_hash() } else { env.out_point_tx.get_nonce() };
+                let value = BigInt::parse_bytes(data.as_bytes(), 16).ok_or(ScriptError::InvalidOperand(op))?;
+                self.stack.push(value);
+            }
+            Opcode::OP_ENVOUTPOINTLOCKTIME => self.stack.push(BigInt::from(env.out_point_tx.get_lock_time())),
+            // transactions have no such field, JS throws
+            Opcode::OP_ENVOUTPOINTVCLYSPUL => return Err(ScriptError::UnsupportedOpcode(op)),
+
+            // outputs of the spending transaction have to call back with the full value of the spent output
+            Opcode::OP_MONOID => {
+                let index = env.out_point.get_index().to_string();
+                let outputs = Self::callback_outputs(env, |hash, i| hash == env.out_point.get_hash() && i == index);
+                let required = bytes_value(Self::out_point_output(env, &spent, op)?.get_value());
+                if outputs.is_empty() {
+                    self.stack.push(BigInt::zero());
+                } else {
+                    self.callback_outputs_checked = true;
+                    if total_value(&outputs) < required {
+                        self.stack.push(BigInt::zero());
+                    }
+                }
+            }
+            Opcode::OP_CALLBACK => {
+                let index = self.pop()?;
+                let hash = self.pop()?;
+                if !self.callback_outputs_checked {
+                    let outputs = Self::callback_outputs(env, |h, i| {
+                        parse_data(h).as_ref() == Some(&hash) && parse_data(i).as_ref() == Some(&index)
+                    });
+                    let required = env.callback.as_ref().and_then(|callback| {
+                        callback.tx.get_outputs().get(callback.index as usize).map(|output| bytes_value(output.get_value()))
+                    });
+                    if !outputs.is_empty() {
+                        self.callback_outputs_checked = true;
+                    }
+                    match required {
+                        Some(ref required) if !outputs.is_empty() && total_value(&outputs) >= *required => {}
+                        _ => self.stack.push(BigInt::zero()),
+                    }
+                }
+            }
+            Opcode::OP_MINUNITVALUE => {
+                let base = self.pop()?;
+                let output = Self::out_point_output(env, &spent, op)?;
+                if env.callback.is_none() || bytes_value(output.get_unit()) * base >= bytes_value(output.get_value()) {
+                    self.stack.push(BigInt::zero());
+                }
+            }
+            Opcode::OP_TAKERPAIR => {
+                let values = self.pop_n(4)?;
+                let long_enough = |value: &BigInt| value.to_str_radix(16).len() >= MIN_TAKER_ADDRESS_LENGTH;
+                self.push_bool(env.callback.is_some() && long_enough(&values[0]) && long_enough(&values[1]));
+            }
+            Opcode::OP_MAKERCOLL => {
+                let values = self.pop_n(7)?;
+                let (taker_to, taker_from, numer, denom, maker_to) = (&values[0], &values[1], &values[2], &values[3], &values[4]);
+                let (buy_units, sell_units) = (&values[5], &values[6]);
+                let matches = |text: &str, value: &BigInt| parse_data(text).as_ref() == Some(value);
+
+                let mut sent = BigInt::zero();
+                let mut received = BigInt::zero();
+                for marked in &env.marked_txs {
+                    let value = bytes_value(marked.get_value());
+                    if matches(marked.get_addr_from(), taker_from) && matches(marked.get_addr_to(), maker_to) && matches(marked.get_id(), denom) {
+                        sent += value.clone();
+                    }
+                    if matches(marked.get_addr_to(), taker_to) && matches(marked.get_id(), numer) {
+                        received += value;
+                    }
+                }
+                let result = match (sent >= *buy_units, received >= *sell_units) {
+                    (false, false) => 0,
+                    (true, true) => 2,
+                    (false, true) => 3,
+                    (true, false) => 4,
+                };
+                self.stack.push(BigInt::from(result));
+            }
+            Opcode::OP_DEPSET => {
+                let values = self.pop_n(3)?;
+                let shift = decimal(&values[0], op)?;
+                let deposit = decimal(&values[1], op)?.saturating_add(shift);
+                let settle = decimal(&values[2], op)?.saturating_add(shift);
+                let start = env.callback.as_ref().map_or(env.out_point_height, |callback| callback.height);
+                let result = if env.height >= start.saturating_add(settle) {
+                    1
+                } else if env.height >= start.saturating_add(deposit) {
+                    3
+                } else if env.height > start.saturating_add(shift) {
+                    2
+                } else {
+                    0
+                };
+                self.stack.push(BigInt::from(result));
+            }
+            // JS calls it without the script, no output matches
+            Opcode::OP_MONAD => self.stack.push(BigInt::zero()),
+            Opcode::OP_X => {
+                let set = self.pop()?;
+                let key = self.pop()?;
+                let value = env.x.get(&set).and_then(|values| values.get(&key)).cloned().unwrap_or_else(BigInt::zero);
+                self.stack.push(value);
+            }
+            Opcode::OP_EMERGENCY => {
+                let expiration = self.pop()?;
+                let set = self.pop()?;
+                let key = self.pop()?;
+                self.push_bool(BigInt::from(env.height) < expiration);
+                if let Some(&event) = env.emergency.get(&(set, key)) {
+                    self.push_bool(event);
+                }
+            }
+            // JS only removes the arguments
+            Opcode::OP_NONCELOCKBL => {
+                self.pop_n(2)?;
+            }
+            Opcode::OP_PROMISE => {
+                self.pop_n(4)?;
+            }
+            Opcode::OP_SCHNACK => {
+                let values = self.pop_n(5)?;
+                // callback schnorr signatures are not implemented in JS
+                if values[4] == BigInt::from(5) {
+                    return Err(ScriptError::UnsupportedOpcode(op));
+                }
+                self.stack.push(BigInt::zero());
+            }
+            // OP_Q does nothing without ENABLE_INFINITE_Q
+            Opcode::OP_MYLX | Opcode::OP_RATEMARKET | Opcode::OP_Q => {}
+            _ => return Err(ScriptError::UnsupportedOpcode(op)),
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, op: Opcode) -> Result<(), ScriptError> {
+        if op.requires_environment() {
+            let env = self.env.ok_or(ScriptError::MissingEnvironment(op))?;
+            return self.call_environment(op, env);
+        }
+
+        match op {
+            // stack
+            Opcode::OP_IFDUP => {
+                let top = self.peek()?;
+                if !top.is_zero() {
+                    self.stack.push(top);
+                }
+            }
+            Opcode::OP_DEPTH => {
+                let depth = BigInt::from(self.stack.len());
+                self.stack.push(depth);
+            }
+            Opcode::OP_DROP => {
+                self.pop()?;
+            }
+            Opcode::OP_2DROP => {
+                self.pop_n(2)?;
+            }
+            Opcode::OP_DUP => self.dup(1)?,
+            Opcode::OP_2DUP => self.dup(2)?,
+            Opcode::OP_3DUP => self.dup(3)?,
+            Opcode::OP_NIP => {
+                let top = self.pop()?;
+                self.pop()?;
+                self.stack.push(top);
+            }
+            Opcode::OP_OVER => self.reorder(2, 0, 3)?,
+            Opcode::OP_PICK => self.pick(false)?,
+            Opcode::OP_ROLL => self.pick(true)?,
+            Opcode::OP_ROT => self.reorder(3, 1, 3)?,
+            Opcode::OP_SWAP => self.reorder(2, 1, 2)?,
+            // JS leaves `a b a` for `a b`
+            Opcode::OP_TUCK => self.reorder(2, 0, 3)?,
+            Opcode::OP_2OVER => self.reorder(4, 0, 6)?,
+            Opcode::OP_2ROT => self.reorder(6, 2, 6)?,
+            Opcode::OP_2SWAP => self.reorder(4, 2, 4)?,
+
+            // bitwise logic
+            Opcode::OP_AND => self.binary(|a, b| a & b)?,
+            Opcode::OP_OR => self.binary(|a, b| a | b)?,
+            Opcode::OP_XOR => self.binary(|a, b| a ^ b)?,
+            Opcode::OP_EQUAL | Opcode::OP_NUMEQUAL => self.compare(|o| o == Ordering::Equal)?,
+            Opcode::OP_EQ => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                if a != b {
+                    self.stack.push(BigInt::zero());
+                }
+            }
+
+            // arithmetic
+            Opcode::OP_ABS => {
+                let value = self.pop()?;
+                self.stack.push(if value < BigInt::zero() { -value } else { value });
+            }
+            // `eq(0)` of BN is false for plain numbers and asserts for negative values
+            Opcode::OP_NOT | Opcode::OP_0NOTEQUAL => {
+                if self.pop()? < BigInt::zero() {
+                    return Err(ScriptError::InvalidOperand(op));
+                }
+                self.push_bool(op == Opcode::OP_0NOTEQUAL);
+            }
+            Opcode::OP_ADD => self.binary(|a, b| a + b)?,
+            Opcode::OP_SUB => self.binary(|a, b| a - b)?,
+            Opcode::OP_MUL => self.binary(|a, b| a * b)?,
+            Opcode::OP_DIV | Opcode::OP_MOD => {
+                if self.peek()?.is_zero() {
+                    return Err(ScriptError::InvalidOperand(op));
+                }
+                if op == Opcode::OP_DIV {
+                    self.binary(|a, b| a / b)?
+                } else {
+                    self.binary(|a, b| a % b)?
+                }
+            }
+            Opcode::OP_BOOLAND => self.binary(|a, b| bool_value(!a.is_zero() && !b.is_zero()))?,
+            Opcode::OP_BOOLOR => self.binary(|a, b| bool_value(!a.is_zero() || !b.is_zero()))?,
+            Opcode::OP_NUMNOTEQUAL => self.compare(|o| o != Ordering::Equal)?,
+            Opcode::OP_LESSTHAN => self.compare(|o| o == Ordering::Less)?,
+            Opcode::OP_GREATERTHAN => self.compare(|o| o == Ordering::Greater)?,
+            Opcode::OP_LESSTHANOREQUAL => self.compare(|o| o != Ordering::Greater)?,
+            Opcode::OP_GREATERTHANOREQUAL => self.compare(|o| o != Ordering::Less)?,
+            Opcode::OP_MIN => self.binary(|a, b| if a <= b { a } else { b })?,
+            Opcode::OP_MAX => self.binary(|a, b| if a >= b { a } else { b })?,
+            Opcode::OP_WITHIN => {
+                let max = self.pop()?;
+                let min = self.pop()?;
+                let x = self.pop()?;
+                self.push_bool(x >= min && x < max);
+            }
+
+            // crypto
+            Opcode::OP_RIPEMD160 => self.hash(|data| digest_hex(Ripemd160::new(), data))?,
+            Opcode::OP_SHA1 => self.hash(|data| digest_hex(Sha1::new(), data))?,
+            Opcode::OP_SHA256 => self.hash(|data| digest_hex(Sha256::new(), data))?,
+            Opcode::OP_HASH160 =>
+                self.hash(|data| digest_hex(Ripemd160::new(), &digest_hex(Sha256::new(), data)))?,
+            Opcode::OP_HASH256 => self.hash(|data| digest_hex(Sha256::new(), &digest_hex(Sha256::new(), data)))?,
+            Opcode::OP_BLAKE2BL => self.hash(blake2bl)?,
+            Opcode::OP_BLAKE2BLS => self.hash(blake2bls)?,
+            Opcode::OP_BLAKE2BLC => self.hash(|data| blake2bls(&blake2bl(data)))?,
+            Opcode::OP_CHECKSIG => self.check_sig()?,
+            Opcode::OP_CHECKSIGNOPUBKEY => self.check_sig_no_pub_key()?,
+            // JS pops its arguments but compares none of the hashing algorithms correctly
+            Opcode::OP_DATATOHASH => {
+                self.pop_n(5)?;
+                return Err(ScriptError::VerifyFailed);
+            }
+
+            // verification
+            Opcode::OP_VERIFY => self.verify()?,
+            Opcode::OP_EQUALVERIFY => {
+                self.compare(|o| o == Ordering::Equal)?;
+                self.verify()?;
+            }
+            Opcode::OP_CHECKSIGVERIFY => {
+                self.check_sig()?;
+                self.verify()?;
+            }
+            Opcode::OP_CHECKSIGNOPUBKEYVERIFY => {
+                self.check_sig_no_pub_key()?;
+                self.verify()?;
+            }
+
+            // arguments of opcodes evaluated by other parts of the node
+            Opcode::OP_MARK => {
+                self.pop_n(3)?;
+            }
+            Opcode::OP_ORDTYPE | Opcode::OP_HTTPSTATUS | Opcode::OP_HTTPSELECT => {}
+
+            // JS hands plain numbers to BN and throws
+            Opcode::OP_1ADD |
+            Opcode::OP_1SUB |
+            Opcode::OP_2MUL |
+            Opcode::OP_2DIV |
+            Opcode::OP_NEGATE |
+            Opcode::OP_1NEGATE |
+            Opcode::OP_INVERT |
+            Opcode::OP_LSHIFT |
+            Opcode::OP_RSHIFT => return Err(ScriptError::UnsupportedOpcode(op)),
+            // `cmp` of BN with a plain number never ends the loop, JS pops until the stack is empty
+            Opcode::OP_CHECKMULTISIG | Opcode::OP_CHECKMULTISIGVERIFY => return Err(ScriptError::UnsupportedOpcode(op)),
+            _ => return Err(ScriptError::UnsupportedOpcode(op)),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+    use std::collections::HashMap;
+
+    use protos::core::{MarkedTransaction, Transaction};
+    use script::environment::Callback;
+
+    fn run(script: &str) -> (Result<bool, ScriptError>, Vec<BigInt>) {
+        let mut interpreter = Interpreter::allowing_disabled();
+        let result = interpreter.run(&script.parse().unwrap());
+        (result, interpreter.stack().to_vec())
+    }
+
+    fn stack(values: &[i64]) -> Vec<BigInt> {
+        values.iter().map(|value| BigInt::from(*value)).collect()
+    }
+
+    #[test]
+    fn stack_opcodes_test() {
+        let cases = vec![
+            ("1 2 OP_DUP", vec![1, 2, 2]),
+            ("1 2 OP_2DUP", vec![1, 2, 1, 2]),
+            ("1 2 3 OP_3DUP", vec![1, 2, 3, 1, 2, 3]),
+            ("1 2 OP_NIP", vec![2]),
+            ("1 2 OP_OVER", vec![1, 2, 1]),
+            ("1 2 3 OP_ROT", vec![2, 3, 1]),
+            ("1 2 OP_SWAP", vec![2, 1]),
+            ("1 2 OP_TUCK", vec![1, 2, 1]),
+            ("1 2 3 4 OP_2OVER", vec![1, 2, 3, 4, 1, 2]),
+            ("1 2 3 4 5 6 OP_2ROT", vec![3, 4, 5, 6, 1, 2]),
+            ("1 2 3 4 OP_2SWAP", vec![3, 4, 1, 2]),
+            ("1 2 3 4 3 OP_PICK", vec![1, 2, 4, 3, 2]),
+            ("1 2 3 4 3 OP_ROLL", vec![1, 4, 3, 2]),
+            ("1 2 0 OP_PICK", vec![1, 2, 2]),
+            ("0 OP_IFDUP 1 OP_IFDUP", vec![0, 1, 1]),
+            ("1 2 OP_DEPTH", vec![1, 2, 2]),
+            ("1 2 3 OP_2DROP", vec![1]),
+        ];
+        for (script, expected) in cases {
+            let (result, values) = run(script);
+            assert_eq!(result, Ok(false), "{}", script);
+            assert_eq!(values, stack(&expected), "{}", script);
+        }
+    }
+
+    #[test]
+    fn arithmetic_opcodes_test() {
+        let cases = vec![
+            ("1 2 OP_SUB OP_ABS", vec![1]),
+            ("0 OP_NOT 7 OP_NOT 0 OP_0NOTEQUAL 7 OP_0NOTEQUAL", vec![0, 0, 1, 1]),
+            ("a 5 OP_ADD a 5 OP_SUB", vec![15, 5]),
+            ("7 2 OP_MUL 7 2 OP_DIV 7 2 OP_MOD", vec![14, 3, 1]),
+            ("c a OP_AND c a OP_OR c a OP_XOR", vec![8, 14, 6]),
+            ("1 0 OP_BOOLAND 1 0 OP_BOOLOR", vec![0, 1]),
+            ("2 2 OP_NUMEQUAL 2 2 OP_NUMNOTEQUAL", vec![1, 0]),
+            ("1 2 OP_LESSTHAN 1 2 OP_GREATERTHAN 2 2 OP_LESSTHANOREQUAL 1 2 OP_GREATERTHANOREQUAL", vec![1, 0, 1, 0]),
+            ("1 2 OP_MIN 1 2 OP_MAX", vec![1, 2]),
+            ("2 1 3 OP_WITHIN 3 1 3 OP_WITHIN", vec![1, 0]),
+            ("1 2 OP_EQ 2 2 OP_EQ", vec![0]),
+            ("OP_0 OP_TRUE OP_3 OP_16 OP_NOP OP_INITBCI", vec![0, 1, 3, 16]),
+        ];
+        for (script, expected) in cases {
+            let (result, values) = run(script);
+            assert_eq!(result, Ok(false), "{}", script);
+            assert_eq!(values, stack(&expected), "{}", script);
+        }
+
+        assert_eq!(run("1 0 OP_DIV").0, Err(ScriptError::InvalidOperand(Opcode::OP_DIV)));
+        assert_eq!(run("1 2 OP_SUB OP_NOT").0, Err(ScriptError::InvalidOperand(Opcode::OP_NOT)));
+
+        // JS throws handing plain numbers to BN
+        let rejected = vec![
+            ("5 OP_1ADD", Opcode::OP_1ADD),
+            ("5 OP_1SUB", Opcode::OP_1SUB),
+            ("7 OP_2MUL", Opcode::OP_2MUL),
+            ("7 OP_2DIV", Opcode::OP_2DIV),
+            ("3 OP_NEGATE", Opcode::OP_NEGATE),
+            ("OP_1NEGATE", Opcode::OP_1NEGATE),
+            ("0 OP_INVERT", Opcode::OP_INVERT),
+            ("1 4 OP_LSHIFT", Opcode::OP_LSHIFT),
+            ("10 4 OP_RSHIFT", Opcode::OP_RSHIFT),
+            ("0 0 OP_CHECKMULTISIG", Opcode::OP_CHECKMULTISIG),
+        ];
+        for (script, op) in rejected {
+            assert_eq!(run(script).0, Err(ScriptError::UnsupportedOpcode(op)), "{}", script);
+        }
+    }
+
+    #[test]
+    fn hash_opcodes_test() {
+        // crypto.createHash of "ab" in node, the leading zero byte of the value is dropped
+        let cases = vec![
+            ("OP_BLAKE2BL", "928443850545770f64abac2a75f18475653fa3d9a52c66a840da3b8617ae9607"),
+            ("OP_BLAKE2BLS", "75f18475653fa3d9a52c66a840da3b8617ae9607"),
+            ("OP_BLAKE2BLC", "76da58c93f643583c41fa6b0df83836d831e66bf"),
+            ("OP_SHA1", "da23614e02469a0d7c7bd1bdab5c9c474b1904dc"),
+            ("OP_SHA256", "fb8e20fc2e4c3f248c60c39bd652f3c1347298bb977b8b4d5903b85055620603"),
+            ("OP_RIPEMD160", "8576c67fcdf6c5d2f648efa58a32856b957f401a"),
+            ("OP_HASH160", "751e8f04bfed987c37fb67c3b742993c68e8efc6"),
+            ("OP_HASH256", "6a866e4029d510139186c362675bb993a91e9132c867722faa519c5711ac1807"),
+        ];
+        for (op, digest) in cases {
+            let (result, values) = run(&format!("00ab {}", op));
+            assert_eq!(result, Ok(false), "{}", op);
+            assert_eq!(values, vec![from_hex_digest(digest)], "{}", op);
+        }
+    }
+
+    #[test]
+    fn control_flow_test() {
+        assert_eq!(run("1 OP_IF 2 OP_ELSE 3 OP_ENDIF").1, stack(&[2]));
+        assert_eq!(run("0 OP_IF 2 OP_ELSE 3 OP_ENDIF").1, stack(&[3]));
+        assert_eq!(run("0 OP_IF 2 OP_ENDIF").1, stack(&[]));
+        assert_eq!(run("0 OP_NOTIF 2 OP_ELSE 3 OP_ENDIF").1, stack(&[2]));
+        assert_eq!(run("1 1 OP_IF 0 OP_IF 4 OP_ENDIF 5 OP_ENDIF").1, stack(&[1, 5]));
+        assert_eq!(run("2 2 OP_IFEQ 3 OP_ENDIFEQ").1, stack(&[2, 3]));
+        assert_eq!(run("2 1 OP_GATE 3 OP_ENDIFEQ").1, stack(&[]));
+
+        assert_eq!(run("1 OP_VERIFY"), (Ok(true), stack(&[])));
+        assert_eq!(run("0 OP_VERIFY"), (Err(ScriptError::VerifyFailed), stack(&[])));
+        assert_eq!(run("1 OP_VERIFY ").0, Ok(false));
+        assert_eq!(run("1 OP_VERIFY 1").0, Ok(false));
+        assert_eq!(run("1 1 OP_EQ").0, Ok(false));
+        assert_eq!(run("1 OP_RETURN 1 OP_VERIFY").0, Ok(false));
+        assert_eq!(run("1 OP_IF OP_RETURN OP_ENDIF 1 OP_VERIFY").0, Ok(false));
+        assert_eq!(run("0 OP_IF OP_RETURN OP_ENDIF 1 OP_VERIFY").0, Ok(true));
+        assert_eq!(run("OP_DROP").0, Err(ScriptError::EmptyStack));
+        assert_eq!(run("1 OP_DEPSET").0, Err(ScriptError::MissingEnvironment(Opcode::OP_DEPSET)));
+        assert_eq!(run("1 2 3 4 5 OP_DATATOHASH").0, Err(ScriptError::VerifyFailed));
+
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.run(&"0 OP_IF 1 2 OP_MUL OP_ENDIF".parse().unwrap()),
+            Err(ScriptError::DisabledOpcode(Opcode::OP_MUL))
+        );
+    }
+
+    fn output(value: u8, unit: u8, script: &str) -> TransactionOutput {
+        let mut output = TransactionOutput::new();
+        output.set_value(vec![value]);
+        output.set_unit(vec![unit]);
+        output.set_output_script(script.as_bytes().to_vec());
+        output
+    }
+
+    fn marked(from: &str, to: &str, id: &str, value: u8) -> MarkedTransaction {
+        let mut marked = MarkedTransaction::new();
+        marked.set_addr_from(from.to_string());
+        marked.set_addr_to(to.to_string());
+        marked.set_id(id.to_string());
+        marked.set_value(vec![value]);
+        marked
+    }
+
+    /// Spends output 1 of transaction `aa` at height 10 with a transaction at height 100
+    fn environment() -> Environment {
+        let mut env = Environment::default();
+        env.out_point.set_hash("aa".to_string());
+        env.out_point.set_index(1);
+        env.out_point_tx.set_hash("0abc".to_string());
+        env.out_point_tx.set_nonce("12".to_string());
+        env.out_point_tx.set_lock_time(7);
+        env.out_point_tx.set_outputs(RepeatedField::from_vec(vec![output(1, 1, ""), output(40, 4, "OP_MONOID")]));
+        env.out_point_height = 10;
+        env.height = 100;
+        env
+    }
+
+    fn with_callback(mut env: Environment, outputs: Vec<TransactionOutput>) -> Environment {
+        let mut tx = Transaction::new();
+        tx.set_outputs(RepeatedField::from_vec(vec![output(1, 1, ""), output(30, 1, "OP_MONOID")]));
+        env.callback = Some(Callback { tx, index: 1, height: 50 });
+        env.tx.set_outputs(RepeatedField::from_vec(outputs));
+        env
+    }
+
+    fn run_in(env: &Environment, script: &str) -> (Result<bool, ScriptError>, Vec<BigInt>) {
+        let mut interpreter = Interpreter::with_environment(env);
+        let result = interpreter.run(&script.parse().unwrap());
+        (result, interpreter.stack().to_vec())
+    }
+
+    #[test]
+    fn environment_opcodes_test() {
+        let env = environment();
+        assert_eq!(run_in(&env, "1 OP_ENVOUTPOINTVALUE 1 OP_ENVOUTPOINTUNIT").1, stack(&[40, 4]));
+        assert_eq!(run_in(&env, "OP_ENVOUTPOINTHASH OP_ENVOUTPOINTNONCE OP_ENVOUTPOINTLOCKTIME").1, stack(&[0xabc, 0x12, 7]));
+        assert_eq!(run_in(&env, "2 OP_ENVOUTPOINTVALUE").0, Err(ScriptError::InvalidOperand(Opcode::OP_ENVOUTPOINTVALUE)));
+        assert_eq!(run_in(&env, "OP_ENVOUTPOINTVCLYSPUL").0, Err(ScriptError::UnsupportedOpcode(Opcode::OP_ENVOUTPOINTVCLYSPUL)));
+
+        // windows of 0 450 800 OP_DEPSET started by the spent output at height 10
+        let cases = vec![(10, 0), (11, 2), (459, 2), (460, 3), (809, 3), (810, 1)];
+        for (height, expected) in cases {
+            let env = Environment { height, ..environment() };
+            assert_eq!(run_in(&env, "0 450 800 OP_DEPSET").1, stack(&[expected]), "{}", height);
+        }
+        assert_eq!(run_in(&env, "0 a 800 OP_DEPSET").0, Err(ScriptError::InvalidOperand(Opcode::OP_DEPSET)));
+        // the callback starts them
+        let env = with_callback(environment(), vec![]);
+        assert_eq!(run_in(&env, "0 50 80 OP_DEPSET").1, stack(&[3]));
+        assert_eq!(run_in(&environment(), "0 50 80 OP_DEPSET").1, stack(&[1]));
+
+        assert_eq!(run_in(&environment(), "OP_MONAD").1, stack(&[0]));
+        assert_eq!(run_in(&environment(), "1 2 3 4 OP_PROMISE 1 2 OP_NONCELOCKBL OP_MYLX OP_Q").1, stack(&[]));
+        assert_eq!(run_in(&environment(), "1 2 3 4 6 OP_SCHNACK").1, stack(&[0]));
+        assert_eq!(run_in(&environment(), "1 2 3 4 5 OP_SCHNACK").0, Err(ScriptError::UnsupportedOpcode(Opcode::OP_SCHNACK)));
+    }
+
+    #[test]
+    fn callback_opcodes_test() {
+        // outputs of the spending transaction have to pay the value of the spent output back
+        let paying = with_callback(environment(), vec![output(25, 1, "aa 1 OP_CALLBACK"), output(15, 1, "aa 1 OP_DROP")]);
+        assert_eq!(run_in(&paying, "OP_MONOID").1, stack(&[]));
+        assert_eq!(run_in(&paying, "OP_MONOID ab 1 OP_CALLBACK").1, stack(&[]));
+        let short = with_callback(environment(), vec![output(39, 1, "aa 1 OP_CALLBACK")]);
+        assert_eq!(run_in(&short, "OP_MONOID").1, stack(&[0]));
+        assert_eq!(run_in(&environment(), "OP_MONOID").1, stack(&[0]));
+
+        // without OP_MONOID the callback outputs pay the maker output
+        assert_eq!(run_in(&paying, "aa 1 OP_CALLBACK").1, stack(&[]));
+        assert_eq!(run_in(&paying, "ab 1 OP_CALLBACK").1, stack(&[0]));
+        let short = with_callback(environment(), vec![output(29, 1, "aa 1 OP_CALLBACK")]);
+        assert_eq!(run_in(&short, "aa 1 OP_CALLBACK").1, stack(&[0]));
+
+        // 4 units of 40 at the minimum of 9 or 10
+        assert_eq!(run_in(&paying, "9 OP_MINUNITVALUE").1, stack(&[]));
+        assert_eq!(run_in(&paying, "a OP_MINUNITVALUE").1, stack(&[0]));
+        assert_eq!(run_in(&environment(), "1 OP_MINUNITVALUE").1, stack(&[0]));
+
+        let address = "25cc7722a6378e11082f7967c09b98bd26c979d3";
+        let pair = format!("{} {} aa 1 OP_TAKERPAIR", address, address);
+        assert_eq!(run_in(&paying, &pair).1, stack(&[1]));
+        assert_eq!(run_in(&environment(), &pair).1, stack(&[0]));
+        assert_eq!(run_in(&paying, &format!("{} 25cc aa 1 OP_TAKERPAIR", address)).1, stack(&[0]));
+    }
+
+    #[test]
+    fn maker_collateral_opcodes_test() {
+        let collateral = "ab0 cd0 eee ddd fa0 2 a OP_MAKERCOLL";
+        let mut env = environment();
+        assert_eq!(run_in(&env, collateral).1, stack(&[0]));
+
+        // the taker pays 2 on ddd, the maker 10 on eee
+        env.marked_txs = vec![marked("cd0", "fa0", "ddd", 1), marked("cd0", "fa0", "ddd", 1), marked("ff", "ab0", "eee", 10)];
+        assert_eq!(run_in(&env, collateral).1, stack(&[2]));
+        env.marked_txs.remove(0);
+        assert_eq!(run_in(&env, collateral).1, stack(&[3]));
+        env.marked_txs = vec![marked("cd0", "fa0", "ddd", 2), marked("ff", "ab0", "ddd", 10)];
+        assert_eq!(run_in(&env, collateral).1, stack(&[4]));
+    }
+
+    #[test]
+    fn lookup_opcodes_test() {
+        let mut env = environment();
+        let mut names = HashMap::new();
+        names.insert(BigInt::from(0xab), BigInt::from(0xcd));
+        env.x.insert(BigInt::from(1), names);
+        assert_eq!(run_in(&env, "ab 1 OP_X ab 2 OP_X ac 1 OP_X").1, stack(&[0xcd, 0, 0]));
+
+        assert_eq!(run_in(&env, "1 9 65 OP_EMERGENCY 1 9 64 OP_EMERGENCY").1, stack(&[1, 0]));
+        env.emergency.insert((BigInt::from(9), BigInt::from(1)), false);
+        assert_eq!(run_in(&env, "1 9 65 OP_EMERGENCY").1, stack(&[1, 0]));
+    }
+}
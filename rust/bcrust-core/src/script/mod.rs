@@ -0,0 +1,325 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! BC script interpreter, port of src/script
+//!
+//! Scripts are compiled to bytecode by the `parser` and evaluated by the stack machine of the
+//! `interpreter`. Opcodes which need the transaction or block being validated (`OP_DEPSET`,
+//! `OP_MAKERCOLL`, `OP_ENVOUTPOINT*`, ...) are evaluated with the `environment` of the spent
+//! input, `unlock_input` loads it the way `evaluateAsync` does in JS.
+pub mod environment;
+pub mod interpreter;
+pub mod opcodes;
+pub mod parser;
+
+use std::error::Error;
+use std::fmt;
+
+use crypto::sig::generate_data_to_sign_for_sig;
+use protos::core::{Transaction, TransactionInput};
+use types::Hash256;
+
+use self::environment::{Environment, ScriptLookup};
+use self::interpreter::Interpreter;
+use self::opcodes::Opcode;
+use self::parser::Script;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    UnknownToken(String),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    DisabledOpcode(Opcode),
+    UnsupportedOpcode(Opcode),
+    EmptyStack,
+    VerifyFailed,
+    InvalidSignatureData(Opcode),
+    InvalidOperand(Opcode),
+    MissingEnvironment(Opcode),
+    MissingTransaction(String),
+    MissingOutput(String, u64),
+    InvalidCallback,
+    MonoidIsomorphism,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScriptError::UnknownToken(ref token) => write!(f, "unknown token '{}'", token),
+            ScriptError::UnexpectedToken(ref token) => write!(f, "unexpected token '{}'", token),
+            ScriptError::UnexpectedEnd => write!(f, "unexpected end of script"),
+            ScriptError::DisabledOpcode(op) => write!(f, "disabled opcode {}", op),
+            ScriptError::UnsupportedOpcode(op) => write!(f, "opcode {} is not supported", op),
+            ScriptError::EmptyStack => write!(f, "attempted to pop from an empty stack"),
+            ScriptError::VerifyFailed => write!(f, "verification failed"),
+            ScriptError::InvalidSignatureData(op) => write!(f, "invalid message, signature or public key for {}", op),
+            ScriptError::InvalidOperand(op) => write!(f, "invalid operand of {}", op),
+            ScriptError::MissingEnvironment(op) => write!(f, "opcode {} needs the environment of a transaction input", op),
+            ScriptError::MissingTransaction(ref hash) => write!(f, "transaction {} not found", hash),
+            ScriptError::MissingOutput(ref hash, index) => write!(f, "output {} of transaction {} not found", index, hash),
+            ScriptError::InvalidCallback => write!(f, "callback does not reference an OP_MONOID output"),
+            ScriptError::MonoidIsomorphism => write!(f, "exactly one input has to spend an OP_MONOID output"),
+        }
+    }
+}
+
+impl Error for ScriptError {
+    fn description(&self) -> &str {
+        "script error"
+    }
+}
+
+/// Evaluates `script` after pushing the blake2bl hash of `data_to_sign`, `Interpreter.evaluate` in JS
+///
+/// The hash is the message checked by the signature opcodes.
+pub fn evaluate(data_to_sign: &[u8], script: &str, allow_disabled: bool) -> Result<bool, ScriptError> {
+    let interpreter = if allow_disabled { Interpreter::allowing_disabled() } else { Interpreter::new() };
+    evaluate_with(interpreter, data_to_sign, script)
+}
+
+fn evaluate_with(mut interpreter: Interpreter, data_to_sign: &[u8], script: &str) -> Result<bool, ScriptError> {
+    let script: Script = format!("{} {}", Hash256::blake2bl(data_to_sign).to_hex(), script).parse()?;
+    interpreter.run(&script)
+}
+
+/// Whether `input_script` unlocks `output_script`, `Interpreter.unlock` in JS
+///
+/// `data_to_sign` is what the input signed, the hex form of `generate_data_to_sign_for_sig` for
+/// transaction inputs.
+pub fn unlock(output_script: &str, input_script: &str, data_to_sign: &[u8]) -> bool {
+    evaluate(data_to_sign, &format!("{} {}", input_script, output_script), false).unwrap_or(false)
+}
+
+/// Whether `input_script` of `input` unlocks `output_script` of the spent output, `Interpreter.evaluateAsync` in JS
+///
+/// Scripts with opcodes reading the transaction are evaluated in the environment loaded from
+/// `lookup`, `tx_height` is the height of the block including `tx` or `None` while it is pending.
+pub fn unlock_input<L: ScriptLookup>(
+    lookup: &L,
+    output_script: &str,
+    input_script: &str,
+    input: &TransactionInput,
+    tx: &Transaction,
+    tx_height: Option<u64>,
+) -> bool {
+    let data_to_sign = generate_data_to_sign_for_sig(input.get_out_point(), tx).to_hex();
+    let script: Script = match format!("{} {}", input_script, output_script).parse() {
+        Ok(script) => script,
+        Err(_) => return false,
+    };
+    if !script.opcodes().any(Opcode::requires_environment) {
+        return unlock(output_script, input_script, data_to_sign.as_bytes());
+    }
+
+    match Environment::load(lookup, output_script, input_script, input, tx, tx_height) {
+        Ok(env) => evaluate_with(Interpreter::with_environment(&env), data_to_sign.as_bytes(), &env.script).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    use std::collections::HashMap;
+
+    use crypto::sig::{public_key_create, sign_data, tx_input_signature};
+    use protos::core::{MarkedTransaction, OutPoint, TransactionOutput};
+    use tx::create_nrg_output_lock_script;
+
+    const PRIVATE_KEY: &str = "e3b7f1c4a2d5960817f3b2a4c6d8e0f1a3b5c7d9e1f30517a9c2b4d6e8f0a1b2";
+    const ADDRESS: &str = "0x25cc7722a6378e11082f7967c09b98bd26c979d3";
+
+    fn run(script: &str) -> Result<bool, ScriptError> {
+        Interpreter::new().run(&script.parse()?)
+    }
+
+    fn blake2bl(data: &str) -> String {
+        Hash256::blake2bl(data.as_bytes()).to_hex()
+    }
+
+    /// Last 20 bytes of blake2bl of blake2bl
+    fn blake2blc(data: &str) -> String {
+        Hash256::blake2bl(blake2bl(data).as_bytes()).as_bytes()[12..].to_hex()
+    }
+
+    struct TestLookup(HashMap<String, (Transaction, u64)>);
+
+    impl ScriptLookup for TestLookup {
+        fn get_transaction(&self, hash: &str) -> Option<(Transaction, u64)> {
+            self.0.get(hash).cloned()
+        }
+
+        fn get_latest_height(&self) -> u64 {
+            unreachable!()
+        }
+
+        fn get_marked_txs(&self, _: u64, _: u64) -> Vec<MarkedTransaction> {
+            unreachable!()
+        }
+    }
+
+    fn output(script: &str) -> TransactionOutput {
+        let mut output = TransactionOutput::new();
+        output.set_value(vec![16]);
+        output.set_unit(vec![1]);
+        output.set_script_length(script.len() as u32);
+        output.set_output_script(script.as_bytes().to_vec());
+        output
+    }
+
+    fn spending_input(hash: &str) -> (TransactionInput, Transaction) {
+        let mut out_point = OutPoint::new();
+        out_point.set_value(vec![16]);
+        out_point.set_hash(hash.to_string());
+        out_point.set_index(0);
+        let mut input = TransactionInput::new();
+        input.set_out_point(out_point);
+
+        let mut tx = Transaction::new();
+        tx.set_inputs(RepeatedField::from_vec(vec![input.clone()]));
+        tx.set_outputs(RepeatedField::from_vec(vec![output("OP_DROP")]));
+        (input, tx)
+    }
+
+    #[test]
+    fn blake2bl_script_test() {
+        let hash = blake2bl("abc");
+        assert_eq!(run(&format!("{} OP_BLAKE2BL {} OP_EQUALVERIFY", hash, blake2bl(&hash))), Ok(true));
+    }
+
+    #[test]
+    fn compare_values_test() {
+        assert_eq!(run("a b OP_EQUALVERIFY"), Err(ScriptError::VerifyFailed));
+        assert_eq!(run("a a OP_EQUALVERIFY"), Ok(true));
+        assert_eq!(run("a 0xa OP_EQUALVERIFY"), Ok(true));
+    }
+
+    #[test]
+    fn signature_script_test() {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let msg = Hash256::blake2bl(b"data to sign").to_hex();
+        let signature = sign_data(b"data to sign", &private_key).unwrap().to_hex();
+        let public_key = public_key_create(&private_key).unwrap().to_hex();
+
+        assert_eq!(run(&format!("{} {} {} OP_CHECKSIGVERIFY", msg, signature, public_key)), Ok(true));
+        assert_eq!(run(&format!("{} {} OP_CHECKSIGNOPUBKEYVERIFY", msg, signature)), Ok(true));
+
+        let other = Hash256::blake2bl(b"other data").to_hex();
+        assert_eq!(run(&format!("{} {} {} OP_CHECKSIGVERIFY", other, signature, public_key)), Err(ScriptError::VerifyFailed));
+        assert_eq!(run(&format!("{} {} {} OP_CHECKSIG", other, signature, public_key)), Ok(false));
+        assert_eq!(
+            run(&format!("{} {} 02 OP_CHECKSIGVERIFY", msg, signature)),
+            Err(ScriptError::InvalidSignatureData(Opcode::OP_CHECKSIG))
+        );
+    }
+
+    #[test]
+    fn unlock_test() {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let address = &ADDRESS[2..];
+        let data_to_sign = b"data to sign";
+        let signature = sign_data(data_to_sign, &private_key).unwrap().to_hex();
+        let public_key = public_key_create(&private_key).unwrap().to_hex();
+
+        let address_hash = Hash256::blake2bl(blake2bl(address).as_bytes()).as_bytes()[12..].to_hex();
+        let output_script = format!("OP_BLAKE2BLC {} OP_EQUALVERIFY OP_CHECKSIGVERIFY", address_hash);
+        let input_script = format!("{} {} {}", signature, public_key, address);
+        assert!(unlock(&output_script, &input_script, data_to_sign));
+        assert!(!unlock(&output_script, &input_script, b"frog"));
+    }
+
+    #[test]
+    fn unlock_nrg_transfer_test() {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let output_script = create_nrg_output_lock_script(ADDRESS);
+        let mut output = TransactionOutput::new();
+        output.set_value(vec![16]);
+        output.set_unit(vec![1]);
+        output.set_script_length(output_script.len() as u32);
+        output.set_output_script(output_script.clone().into_bytes());
+        let mut tx = Transaction::new();
+        tx.set_outputs(RepeatedField::from_vec(vec![output]));
+        tx.set_nout_count(1);
+
+        let mut out_point = OutPoint::new();
+        out_point.set_value(vec![32]);
+        out_point.set_hash(blake2bl("i am hash"));
+        out_point.set_index(0);
+
+        let data_to_sign = generate_data_to_sign_for_sig(&out_point, &tx).to_hex();
+        let public_key = public_key_create(&private_key).unwrap().to_hex();
+        let signature = tx_input_signature(&out_point, &tx, &private_key).unwrap().to_hex();
+        let input_script = format!("{} {} {}", signature, public_key, blake2bl(ADDRESS));
+        assert!(unlock(&output_script, &input_script, data_to_sign.as_bytes()));
+
+        let invalid_signature = sign_data(b"random bytes", &private_key).unwrap().to_hex();
+        let input_script = format!("{} {} {}", invalid_signature, public_key, blake2bl(ADDRESS));
+        assert!(!unlock(&output_script, &input_script, data_to_sign.as_bytes()));
+    }
+
+    #[test]
+    fn monad_on_subsets_test() {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let address = &ADDRESS[2..];
+        let output_script =
+            format!("{} OP_BLAKE2BLC {} OP_EQUALVERIFY OP_CHECKSIGNOPUBKEYVERIFY", address, blake2blc(address));
+        let (input, tx) = spending_input(&blake2bl("genesis"));
+        let signature = tx_input_signature(input.get_out_point(), &tx, &private_key).unwrap().to_hex();
+        let data_to_sign = generate_data_to_sign_for_sig(input.get_out_point(), &tx).to_hex();
+
+        // no opcode reads the environment, the lookup is not used
+        let lookup = TestLookup(HashMap::new());
+        let input_script = format!("{} {} {}", blake2bl(&data_to_sign), signature, blake2bl(address));
+        assert!(!unlock_input(&lookup, &output_script, &input_script, &input, &tx, None));
+        assert!(unlock_input(&lookup, &output_script, &signature, &input, &tx, None));
+    }
+
+    #[test]
+    fn deposit_settlement_test() {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let output_script = format!(
+            "0 450 800 OP_DEPSET OP_1 OP_EQUALVERIFY OP_BLAKE2BL {} OP_EQUALVERIFY OP_CHECKSIGNOPUBKEYVERIFY",
+            blake2bl(&blake2bl(ADDRESS))
+        );
+        let mut parent = Transaction::new();
+        parent.set_outputs(RepeatedField::from_vec(vec![output(&output_script)]));
+        let mut txs = HashMap::new();
+        txs.insert(blake2bl("parent"), (parent, 1));
+        let lookup = TestLookup(txs);
+
+        let (input, tx) = spending_input(&blake2bl("parent"));
+        let signature = tx_input_signature(input.get_out_point(), &tx, &private_key).unwrap().to_hex();
+        let input_script = format!("{} {}", signature, blake2bl(ADDRESS));
+        assert!(unlock_input(&lookup, &output_script, &input_script, &input, &tx, Some(880)));
+        // the deposit window ends at 451
+        assert!(!unlock_input(&lookup, &output_script, &input_script, &input, &tx, Some(460)));
+
+        let (missing, tx) = spending_input(&blake2bl("missing"));
+        assert!(!unlock_input(&lookup, &output_script, &input_script, &missing, &tx, Some(880)));
+    }
+
+    #[test]
+    fn stack_serialization_test() {
+        let cases = vec![
+            "08192f8e2089ce8afdf11af66ff2bfb1574259a4dccc4150d27cfa489b236e880e2121d6dc3310c853d234129fe0023edaa341ab407c76e0dd243ed2fbbb5f0301",
+            "02514ce489b1aa35ea4c2fda9486f5ef9fb4b5a70bd9650533560d2ef9c2bb8d70",
+            "0992df33fd79c90da6b9db9c86eaa7bd44af96b2d30a2c477c0a89784c4ed744",
+        ];
+        for data in cases {
+            let mut interpreter = Interpreter::new();
+            interpreter.run(&data.parse().unwrap()).unwrap();
+            assert_eq!(interpreter.stack()[0].to_bytes_be().1.to_hex(), data);
+        }
+    }
+
+    #[test]
+    fn disabled_opcodes_test() {
+        assert_eq!(evaluate(b"data", "2 3 OP_MUL 6 OP_EQUALVERIFY", false), Err(ScriptError::DisabledOpcode(Opcode::OP_MUL)));
+        assert_eq!(evaluate(b"data", "2 3 OP_MUL 6 OP_EQUALVERIFY", true), Ok(true));
+    }
+}
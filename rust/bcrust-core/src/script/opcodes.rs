@@ -0,0 +1,431 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Script opcodes, the ones of src/vendor/ops.json and the ones added by the BC script grammar
+use std::fmt;
+
+macro_rules! opcodes {
+    ($($name:ident = $code:expr,)*) => {
+        /// Opcode of a script, variants are named as in the textual scripts
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Opcode {
+            $($name,)*
+        }
+
+        impl Opcode {
+            /// Name used in the textual scripts, e.g. `OP_DUP`
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$name => stringify!($name),)*
+                }
+            }
+
+            /// Code from src/vendor/ops.json, `None` for opcodes only known to the BC script grammar
+            pub fn code(self) -> Option<u16> {
+                match self {
+                    $(Opcode::$name => $code,)*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<Opcode> {
+                match name {
+                    $(stringify!($name) => Some(Opcode::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+opcodes! {
+    // constants
+    OP_FALSE = Some(0),
+    OP_0 = Some(0),
+    OP_PUSHDATA1 = Some(76),
+    OP_PUSHDATA2 = Some(77),
+    OP_PUSHDATA4 = Some(78),
+    OP_1NEGATE = Some(79),
+    OP_RESERVED = Some(80),
+    OP_1 = Some(81),
+    OP_TRUE = Some(81),
+    OP_2 = Some(82),
+    OP_3 = Some(83),
+    OP_4 = Some(84),
+    OP_5 = Some(85),
+    OP_6 = Some(86),
+    OP_7 = Some(87),
+    OP_8 = Some(88),
+    OP_9 = Some(89),
+    OP_10 = Some(90),
+    OP_11 = Some(91),
+    OP_12 = Some(92),
+    OP_13 = Some(93),
+    OP_14 = Some(94),
+    OP_15 = Some(95),
+    OP_16 = Some(96),
+
+    // flow control
+    OP_NOP = Some(97),
+    OP_VER = Some(98),
+    OP_IF = Some(99),
+    OP_NOTIF = Some(100),
+    OP_VERIF = Some(101),
+    OP_VERNOTIF = Some(102),
+    OP_ELSE = Some(103),
+    OP_ENDIF = Some(104),
+    OP_VERIFY = Some(105),
+    OP_RETURN = Some(106),
+    OP_IFEQ = None,
+    OP_ENDIFEQ = None,
+    OP_GATE = None,
+    OP_EQ = None,
+
+    // stack
+    OP_TOALTSTACK = Some(107),
+    OP_FROMALTSTACK = Some(108),
+    OP_2DROP = Some(109),
+    OP_2DUP = Some(110),
+    OP_3DUP = Some(111),
+    OP_2OVER = Some(112),
+    OP_2ROT = Some(113),
+    OP_2SWAP = Some(114),
+    OP_IFDUP = Some(115),
+    OP_DEPTH = Some(116),
+    OP_DROP = Some(117),
+    OP_DUP = Some(118),
+    OP_NIP = Some(119),
+    OP_OVER = Some(120),
+    OP_PICK = Some(121),
+    OP_ROLL = Some(122),
+    OP_ROT = Some(123),
+    OP_SWAP = Some(124),
+    OP_TUCK = Some(125),
+
+    // splice
+    OP_CAT = Some(126),
+    OP_SUBSTR = Some(127),
+    OP_LEFT = Some(128),
+    OP_RIGHT = Some(129),
+    OP_SIZE = Some(130),
+
+    // bitwise logic
+    OP_INVERT = Some(131),
+    OP_AND = Some(132),
+    OP_OR = Some(133),
+    OP_XOR = Some(134),
+    OP_EQUAL = Some(135),
+    OP_EQUALVERIFY = Some(136),
+    OP_RESERVED1 = Some(137),
+    OP_RESERVED2 = Some(138),
+
+    // arithmetic
+    OP_1ADD = Some(139),
+    OP_1SUB = Some(140),
+    OP_2MUL = Some(141),
+    OP_2DIV = Some(142),
+    OP_NEGATE = Some(143),
+    OP_ABS = Some(144),
+    OP_NOT = Some(145),
+    OP_0NOTEQUAL = Some(146),
+    OP_ADD = Some(147),
+    OP_SUB = Some(148),
+    OP_MUL = Some(149),
+    OP_DIV = Some(150),
+    OP_MOD = Some(151),
+    OP_LSHIFT = Some(152),
+    OP_RSHIFT = Some(153),
+    OP_BOOLAND = Some(154),
+    OP_BOOLOR = Some(155),
+    OP_NUMEQUAL = Some(156),
+    OP_NUMEQUALVERIFY = Some(157),
+    OP_NUMNOTEQUAL = Some(158),
+    OP_LESSTHAN = Some(159),
+    OP_GREATERTHAN = Some(160),
+    OP_LESSTHANOREQUAL = Some(161),
+    OP_GREATERTHANOREQUAL = Some(162),
+    OP_MIN = Some(163),
+    OP_MAX = Some(164),
+    OP_WITHIN = Some(165),
+
+    // crypto
+    OP_RIPEMD160 = Some(166),
+    OP_SHA1 = Some(167),
+    OP_SHA256 = Some(168),
+    OP_HASH160 = Some(169),
+    OP_HASH256 = Some(170),
+    OP_CODESEPARATOR = Some(171),
+    OP_CHECKSIG = Some(172),
+    OP_CHECKSIGVERIFY = Some(173),
+    OP_CHECKMULTISIG = Some(174),
+    OP_CHECKMULTISIGVERIFY = Some(175),
+    OP_BLAKE2BL = None,
+    OP_BLAKE2BLS = None,
+    OP_BLAKE2BLC = None,
+    OP_CHECKSIGNOPUBKEY = None,
+    OP_CHECKSIGNOPUBKEYVERIFY = None,
+    OP_DATATOHASH = None,
+    OP_NONCELOCKBL = None,
+
+    // expansion
+    OP_NOP1 = Some(176),
+    OP_NOP2 = Some(177),
+    OP_CHECKLOCKTIMEVERIFY = Some(177),
+    OP_NOP3 = Some(178),
+    OP_NOP4 = Some(179),
+    OP_NOP5 = Some(180),
+    OP_NOP6 = Some(181),
+    OP_NOP7 = Some(182),
+    OP_NOP8 = Some(183),
+    OP_NOP9 = Some(184),
+    OP_NOP10 = Some(185),
+
+    // pseudo-words
+    OP_PUBKEYHASH = Some(253),
+    OP_PUBKEY = Some(254),
+    OP_INVALIDOPCODE = Some(255),
+
+    // BC extensions of ops.json
+    OP_RFBAND = Some(256),
+    OP_CHECKSIGFROMCHAIN = Some(257),
+    OP_HASHSCHNORR = Some(258),
+    OP_HASHBLAKE = Some(259),
+    OP_VERIFYSTACK = Some(260),
+    OP_TOSTACK = Some(261),
+    OP_FROMSTACK = Some(262),
+    OP_MARK = Some(263),
+    OP_BALANCE = Some(264),
+    OP_BALANCEUNIT = Some(265),
+    OP_VERIFYBLOCKCHAINSEQGTE = Some(266),
+    OP_CHECKSIGCHAINBENEFACTOR = Some(267),
+    OP_BITFILTER = Some(268),
+    OP_LOCKSTACKMUTABLEEXP = Some(269),
+    OP_LOCKSTACKIMMUTABLEEXP = Some(270),
+    OP_AGELTE = Some(271),
+    OP_AGEGTE = Some(272),
+    OP_DEPTHLTE = Some(273),
+    OP_DEPTHGTE = Some(274),
+    OP_SWITCH = Some(275),
+    OP_VERIFYLEASEDBALANCE = Some(276),
+
+    // BC script grammar
+    OP_ENVOUTPOINTUNIT = None,
+    OP_ENVOUTPOINTVALUE = None,
+    OP_ENVOUTPOINTHASH = None,
+    OP_ENVOUTPOINTNONCE = None,
+    OP_ENVOUTPOINTVCLYSPUL = None,
+    OP_ENVOUTPOINTLOCKTIME = None,
+    OP_MINUNITVALUE = None,
+    OP_TAKERPAIR = None,
+    OP_MYLX = None,
+    OP_ORDTYPE = None,
+    OP_INITBCI = None,
+    OP_ENDBCI = None,
+    OP_STATBCI = None,
+    OP_HTTPSTATUS = None,
+    OP_HTTPSELECT = None,
+    OP_DEPSET = None,
+    OP_RATEMARKET = None,
+    OP_ENDRATEMARKET = None,
+    OP_CALLBACK = None,
+    OP_PROMISE = None,
+    OP_MAKERCOLL = None,
+    OP_SCHNACK = None,
+    OP_MONOID = None,
+    OP_MONAD = None,
+    OP_ENDMONAD = None,
+    OP_X = None,
+    OP_Q = None,
+    OP_EMERGENCY = None,
+    OP_FIX = None,
+}
+
+impl Opcode {
+    /// Value pushed by a constant opcode
+    ///
+    /// `OP_1NEGATE` is not one, JS negates 1 with `OP_NEGATE`.
+    pub fn small_integer(self) -> Option<i64> {
+        match self {
+            Opcode::OP_0 | Opcode::OP_FALSE => Some(0),
+            Opcode::OP_1 | Opcode::OP_TRUE => Some(1),
+            Opcode::OP_2 => Some(2),
+            Opcode::OP_3 => Some(3),
+            Opcode::OP_4 => Some(4),
+            Opcode::OP_5 => Some(5),
+            Opcode::OP_6 => Some(6),
+            Opcode::OP_7 => Some(7),
+            Opcode::OP_8 => Some(8),
+            Opcode::OP_9 => Some(9),
+            Opcode::OP_10 => Some(10),
+            Opcode::OP_11 => Some(11),
+            Opcode::OP_12 => Some(12),
+            Opcode::OP_13 => Some(13),
+            Opcode::OP_14 => Some(14),
+            Opcode::OP_15 => Some(15),
+            Opcode::OP_16 => Some(16),
+            _ => None,
+        }
+    }
+
+    /// Whether the script grammar (src/script/script.jison) knows the opcode
+    ///
+    /// The lexer rejects the others even though ops.json lists them, `OP_FIX` is lexed to a token
+    /// the grammar does not accept.
+    pub fn is_in_grammar(self) -> bool {
+        match self {
+            Opcode::OP_PUSHDATA1
+            | Opcode::OP_PUSHDATA2
+            | Opcode::OP_PUSHDATA4
+            | Opcode::OP_RESERVED
+            | Opcode::OP_VER
+            | Opcode::OP_VERIF
+            | Opcode::OP_VERNOTIF
+            | Opcode::OP_TOALTSTACK
+            | Opcode::OP_FROMALTSTACK
+            | Opcode::OP_CAT
+            | Opcode::OP_SUBSTR
+            | Opcode::OP_LEFT
+            | Opcode::OP_RIGHT
+            | Opcode::OP_SIZE
+            | Opcode::OP_RESERVED1
+            | Opcode::OP_RESERVED2
+            | Opcode::OP_NUMEQUALVERIFY
+            | Opcode::OP_CODESEPARATOR
+            | Opcode::OP_NOP1
+            | Opcode::OP_NOP2
+            | Opcode::OP_CHECKLOCKTIMEVERIFY
+            | Opcode::OP_NOP3
+            | Opcode::OP_NOP4
+            | Opcode::OP_NOP5
+            | Opcode::OP_NOP6
+            | Opcode::OP_NOP7
+            | Opcode::OP_NOP8
+            | Opcode::OP_NOP9
+            | Opcode::OP_NOP10
+            | Opcode::OP_PUBKEYHASH
+            | Opcode::OP_PUBKEY
+            | Opcode::OP_INVALIDOPCODE
+            | Opcode::OP_RFBAND
+            | Opcode::OP_CHECKSIGFROMCHAIN
+            | Opcode::OP_HASHSCHNORR
+            | Opcode::OP_HASHBLAKE
+            | Opcode::OP_VERIFYSTACK
+            | Opcode::OP_TOSTACK
+            | Opcode::OP_FROMSTACK
+            | Opcode::OP_BALANCE
+            | Opcode::OP_BALANCEUNIT
+            | Opcode::OP_VERIFYBLOCKCHAINSEQGTE
+            | Opcode::OP_CHECKSIGCHAINBENEFACTOR
+            | Opcode::OP_BITFILTER
+            | Opcode::OP_LOCKSTACKMUTABLEEXP
+            | Opcode::OP_LOCKSTACKIMMUTABLEEXP
+            | Opcode::OP_AGELTE
+            | Opcode::OP_AGEGTE
+            | Opcode::OP_DEPTHLTE
+            | Opcode::OP_DEPTHGTE
+            | Opcode::OP_SWITCH
+            | Opcode::OP_VERIFYLEASEDBALANCE
+            | Opcode::OP_FIX => false,
+            _ => true,
+        }
+    }
+
+    /// Opcodes the grammar compiles to nothing
+    pub fn is_nop(self) -> bool {
+        match self {
+            Opcode::OP_NOP
+            | Opcode::OP_INITBCI
+            | Opcode::OP_ENDBCI
+            | Opcode::OP_STATBCI
+            | Opcode::OP_ENDRATEMARKET
+            | Opcode::OP_ENDMONAD => true,
+            _ => false,
+        }
+    }
+
+    /// Opcodes ending the script with their result when they are its last token
+    pub fn is_terminal(self) -> bool {
+        match self {
+            Opcode::OP_VERIFY
+            | Opcode::OP_EQ
+            | Opcode::OP_EQUALVERIFY
+            | Opcode::OP_CHECKSIGVERIFY
+            | Opcode::OP_CHECKSIGNOPUBKEYVERIFY
+            | Opcode::OP_CHECKMULTISIGVERIFY => true,
+            _ => false,
+        }
+    }
+
+    /// Opcodes rejected unless disabled opcodes are allowed, `DISABLED_OPCODES` of src/script/validator.js
+    ///
+    /// JS looks for the names as substrings of the script which also catches `OP_ORDTYPE`.
+    pub fn is_disabled(self) -> bool {
+        match self {
+            Opcode::OP_INVERT
+            | Opcode::OP_AND
+            | Opcode::OP_OR
+            | Opcode::OP_XOR
+            | Opcode::OP_2MUL
+            | Opcode::OP_2DIV
+            | Opcode::OP_MUL
+            | Opcode::OP_DIV
+            | Opcode::OP_MOD
+            | Opcode::OP_LSHIFT
+            | Opcode::OP_RSHIFT
+            | Opcode::OP_ORDTYPE => true,
+            _ => false,
+        }
+    }
+
+    /// Opcodes reading the transaction, block or persistence context JS evaluates scripts in
+    pub fn requires_environment(self) -> bool {
+        match self {
+            Opcode::OP_ENVOUTPOINTUNIT
+            | Opcode::OP_ENVOUTPOINTVALUE
+            | Opcode::OP_ENVOUTPOINTHASH
+            | Opcode::OP_ENVOUTPOINTNONCE
+            | Opcode::OP_ENVOUTPOINTVCLYSPUL
+            | Opcode::OP_ENVOUTPOINTLOCKTIME
+            | Opcode::OP_MINUNITVALUE
+            | Opcode::OP_TAKERPAIR
+            | Opcode::OP_NONCELOCKBL
+            | Opcode::OP_MYLX
+            | Opcode::OP_DEPSET
+            | Opcode::OP_RATEMARKET
+            | Opcode::OP_CALLBACK
+            | Opcode::OP_PROMISE
+            | Opcode::OP_MAKERCOLL
+            | Opcode::OP_SCHNACK
+            | Opcode::OP_MONOID
+            | Opcode::OP_MONAD
+            | Opcode::OP_X
+            | Opcode::OP_Q
+            | Opcode::OP_EMERGENCY => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcode_names_test() {
+        assert_eq!(Opcode::from_name("OP_CHECKSIGVERIFY"), Some(Opcode::OP_CHECKSIGVERIFY));
+        assert_eq!(Opcode::from_name("OP_CHECKSIGVERIFY").unwrap().code(), Some(173));
+        assert_eq!(Opcode::from_name("OP_VERIFYLEASEDBALANCE").unwrap().code(), Some(276));
+        assert_eq!(Opcode::OP_BLAKE2BL.code(), None);
+        assert_eq!(Opcode::OP_2DUP.name(), "OP_2DUP");
+        assert_eq!(Opcode::from_name("OP_DUPS"), None);
+        assert_eq!(Opcode::from_name("op_dup"), None);
+    }
+}
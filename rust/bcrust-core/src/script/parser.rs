@@ -0,0 +1,263 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Compiles textual scripts to bytecode, port of the grammar in src/script/script.jison
+//!
+//! Tokens are separated by whitespace and are either hex data, optionally prefixed by `0x`, or
+//! opcode names. Blocks (`OP_IF`, `OP_NOTIF` and `OP_IFEQ`) become jumps, a verifying opcode which
+//! is the very last token of the script ends the evaluation with its result.
+use std::str::FromStr;
+
+use num_bigint::BigInt;
+
+use super::opcodes::Opcode;
+use super::ScriptError;
+
+/// Bytecode instruction, jump targets are indexes into the instructions of the `Script`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Push(BigInt),
+    Call(Opcode),
+    /// Runs the opcode and returns its result as the result of the script
+    Return(Opcode),
+    /// Pops the top value and jumps if it is zero, `OP_IF`
+    JumpIfZero(usize),
+    /// Pops the top value and jumps if it is not zero, `OP_NOTIF`
+    JumpIfNotZero(usize),
+    /// Pops two values and jumps if they differ, otherwise pushes the value back, `OP_IFEQ`
+    JumpIfNotEqual(usize),
+    Jump(usize),
+}
+
+/// Compiled script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    instructions: Vec<Instruction>,
+}
+
+impl Script {
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Opcodes called by the script, in order of appearance
+    pub fn opcodes<'a>(&'a self) -> impl Iterator<Item = Opcode> + 'a {
+        self.instructions.iter().filter_map(|instruction| match *instruction {
+            Instruction::Call(op) | Instruction::Return(op) => Some(op),
+            _ => None,
+        })
+    }
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(s: &str) -> Result<Script, ScriptError> {
+        // a trailing whitespace follows the last token in JS, the terminal opcode is then a call
+        let terminal_allowed = !s.ends_with(char::is_whitespace);
+        let mut parser = Parser {
+            tokens: s.split_whitespace().collect(),
+            position: 0,
+            terminal_allowed,
+            instructions: Vec::new(),
+        };
+
+        match parser.parse_sequence()? {
+            None => Ok(Script { instructions: parser.instructions }),
+            Some(op) => Err(ScriptError::UnexpectedToken(op.name().to_string())),
+        }
+    }
+}
+
+/// Data token, `(0x)?[0-9a-fA-F]+` in the lexer
+pub fn parse_data(token: &str) -> Option<BigInt> {
+    let hex = if token.starts_with("0x") { &token[2..] } else { token };
+    if hex.is_empty() || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    BigInt::parse_bytes(hex.as_bytes(), 16)
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    position: usize,
+    terminal_allowed: bool,
+    instructions: Vec<Instruction>,
+}
+
+enum Token {
+    Data(BigInt),
+    Opcode(Opcode),
+}
+
+impl<'a> Parser<'a> {
+    fn next_token(&mut self) -> Result<Option<Token>, ScriptError> {
+        let token = match self.tokens.get(self.position) {
+            Some(token) => *token,
+            None => return Ok(None),
+        };
+        self.position += 1;
+
+        if let Some(value) = parse_data(token) {
+            return Ok(Some(Token::Data(value)));
+        }
+
+        match Opcode::from_name(token) {
+            Some(op) if op.is_in_grammar() => Ok(Some(Token::Opcode(op))),
+            _ => Err(ScriptError::UnknownToken(token.to_string())),
+        }
+    }
+
+    fn is_last_token(&self) -> bool {
+        self.terminal_allowed && self.position == self.tokens.len()
+    }
+
+    /// Parses a non empty sequence of opcodes, returns the `OP_ELSE`, `OP_ENDIF` or `OP_ENDIFEQ` ending it
+    fn parse_sequence(&mut self) -> Result<Option<Opcode>, ScriptError> {
+        let mut empty = true;
+        while let Some(token) = self.next_token()? {
+            let op = match token {
+                Token::Data(value) => {
+                    self.instructions.push(Instruction::Push(value));
+                    empty = false;
+                    continue;
+                }
+                Token::Opcode(op) => op,
+            };
+
+            match op {
+                Opcode::OP_ELSE | Opcode::OP_ENDIF | Opcode::OP_ENDIFEQ if !empty => return Ok(Some(op)),
+                Opcode::OP_ELSE | Opcode::OP_ENDIF | Opcode::OP_ENDIFEQ =>
+                    return Err(ScriptError::UnexpectedToken(op.name().to_string())),
+                Opcode::OP_IF => self.parse_if(Instruction::JumpIfZero(0))?,
+                Opcode::OP_NOTIF => self.parse_if(Instruction::JumpIfNotZero(0))?,
+                Opcode::OP_IFEQ | Opcode::OP_GATE => self.parse_if_equal()?,
+                Opcode::OP_RETURN => self.instructions.push(Instruction::Return(op)),
+                op if op.is_terminal() && self.is_last_token() => self.instructions.push(Instruction::Return(op)),
+                op if op.is_nop() => {}
+                op => match op.small_integer() {
+                    Some(value) => self.instructions.push(Instruction::Push(BigInt::from(value))),
+                    None => self.instructions.push(Instruction::Call(op)),
+                },
+            }
+            empty = false;
+        }
+
+        if empty {
+            Err(ScriptError::UnexpectedEnd)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Index of a placeholder instruction which is patched once the jump target is known
+    fn placeholder(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    fn patch(&mut self, index: usize) {
+        let target = self.instructions.len();
+        match self.instructions[index] {
+            Instruction::JumpIfZero(ref mut t)
+            | Instruction::JumpIfNotZero(ref mut t)
+            | Instruction::JumpIfNotEqual(ref mut t)
+            | Instruction::Jump(ref mut t) => *t = target,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn parse_if(&mut self, jump: Instruction) -> Result<(), ScriptError> {
+        let condition = self.placeholder(jump);
+        match self.parse_sequence()? {
+            Some(Opcode::OP_ENDIF) => self.patch(condition),
+            Some(Opcode::OP_ELSE) => {
+                let end = self.placeholder(Instruction::Jump(0));
+                self.patch(condition);
+                match self.parse_sequence()? {
+                    Some(Opcode::OP_ENDIF) => self.patch(end),
+                    Some(op) => return Err(ScriptError::UnexpectedToken(op.name().to_string())),
+                    None => return Err(ScriptError::UnexpectedEnd),
+                }
+            }
+            Some(op) => return Err(ScriptError::UnexpectedToken(op.name().to_string())),
+            None => return Err(ScriptError::UnexpectedEnd),
+        }
+        Ok(())
+    }
+
+    fn parse_if_equal(&mut self) -> Result<(), ScriptError> {
+        let condition = self.placeholder(Instruction::JumpIfNotEqual(0));
+        match self.parse_sequence()? {
+            Some(Opcode::OP_ENDIFEQ) => self.patch(condition),
+            Some(op) => return Err(ScriptError::UnexpectedToken(op.name().to_string())),
+            None => return Err(ScriptError::UnexpectedEnd),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(script: &str) -> Result<Vec<Instruction>, ScriptError> {
+        script.parse::<Script>().map(|script| script.instructions().to_vec())
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            parse("0xff 10 OP_4 OP_DUP OP_NOP OP_EQUALVERIFY"),
+            Ok(vec![
+                Instruction::Push(BigInt::from(255)),
+                Instruction::Push(BigInt::from(16)),
+                Instruction::Push(BigInt::from(4)),
+                Instruction::Call(Opcode::OP_DUP),
+                Instruction::Return(Opcode::OP_EQUALVERIFY),
+            ])
+        );
+        assert_eq!(
+            parse("1 OP_EQUALVERIFY OP_RETURN"),
+            Ok(vec![
+                Instruction::Push(BigInt::from(1)),
+                Instruction::Call(Opcode::OP_EQUALVERIFY),
+                Instruction::Return(Opcode::OP_RETURN),
+            ])
+        );
+        assert_eq!(parse("1 OP_VERIFY\n"), Ok(vec![Instruction::Push(BigInt::from(1)), Instruction::Call(Opcode::OP_VERIFY)]));
+    }
+
+    #[test]
+    fn parse_blocks_test() {
+        assert_eq!(
+            parse("OP_IF 1 OP_ELSE 2 OP_ENDIF OP_NOTIF 3 OP_ENDIF OP_GATE 4 OP_ENDIFEQ"),
+            Ok(vec![
+                Instruction::JumpIfZero(3),
+                Instruction::Push(BigInt::from(1)),
+                Instruction::Jump(4),
+                Instruction::Push(BigInt::from(2)),
+                Instruction::JumpIfNotZero(6),
+                Instruction::Push(BigInt::from(3)),
+                Instruction::JumpIfNotEqual(8),
+                Instruction::Push(BigInt::from(4)),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        assert_eq!(parse(""), Err(ScriptError::UnexpectedEnd));
+        assert_eq!(parse("1 OP_IF 2"), Err(ScriptError::UnexpectedEnd));
+        assert_eq!(parse("1 OP_IF OP_ENDIF"), Err(ScriptError::UnexpectedToken("OP_ENDIF".to_string())));
+        assert_eq!(parse("1 OP_ENDIF"), Err(ScriptError::UnexpectedToken("OP_ENDIF".to_string())));
+        assert_eq!(parse("1 OP_IFEQ 2 OP_ENDIF"), Err(ScriptError::UnexpectedToken("OP_ENDIF".to_string())));
+        assert_eq!(parse("1 OP_DUPS"), Err(ScriptError::UnknownToken("OP_DUPS".to_string())));
+        assert_eq!(parse("0x"), Err(ScriptError::UnknownToken("0x".to_string())));
+        assert_eq!(parse("1 OP_TOALTSTACK"), Err(ScriptError::UnknownToken("OP_TOALTSTACK".to_string())));
+        assert_eq!(parse("1 OP_FIX"), Err(ScriptError::UnknownToken("OP_FIX".to_string())));
+    }
+}
@@ -0,0 +1,777 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Offline consistency check of a node database, replaces src/bin/check-db.es6
+//!
+//! Every value is decoded and the indices are checked against the stored blocks: the height
+//! index, the parent links of the main branch, the transaction indices and the NRG minted so far
+//! against the outputs left unspent by the main branch. Indices which can be rebuilt from the
+//! stored blocks come with the writes repairing them. Entries of the persisted UTXO set are only
+//! checked to decode, see `utxo`.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use num_bigint::BigUint;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json::{Json, Object};
+
+use coin::internal_to_biguint;
+use protos::core::{BcBlock, Transaction};
+use utxo::{latest_from_json, unspent_output_from_json, utxo_latest_key, utxo_of_key, Utxo, UtxoError};
+
+use super::codec::{encode_message, encode_native, Codec, DbObject};
+use super::{
+    block_at_height_key, block_key, block_txs_key, height_key, json_to_strings, latest_block_key, tx_block_key, tx_key,
+    tx_other_blocks_key, Batch, KeyValueDb, Reader, Store, StoreError, WriteOp, BLOCKCHAIN,
+};
+
+const BOSON_PER_NRG: u64 = 1_000_000_000_000_000_000;
+
+/// Whole NRG minted so far, `NRG_MINTED_PERISTENCE_KEY` in JS
+pub fn nrg_minted_key() -> String {
+    format!("{}.nrg.granted", BLOCKCHAIN)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// Value is not a `DbValue` of a known type
+    Undecodable { key: String, reason: String },
+    /// Value is of another type than the key holds
+    UnexpectedValue { key: String, expected: String },
+    /// Key holds a block which is not stored by its hash
+    MissingBlock { key: String, hash: String },
+    /// `bc.block.<height>` holds a block of another height
+    WrongHeight { key: String, height: u64 },
+    /// Stored block is missing from the hashes at its height
+    NotIndexedAtHeight { hash: String, height: u64 },
+    /// Hash at a height of a block which is not stored at the height
+    StaleHeightIndex { hash: String, height: u64 },
+    /// No block of the main branch at a height up to the latest block
+    MissingHeight(u64),
+    /// Block of the main branch does not follow the block below it
+    BrokenParentLink { height: u64, previous_hash: String, parent_hash: String },
+    /// Transaction index of a stored block is missing or wrong
+    MissingTxIndex { key: String },
+    /// Transaction index without a stored block
+    StaleTxIndex { key: String },
+    /// Main branch spends outputs it does not create
+    UtxoReplay { height: u64, reason: String },
+    MissingMintedNrg,
+    /// Outputs left unspent by the main branch do not add up to the NRG minted so far
+    MintedNrgMismatch { minted: u64, unspent: BigUint, truncations: u64 },
+}
+
+impl Issue {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Issue::Undecodable { .. } => "undecodable",
+            Issue::UnexpectedValue { .. } => "unexpected_value",
+            Issue::MissingBlock { .. } => "missing_block",
+            Issue::WrongHeight { .. } => "wrong_height",
+            Issue::NotIndexedAtHeight { .. } => "not_indexed_at_height",
+            Issue::StaleHeightIndex { .. } => "stale_height_index",
+            Issue::MissingHeight(_) => "missing_height",
+            Issue::BrokenParentLink { .. } => "broken_parent_link",
+            Issue::MissingTxIndex { .. } => "missing_tx_index",
+            Issue::StaleTxIndex { .. } => "stale_tx_index",
+            Issue::UtxoReplay { .. } => "utxo_replay",
+            Issue::MissingMintedNrg => "missing_minted_nrg",
+            Issue::MintedNrgMismatch { .. } => "minted_nrg_mismatch",
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        let string = |value: &str| Json::String(value.to_string());
+        let fields = match *self {
+            Issue::Undecodable { ref key, ref reason } => vec![("key", string(key)), ("reason", string(reason))],
+            Issue::UnexpectedValue { ref key, ref expected } => vec![("key", string(key)), ("expected", string(expected))],
+            Issue::MissingBlock { ref key, ref hash } => vec![("key", string(key)), ("hash", string(hash))],
+            Issue::WrongHeight { ref key, height } => vec![("key", string(key)), ("height", Json::U64(height))],
+            Issue::NotIndexedAtHeight { ref hash, height } | Issue::StaleHeightIndex { ref hash, height } =>
+                vec![("hash", string(hash)), ("height", Json::U64(height))],
+            Issue::MissingHeight(height) => vec![("height", Json::U64(height))],
+            Issue::BrokenParentLink { height, ref previous_hash, ref parent_hash } => vec![
+                ("height", Json::U64(height)),
+                ("previous_hash", string(previous_hash)),
+                ("parent_hash", string(parent_hash)),
+            ],
+            Issue::MissingTxIndex { ref key } | Issue::StaleTxIndex { ref key } => vec![("key", string(key))],
+            Issue::UtxoReplay { height, ref reason } => vec![("height", Json::U64(height)), ("reason", string(reason))],
+            Issue::MissingMintedNrg => vec![("key", string(&nrg_minted_key()))],
+            Issue::MintedNrgMismatch { minted, ref unspent, truncations } => vec![
+                ("minted", Json::U64(minted)),
+                ("unspent", string(&unspent.to_string())),
+                ("truncations", Json::U64(truncations)),
+            ],
+        };
+
+        let mut json = Object::new();
+        json.insert("kind".to_string(), string(self.kind()));
+        for (name, value) in fields {
+            json.insert(name.to_string(), value);
+        }
+        Json::Object(json)
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+/// Outcome of `Store::check`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckReport {
+    /// Keys in the database
+    pub keys: u64,
+    /// Blocks stored by hash
+    pub blocks: u64,
+    /// Height of the latest block, 0 without blocks
+    pub latest_height: u64,
+    pub issues: Vec<Issue>,
+    /// Writes fixing the issues which can be fixed from the stored blocks
+    pub repairs: Vec<WriteOp>,
+}
+
+impl CheckReport {
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Report as JSON, repairs are listed with `with_repairs` with values in base64
+    pub fn to_json(&self, with_repairs: bool) -> Json {
+        let mut json = Object::new();
+        json.insert("keys".to_string(), Json::U64(self.keys));
+        json.insert("blocks".to_string(), Json::U64(self.blocks));
+        json.insert("latest_height".to_string(), Json::U64(self.latest_height));
+        json.insert("issues".to_string(), Json::Array(self.issues.iter().map(Issue::to_json).collect()));
+        if with_repairs {
+            let repairs = self.repairs.iter().map(|op| {
+                let mut repair = Object::new();
+                match *op {
+                    WriteOp::Put(ref key, ref value) => {
+                        repair.insert("op".to_string(), Json::String("put".to_string()));
+                        repair.insert("key".to_string(), Json::String(key.clone()));
+                        repair.insert("value".to_string(), Json::String(value.to_base64(STANDARD)));
+                    }
+                    WriteOp::Delete(ref key) => {
+                        repair.insert("op".to_string(), Json::String("del".to_string()));
+                        repair.insert("key".to_string(), Json::String(key.clone()));
+                    }
+                }
+                Json::Object(repair)
+            });
+            json.insert("repairs".to_string(), Json::Array(repairs.collect()));
+        }
+        Json::Object(json)
+    }
+}
+
+#[derive(Clone)]
+struct BlockInfo {
+    hash: String,
+    height: u64,
+    previous_hash: String,
+    tx_hashes: Vec<String>,
+}
+
+impl BlockInfo {
+    fn new(block: &BcBlock) -> BlockInfo {
+        BlockInfo {
+            hash: block.get_hash().to_string(),
+            height: block.get_height(),
+            previous_hash: block.get_previous_hash().to_string(),
+            tx_hashes: block.get_txs().iter().map(|tx| tx.get_hash().to_string()).collect(),
+        }
+    }
+}
+
+/// Indices collected from all keys, blocks are kept without their transactions
+#[derive(Default)]
+struct Scan {
+    keys: u64,
+    issues: Vec<Issue>,
+    /// Blocks by hash
+    blocks: BTreeMap<String, BlockInfo>,
+    /// Blocks of the main branch by height
+    main: BTreeMap<u64, BlockInfo>,
+    /// Other keys holding blocks, as `bc.block.latest`
+    pointers: BTreeMap<String, BlockInfo>,
+    heights: BTreeMap<u64, Vec<String>>,
+    tx_lists: BTreeMap<String, Vec<String>>,
+    txs: BTreeSet<String>,
+    /// Block keys by transaction hash
+    tx_blocks: BTreeMap<String, String>,
+    /// Hashes of other blocks by transaction hash
+    tx_other_blocks: BTreeMap<String, Vec<String>>,
+    minted: Option<u64>,
+}
+
+impl Scan {
+    fn visit(&mut self, codec: &Codec, key: &str, value: &[u8]) {
+        self.keys += 1;
+        let object = match codec.decode(value) {
+            Ok(object) => object,
+            Err(err) => {
+                self.issues.push(Issue::Undecodable { key: key.to_string(), reason: err.to_string() });
+                return;
+            }
+        };
+        if let Err(expected) = self.collect(key, object) {
+            self.issues.push(Issue::UnexpectedValue { key: key.to_string(), expected: expected.to_string() });
+        }
+    }
+
+    /// Collects `object` stored at `key` if the key is an index, returns the expected type otherwise
+    fn collect(&mut self, key: &str, object: DbObject) -> Result<(), &'static str> {
+        let strings = |object: &DbObject| match *object {
+            DbObject::Native(ref json) => json_to_strings(key, json).ok(),
+            _ => None,
+        };
+
+        if let Some(suffix) = key.strip_prefix(&block_key("")) {
+            let block = match object {
+                DbObject::BcBlock(block) => block,
+                _ => return Err("BcBlock"),
+            };
+            let info = BlockInfo::new(&block);
+            if let Ok(height) = suffix.parse() {
+                self.main.insert(height, info);
+            } else if suffix == block.get_hash() {
+                self.blocks.insert(info.hash.clone(), info);
+            } else {
+                self.pointers.insert(key.to_string(), info);
+            }
+        } else if let Some(height) = key.strip_prefix(&format!("{}.height.", BLOCKCHAIN)) {
+            match (height.parse(), strings(&object)) {
+                (Ok(height), Some(hashes)) => {
+                    self.heights.insert(height, hashes);
+                }
+                _ => return Err("array of block hashes"),
+            }
+        } else if let Some(hash) = key.strip_prefix(&block_txs_key("")) {
+            self.tx_lists.insert(hash.to_string(), strings(&object).ok_or("array of transaction hashes")?);
+        } else if let Some(hash) = key.strip_prefix(&tx_key("")) {
+            match object {
+                DbObject::Transaction(_) => self.txs.insert(hash.to_string()),
+                _ => return Err("Transaction"),
+            };
+        } else if let Some(hash) = key.strip_prefix(&tx_block_key("")) {
+            match object {
+                DbObject::Native(Json::String(block_key)) => self.tx_blocks.insert(hash.to_string(), block_key),
+                _ => return Err("block key"),
+            };
+        } else if let Some(hash) = key.strip_prefix(&tx_other_blocks_key("")) {
+            self.tx_other_blocks.insert(hash.to_string(), strings(&object).ok_or("array of block hashes")?);
+        } else if key == nrg_minted_key() {
+            let minted = match object {
+                DbObject::Native(ref json) => json.as_u64().or_else(|| json.as_f64().map(|minted| minted as u64)),
+                _ => None,
+            };
+            self.minted = Some(minted.ok_or("Number")?);
+        } else if key == utxo_latest_key() {
+            match object {
+                DbObject::Native(ref json) if latest_from_json(json).is_some() => {}
+                _ => return Err("best block of the UTXO set"),
+            }
+        } else if utxo_of_key(key).is_some() {
+            match object {
+                DbObject::Native(ref json) if unspent_output_from_json(json).is_some() => {}
+                _ => return Err("unspent output"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Height of the latest block, the highest block of the main branch without `bc.block.latest`
+    fn latest_height(&self) -> u64 {
+        match self.pointers.get(&latest_block_key()) {
+            Some(latest) => latest.height,
+            None => self.main.keys().next_back().cloned().unwrap_or(0),
+        }
+    }
+
+    /// If transaction `tx_hash` is in a block of the main branch below `height`, found through the
+    /// transaction indices
+    fn is_on_main_branch(&self, tx_hash: &str, height: u64) -> bool {
+        let prefix = block_key("");
+        let indexed = self.tx_blocks.get(tx_hash).and_then(|key| key.strip_prefix(&prefix));
+        let others = self.tx_other_blocks.get(tx_hash).into_iter().flat_map(|hashes| hashes.iter().map(|hash| hash.as_str()));
+        indexed.into_iter().chain(others).any(|hash| match self.blocks.get(hash) {
+            Some(info) => {
+                info.height < height &&
+                    self.main.get(&info.height).map(|main| main.hash.as_str()) == Some(hash) &&
+                    info.tx_hashes.iter().any(|known| known == tx_hash)
+            }
+            None => false,
+        })
+    }
+
+    fn check_height_index<D: KeyValueDb>(&mut self, repairs: &mut Batch<'_, D>) -> Result<(), StoreError> {
+        let Scan { ref mut issues, ref mut blocks, ref main, ref pointers, ref mut heights, .. } = *self;
+
+        let at_heights = main.iter().map(|(height, info)| (block_at_height_key(*height), info));
+        for (key, info) in at_heights.chain(pointers.iter().map(|(key, info)| (key.clone(), info))) {
+            if blocks.contains_key(&info.hash) {
+                continue;
+            }
+            issues.push(Issue::MissingBlock { key: key.clone(), hash: info.hash.clone() });
+            if let Some(bytes) = repairs.get_raw(&key)? {
+                repairs.put_raw(block_key(&info.hash), bytes);
+            }
+            blocks.insert(info.hash.clone(), info.clone());
+        }
+
+        for (height, info) in main {
+            if info.height != *height {
+                issues.push(Issue::WrongHeight { key: block_at_height_key(*height), height: info.height });
+            }
+        }
+
+        for (hash, info) in blocks.iter() {
+            let hashes = heights.entry(info.height).or_default();
+            if !hashes.contains(hash) {
+                issues.push(Issue::NotIndexedAtHeight { hash: hash.clone(), height: info.height });
+                hashes.push(hash.clone());
+                repairs.put_strings(height_key(info.height), hashes);
+            }
+        }
+
+        for (height, hashes) in heights.iter_mut() {
+            let is_stored = |hash: &String| match blocks.get(hash) {
+                Some(info) => info.height == *height,
+                None => false,
+            };
+            let stale: Vec<String> = hashes.iter().filter(|hash| !is_stored(hash)).cloned().collect();
+            if stale.is_empty() {
+                continue;
+            }
+            for hash in stale {
+                issues.push(Issue::StaleHeightIndex { hash, height: *height });
+            }
+            hashes.retain(|hash| is_stored(hash));
+            if hashes.is_empty() {
+                repairs.delete(&height_key(*height));
+            } else {
+                repairs.put_strings(height_key(*height), hashes);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_main_branch(&mut self) {
+        for height in 1..=self.latest_height() {
+            let info = match self.main.get(&height) {
+                Some(info) => info,
+                None => {
+                    self.issues.push(Issue::MissingHeight(height));
+                    continue;
+                }
+            };
+            if let Some(parent) = self.main.get(&(height - 1)) {
+                if info.previous_hash != parent.hash {
+                    self.issues.push(Issue::BrokenParentLink {
+                        height,
+                        previous_hash: info.previous_hash.clone(),
+                        parent_hash: parent.hash.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn check_tx_indices<D: KeyValueDb>(&mut self, repairs: &mut Batch<'_, D>) -> Result<(), StoreError> {
+        let mut blocks_of_txs: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (hash, info) in &self.blocks {
+            for tx_hash in &info.tx_hashes {
+                blocks_of_txs.entry(tx_hash).or_default().push(hash);
+            }
+            if self.tx_lists.get(hash) != Some(&info.tx_hashes) {
+                self.issues.push(Issue::MissingTxIndex { key: block_txs_key(hash) });
+                repairs.put_strings(block_txs_key(hash), &info.tx_hashes);
+            }
+        }
+
+        for (tx_hash, block_hashes) in &blocks_of_txs {
+            if !self.txs.contains(*tx_hash) {
+                self.issues.push(Issue::MissingTxIndex { key: tx_key(tx_hash) });
+                let block: Option<BcBlock> = repairs.get_message(&block_key(block_hashes[0]))?;
+                let tx: Option<&Transaction> =
+                    block.as_ref().and_then(|block| block.get_txs().iter().find(|tx| tx.get_hash() == *tx_hash));
+                if let Some(tx) = tx {
+                    repairs.put_raw(tx_key(tx_hash), encode_message(tx));
+                }
+            }
+            let indexed = self.tx_blocks.get(*tx_hash).and_then(|key| block_hashes.iter().find(|hash| *key == block_key(hash)));
+            let indexed_hash = match indexed {
+                Some(hash) => *hash,
+                None => {
+                    self.issues.push(Issue::MissingTxIndex { key: tx_block_key(tx_hash) });
+                    repairs.put_raw(tx_block_key(tx_hash), encode_native(&Json::String(block_key(block_hashes[0]))));
+                    block_hashes[0]
+                }
+            };
+
+            let others: BTreeSet<&str> = block_hashes.iter().cloned().filter(|hash| *hash != indexed_hash).collect();
+            let stored: BTreeSet<&str> = match self.tx_other_blocks.get(*tx_hash) {
+                Some(stored) => stored.iter().map(|hash| hash.as_str()).collect(),
+                None => BTreeSet::new(),
+            };
+            if others != stored {
+                self.issues.push(Issue::MissingTxIndex { key: tx_other_blocks_key(tx_hash) });
+                if others.is_empty() {
+                    repairs.delete(&tx_other_blocks_key(tx_hash));
+                } else {
+                    let others: Vec<String> = others.iter().map(|hash| hash.to_string()).collect();
+                    repairs.put_strings(tx_other_blocks_key(tx_hash), &others);
+                }
+            }
+        }
+
+        let stale_lists = self.tx_lists.keys().filter(|hash| !self.blocks.contains_key(*hash)).map(|hash| block_txs_key(hash));
+        let stale_txs = self.txs.iter().filter(|hash| !blocks_of_txs.contains_key(hash.as_str())).map(|hash| tx_key(hash));
+        let stale_tx_blocks =
+            self.tx_blocks.keys().filter(|hash| !blocks_of_txs.contains_key(hash.as_str())).map(|hash| tx_block_key(hash));
+        let stale_tx_other_blocks = self
+            .tx_other_blocks
+            .keys()
+            .filter(|hash| !blocks_of_txs.contains_key(hash.as_str()))
+            .map(|hash| tx_other_blocks_key(hash));
+        let stale: Vec<String> = stale_lists.chain(stale_txs).chain(stale_tx_blocks).chain(stale_tx_other_blocks).collect();
+        for key in stale {
+            repairs.delete(&key);
+            self.issues.push(Issue::StaleTxIndex { key });
+        }
+        Ok(())
+    }
+}
+
+impl<D: KeyValueDb> Store<D> {
+    /// Checks all keys of the database, replaces `check-db` in JS
+    ///
+    /// Inconsistencies are reported as issues, only failures to read the database are errors.
+    pub fn check(&self) -> Result<CheckReport, StoreError> {
+        let mut scan = Scan::default();
+        {
+            let codec = &self.codec;
+            self.db.scan(&mut |key, value| scan.visit(codec, key, value))?;
+        }
+
+        let mut repairs = self.batch();
+        scan.check_height_index(&mut repairs)?;
+        scan.check_main_branch();
+        scan.check_tx_indices(&mut repairs)?;
+        self.check_minted_nrg(&mut scan)?;
+
+        Ok(CheckReport {
+            keys: scan.keys,
+            blocks: scan.blocks.len() as u64,
+            latest_height: scan.latest_height(),
+            issues: scan.issues,
+            repairs: repairs.write_ops(),
+        })
+    }
+
+    /// Replays the main branch and compares the unspent outputs to `getNrgMintedSoFar` in JS
+    ///
+    /// JS truncates the NRG minted by the outputs of the genesis block and by every later block to
+    /// whole NRG, so the unspent outputs can exceed the minted NRG by less than one NRG for each.
+    /// Only the total of the unspent outputs is kept: the outputs a block spends are read from the
+    /// stored transactions of the main branch below the block and from the transactions before the
+    /// spending one in the block, outputs spent twice only show in the total. Gaps in the main
+    /// branch are reported by `check_main_branch` and stop the replay, as do blocks which can not
+    /// be decoded and spends which can not be resolved.
+    fn check_minted_nrg(&self, scan: &mut Scan) -> Result<(), StoreError> {
+        let latest_height = scan.latest_height();
+        if latest_height == 0 {
+            return Ok(());
+        }
+
+        let mut unspent = BigUint::from(0u32);
+        let mut truncations = 0;
+        let mut best_block_hash = String::new();
+        for height in 1..=latest_height {
+            let block: BcBlock = match self.get_message(&block_at_height_key(height)) {
+                Ok(Some(block)) => block,
+                Ok(None) => return Ok(()),
+                Err(err @ StoreError::InvalidValue { .. }) => {
+                    scan.issues.push(Issue::UtxoReplay { height, reason: err.to_string() });
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            if !best_block_hash.is_empty() && block.get_previous_hash() != best_block_hash {
+                let err = UtxoError::NotConnected { best_block_hash, previous_hash: block.get_previous_hash().to_string() };
+                scan.issues.push(Issue::UtxoReplay { height, reason: err.to_string() });
+                return Ok(());
+            }
+
+            let mut spent = BigUint::from(0u32);
+            for (position, tx) in block.get_txs().iter().enumerate() {
+                for input in tx.get_inputs() {
+                    let utxo = Utxo::from_out_point(input.get_out_point());
+                    let earlier = block.get_txs()[..position].iter().find(|earlier| earlier.get_hash() == utxo.tx_hash);
+                    let value = match earlier {
+                        Some(earlier) => {
+                            let output = earlier.get_outputs().get(utxo.index as usize);
+                            Ok(output.map(|output| internal_to_biguint(output.get_value())))
+                        }
+                        None => self.spent_output_value(scan, &utxo, height),
+                    };
+                    match value {
+                        Ok(Some(value)) => spent += value,
+                        Ok(None) => {
+                            scan.issues.push(Issue::UtxoReplay { height, reason: UtxoError::MissingOutput(utxo).to_string() });
+                            return Ok(());
+                        }
+                        Err(err @ StoreError::InvalidValue { .. }) => {
+                            scan.issues.push(Issue::UtxoReplay { height, reason: err.to_string() });
+                            return Ok(());
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                for output in tx.get_outputs() {
+                    unspent += internal_to_biguint(output.get_value());
+                }
+            }
+            if spent > unspent {
+                let reason = format!("block spends {} more than the unspent outputs", &spent - &unspent);
+                scan.issues.push(Issue::UtxoReplay { height, reason });
+                return Ok(());
+            }
+            unspent -= spent;
+            best_block_hash = block.get_hash().to_string();
+            truncations += match block.get_txs().first() {
+                Some(coinbase) if height == 1 => coinbase.get_outputs().len() as u64,
+                _ => 1,
+            };
+        }
+
+        let minted = match scan.minted {
+            Some(minted) => minted,
+            None => {
+                scan.issues.push(Issue::MissingMintedNrg);
+                return Ok(());
+            }
+        };
+        let unspent_nrg = &unspent / BigUint::from(BOSON_PER_NRG);
+        if unspent_nrg < BigUint::from(minted) || unspent_nrg > BigUint::from(minted) + BigUint::from(truncations) {
+            scan.issues.push(Issue::MintedNrgMismatch { minted, unspent, truncations });
+        }
+        Ok(())
+    }
+
+    /// Value of output `utxo` spent at `height`, `None` if it is not created by the main branch below
+    fn spent_output_value(&self, scan: &Scan, utxo: &Utxo, height: u64) -> Result<Option<BigUint>, StoreError> {
+        if !scan.is_on_main_branch(&utxo.tx_hash, height) {
+            return Ok(None);
+        }
+        let tx: Option<Transaction> = self.get_message(&tx_key(&utxo.tx_hash))?;
+        Ok(tx.and_then(|tx| tx.get_outputs().get(utxo.index as usize).map(|output| internal_to_biguint(output.get_value()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use protos::core::{TransactionInput, TransactionOutput};
+    use store::MemoryDb;
+    use utxo::UtxoSet;
+
+    fn coinbase(hash: &str, nrg: u64) -> Transaction {
+        let mut output = TransactionOutput::new();
+        output.set_value((BigUint::from(nrg) * BigUint::from(BOSON_PER_NRG)).to_bytes_be());
+        let mut tx = Transaction::new();
+        tx.set_hash(hash.to_string());
+        tx.set_outputs(RepeatedField::from_vec(vec![output]));
+        tx
+    }
+
+    fn block(hash: &str, previous_hash: &str, height: u64, txs: Vec<Transaction>) -> BcBlock {
+        let mut block = BcBlock::new();
+        block.set_hash(hash.to_string());
+        block.set_previous_hash(previous_hash.to_string());
+        block.set_height(height);
+        block.set_txs(RepeatedField::from_vec(txs));
+        block
+    }
+
+    fn chain() -> Store<MemoryDb> {
+        let store = Store::new(MemoryDb::new());
+        let mut batch = store.batch();
+        batch.put_block(&block("b1", "", 1, vec![coinbase("t1", 100)]), true).unwrap();
+        batch.put_block(&block("b2", "b1", 2, vec![coinbase("t2", 10)]), true).unwrap();
+        batch.put_block(&block("o2", "b1", 2, vec![coinbase("t3", 10), coinbase("t2", 10)]), false).unwrap();
+        let b3 = block("b3", "b2", 3, vec![coinbase("t4", 10)]);
+        batch.put_block(&b3, true).unwrap();
+        batch.put_latest_block(&b3);
+        batch.put_raw(nrg_minted_key(), encode_native(&Json::U64(120)));
+        batch.commit().unwrap();
+        store
+    }
+
+    #[test]
+    fn consistent_test() {
+        let report = chain().check().unwrap();
+        assert_eq!(report.issues, vec![]);
+        assert_eq!(report.repairs, vec![]);
+        assert_eq!((report.blocks, report.latest_height), (4, 3));
+        assert_eq!(
+            report.to_json(true).to_string(),
+            format!(r#"{{"blocks":4,"issues":[],"keys":{},"latest_height":3,"repairs":[]}}"#, report.keys)
+        );
+    }
+
+    #[test]
+    fn repair_test() {
+        let store = chain();
+        store
+            .db()
+            .write(vec![
+                WriteOp::Delete("bc.height.2".to_string()),
+                WriteOp::Delete("bc.block.b3".to_string()),
+                WriteOp::Delete("bc.tx.t2".to_string()),
+                WriteOp::Put("bc.txblock.t3".to_string(), encode_native(&Json::String("bc.block.b9".to_string()))),
+                WriteOp::Put("bc.txs.b9".to_string(), encode_native(&Json::Array(vec![]))),
+                WriteOp::Delete("bc.txblocks.t2".to_string()),
+                WriteOp::Put("bc.junk".to_string(), b"\xff".to_vec()),
+            ])
+            .unwrap();
+
+        let report = store.check().unwrap();
+        assert_eq!(report.issues[0].kind(), "undecodable");
+        let issues: Vec<String> = report.issues[1..].iter().map(|issue| issue.to_string()).collect();
+        assert_eq!(
+            issues,
+            vec![
+                r#"{"hash":"b3","key":"bc.block.3","kind":"missing_block"}"#,
+                r#"{"hash":"b2","height":2,"kind":"not_indexed_at_height"}"#,
+                r#"{"hash":"o2","height":2,"kind":"not_indexed_at_height"}"#,
+                r#"{"key":"bc.tx.t2","kind":"missing_tx_index"}"#,
+                r#"{"key":"bc.txblocks.t2","kind":"missing_tx_index"}"#,
+                r#"{"key":"bc.txblock.t3","kind":"missing_tx_index"}"#,
+                r#"{"key":"bc.txs.b9","kind":"stale_tx_index"}"#,
+            ]
+        );
+        assert!(!report.to_json(true)["repairs"].as_array().unwrap().is_empty());
+
+        store.db().write(report.repairs).unwrap();
+        let report = store.check().unwrap();
+        assert_eq!(report.issues.iter().map(Issue::kind).collect::<Vec<_>>(), vec!["undecodable"]);
+        assert_eq!(store.get_block_by_hash("b2"), Ok(Some(block("b2", "b1", 2, vec![coinbase("t2", 10)]))));
+        assert_eq!(store.get_block_hash_of_tx("t3"), Ok(Some("o2".to_string())));
+    }
+
+    #[test]
+    fn main_branch_test() {
+        let store = chain();
+        let mut batch = store.batch();
+        batch.put_raw(nrg_minted_key(), encode_native(&Json::U64(200)));
+        batch.put_block(&block("b4", "x3", 4, vec![coinbase("t5", 10)]), true).unwrap();
+        let b6 = block("b6", "b5", 6, vec![]);
+        batch.put_block(&b6, true).unwrap();
+        batch.put_latest_block(&b6);
+        batch.commit().unwrap();
+
+        let report = store.check().unwrap();
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue::BrokenParentLink { height: 4, previous_hash: "x3".to_string(), parent_hash: "b3".to_string() },
+                Issue::MissingHeight(5),
+                Issue::UtxoReplay {
+                    height: 4,
+                    reason: "block with previous hash x3 does not follow best block b3".to_string(),
+                },
+            ]
+        );
+
+        store.del_block("b6").unwrap();
+        store.del_block("b4").unwrap();
+        let mut batch = store.batch();
+        batch.put_latest_block(&block("b3", "b2", 3, vec![coinbase("t4", 10)]));
+        batch.commit().unwrap();
+        let unspent = BigUint::from(120u64) * BigUint::from(BOSON_PER_NRG);
+        let report = store.check().unwrap();
+        assert_eq!(report.issues, vec![Issue::MintedNrgMismatch { minted: 200, unspent: unspent.clone(), truncations: 3 }]);
+        assert!(!report.is_consistent());
+
+        store.db().write(vec![WriteOp::Put(nrg_minted_key(), encode_native(&Json::U64(100)))]).unwrap();
+        let report = store.check().unwrap();
+        assert_eq!(report.issues, vec![Issue::MintedNrgMismatch { minted: 100, unspent, truncations: 3 }]);
+        assert!(!report.is_consistent());
+
+        // blocks which can not be decoded stop the replay
+        store.db().write(vec![WriteOp::Put(block_at_height_key(2), encode_native(&Json::U64(2)))]).unwrap();
+        let issues = store.check().unwrap().issues;
+        assert_eq!(issues.iter().map(Issue::kind).collect::<Vec<_>>(), vec!["unexpected_value", "missing_height", "utxo_replay"]);
+    }
+
+    fn spend(hash: &str, spent: &[(&str, u64)], nrg: u64) -> Transaction {
+        let inputs = spent
+            .iter()
+            .map(|&(hash, index)| {
+                let mut input = TransactionInput::new();
+                input.mut_out_point().set_hash(hash.to_string());
+                input.mut_out_point().set_index(index);
+                input
+            })
+            .collect();
+        let mut tx = coinbase(hash, nrg);
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx
+    }
+
+    #[test]
+    fn spend_test() {
+        let store = chain();
+        let b4 = block("b4", "b3", 4, vec![coinbase("t5", 10), spend("t6", &[("t1", 0), ("t4", 0)], 110)]);
+        let mut batch = store.batch();
+        batch.put_raw(nrg_minted_key(), encode_native(&Json::U64(130)));
+        batch.put_block(&b4, true).unwrap();
+        batch.put_latest_block(&b4);
+        batch.commit().unwrap();
+        assert_eq!(store.check().unwrap().issues, vec![]);
+
+        // an output of another branch, an output spent twice only shows in the total
+        for &(hash, spent, kind) in &[("t7", ("t3", 0), "utxo_replay"), ("t8", ("t1", 0), "minted_nrg_mismatch")] {
+            let b5 = block("b5", "b4", 5, vec![spend(hash, &[spent], 0)]);
+            let mut batch = store.batch();
+            batch.put_block(&b5, true).unwrap();
+            batch.put_latest_block(&b5);
+            batch.commit().unwrap();
+            assert_eq!(store.check().unwrap().issues.iter().map(Issue::kind).collect::<Vec<_>>(), vec![kind]);
+            store.del_block("b5").unwrap();
+        }
+        let b5 = block("b5", "b4", 5, vec![spend("t9", &[("t9", 0)], 0)]);
+        let mut batch = store.batch();
+        batch.put_block(&b5, true).unwrap();
+        batch.put_latest_block(&b5);
+        batch.commit().unwrap();
+        let issues = store.check().unwrap().issues;
+        assert_eq!(issues, vec![Issue::UtxoReplay { height: 5, reason: "output t9.0 is missing or spent".to_string() }]);
+    }
+
+    #[test]
+    fn utxo_entries_test() {
+        let store = chain();
+        let mut utxos = UtxoSet::new();
+        for height in 1..4 {
+            utxos.apply_block(&store.get_block_by_height(height).unwrap().unwrap()).unwrap();
+        }
+        store.db().write(utxos.write_ops().unwrap()).unwrap();
+        assert_eq!(store.check().unwrap().issues, vec![]);
+
+        store.db().write(vec![WriteOp::Put("utxo.t1.0".to_string(), encode_native(&Json::U64(1)))]).unwrap();
+        assert_eq!(
+            store.check().unwrap().issues,
+            vec![Issue::UnexpectedValue { key: "utxo.t1.0".to_string(), expected: "unspent output".to_string() }]
+        );
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! `DbValue` envelope of stored values, port of src/persistence/codec.es6
+//!
+//! Protobuf messages are stored with the name of their type, plain values as JSON with the name
+//! JS gives to their type and `is_native` set. Values written with an older `version` are
+//! upgraded by the migrations registered for their type when they are read.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str;
+
+use protobuf::{self, Message};
+use rustc_serialize::json::Json;
+
+use protos::core::{
+    BcBlock, Block, BlockchainHeader, BlockchainHeaders, MarkedTransaction, OutPoint, Transaction, TransactionInput,
+    TransactionOutput,
+};
+use protos::db::DbValue;
+
+/// Version of values written by the codec, `DB_VALUES_VERSION` in JS
+pub const DB_VALUES_VERSION: i32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    InvalidEnvelope(String),
+    UnknownType(String),
+    UnexpectedType { expected: String, found: String },
+    UnsupportedVersion { type_name: String, version: i32 },
+    Migration { type_name: String, version: i32, reason: String },
+    InvalidData { type_name: String, reason: String },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::InvalidEnvelope(ref reason) => write!(f, "invalid DbValue: {}", reason),
+            CodecError::UnknownType(ref type_name) => write!(f, "could not find '{}' in stored messages", type_name),
+            CodecError::UnexpectedType { ref expected, ref found } => write!(f, "expected {}, found {}", expected, found),
+            CodecError::UnsupportedVersion { ref type_name, version } =>
+                write!(f, "no migration of {} from version {}", type_name, version),
+            CodecError::Migration { ref type_name, version, ref reason } =>
+                write!(f, "migration of {} from version {} failed: {}", type_name, version, reason),
+            CodecError::InvalidData { ref type_name, ref reason } => write!(f, "invalid {}: {}", type_name, reason),
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn description(&self) -> &str {
+        "codec error"
+    }
+}
+
+/// Protobuf message stored in a `DbValue`, `BC_MESSAGES_MAP` in JS
+///
+/// `InitialPeer` of the p2p protocol has no Rust counterpart and is not supported.
+pub trait StoredMessage: Message {
+    fn type_name() -> &'static str;
+}
+
+macro_rules! stored_messages {
+    ($($message:ident),*) => {
+        $(
+            impl StoredMessage for $message {
+                fn type_name() -> &'static str {
+                    stringify!($message)
+                }
+            }
+        )*
+
+        /// Any value which can be stored
+        #[derive(Debug, Clone, PartialEq)]
+        #[allow(clippy::large_enum_variant)]
+        pub enum DbObject {
+            $($message($message),)*
+            Native(Json),
+        }
+
+        /// `DbValue` of `object`, `serialize` in JS
+        pub fn encode(object: &DbObject) -> Vec<u8> {
+            match *object {
+                $(DbObject::$message(ref message) => encode_message(message),)*
+                DbObject::Native(ref json) => encode_native(json),
+            }
+        }
+
+        fn decode_object(value: &DbValue) -> Result<DbObject, CodecError> {
+            match value.get_field_type() {
+                $(stringify!($message) => parse::<$message>(value).map(DbObject::$message),)*
+                type_name => Err(CodecError::UnknownType(type_name.to_string())),
+            }
+        }
+    };
+}
+
+stored_messages!(
+    Block,
+    BcBlock,
+    BlockchainHeader,
+    BlockchainHeaders,
+    Transaction,
+    MarkedTransaction,
+    TransactionOutput,
+    TransactionInput,
+    OutPoint
+);
+
+fn envelope(type_name: &str, data: Vec<u8>, is_native: bool) -> Vec<u8> {
+    let mut value = DbValue::new();
+    value.set_field_type(type_name.to_string());
+    value.set_data(data);
+    value.set_version(DB_VALUES_VERSION);
+    value.set_is_native(is_native);
+    value.write_to_bytes().expect("DbValue serializes")
+}
+
+/// `DbValue` of protobuf message `message`
+pub fn encode_message<M: StoredMessage>(message: &M) -> Vec<u8> {
+    envelope(M::type_name(), message.write_to_bytes().expect("stored messages serialize"), false)
+}
+
+/// `DbValue` of plain value `json`, `type` is the name JS gives to the type of the value
+pub fn encode_native(json: &Json) -> Vec<u8> {
+    let type_name = match *json {
+        Json::Array(_) => "Array",
+        Json::Object(_) => "Object",
+        Json::String(_) => "String",
+        Json::Boolean(_) => "Boolean",
+        Json::I64(_) | Json::U64(_) | Json::F64(_) => "Number",
+        Json::Null => "Null",
+    };
+    envelope(type_name, json.to_string().into_bytes(), true)
+}
+
+fn parse<M: StoredMessage>(value: &DbValue) -> Result<M, CodecError> {
+    protobuf::parse_from_bytes(value.get_data())
+        .map_err(|err| CodecError::InvalidData { type_name: M::type_name().to_string(), reason: err.to_string() })
+}
+
+fn parse_native(value: &DbValue) -> Result<Json, CodecError> {
+    let invalid = |reason: String| CodecError::InvalidData { type_name: value.get_field_type().to_string(), reason };
+    let json = str::from_utf8(value.get_data()).map_err(|err| invalid(err.to_string()))?;
+    Json::from_str(json).map_err(|err| invalid(err.to_string()))
+}
+
+/// Upgrades data of a type from one version to the next one
+pub type Migration = fn(&[u8]) -> Result<Vec<u8>, String>;
+
+/// Decodes stored values, upgrading them with the registered migrations
+#[derive(Debug, Clone, Default)]
+pub struct Codec {
+    migrations: HashMap<(String, i32), Migration>,
+}
+
+impl Codec {
+    pub fn new() -> Codec {
+        Codec::default()
+    }
+
+    /// Registers `migration` of `type_name` data from `version` to `version + 1`
+    pub fn register_migration(&mut self, type_name: &str, version: i32, migration: Migration) {
+        self.migrations.insert((type_name.to_string(), version), migration);
+    }
+
+    /// `DbValue` in `bytes` upgraded to `DB_VALUES_VERSION`
+    fn upgrade(&self, bytes: &[u8]) -> Result<DbValue, CodecError> {
+        let mut value: DbValue =
+            protobuf::parse_from_bytes(bytes).map_err(|err| CodecError::InvalidEnvelope(err.to_string()))?;
+        while value.get_version() != DB_VALUES_VERSION {
+            let version = value.get_version();
+            let type_name = value.get_field_type().to_string();
+            let migration = match self.migrations.get(&(type_name.clone(), version)) {
+                Some(migration) if version < DB_VALUES_VERSION => migration,
+                _ => return Err(CodecError::UnsupportedVersion { type_name, version }),
+            };
+            let data = migration(value.get_data()).map_err(|reason| CodecError::Migration { type_name, version, reason })?;
+            value.set_data(data);
+            value.set_version(version + 1);
+        }
+        Ok(value)
+    }
+
+    /// Value stored in `bytes`, `deserialize` in JS
+    pub fn decode(&self, bytes: &[u8]) -> Result<DbObject, CodecError> {
+        let value = self.upgrade(bytes)?;
+        if value.get_is_native() {
+            parse_native(&value).map(DbObject::Native)
+        } else {
+            decode_object(&value)
+        }
+    }
+
+    /// Protobuf message of type `M` stored in `bytes`
+    pub fn decode_message<M: StoredMessage>(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        let value = self.upgrade(bytes)?;
+        if value.get_is_native() || value.get_field_type() != M::type_name() {
+            return Err(CodecError::UnexpectedType {
+                expected: M::type_name().to_string(),
+                found: value.get_field_type().to_string(),
+            });
+        }
+        parse(&value)
+    }
+
+    /// Plain value stored in `bytes`
+    pub fn decode_native(&self, bytes: &[u8]) -> Result<Json, CodecError> {
+        let value = self.upgrade(bytes)?;
+        if !value.get_is_native() {
+            return Err(CodecError::UnexpectedType {
+                expected: "native value".to_string(),
+                found: value.get_field_type().to_string(),
+            });
+        }
+        parse_native(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_value(type_name: &str, data: &[u8], version: i32, is_native: bool) -> Vec<u8> {
+        let mut value = DbValue::new();
+        value.set_field_type(type_name.to_string());
+        value.set_data(data.to_vec());
+        value.set_version(version);
+        value.set_is_native(is_native);
+        value.write_to_bytes().unwrap()
+    }
+
+    #[test]
+    fn encode_decode_test() {
+        let codec = Codec::new();
+        let mut tx = Transaction::new();
+        tx.set_hash("t1".to_string());
+        let mut header = BlockchainHeader::new();
+        header.set_blockchain("btc".to_string());
+        let objects = vec![
+            DbObject::Transaction(tx.clone()),
+            DbObject::BlockchainHeader(header),
+            DbObject::BcBlock(BcBlock::new()),
+            DbObject::Native(Json::Array(vec![Json::String("t1".to_string())])),
+            DbObject::Native(Json::U64(12)),
+        ];
+        for object in objects {
+            assert_eq!(codec.decode(&encode(&object)), Ok(object));
+        }
+        assert_eq!(codec.decode_message::<Transaction>(&encode_message(&tx)), Ok(tx.clone()));
+        assert_eq!(
+            codec.decode_message::<BcBlock>(&encode_message(&tx)),
+            Err(CodecError::UnexpectedType { expected: "BcBlock".to_string(), found: "Transaction".to_string() })
+        );
+        assert!(codec.decode_native(&encode_message(&tx)).is_err());
+    }
+
+    #[test]
+    fn js_values_test() {
+        let codec = Codec::new();
+        let written = db_value("Array", br#"["b1","b2"]"#, 1, true);
+        let json = Json::Array(vec![Json::String("b1".to_string()), Json::String("b2".to_string())]);
+        assert_eq!(encode_native(&json), written);
+        assert_eq!(codec.decode_native(&written), Ok(json));
+        assert_eq!(codec.decode_native(&db_value("String", br#""bc.block.b1""#, 1, true)), Ok(Json::String("bc.block.b1".to_string())));
+
+        assert_eq!(
+            codec.decode(&db_value("InitialPeer", b"", 1, false)),
+            Err(CodecError::UnknownType("InitialPeer".to_string()))
+        );
+        assert!(codec.decode(b"\xff\xff").is_err());
+    }
+
+    #[test]
+    fn migration_test() {
+        fn append_hash(data: &[u8]) -> Result<Vec<u8>, String> {
+            let mut tx: Transaction = protobuf::parse_from_bytes(data).map_err(|err| err.to_string())?;
+            let hash = format!("{}-1", tx.get_hash());
+            tx.set_hash(hash);
+            tx.write_to_bytes().map_err(|err| err.to_string())
+        }
+        fn fail(_: &[u8]) -> Result<Vec<u8>, String> {
+            Err("corrupted".to_string())
+        }
+
+        let mut tx = Transaction::new();
+        tx.set_hash("t1".to_string());
+        let old = db_value("Transaction", &tx.write_to_bytes().unwrap(), 0, false);
+
+        let mut codec = Codec::new();
+        assert_eq!(
+            codec.decode(&old),
+            Err(CodecError::UnsupportedVersion { type_name: "Transaction".to_string(), version: 0 })
+        );
+        codec.register_migration("Transaction", 0, append_hash);
+        tx.set_hash("t1-1".to_string());
+        assert_eq!(codec.decode_message(&old), Ok(tx.clone()));
+
+        let newer = db_value("Transaction", &tx.write_to_bytes().unwrap(), DB_VALUES_VERSION + 1, false);
+        codec.register_migration("Transaction", DB_VALUES_VERSION + 1, append_hash);
+        assert_eq!(
+            codec.decode(&newer),
+            Err(CodecError::UnsupportedVersion { type_name: "Transaction".to_string(), version: DB_VALUES_VERSION + 1 })
+        );
+
+        codec.register_migration("Transaction", 0, fail);
+        assert_eq!(
+            codec.decode(&old),
+            Err(CodecError::Migration { type_name: "Transaction".to_string(), version: 0, reason: "corrupted".to_string() })
+        );
+    }
+}
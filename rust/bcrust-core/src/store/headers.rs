@@ -0,0 +1,338 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Headers of child chains rovered into BC blocks and the ranges missing from them
+//!
+//! Headers are stored by height under `<chain>.block.<height>`, the highest one also under
+//! `<chain>.block.latest`, as `putChildHeaders` in JS does.
+use std::collections::HashMap;
+
+use data::{BLOCKCHAINS, ROVER_RESYNC_PERIOD, ROVER_SECONDS_PER_BLOCK};
+use mining::primitives::blockchain_headers_to_lists;
+use protos::core::{BcBlock, Block, BlockchainHeader, BlockchainHeaders};
+use protos::rover::{RoverMessage, RoverMessageType, RoverMessage_Resync, RoverMessage_Resync_Interval};
+
+use super::codec::encode_message;
+use super::{Batch, KeyValueDb, Reader, Store, StoreError};
+
+/// Headers older than the latest stored one by more are not fetched, in milliseconds
+const TOO_OLD_LATEST_THRESHOLD: u64 = 10 * 60 * 1000;
+
+pub fn child_block_key(blockchain: &str, height: u64) -> String {
+    format!("{}.block.{}", blockchain, height)
+}
+
+pub fn child_latest_key(blockchain: &str) -> String {
+    format!("{}.block.latest", blockchain)
+}
+
+/// Stored headers of `blockchain` around a header which is not stored, `BlockBoundary` in JS
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockBoundary {
+    pub blockchain: String,
+    pub from: BlockchainHeader,
+    pub to: BlockchainHeader,
+}
+
+/// Ranges of consecutive heights in `heights` ordered from the highest, `toMissingIntervals` in JS
+pub fn to_missing_intervals(heights: &[u64]) -> Vec<RoverMessage_Resync_Interval> {
+    let mut intervals: Vec<RoverMessage_Resync_Interval> = vec![];
+    for &height in heights {
+        if let Some(interval) = intervals.last_mut() {
+            if interval.get_from_block() == height + 1 {
+                interval.set_from_block(height);
+                continue;
+            }
+        }
+        let mut interval = RoverMessage_Resync_Interval::new();
+        interval.set_from_block(height);
+        interval.set_to_block(height);
+        intervals.push(interval);
+    }
+    intervals
+}
+
+/// Lowest height of `blockchain` kept in sync below its `latest` header
+fn resync_lowest_height(blockchain: &str, latest: &BlockchainHeader) -> u64 {
+    let seconds_per_block = BLOCKCHAINS
+        .iter()
+        .position(|known| *known == blockchain)
+        .map_or(1, |index| ROVER_SECONDS_PER_BLOCK[index]);
+    latest.get_height().saturating_sub(ROVER_RESYNC_PERIOD / seconds_per_block)
+}
+
+fn header_to_block(header: &BlockchainHeader) -> Block {
+    let mut block = Block::new();
+    block.set_blockchain(header.get_blockchain().to_string());
+    block.set_hash(header.get_hash().to_string());
+    block.set_previous_hash(header.get_previous_hash().to_string());
+    block.set_timestamp(header.get_timestamp());
+    block.set_height(header.get_height());
+    block.set_merkle_root(header.get_merkle_root().to_string());
+    block.set_markedtx_count(header.get_marked_txs().len() as u64);
+    block.set_marked_txs(header.get_marked_txs().to_vec().into());
+    block
+}
+
+impl<'a, D: KeyValueDb> Batch<'a, D> {
+    /// Stores the child headers of `block`, see `Store::put_child_headers`
+    pub fn put_child_headers(&mut self, block: &BcBlock) -> Result<(), StoreError> {
+        let lists = blockchain_headers_to_lists(block.get_blockchain_headers());
+        for (blockchain, headers) in BLOCKCHAINS.iter().zip(lists.iter()) {
+            let mut headers = headers.to_vec();
+            headers.sort_by_key(|header| header.get_height());
+            for header in headers {
+                let key = child_latest_key(blockchain);
+                let is_latest = match self.get_message::<BlockchainHeader>(&key)? {
+                    Some(latest) => header.get_height() > latest.get_height(),
+                    None => true,
+                };
+                if is_latest {
+                    self.put_raw(key, encode_message(&header));
+                }
+                self.put_raw(child_block_key(blockchain, header.get_height()), encode_message(&header));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: KeyValueDb> Store<D> {
+    /// Stores the child headers of `block` and moves the latest ones, `putChildHeaders` in JS
+    pub fn put_child_headers(&self, block: &BcBlock) -> Result<(), StoreError> {
+        let mut batch = self.batch();
+        batch.put_child_headers(block)?;
+        batch.commit()
+    }
+
+    pub fn get_child_header(&self, blockchain: &str, height: u64) -> Result<Option<BlockchainHeader>, StoreError> {
+        self.get_message(&child_block_key(blockchain, height))
+    }
+
+    /// Highest stored header of `blockchain`
+    pub fn get_latest_child_header(&self, blockchain: &str) -> Result<Option<BlockchainHeader>, StoreError> {
+        self.get_message(&child_latest_key(blockchain))
+    }
+
+    /// Ranges of headers of `blockchain` missing in the resync period below the latest one
+    ///
+    /// Intervals are ordered from the highest, without a latest header nothing is missing yet.
+    pub fn missing_intervals(&self, blockchain: &str) -> Result<Vec<RoverMessage_Resync_Interval>, StoreError> {
+        let latest = match self.get_latest_child_header(blockchain)? {
+            Some(latest) => latest,
+            None => return Ok(vec![]),
+        };
+        let lowest = resync_lowest_height(blockchain, &latest);
+
+        let mut missing = vec![];
+        for height in (lowest..latest.get_height()).rev() {
+            if self.get_raw(&child_block_key(blockchain, height))?.is_none() {
+                missing.push(height);
+            }
+        }
+        Ok(to_missing_intervals(&missing))
+    }
+
+    /// `RequestResync` message asking the rover of `blockchain` for the missing headers,
+    /// see `getDecisivePeriodOfCrossChainBlocksStatus` in JS
+    pub fn resync_message(&self, blockchain: &str) -> Result<RoverMessage, StoreError> {
+        let mut resync = RoverMessage_Resync::new();
+        if let Some(latest) = self.get_latest_child_header(blockchain)? {
+            resync.set_latest_block(header_to_block(&latest));
+        }
+        resync.set_intervals(self.missing_intervals(blockchain)?.into());
+
+        let mut message = RoverMessage::new();
+        message.set_field_type(RoverMessageType::RequestResync);
+        message.set_resync(resync);
+        Ok(message)
+    }
+
+    /// First stored header of `blockchain` at `heights`
+    fn first_child_header<I: Iterator<Item = u64>>(&self, blockchain: &str, heights: I) -> Result<Option<BlockchainHeader>, StoreError> {
+        for height in heights {
+            if let Some(header) = self.get_child_header(blockchain, height)? {
+                return Ok(Some(header));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Ranges of child blocks to fetch for the headers of a new block, `getBlockBoundariesToFetch` in JS
+    ///
+    /// A header above the latest stored one is fetched from the latest, a header in a gap between
+    /// the stored ones from the closest lower to the closest higher header. Lower headers are looked
+    /// up in the resync period only, headers below it are not fetched. The boundaries of all
+    /// headers of a chain are merged to one range.
+    pub fn get_block_boundaries_to_fetch(&self, headers: &BlockchainHeaders) -> Result<Vec<BlockBoundary>, StoreError> {
+        let mut boundaries: HashMap<&str, (BlockchainHeader, BlockchainHeader)> = HashMap::new();
+        let lists = blockchain_headers_to_lists(headers);
+        for (blockchain, headers) in BLOCKCHAINS.iter().zip(lists.iter()) {
+            for header in headers.iter() {
+                let height = header.get_height();
+                if self.get_raw(&child_block_key(blockchain, height))?.is_some() {
+                    continue;
+                }
+                let latest = match self.get_latest_child_header(blockchain)? {
+                    Some(latest) => latest,
+                    None => continue,
+                };
+                if header.get_timestamp().saturating_sub(latest.get_timestamp()) > TOO_OLD_LATEST_THRESHOLD {
+                    continue;
+                }
+
+                let (from, to) = if latest.get_height() < height {
+                    (latest, header.clone())
+                } else {
+                    let lowest = resync_lowest_height(blockchain, &latest);
+                    if height < lowest {
+                        continue;
+                    }
+                    let lower = self.first_child_header(blockchain, (lowest..height).rev())?;
+                    // the latest header is stored, so `height` is below it
+                    let higher = self.first_child_header(blockchain, height + 1..=latest.get_height())?;
+                    match (lower, higher) {
+                        (Some(lower), Some(higher)) => (lower, higher),
+                        _ => continue,
+                    }
+                };
+
+                let boundary = boundaries.entry(blockchain).or_insert_with(|| (from.clone(), to.clone()));
+                if from.get_height() < boundary.0.get_height() {
+                    boundary.0 = from;
+                }
+                if to.get_height() > boundary.1.get_height() {
+                    boundary.1 = to;
+                }
+            }
+        }
+
+        Ok(BLOCKCHAINS
+            .iter()
+            .filter_map(|blockchain| {
+                boundaries.remove(blockchain).map(|(from, to)| BlockBoundary { blockchain: blockchain.to_string(), from, to })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use store::MemoryDb;
+
+    fn header(blockchain: &str, height: u64, timestamp: u64) -> BlockchainHeader {
+        let mut header = BlockchainHeader::new();
+        header.set_blockchain(blockchain.to_string());
+        header.set_hash(format!("{}{}", blockchain, height));
+        header.set_height(height);
+        header.set_timestamp(timestamp);
+        header
+    }
+
+    fn headers(btc: Vec<BlockchainHeader>, eth: Vec<BlockchainHeader>) -> BlockchainHeaders {
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(btc));
+        headers.set_eth(RepeatedField::from_vec(eth));
+        headers
+    }
+
+    fn block(btc: Vec<BlockchainHeader>, eth: Vec<BlockchainHeader>) -> BcBlock {
+        let mut block = BcBlock::new();
+        block.set_blockchain_headers(headers(btc, eth));
+        block
+    }
+
+    fn interval(from: u64, to: u64) -> RoverMessage_Resync_Interval {
+        let mut interval = RoverMessage_Resync_Interval::new();
+        interval.set_from_block(from);
+        interval.set_to_block(to);
+        interval
+    }
+
+    #[test]
+    fn to_missing_intervals_test() {
+        assert_eq!(to_missing_intervals(&[]), vec![]);
+        assert_eq!(to_missing_intervals(&[9, 8, 7, 5, 3, 2]), vec![interval(7, 9), interval(5, 5), interval(2, 3)]);
+    }
+
+    #[test]
+    fn put_child_headers_test() {
+        let store = Store::new(MemoryDb::new());
+        store.put_child_headers(&block(vec![header("btc", 12, 0), header("btc", 10, 0)], vec![header("eth", 5, 0)])).unwrap();
+        store.put_child_headers(&block(vec![header("btc", 11, 0)], vec![])).unwrap();
+
+        assert_eq!(store.get_latest_child_header("btc"), Ok(Some(header("btc", 12, 0))));
+        assert_eq!(store.get_latest_child_header("eth"), Ok(Some(header("eth", 5, 0))));
+        assert_eq!(store.get_latest_child_header("lsk"), Ok(None));
+        assert_eq!(store.get_child_header("btc", 11), Ok(Some(header("btc", 11, 0))));
+
+        // headers are stored along with BC blocks
+        let mut bc_block = block(vec![header("btc", 13, 0)], vec![]);
+        bc_block.set_hash("b2".to_string());
+        store.put_block(&bc_block, true).unwrap();
+        assert_eq!(store.get_latest_child_header("btc"), Ok(Some(header("btc", 13, 0))));
+    }
+
+    #[test]
+    fn resync_message_test() {
+        let store = Store::new(MemoryDb::new());
+        let message = store.resync_message("btc").unwrap();
+        assert_eq!(message.get_field_type(), RoverMessageType::RequestResync);
+        assert!(!message.get_resync().has_latest_block());
+        assert!(message.get_resync().get_intervals().is_empty());
+
+        // the btc resync period is 432 blocks
+        store.put_child_headers(&block(vec![header("btc", 1000, 0), header("btc", 995, 0), header("btc", 994, 0)], vec![])).unwrap();
+        let message = store.resync_message("btc").unwrap();
+        assert_eq!(message.get_resync().get_latest_block().get_hash(), "btc1000");
+        assert_eq!(message.get_resync().get_intervals(), &[interval(996, 999), interval(568, 993)][..]);
+    }
+
+    #[test]
+    fn get_block_boundaries_to_fetch_test() {
+        let store = Store::new(MemoryDb::new());
+        store.put_child_headers(&block(vec![header("btc", 10, 0), header("btc", 14, 1000)], vec![header("eth", 7, 0)])).unwrap();
+
+        // stored headers and chains without a latest header are not fetched
+        assert_eq!(store.get_block_boundaries_to_fetch(&headers(vec![header("btc", 14, 1000)], vec![])), Ok(vec![]));
+        let new = headers(vec![header("btc", 12, 2000), header("btc", 16, 2000)], vec![header("eth", 8, TOO_OLD_LATEST_THRESHOLD + 1)]);
+        assert_eq!(
+            store.get_block_boundaries_to_fetch(&new),
+            Ok(vec![BlockBoundary { blockchain: "btc".to_string(), from: header("btc", 10, 0), to: header("btc", 16, 2000) }])
+        );
+        let new = headers(vec![], vec![header("eth", 8, TOO_OLD_LATEST_THRESHOLD)]);
+        assert_eq!(
+            store.get_block_boundaries_to_fetch(&new),
+            Ok(vec![BlockBoundary {
+                blockchain: "eth".to_string(),
+                from: header("eth", 7, 0),
+                to: header("eth", 8, TOO_OLD_LATEST_THRESHOLD),
+            }])
+        );
+
+        // gaps are closed by headers in the btc resync period of 432 blocks below the latest one
+        store.put_child_headers(&block(vec![header("btc", 1000, 1000)], vec![])).unwrap();
+        let new = headers(vec![header("btc", 567, 1000)], vec![]);
+        assert_eq!(store.get_block_boundaries_to_fetch(&new), Ok(vec![]));
+        store.put_child_headers(&block(vec![header("btc", 568, 1000)], vec![])).unwrap();
+        let new = headers(vec![header("btc", 569, 1000)], vec![]);
+        assert_eq!(
+            store.get_block_boundaries_to_fetch(&new),
+            Ok(vec![BlockBoundary { blockchain: "btc".to_string(), from: header("btc", 568, 1000), to: header("btc", 1000, 1000) }])
+        );
+
+        // a gap below the last height
+        let max = u64::max_value();
+        store.put_child_headers(&block(vec![header("btc", max - 2, 1000), header("btc", max, 1000)], vec![])).unwrap();
+        let new = headers(vec![header("btc", max - 1, 1000)], vec![]);
+        assert_eq!(
+            store.get_block_boundaries_to_fetch(&new),
+            Ok(vec![BlockBoundary { blockchain: "btc".to_string(), from: header("btc", max - 2, 1000), to: header("btc", max, 1000) }])
+        );
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Balances of tokens on connected chains moved by marked transactions, `updateMarkedBalances` in JS
+//!
+//! Balances are stored as JS stores them, the JSON text of `{chain: {token: {address: balance}}}`
+//! as string under `bc.marked.balances` with the last block applied under `bc.marked.latest`.
+//! Every `MARKED_SNAPSHOT_INTERVAL` blocks both are copied to `.snapshot` keys, which JS restores
+//! when a block is removed. The store reverts a removed block by subtracting its transactions,
+//! and the blocks down to the fork point when a block replaces one of the main branch.
+//!
+//! Balances start at zero with the first block scanned, so an address sending tokens it received
+//! before can have a negative balance.
+use std::collections::BTreeMap;
+
+use num_bigint::{BigInt, Sign};
+use rustc_serialize::json::{Json, Object};
+
+use data::BLOCKCHAINS;
+use mining::primitives::blockchain_headers_to_lists;
+use protos::core::{BcBlock, MarkedTransaction};
+
+use super::codec::{encode_message, encode_native};
+use super::{block_at_height_key, block_key, invalid_value, Batch, KeyValueDb, Reader, Store, StoreError, BLOCKCHAIN};
+
+/// Blocks between snapshots of the balances
+pub const MARKED_SNAPSHOT_INTERVAL: u64 = 3000;
+
+pub fn marked_latest_key() -> String {
+    format!("{}.marked.latest", BLOCKCHAIN)
+}
+
+pub fn marked_balances_key() -> String {
+    format!("{}.marked.balances", BLOCKCHAIN)
+}
+
+fn snapshot_key(key: &str) -> String {
+    format!("{}.snapshot", key)
+}
+
+/// Balances of all addresses by connected chain and token
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MarkedBalances {
+    balances: BTreeMap<String, BTreeMap<String, BTreeMap<String, BigInt>>>,
+}
+
+impl MarkedBalances {
+    pub fn new() -> MarkedBalances {
+        MarkedBalances::default()
+    }
+
+    /// Balance of `address` in `token` of `connected_chain`, `getMarkedBalanceData` in JS
+    pub fn balance(&self, address: &str, token: &str, connected_chain: &str) -> BigInt {
+        self.balances
+            .get(connected_chain)
+            .and_then(|tokens| tokens.get(token))
+            .and_then(|addresses| addresses.get(address))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn add(&mut self, connected_chain: &str, tx: &MarkedTransaction, reverted: bool) {
+        let mut value = BigInt::from_bytes_be(Sign::Plus, tx.get_value());
+        if reverted {
+            value = -value;
+        }
+        let addresses = self
+            .balances
+            .entry(connected_chain.to_string())
+            .or_default()
+            .entry(tx.get_token().to_string())
+            .or_default();
+        *addresses.entry(tx.get_addr_from().to_string()).or_default() -= &value;
+        *addresses.entry(tx.get_addr_to().to_string()).or_default() += value;
+    }
+
+    fn add_block(&mut self, block: &BcBlock, reverted: bool) {
+        let lists = blockchain_headers_to_lists(block.get_blockchain_headers());
+        for (connected_chain, headers) in BLOCKCHAINS.iter().zip(lists.iter()) {
+            for tx in headers.iter().flat_map(|header| header.get_marked_txs()) {
+                self.add(connected_chain, tx, reverted);
+            }
+        }
+    }
+
+    /// Adds the transfers of the marked transactions in the child headers of `block`
+    pub fn apply_block(&mut self, block: &BcBlock) {
+        self.add_block(block, false);
+    }
+
+    /// Undoes `apply_block`
+    pub fn revert_block(&mut self, block: &BcBlock) {
+        self.add_block(block, true);
+    }
+
+    fn to_json(&self) -> Json {
+        let chains = self.balances.iter().map(|(chain, tokens)| {
+            let tokens = tokens.iter().map(|(token, addresses)| {
+                let addresses = addresses.iter().map(|(address, balance)| (address.clone(), Json::String(balance.to_string())));
+                (token.clone(), Json::Object(addresses.collect()))
+            });
+            (chain.clone(), Json::Object(tokens.collect()))
+        });
+        Json::Object(chains.collect())
+    }
+
+    fn from_json(json: &Json) -> Option<MarkedBalances> {
+        fn object(json: &Json) -> Option<&Object> {
+            json.as_object()
+        }
+
+        let mut balances = MarkedBalances::new();
+        for (chain, tokens) in object(json)? {
+            let chain_balances = balances.balances.entry(chain.clone()).or_default();
+            for (token, addresses) in object(tokens)? {
+                let token_balances = chain_balances.entry(token.clone()).or_default();
+                for (address, balance) in object(addresses)? {
+                    token_balances.insert(address.clone(), balance.as_string()?.parse().ok()?);
+                }
+            }
+        }
+        Some(balances)
+    }
+}
+
+trait MarkedReader: Reader {
+    fn get_marked_balances(&self) -> Result<MarkedBalances, StoreError> {
+        let key = marked_balances_key();
+        let text = match self.get_native(&key)? {
+            Some(Json::String(text)) => text,
+            Some(_) => return Err(invalid_value(&key, "expected JSON text")),
+            None => return Ok(MarkedBalances::new()),
+        };
+        Json::from_str(&text)
+            .ok()
+            .as_ref()
+            .and_then(MarkedBalances::from_json)
+            .ok_or_else(|| invalid_value(&key, "expected balances by chain, token and address"))
+    }
+}
+
+impl<T: Reader> MarkedReader for T {}
+
+impl<'a, D: KeyValueDb> Batch<'a, D> {
+    fn put_marked_balances(&mut self, balances: &MarkedBalances, latest: &BcBlock) {
+        let balances = encode_native(&Json::String(balances.to_json().to_string()));
+        let latest_block = encode_message(latest);
+        if latest.get_height().is_multiple_of(MARKED_SNAPSHOT_INTERVAL) {
+            self.put_raw(snapshot_key(&marked_balances_key()), balances.clone());
+            self.put_raw(snapshot_key(&marked_latest_key()), latest_block.clone());
+        }
+        self.put_raw(marked_balances_key(), balances);
+        self.put_raw(marked_latest_key(), latest_block);
+    }
+
+    /// Applies the marked transactions of `block` and of blocks of the main branch since the last
+    /// one applied, `updateMarkedBalances` in JS
+    ///
+    /// Blocks not above the last one applied are skipped.
+    pub fn update_marked_balances(&mut self, block: &BcBlock) -> Result<(), StoreError> {
+        let from = match self.get_message::<BcBlock>(&marked_latest_key())? {
+            Some(ref latest) if latest.get_height() >= block.get_height() => return Ok(()),
+            Some(latest) => latest.get_height() + 1,
+            None => 1,
+        };
+        let mut balances = self.get_marked_balances()?;
+        for height in from..block.get_height() {
+            if let Some(frame) = self.get_message::<BcBlock>(&block_at_height_key(height))? {
+                balances.apply_block(&frame);
+            }
+        }
+        balances.apply_block(block);
+        self.put_marked_balances(&balances, block);
+        Ok(())
+    }
+
+    /// Undoes `update_marked_balances` of `block` if it is the last block applied
+    ///
+    /// Blocks which have not been applied are ignored, a block of the main branch below the last
+    /// one applied can not be reverted. Without its parent stored the balances are removed, to be
+    /// scanned again from the first block.
+    pub fn revert_marked_balances(&mut self, block: &BcBlock) -> Result<(), StoreError> {
+        let latest = match self.get_message::<BcBlock>(&marked_latest_key())? {
+            Some(latest) => latest,
+            None => return Ok(()),
+        };
+        if latest.get_hash() != block.get_hash() {
+            let is_main = match self.get_message::<BcBlock>(&block_at_height_key(block.get_height()))? {
+                Some(at_height) => at_height.get_hash() == block.get_hash(),
+                None => false,
+            };
+            if is_main && latest.get_height() > block.get_height() {
+                return Err(StoreError::OutOfOrderRevert { height: block.get_height(), above: latest.get_height() });
+            }
+            return Ok(());
+        }
+        self.revert_latest_marked_balances(&latest)
+    }
+
+    /// Reverts the blocks applied at `height` and above, before a block of another branch replaces
+    /// the one of the main branch at `height`
+    pub fn revert_marked_balances_from(&mut self, height: u64) -> Result<(), StoreError> {
+        while let Some(latest) = self.get_message::<BcBlock>(&marked_latest_key())? {
+            if latest.get_height() < height {
+                break;
+            }
+            self.revert_latest_marked_balances(&latest)?;
+        }
+        Ok(())
+    }
+
+    fn revert_latest_marked_balances(&mut self, latest: &BcBlock) -> Result<(), StoreError> {
+        if latest.get_height().is_multiple_of(MARKED_SNAPSHOT_INTERVAL) {
+            self.delete(&snapshot_key(&marked_balances_key()));
+            self.delete(&snapshot_key(&marked_latest_key()));
+        }
+
+        match self.get_message::<BcBlock>(&block_key(latest.get_previous_hash()))? {
+            Some(parent) => {
+                let mut balances = self.get_marked_balances()?;
+                balances.revert_block(latest);
+                self.put_marked_balances(&balances, &parent);
+            }
+            None => {
+                self.delete(&marked_balances_key());
+                self.delete(&marked_latest_key());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: KeyValueDb> Store<D> {
+    /// Balances of all addresses, read them once to look up many balances
+    pub fn get_marked_balances(&self) -> Result<MarkedBalances, StoreError> {
+        MarkedReader::get_marked_balances(self)
+    }
+
+    /// Balance of `address` in `token` of `connected_chain`, `getMarkedBalanceData` in JS
+    pub fn get_marked_balance(&self, address: &str, token: &str, connected_chain: &str) -> Result<BigInt, StoreError> {
+        Ok(self.get_marked_balances()?.balance(address, token, connected_chain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use protos::core::{BlockchainHeader, BlockchainHeaders};
+    use store::MemoryDb;
+
+    const EMB: &str = "0x28b94f58b11ac945341329dbf2e5ef7f8bd44225";
+
+    fn marked(from: &str, to: &str, value: u64) -> MarkedTransaction {
+        let mut tx = MarkedTransaction::new();
+        tx.set_token(EMB.to_string());
+        tx.set_addr_from(from.to_string());
+        tx.set_addr_to(to.to_string());
+        tx.set_value(BigInt::from(value).to_bytes_be().1);
+        tx
+    }
+
+    fn block(hash: &str, previous_hash: &str, height: u64, txs: Vec<MarkedTransaction>) -> BcBlock {
+        let mut header = BlockchainHeader::new();
+        header.set_blockchain("eth".to_string());
+        header.set_marked_txs(RepeatedField::from_vec(txs));
+        let mut headers = BlockchainHeaders::new();
+        headers.set_eth(RepeatedField::from_vec(vec![header]));
+        let mut block = BcBlock::new();
+        block.set_hash(hash.to_string());
+        block.set_previous_hash(previous_hash.to_string());
+        block.set_height(height);
+        block.set_blockchain_headers(headers);
+        block
+    }
+
+    fn balance<D: KeyValueDb>(store: &Store<D>, address: &str) -> BigInt {
+        store.get_marked_balance(address, EMB, "eth").unwrap()
+    }
+
+    #[test]
+    fn update_marked_balances_test() {
+        let store = Store::new(MemoryDb::new());
+        assert_eq!(balance(&store, "a"), BigInt::from(0));
+
+        store.put_block(&block("b1", "b0", 1, vec![marked("x", "a", 50)]), true).unwrap();
+        store.put_block(&block("b2", "b1", 2, vec![marked("a", "b", 20), marked("a", "c", 5)]), true).unwrap();
+        // blocks of side branches are not applied, blocks already applied are skipped
+        store.put_block(&block("o2", "b1", 2, vec![marked("a", "b", 1000)]), false).unwrap();
+        store.put_block(&block("b2", "b1", 2, vec![marked("a", "b", 20), marked("a", "c", 5)]), true).unwrap();
+
+        let balances = store.get_marked_balances().unwrap();
+        assert_eq!(balances.balance("a", EMB, "eth"), BigInt::from(25));
+        assert_eq!(balances.balance("b", EMB, "eth"), BigInt::from(20));
+        assert_eq!(balances.balance("x", EMB, "eth"), BigInt::from(-50));
+        assert_eq!(balances.balance("a", EMB, "btc"), BigInt::from(0));
+        assert_eq!(balances.balance("a", "0x0", "eth"), BigInt::from(0));
+
+        // the JSON text JS writes
+        let json = store.get_native(&marked_balances_key()).unwrap().unwrap();
+        let expected = format!(r#"{{"eth":{{"{}":{{"a":"25","b":"20","c":"5","x":"-50"}}}}}}"#, EMB);
+        assert_eq!(json, Json::String(expected));
+    }
+
+    #[test]
+    fn reorg_test() {
+        let store = Store::new(MemoryDb::new());
+        store.put_block(&block("b1", "b0", 1, vec![marked("x", "a", 50)]), true).unwrap();
+        store.put_block(&block("b2", "b1", 2, vec![marked("a", "b", 20)]), true).unwrap();
+        store.put_block(&block("b3", "b2", 3, vec![marked("b", "c", 20)]), true).unwrap();
+
+        // blocks of the main branch are removed from the highest, other blocks are ignored
+        assert_eq!(store.del_block("b2"), Err(StoreError::OutOfOrderRevert { height: 2, above: 3 }));
+        assert_eq!(balance(&store, "c"), BigInt::from(20));
+        store.put_block(&block("o3", "b2", 3, vec![marked("a", "d", 5)]), false).unwrap();
+        store.del_block("o3").unwrap();
+        assert_eq!(balance(&store, "c"), BigInt::from(20));
+
+        let mut batch = store.batch();
+        batch.del_block("b3").unwrap();
+        batch.del_block("b2").unwrap();
+        batch.put_block(&block("c2", "b1", 2, vec![marked("a", "c", 30)]), true).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(balance(&store, "a"), BigInt::from(20));
+        assert_eq!(balance(&store, "b"), BigInt::from(0));
+        assert_eq!(balance(&store, "c"), BigInt::from(30));
+
+        // without the parent the balances are scanned again from the main branch
+        store.del_block("c2").unwrap();
+        store.del_block("b1").unwrap();
+        assert_eq!(store.get_raw(&marked_balances_key()), Ok(None));
+        store.put_block(&block("b1", "b0", 1, vec![marked("x", "a", 50)]), true).unwrap();
+        store.put_block(&block("c3", "c2", 3, vec![marked("a", "d", 1)]), true).unwrap();
+        assert_eq!(balance(&store, "a"), BigInt::from(49));
+    }
+
+    #[test]
+    fn replace_test() {
+        // reorg to a branch forking after height 1 without removing the blocks of the old one
+        let store = Store::new(MemoryDb::new());
+        store.put_block(&block("b1", "b0", 1, vec![marked("x", "a", 50)]), true).unwrap();
+        store.put_block(&block("b2", "b1", 2, vec![marked("a", "b", 20)]), true).unwrap();
+        store.put_block(&block("b3", "b2", 3, vec![marked("b", "c", 20)]), true).unwrap();
+
+        store.put_block(&block("c2", "b1", 2, vec![marked("a", "d", 30)]), true).unwrap();
+        let balances = store.get_marked_balances().unwrap();
+        assert_eq!(balances.balance("a", EMB, "eth"), BigInt::from(20));
+        assert_eq!(balances.balance("b", EMB, "eth"), BigInt::from(0));
+        assert_eq!(balances.balance("c", EMB, "eth"), BigInt::from(0));
+        assert_eq!(balances.balance("d", EMB, "eth"), BigInt::from(30));
+
+        store.put_block(&block("c3", "c2", 3, vec![marked("d", "c", 10)]), true).unwrap();
+        assert_eq!(balance(&store, "c"), BigInt::from(10));
+        assert_eq!(balance(&store, "d"), BigInt::from(20));
+        let latest: Option<BcBlock> = store.get_message(&marked_latest_key()).unwrap();
+        assert_eq!(latest.map(|latest| latest.get_hash().to_string()), Some("c3".to_string()));
+    }
+}
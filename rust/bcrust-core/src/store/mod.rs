@@ -0,0 +1,669 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Persistent block store, port of src/persistence/rocksdb.es6
+//!
+//! Keys and values are the ones of the JS node, so databases written by either can be opened by
+//! the other. Every value is wrapped in a `DbValue`, see `codec`.
+//!
+//! | key                          | value                                        |
+//! |------------------------------|----------------------------------------------|
+//! | `bc.block.<hash>`            | `BcBlock`                                    |
+//! | `bc.block.<height>`          | `BcBlock` of the main branch at the height   |
+//! | `bc.block.latest`            | `BcBlock` at the tip of the main branch      |
+//! | `bc.height.<height>`         | hashes of all known blocks at the height     |
+//! | `bc.txs.<block hash>`        | hashes of transactions of the block          |
+//! | `bc.tx.<tx hash>`            | `Transaction`                                |
+//! | `bc.txblock.<tx hash>`       | `bc.block.<block hash>` of the block         |
+//! | `bc.txblocks.<tx hash>`      | hashes of other blocks with the transaction  |
+//! | `<chain>.block.<height>`     | `BlockchainHeader` of a child chain          |
+//! | `<chain>.block.latest`       | highest `BlockchainHeader` of a child chain  |
+//! | `bc.nrg.granted`             | whole NRG minted so far, see `check`         |
+//! | `bc.marked.balances`         | JSON text of balances, see `marked`          |
+//! | `bc.marked.latest`           | last `BcBlock` applied to the balances       |
+//! | `bc.schedule.<height>`       | operations run at the height, see `schedule` |
+//! | `bc.schedule.<height>.undo`  | values the operations overwrote              |
+//! | `bc.schedule.latest`         | highest height with overwritten values       |
+//! | `bc.schedule.final`          | highest height whose values were dropped     |
+//! | `bc.settle.state.<entry>`    | entry of the settlement, see `settlement`    |
+//! | `bc.settle.latest`           | last block applied to the settlement         |
+//! | `bc.settle.undo.<hash>`      | entries the block changed in the settlement  |
+pub mod check;
+pub mod codec;
+pub mod headers;
+pub mod marked;
+pub mod rocks;
+pub mod schedule;
+pub mod settlement;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::RwLock;
+
+use rustc_serialize::json::Json;
+
+use protos::core::{BcBlock, Transaction};
+
+use self::codec::{encode_message, encode_native, Codec, StoredMessage};
+
+/// Blocks returned by a range query at most
+pub const MAX_BLOCK_RANGE: u64 = 5000;
+
+/// Chain of blocks stored by the store
+const BLOCKCHAIN: &str = "bc";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreError {
+    Db(String),
+    InvalidValue { key: String, reason: String },
+    MissingKey(String),
+    InvalidRange { from: u64, to: u64 },
+    /// Block at `height` is reverted before the block at the higher height `above`
+    OutOfOrderRevert { height: u64, above: u64 },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Db(ref err) => write!(f, "database error: {}", err),
+            StoreError::InvalidValue { ref key, ref reason } => write!(f, "invalid value of {}: {}", key, reason),
+            StoreError::MissingKey(ref key) => write!(f, "{} is referenced but not stored", key),
+            StoreError::InvalidRange { from, to } => write!(f, "from {} cannot be greater than to {}", from, to),
+            StoreError::OutOfOrderRevert { height, above } =>
+                write!(f, "block at height {} cannot be reverted before the block at height {}", height, above),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn description(&self) -> &str {
+        "store error"
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
+/// Embedded key value database the store is backed by
+pub trait KeyValueDb {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError>;
+
+    /// Applies all `ops` or none of them
+    fn write(&self, ops: Vec<WriteOp>) -> Result<(), StoreError>;
+
+    /// Visits all keys with their values in key order
+    fn scan(&self, visit: &mut dyn FnMut(&str, &[u8])) -> Result<(), StoreError>;
+}
+
+/// Database kept in memory, for tests and tools working on a copy of the data
+#[derive(Debug, Default)]
+pub struct MemoryDb {
+    values: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryDb {
+    pub fn new() -> MemoryDb {
+        MemoryDb::default()
+    }
+}
+
+impl KeyValueDb for MemoryDb {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.values.read().expect("memory db lock is not poisoned").get(key).cloned())
+    }
+
+    fn write(&self, ops: Vec<WriteOp>) -> Result<(), StoreError> {
+        let mut values = self.values.write().expect("memory db lock is not poisoned");
+        for op in ops {
+            match op {
+                WriteOp::Put(key, value) => {
+                    values.insert(key, value);
+                }
+                WriteOp::Delete(key) => {
+                    values.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(&str, &[u8])) -> Result<(), StoreError> {
+        for (key, value) in self.values.read().expect("memory db lock is not poisoned").iter() {
+            visit(key, value);
+        }
+        Ok(())
+    }
+}
+
+pub fn block_key(hash: &str) -> String {
+    format!("{}.block.{}", BLOCKCHAIN, hash)
+}
+
+pub fn block_at_height_key(height: u64) -> String {
+    format!("{}.block.{}", BLOCKCHAIN, height)
+}
+
+pub fn latest_block_key() -> String {
+    format!("{}.block.latest", BLOCKCHAIN)
+}
+
+pub fn height_key(height: u64) -> String {
+    format!("{}.height.{}", BLOCKCHAIN, height)
+}
+
+pub fn block_txs_key(block_hash: &str) -> String {
+    format!("{}.txs.{}", BLOCKCHAIN, block_hash)
+}
+
+pub fn tx_key(tx_hash: &str) -> String {
+    format!("{}.tx.{}", BLOCKCHAIN, tx_hash)
+}
+
+pub fn tx_block_key(tx_hash: &str) -> String {
+    format!("{}.txblock.{}", BLOCKCHAIN, tx_hash)
+}
+
+/// Blocks of other branches with the transaction, JS keeps only the last one in `tx_block_key`
+pub fn tx_other_blocks_key(tx_hash: &str) -> String {
+    format!("{}.txblocks.{}", BLOCKCHAIN, tx_hash)
+}
+
+fn invalid_value<E: fmt::Display>(key: &str, err: E) -> StoreError {
+    StoreError::InvalidValue { key: key.to_string(), reason: err.to_string() }
+}
+
+fn strings_to_json(strings: &[String]) -> Json {
+    Json::Array(strings.iter().map(|string| Json::String(string.clone())).collect())
+}
+
+fn json_to_strings(key: &str, json: &Json) -> Result<Vec<String>, StoreError> {
+    let items = json.as_array().ok_or_else(|| invalid_value(key, "expected an array"))?;
+    items
+        .iter()
+        .map(|item| item.as_string().map(|item| item.to_string()).ok_or_else(|| invalid_value(key, "expected a string")))
+        .collect()
+}
+
+/// Reads of stored values, shared by the store and its batches
+trait Reader {
+    fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError>;
+
+    fn codec(&self) -> &Codec;
+
+    fn get_message<M: StoredMessage>(&self, key: &str) -> Result<Option<M>, StoreError> {
+        match self.get_raw(key)? {
+            Some(bytes) => self.codec().decode_message(&bytes).map(Some).map_err(|err| invalid_value(key, err)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_native(&self, key: &str) -> Result<Option<Json>, StoreError> {
+        match self.get_raw(key)? {
+            Some(bytes) => self.codec().decode_native(&bytes).map(Some).map_err(|err| invalid_value(key, err)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_strings(&self, key: &str) -> Result<Option<Vec<String>>, StoreError> {
+        match self.get_native(key)? {
+            Some(json) => json_to_strings(key, &json).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Block collider blocks and transactions over a key value database
+#[derive(Debug)]
+pub struct Store<D> {
+    db: D,
+    codec: Codec,
+}
+
+impl<D: KeyValueDb> Reader for Store<D> {
+    fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        self.db.get(key)
+    }
+
+    fn codec(&self) -> &Codec {
+        &self.codec
+    }
+}
+
+impl<D: KeyValueDb> Store<D> {
+    pub fn new(db: D) -> Store<D> {
+        Store::with_codec(db, Codec::new())
+    }
+
+    /// Store reading values with `codec`, which upgrades values of older versions
+    pub fn with_codec(db: D, codec: Codec) -> Store<D> {
+        Store { db, codec }
+    }
+
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Batch of writes applied atomically by `Batch::commit`
+    pub fn batch(&self) -> Batch<'_, D> {
+        Batch { store: self, changes: BTreeMap::new() }
+    }
+
+    /// Stores `block` with its transactions and indices, `putBlock` in JS
+    ///
+    /// The block is indexed at its height as part of the main branch only with `main_branch`.
+    pub fn put_block(&self, block: &BcBlock, main_branch: bool) -> Result<(), StoreError> {
+        let mut batch = self.batch();
+        batch.put_block(block, main_branch)?;
+        batch.commit()
+    }
+
+    /// Removes block `hash` with its transactions and indices, `delBlock` in JS
+    pub fn del_block(&self, hash: &str) -> Result<bool, StoreError> {
+        let mut batch = self.batch();
+        let deleted = batch.del_block(hash)?;
+        batch.commit()?;
+        Ok(deleted)
+    }
+
+    /// Block with transactions restored from the transaction index
+    fn with_txs(&self, block: Option<BcBlock>) -> Result<Option<BcBlock>, StoreError> {
+        let mut block = match block {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let key = block_txs_key(block.get_hash());
+        let hashes = self.get_strings(&key)?.ok_or(StoreError::MissingKey(key))?;
+        let mut txs = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let key = tx_key(&hash);
+            txs.push(self.get_message(&key)?.ok_or(StoreError::MissingKey(key))?);
+        }
+        block.set_txs(txs.into());
+        Ok(Some(block))
+    }
+
+    /// Block `hash` of any branch, `getBlockByHash` in JS
+    pub fn get_block_by_hash(&self, hash: &str) -> Result<Option<BcBlock>, StoreError> {
+        let block = self.get_message(&block_key(hash))?;
+        self.with_txs(block)
+    }
+
+    /// Block of the main branch at `height`, `getBlockByHeight` in JS
+    pub fn get_block_by_height(&self, height: u64) -> Result<Option<BcBlock>, StoreError> {
+        let block = self.get_message(&block_at_height_key(height))?;
+        self.with_txs(block)
+    }
+
+    /// Tip of the main branch
+    pub fn get_latest_block(&self) -> Result<Option<BcBlock>, StoreError> {
+        let block = self.get_message(&latest_block_key())?;
+        self.with_txs(block)
+    }
+
+    /// Hashes of the blocks of all branches at `height`
+    pub fn get_block_hashes_at_height(&self, height: u64) -> Result<Vec<String>, StoreError> {
+        Ok(self.get_strings(&height_key(height))?.unwrap_or_default())
+    }
+
+    /// Blocks of all branches at `height`, `getBlocksByHeight` in JS
+    pub fn get_blocks_by_height(&self, height: u64) -> Result<Vec<BcBlock>, StoreError> {
+        let mut blocks = vec![];
+        for hash in self.get_block_hashes_at_height(height)? {
+            let block = self.get_block_by_hash(&hash)?.ok_or_else(|| StoreError::MissingKey(block_key(&hash)))?;
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    /// Blocks of all branches from height `from` up to `to` excluded, `getBlocksByRange` in JS
+    ///
+    /// The range is limited to `MAX_BLOCK_RANGE` heights ending at `to`.
+    pub fn get_blocks_by_range(&self, from: u64, to: u64) -> Result<Vec<BcBlock>, StoreError> {
+        if from > to {
+            return Err(StoreError::InvalidRange { from, to });
+        }
+        let from = if to - from > MAX_BLOCK_RANGE { ::std::cmp::max(to - MAX_BLOCK_RANGE, 2) } else { from };
+
+        let mut blocks = vec![];
+        for height in from..to {
+            blocks.extend(self.get_blocks_by_height(height)?);
+        }
+        Ok(blocks)
+    }
+
+    /// Transaction `hash` of a stored block, `getTransactionByHash` in JS
+    pub fn get_transaction_by_hash(&self, hash: &str) -> Result<Option<Transaction>, StoreError> {
+        self.get_message(&tx_key(hash))
+    }
+
+    /// Hash of the block transaction `tx_hash` was stored with
+    pub fn get_block_hash_of_tx(&self, tx_hash: &str) -> Result<Option<String>, StoreError> {
+        let key = tx_block_key(tx_hash);
+        let value = match self.get_native(&key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let prefix = block_key("");
+        match value.as_string() {
+            Some(value) if value.starts_with(&prefix) => Ok(Some(value[prefix.len()..].to_string())),
+            _ => Err(invalid_value(&key, "expected a block key")),
+        }
+    }
+}
+
+/// Writes applied to the database at once, reads see the writes of the batch
+#[derive(Debug)]
+pub struct Batch<'a, D: 'a> {
+    store: &'a Store<D>,
+    changes: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+impl<'a, D: KeyValueDb> Reader for Batch<'a, D> {
+    fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        match self.changes.get(key) {
+            Some(change) => Ok(change.clone()),
+            None => self.store.db.get(key),
+        }
+    }
+
+    fn codec(&self) -> &Codec {
+        &self.store.codec
+    }
+}
+
+impl<'a, D: KeyValueDb> Batch<'a, D> {
+    fn put_raw(&mut self, key: String, value: Vec<u8>) {
+        self.changes.insert(key, Some(value));
+    }
+
+    fn put_strings(&mut self, key: String, strings: &[String]) {
+        self.put_raw(key, encode_native(&strings_to_json(strings)));
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.changes.insert(key.to_string(), None);
+    }
+
+    /// Stores `block` with its transactions and indices, see `Store::put_block`
+    ///
+    /// Child headers of the block are stored as well. For a block of the main branch operations
+    /// scheduled at its height are run and its marked transactions are applied to the balances.
+    /// Operations and marked transactions of a block of the main branch it replaces and of the
+    /// blocks above are reverted first.
+    /// Outpoint claims JS updates along are not written.
+    pub fn put_block(&mut self, block: &BcBlock, main_branch: bool) -> Result<(), StoreError> {
+        let hash = block.get_hash();
+        self.put_raw(block_key(hash), encode_message(block));
+        if main_branch {
+            let at_height_key = block_at_height_key(block.get_height());
+            if let Some(displaced) = self.get_message::<BcBlock>(&at_height_key)? {
+                if displaced.get_hash() != hash {
+                    self.revert_marked_balances_from(block.get_height())?;
+                    self.revert_scheduled_operations_from(block.get_height())?;
+                }
+            }
+            self.put_raw(block_at_height_key(block.get_height()), encode_message(block));
+            self.run_scheduled_operations(block.get_height())?;
+            self.update_marked_balances(block)?;
+        }
+
+        let key = height_key(block.get_height());
+        let mut hashes = self.get_strings(&key)?.unwrap_or_default();
+        if !hashes.iter().any(|known| known == hash) {
+            hashes.push(hash.to_string());
+            self.put_strings(key, &hashes);
+        }
+
+        let tx_hashes: Vec<String> = block.get_txs().iter().map(|tx| tx.get_hash().to_string()).collect();
+        self.put_strings(block_txs_key(hash), &tx_hashes);
+        for tx in block.get_txs() {
+            self.put_raw(tx_key(tx.get_hash()), encode_message(tx));
+            self.index_tx_block(tx.get_hash(), hash)?;
+        }
+        self.put_child_headers(block)
+    }
+
+    /// Points `bc.txblock.<tx hash>` at block `hash`, a stored block it pointed at before is kept
+    /// under `bc.txblocks.<tx hash>`
+    fn index_tx_block(&mut self, tx_hash: &str, hash: &str) -> Result<(), StoreError> {
+        let key = tx_other_blocks_key(tx_hash);
+        let mut others = self.get_strings(&key)?.unwrap_or_default();
+        let previous = match self.get_native(&tx_block_key(tx_hash))? {
+            Some(Json::String(previous)) => previous.strip_prefix(&block_key("")).map(|previous| previous.to_string()),
+            _ => None,
+        };
+        if let Some(previous) = previous {
+            if previous != hash && !others.contains(&previous) && self.get_raw(&block_key(&previous))?.is_some() {
+                others.push(previous);
+            }
+        }
+        others.retain(|other| other != hash);
+        if others.is_empty() {
+            self.delete(&key);
+        } else {
+            self.put_strings(key, &others);
+        }
+        self.put_raw(tx_block_key(tx_hash), encode_native(&Json::String(block_key(hash))));
+        Ok(())
+    }
+
+    /// Removes block `hash` from the blocks of transaction `tx_hash`
+    ///
+    /// The transaction is removed with the last stored block listing it, before that
+    /// `bc.txblock.<tx hash>` is pointed at another block listing it.
+    fn unindex_tx_block(&mut self, tx_hash: &str, hash: &str) -> Result<(), StoreError> {
+        let key = tx_other_blocks_key(tx_hash);
+        let mut others = self.get_strings(&key)?.unwrap_or_default();
+        others.retain(|other| other != hash);
+        let indexed = match self.get_native(&tx_block_key(tx_hash))? {
+            Some(Json::String(indexed)) => indexed == block_key(hash),
+            _ => false,
+        };
+        if indexed {
+            match others.pop() {
+                Some(other) => self.put_raw(tx_block_key(tx_hash), encode_native(&Json::String(block_key(&other)))),
+                None => {
+                    self.delete(&tx_key(tx_hash));
+                    self.delete(&tx_block_key(tx_hash));
+                }
+            }
+        }
+        if others.is_empty() {
+            self.delete(&key);
+        } else {
+            self.put_strings(key, &others);
+        }
+        Ok(())
+    }
+
+    /// Removes block `hash` with its transactions and indices, see `Store::del_block`
+    ///
+    /// Writes of operations scheduled at the height of a block of the main branch are undone, as
+    /// are its marked transactions if it is the last block applied to the balances. Blocks of the
+    /// main branch are removed from the highest, removing one below a block whose operations ran
+    /// fails. Transactions listed by other stored blocks are kept, JS removes them. Returns false
+    /// if the block is not stored.
+    pub fn del_block(&mut self, hash: &str) -> Result<bool, StoreError> {
+        let key = block_key(hash);
+        let block: BcBlock = match self.get_message(&key)? {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        self.delete(&key);
+        self.revert_marked_balances(&block)?;
+
+        let at_height_key = block_at_height_key(block.get_height());
+        if let Some(at_height) = self.get_message::<BcBlock>(&at_height_key)? {
+            if at_height.get_hash() == hash {
+                self.delete(&at_height_key);
+                self.revert_scheduled_operations(block.get_height())?;
+            }
+        }
+
+        let key = height_key(block.get_height());
+        let mut hashes = self.get_strings(&key)?.unwrap_or_default();
+        hashes.retain(|known| known != hash);
+        if hashes.is_empty() {
+            self.delete(&key);
+        } else {
+            self.put_strings(key, &hashes);
+        }
+
+        let key = block_txs_key(hash);
+        for tx_hash in self.get_strings(&key)?.unwrap_or_default() {
+            self.unindex_tx_block(&tx_hash, hash)?;
+        }
+        self.delete(&key);
+        Ok(true)
+    }
+
+    /// Marks `block` as the tip of the main branch
+    pub fn put_latest_block(&mut self, block: &BcBlock) {
+        self.put_raw(latest_block_key(), encode_message(block));
+    }
+
+    /// Changes of the batch as writes to the database
+    pub fn write_ops(&self) -> Vec<WriteOp> {
+        self.changes
+            .iter()
+            .map(|(key, value)| match *value {
+                Some(ref value) => WriteOp::Put(key.clone(), value.clone()),
+                None => WriteOp::Delete(key.clone()),
+            })
+            .collect()
+    }
+
+    /// Writes all changes of the batch at once
+    pub fn commit(self) -> Result<(), StoreError> {
+        self.store.db.write(self.write_ops())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::{Message, RepeatedField};
+
+    use protos::db::DbValue;
+
+    fn tx(hash: &str) -> Transaction {
+        let mut tx = Transaction::new();
+        tx.set_hash(hash.to_string());
+        tx
+    }
+
+    fn block(hash: &str, height: u64, txs: Vec<Transaction>) -> BcBlock {
+        let mut block = BcBlock::new();
+        block.set_hash(hash.to_string());
+        block.set_height(height);
+        block.set_txs(RepeatedField::from_vec(txs));
+        block
+    }
+
+    #[test]
+    fn put_block_test() {
+        let store = Store::new(MemoryDb::new());
+        let b2 = block("b2", 2, vec![tx("t1"), tx("t2")]);
+        let orphan = block("o2", 2, vec![tx("t3")]);
+        store.put_block(&b2, true).unwrap();
+        store.put_block(&orphan, false).unwrap();
+
+        assert_eq!(store.get_block_by_hash("b2"), Ok(Some(b2.clone())));
+        assert_eq!(store.get_block_by_hash("o2"), Ok(Some(orphan.clone())));
+        assert_eq!(store.get_block_by_height(2), Ok(Some(b2.clone())));
+        assert_eq!(store.get_block_by_height(3), Ok(None));
+        assert_eq!(store.get_block_hashes_at_height(2), Ok(vec!["b2".to_string(), "o2".to_string()]));
+        assert_eq!(store.get_blocks_by_height(2), Ok(vec![b2.clone(), orphan.clone()]));
+        assert_eq!(store.get_transaction_by_hash("t2"), Ok(Some(tx("t2"))));
+        assert_eq!(store.get_block_hash_of_tx("t3"), Ok(Some("o2".to_string())));
+
+        let b3 = block("b3", 3, vec![]);
+        store.put_block(&b3, true).unwrap();
+        assert_eq!(store.get_blocks_by_range(2, 4), Ok(vec![b2.clone(), orphan.clone(), b3]));
+        assert_eq!(store.get_blocks_by_range(3, 3), Ok(vec![]));
+        assert_eq!(store.get_blocks_by_range(4, 3), Err(StoreError::InvalidRange { from: 4, to: 3 }));
+
+        assert_eq!(store.del_block("o2"), Ok(true));
+        assert_eq!(store.del_block("o2"), Ok(false));
+        assert_eq!(store.get_block_by_hash("o2"), Ok(None));
+        assert_eq!(store.get_transaction_by_hash("t3"), Ok(None));
+        assert_eq!(store.get_block_hash_of_tx("t3"), Ok(None));
+        assert_eq!(store.get_block_hashes_at_height(2), Ok(vec!["b2".to_string()]));
+        assert_eq!(store.get_block_by_height(2), Ok(Some(b2)));
+    }
+
+    #[test]
+    fn fork_test() {
+        // a transaction mined on both branches of a fork
+        let store = Store::new(MemoryDb::new());
+        store.put_block(&block("b2", 2, vec![tx("t1"), tx("t2")]), true).unwrap();
+        store.put_block(&block("o2", 2, vec![tx("t1")]), false).unwrap();
+        store.put_block(&block("o3", 3, vec![tx("t1")]), false).unwrap();
+        assert_eq!(store.get_block_hash_of_tx("t1"), Ok(Some("o3".to_string())));
+        assert_eq!(store.get_strings(&tx_other_blocks_key("t1")), Ok(Some(vec!["b2".to_string(), "o2".to_string()])));
+        assert_eq!(store.get_raw(&tx_other_blocks_key("t2")), Ok(None));
+
+        // the transaction stays with the blocks still listing it
+        store.del_block("o3").unwrap();
+        assert_eq!(store.get_block_hash_of_tx("t1"), Ok(Some("o2".to_string())));
+        store.del_block("b2").unwrap();
+        assert_eq!(store.get_block_hash_of_tx("t1"), Ok(Some("o2".to_string())));
+        assert_eq!(store.get_transaction_by_hash("t1"), Ok(Some(tx("t1"))));
+        assert_eq!(store.get_transaction_by_hash("t2"), Ok(None));
+        assert_eq!(store.get_raw(&tx_other_blocks_key("t1")), Ok(None));
+
+        store.del_block("o2").unwrap();
+        assert_eq!(store.get_transaction_by_hash("t1"), Ok(None));
+        assert_eq!(store.get_block_hash_of_tx("t1"), Ok(None));
+    }
+
+    #[test]
+    fn batch_test() {
+        let store = Store::new(MemoryDb::new());
+        let b2 = block("b2", 2, vec![tx("t1")]);
+        let mut batch = store.batch();
+        batch.put_block(&b2, true).unwrap();
+        batch.put_block(&block("o2", 2, vec![]), false).unwrap();
+        batch.put_latest_block(&b2);
+        assert_eq!(store.get_latest_block(), Ok(None));
+        batch.commit().unwrap();
+
+        assert_eq!(store.get_latest_block(), Ok(Some(b2)));
+        assert_eq!(store.get_block_hashes_at_height(2), Ok(vec!["b2".to_string(), "o2".to_string()]));
+    }
+
+    #[test]
+    fn js_values_test() {
+        // values written by the JS codec
+        let store = Store::new(MemoryDb::new());
+        let mut hashes = DbValue::new();
+        hashes.set_field_type("Array".to_string());
+        hashes.set_data(br#"["b2"]"#.to_vec());
+        hashes.set_version(1);
+        hashes.set_is_native(true);
+        let mut block = DbValue::new();
+        block.set_field_type("BcBlock".to_string());
+        block.set_data(self::block("b2", 2, vec![]).write_to_bytes().unwrap());
+        block.set_version(1);
+        store
+            .db()
+            .write(vec![
+                WriteOp::Put("bc.height.2".to_string(), hashes.write_to_bytes().unwrap()),
+                WriteOp::Put("bc.block.b2".to_string(), block.write_to_bytes().unwrap()),
+                WriteOp::Put("bc.txs.b2".to_string(), encode_native(&Json::Array(vec![]))),
+            ])
+            .unwrap();
+
+        assert_eq!(store.get_blocks_by_height(2), Ok(vec![self::block("b2", 2, vec![])]));
+        assert_eq!(encode_native(&Json::Array(vec![Json::String("b2".to_string())])), hashes.write_to_bytes().unwrap());
+
+        store.db().write(vec![WriteOp::Put("bc.tx.t1".to_string(), encode_native(&Json::Boolean(true)))]).unwrap();
+        assert!(store.get_transaction_by_hash("t1").is_err());
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! RocksDB backend, the database engine of the JS node
+use std::path::Path;
+
+use rocksdb::{Options, WriteBatch, DB};
+
+use super::{KeyValueDb, StoreError, WriteOp};
+
+pub struct RocksDb {
+    db: DB,
+}
+
+impl RocksDb {
+    /// Opens the database at `path`, the data directory of the JS node, fails if there is none
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RocksDb, StoreError> {
+        let mut options = Options::default();
+        options.create_if_missing(false);
+        DB::open(&options, path).map(|db| RocksDb { db }).map_err(|err| StoreError::Db(err.to_string()))
+    }
+
+    /// Opens the database at `path` without taking its lock, writes fail
+    ///
+    /// A node running on the database is not disturbed, but writes it has not flushed are not seen.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<RocksDb, StoreError> {
+        let mut options = Options::default();
+        options.create_if_missing(false);
+        DB::open_for_read_only(&options, path, false).map(|db| RocksDb { db }).map_err(|err| StoreError::Db(err.to_string()))
+    }
+}
+
+impl KeyValueDb for RocksDb {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        self.db.get(key).map_err(|err| StoreError::Db(err.to_string()))
+    }
+
+    fn write(&self, ops: Vec<WriteOp>) -> Result<(), StoreError> {
+        let mut batch = WriteBatch::default();
+        for op in ops {
+            match op {
+                WriteOp::Put(key, value) => batch.put(key, value),
+                WriteOp::Delete(key) => batch.delete(key),
+            }
+        }
+        self.db.write(batch).map_err(|err| StoreError::Db(err.to_string()))
+    }
+
+    /// Keys the JS node writes are text, others are visited with invalid characters replaced
+    ///
+    /// Fails if the iterator stops at a read error, rather than at the last key.
+    fn scan(&self, visit: &mut dyn FnMut(&str, &[u8])) -> Result<(), StoreError> {
+        let mut iterator = self.db.raw_iterator();
+        iterator.seek_to_first();
+        while iterator.valid() {
+            if let (Some(key), Some(value)) = (iterator.key(), iterator.value()) {
+                visit(&String::from_utf8_lossy(key), value);
+            }
+            iterator.next();
+        }
+        iterator.status().map_err(|err| StoreError::Db(err.to_string()))
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Writes delayed until a block height, `scheduleAtBlockHeight` and `runScheduledOperations` in JS
+//!
+//! Operations are stored under `bc.schedule.<height>` as the JS node stores them, a list of
+//! `[height, operation, key, value, blockchain]` with the value left out if there is none. They
+//! run in the batch which stores the block of the main branch at the height. The values they
+//! overwrite are kept under `bc.schedule.<height>.undo` until the block is removed or replaced
+//! again, the highest height with these values under `bc.schedule.latest`. Heights are reverted
+//! from the highest, so a block can only be removed from the main branch at its tip.
+//!
+//! Heights without operations get no undo record. Operations `SCHEDULE_FINALITY_DEPTH` blocks
+//! below the last run height are final: their undo records are dropped, the highest final height
+//! is kept under `bc.schedule.final` and operations at final heights neither run again nor get
+//! reverted when the main branch is replaced below them.
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json::Json;
+
+use super::codec::encode_native;
+use super::{invalid_value, Batch, KeyValueDb, Reader, Store, StoreError, BLOCKCHAIN};
+
+pub fn schedule_key(height: u64) -> String {
+    format!("{}.schedule.{}", BLOCKCHAIN, height)
+}
+
+pub fn schedule_undo_key(height: u64) -> String {
+    format!("{}.schedule.{}.undo", BLOCKCHAIN, height)
+}
+
+pub fn schedule_latest_key() -> String {
+    format!("{}.schedule.latest", BLOCKCHAIN)
+}
+
+pub fn schedule_final_key() -> String {
+    format!("{}.schedule.final", BLOCKCHAIN)
+}
+
+/// Blocks below the last run height after which scheduled operations are not reverted anymore
+pub const SCHEDULE_FINALITY_DEPTH: u64 = 1000;
+
+/// Operation on a key, `SupportedScheduledOperations` in JS
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduledOperation {
+    /// Reads the key, does nothing
+    Get(String),
+    Put(String, Json),
+    Del(String),
+    /// Removes the value from the list stored at the key, the key is removed with the last value
+    DelFromList(String, Json),
+}
+
+impl ScheduledOperation {
+    pub fn key(&self) -> &str {
+        match *self {
+            ScheduledOperation::Get(ref key) |
+            ScheduledOperation::Put(ref key, _) |
+            ScheduledOperation::Del(ref key) |
+            ScheduledOperation::DelFromList(ref key, _) => key,
+        }
+    }
+
+    fn to_json(&self, height: u64) -> Json {
+        let (name, value) = match *self {
+            ScheduledOperation::Get(_) => ("get", None),
+            ScheduledOperation::Put(_, ref value) => ("put", Some(value)),
+            ScheduledOperation::Del(_) => ("del", None),
+            ScheduledOperation::DelFromList(_, ref value) => ("delfromlist", Some(value)),
+        };
+        let mut json = vec![Json::U64(height), Json::String(name.to_string()), Json::String(self.key().to_string())];
+        json.extend(value.cloned());
+        json.push(Json::String(BLOCKCHAIN.to_string()));
+        Json::Array(json)
+    }
+
+    fn from_json(json: &Json) -> Option<ScheduledOperation> {
+        let args = json.as_array()?;
+        let (name, key) = match (args.get(1).and_then(Json::as_string), args.get(2).and_then(Json::as_string)) {
+            (Some(name), Some(key)) => (name, key.to_string()),
+            _ => return None,
+        };
+        let value = if args.len() == 5 { Some(args[3].clone()) } else { None };
+        match (name, value) {
+            ("get", _) => Some(ScheduledOperation::Get(key)),
+            ("put", Some(value)) => Some(ScheduledOperation::Put(key, value)),
+            ("del", _) => Some(ScheduledOperation::Del(key)),
+            ("delfromlist", Some(value)) => Some(ScheduledOperation::DelFromList(key, value)),
+            _ => None,
+        }
+    }
+}
+
+trait ScheduleReader: Reader {
+    fn get_scheduled_json(&self, height: u64) -> Result<Vec<Json>, StoreError> {
+        let key = schedule_key(height);
+        match self.get_native(&key)? {
+            Some(Json::Array(operations)) => Ok(operations),
+            Some(_) => Err(invalid_value(&key, "expected an array")),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Heights above `height` with undo records, from the highest
+    fn undo_heights_above(&self, height: u64) -> Result<Vec<u64>, StoreError> {
+        let key = schedule_latest_key();
+        let latest = match self.get_native(&key)? {
+            Some(latest) => latest.as_u64().ok_or_else(|| invalid_value(&key, "expected a height"))?,
+            None => return Ok(vec![]),
+        };
+        let mut heights = vec![];
+        for above in (height + 1..=latest).rev() {
+            if self.get_raw(&schedule_undo_key(above))?.is_some() {
+                heights.push(above);
+            }
+        }
+        Ok(heights)
+    }
+
+    /// Highest height whose operations are final, `None` before any undo record was dropped
+    fn final_height(&self) -> Result<Option<u64>, StoreError> {
+        let key = schedule_final_key();
+        match self.get_native(&key)? {
+            Some(height) => height.as_u64().map(Some).ok_or_else(|| invalid_value(&key, "expected a height")),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T: Reader> ScheduleReader for T {}
+
+impl<'a, D: KeyValueDb> Batch<'a, D> {
+    /// Schedules `operation` at `height`, see `Store::schedule_at_block_height`
+    pub fn schedule_at_block_height(&mut self, height: u64, operation: &ScheduledOperation) -> Result<bool, StoreError> {
+        let mut operations = self.get_scheduled_json(height)?;
+        let json = operation.to_json(height);
+        if operations.contains(&json) {
+            return Ok(false);
+        }
+        operations.push(json);
+        self.put_raw(schedule_key(height), encode_native(&Json::Array(operations)));
+        Ok(true)
+    }
+
+    fn run_operation(&mut self, operation: &ScheduledOperation) -> Result<(), StoreError> {
+        match *operation {
+            ScheduledOperation::Get(_) => {}
+            ScheduledOperation::Put(ref key, ref value) => self.put_raw(key.clone(), encode_native(value)),
+            ScheduledOperation::Del(ref key) => self.delete(key),
+            ScheduledOperation::DelFromList(ref key, ref value) => {
+                let list = match self.get_native(key)? {
+                    Some(Json::Array(list)) => list,
+                    Some(_) => return Err(invalid_value(key, "expected an array")),
+                    None => return Ok(()),
+                };
+                let list: Vec<Json> = list.into_iter().filter(|item| item != value).collect();
+                if list.is_empty() {
+                    self.delete(key);
+                } else {
+                    self.put_raw(key.clone(), encode_native(&Json::Array(list)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the operations scheduled at `height` once, `runScheduledOperations` in JS
+    ///
+    /// Operations which can not be read are skipped as in JS. Undo records of the heights
+    /// becoming final are dropped.
+    pub fn run_scheduled_operations(&mut self, height: u64) -> Result<(), StoreError> {
+        let undo_key = schedule_undo_key(height);
+        let is_final = match self.final_height()? {
+            Some(final_height) => height <= final_height,
+            None => false,
+        };
+        if is_final || self.get_raw(&undo_key)?.is_some() {
+            return Ok(());
+        }
+
+        let operations: Vec<ScheduledOperation> =
+            self.get_scheduled_json(height)?.iter().filter_map(ScheduledOperation::from_json).collect();
+        let mut undo: Vec<(String, Json)> = vec![];
+        for operation in operations {
+            let key = operation.key().to_string();
+            if !undo.iter().any(|(known, _)| *known == key) {
+                let previous = self.get_raw(&key)?.map_or(Json::Null, |bytes| Json::String(bytes.to_base64(STANDARD)));
+                undo.push((key, previous));
+            }
+            self.run_operation(&operation)?;
+        }
+
+        if !undo.is_empty() {
+            let undo = undo.into_iter().map(|(key, previous)| Json::Array(vec![Json::String(key), previous])).collect();
+            self.put_raw(undo_key, encode_native(&Json::Array(undo)));
+            if self.undo_heights_above(height)?.is_empty() {
+                self.put_raw(schedule_latest_key(), encode_native(&Json::U64(height)));
+            }
+        }
+        self.prune_undo_records(height)
+    }
+
+    /// Drops the undo records `SCHEDULE_FINALITY_DEPTH` blocks and more below `height`
+    fn prune_undo_records(&mut self, height: u64) -> Result<(), StoreError> {
+        let final_height = match height.checked_sub(SCHEDULE_FINALITY_DEPTH) {
+            Some(final_height) => final_height,
+            None => return Ok(()),
+        };
+        let from = match self.final_height()? {
+            Some(known) if known >= final_height => return Ok(()),
+            Some(known) => known + 1,
+            None => 0,
+        };
+        for below in from..=final_height {
+            let undo_key = schedule_undo_key(below);
+            if self.get_raw(&undo_key)?.is_some() {
+                self.delete(&undo_key);
+            }
+        }
+        self.put_raw(schedule_final_key(), encode_native(&Json::U64(final_height)));
+        Ok(())
+    }
+
+    /// Restores the values the operations scheduled at `height` and above overwrote, from the
+    /// highest height
+    pub fn revert_scheduled_operations_from(&mut self, height: u64) -> Result<(), StoreError> {
+        for above in self.undo_heights_above(height)? {
+            self.revert_scheduled_operations(above)?;
+        }
+        self.revert_scheduled_operations(height)
+    }
+
+    /// Restores the values the operations scheduled at `height` overwrote
+    ///
+    /// Fails if operations at a higher height have not been reverted yet. Heights without an undo
+    /// record, because nothing ran or the operations are final, are left as they are.
+    pub fn revert_scheduled_operations(&mut self, height: u64) -> Result<(), StoreError> {
+        if let Some(&above) = self.undo_heights_above(height)?.first() {
+            return Err(StoreError::OutOfOrderRevert { height, above });
+        }
+        let undo_key = schedule_undo_key(height);
+        let undo = match self.get_native(&undo_key)? {
+            Some(Json::Array(undo)) => undo,
+            Some(_) => return Err(invalid_value(&undo_key, "expected an array")),
+            None => return Ok(()),
+        };
+
+        for entry in undo.iter().rev() {
+            let (key, previous) = match entry.as_array().map(|entry| &entry[..]) {
+                Some(&[Json::String(ref key), ref previous]) => (key, previous),
+                _ => return Err(invalid_value(&undo_key, "expected [key, value] entries")),
+            };
+            match *previous {
+                Json::String(ref previous) => {
+                    let bytes = previous.from_base64().map_err(|err| invalid_value(&undo_key, err))?;
+                    self.put_raw(key.clone(), bytes);
+                }
+                _ => self.delete(key),
+            }
+        }
+        self.delete(&undo_key);
+        match height.checked_sub(1) {
+            Some(below) => self.put_raw(schedule_latest_key(), encode_native(&Json::U64(below))),
+            None => self.delete(&schedule_latest_key()),
+        }
+        Ok(())
+    }
+}
+
+impl<D: KeyValueDb> Store<D> {
+    /// Schedules `operation` to run when the block at `height` is stored, `scheduleAtBlockHeight` in JS
+    ///
+    /// Returns false if the same operation is scheduled already.
+    pub fn schedule_at_block_height(&self, height: u64, operation: &ScheduledOperation) -> Result<bool, StoreError> {
+        let mut batch = self.batch();
+        let scheduled = batch.schedule_at_block_height(height, operation)?;
+        batch.commit()?;
+        Ok(scheduled)
+    }
+
+    /// Operations scheduled at `height`
+    pub fn get_scheduled_operations(&self, height: u64) -> Result<Vec<ScheduledOperation>, StoreError> {
+        Ok(self.get_scheduled_json(height)?.iter().filter_map(ScheduledOperation::from_json).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use protos::core::BcBlock;
+    use store::{MemoryDb, WriteOp};
+
+    fn block(hash: &str, height: u64) -> BcBlock {
+        let mut block = BcBlock::new();
+        block.set_hash(hash.to_string());
+        block.set_height(height);
+        block.set_txs(RepeatedField::new());
+        block
+    }
+
+    fn string(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+
+    fn list(values: &[&str]) -> Option<Vec<String>> {
+        Some(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    #[test]
+    fn schedule_at_block_height_test() {
+        let store = Store::new(MemoryDb::new());
+        let put = ScheduledOperation::Put("watch".to_string(), string("a"));
+        assert_eq!(store.schedule_at_block_height(3, &put), Ok(true));
+        assert_eq!(store.schedule_at_block_height(3, &put), Ok(false));
+        assert_eq!(store.schedule_at_block_height(3, &ScheduledOperation::Del("watch".to_string())), Ok(true));
+        assert_eq!(store.get_scheduled_operations(3), Ok(vec![put, ScheduledOperation::Del("watch".to_string())]));
+
+        // the list JS writes
+        let json = store.get_native(&schedule_key(3)).unwrap().unwrap();
+        assert_eq!(json.to_string(), r#"[[3,"put","watch","a","bc"],[3,"del","watch","bc"]]"#);
+    }
+
+    #[test]
+    fn reorg_test() {
+        let store = Store::new(MemoryDb::new());
+        let mut batch = store.batch();
+        batch.put_raw("watch".to_string(), encode_native(&Json::Array(vec![string("a"), string("b")])));
+        batch.put_raw("order".to_string(), encode_native(&string("open")));
+        batch.commit().unwrap();
+        store.schedule_at_block_height(3, &ScheduledOperation::DelFromList("watch".to_string(), string("a"))).unwrap();
+        store.schedule_at_block_height(3, &ScheduledOperation::Put("order".to_string(), string("expired"))).unwrap();
+        store.schedule_at_block_height(4, &ScheduledOperation::DelFromList("watch".to_string(), string("b"))).unwrap();
+        store.schedule_at_block_height(4, &ScheduledOperation::Del("order".to_string())).unwrap();
+
+        store.put_block(&block("b2", 2), true).unwrap();
+        store.put_block(&block("b3", 3), true).unwrap();
+        assert_eq!(store.get_strings("watch"), Ok(list(&["b"])));
+        assert_eq!(store.get_native("order"), Ok(Some(string("expired"))));
+
+        // blocks of side branches do not run operations
+        store.put_block(&block("o4", 4), false).unwrap();
+        assert_eq!(store.get_strings("watch"), Ok(list(&["b"])));
+
+        store.put_block(&block("b4", 4), true).unwrap();
+        assert_eq!(store.get_strings("watch"), Ok(None));
+        assert_eq!(store.get_native("order"), Ok(None));
+
+        // reorg to a branch forking after height 2
+        store.del_block("b4").unwrap();
+        assert_eq!(store.get_strings("watch"), Ok(list(&["b"])));
+        assert_eq!(store.get_native("order"), Ok(Some(string("expired"))));
+        store.del_block("b3").unwrap();
+        assert_eq!(store.get_strings("watch"), Ok(list(&["a", "b"])));
+        assert_eq!(store.get_native("order"), Ok(Some(string("open"))));
+        assert_eq!(store.get_raw(&schedule_undo_key(3)), Ok(None));
+
+        let mut batch = store.batch();
+        batch.put_block(&block("c3", 3), true).unwrap();
+        batch.put_block(&block("c4", 4), true).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(store.get_strings("watch"), Ok(None));
+        assert_eq!(store.get_native("order"), Ok(None));
+        assert_eq!(store.get_scheduled_operations(4).unwrap().len(), 2);
+    }
+
+    fn scheduled_store() -> Store<MemoryDb> {
+        let store = Store::new(MemoryDb::new());
+        store.db().write(vec![WriteOp::Put("order".to_string(), encode_native(&string("open")))]).unwrap();
+        store.schedule_at_block_height(3, &ScheduledOperation::Put("order".to_string(), string("expired"))).unwrap();
+        store.schedule_at_block_height(4, &ScheduledOperation::Put("order".to_string(), string("settled"))).unwrap();
+        store.put_block(&block("b2", 2), true).unwrap();
+        store.put_block(&block("b3", 3), true).unwrap();
+        store.put_block(&block("b4", 4), true).unwrap();
+        store
+    }
+
+    #[test]
+    fn replace_test() {
+        // reorg to a branch forking after height 2 without removing the blocks of the old one
+        let store = scheduled_store();
+        assert_eq!(store.get_native("order"), Ok(Some(string("settled"))));
+
+        store.put_block(&block("c3", 3), true).unwrap();
+        assert_eq!(store.get_native("order"), Ok(Some(string("expired"))));
+        assert_eq!(store.get_raw(&schedule_undo_key(4)), Ok(None));
+        store.put_block(&block("c4", 4), true).unwrap();
+        assert_eq!(store.get_native("order"), Ok(Some(string("settled"))));
+
+        // the operations ran once per block of the main branch
+        store.del_block("c4").unwrap();
+        store.del_block("c3").unwrap();
+        assert_eq!(store.get_native("order"), Ok(Some(string("open"))));
+        assert_eq!(store.get_native(&schedule_latest_key()), Ok(Some(Json::U64(2))));
+    }
+
+    #[test]
+    fn prune_test() {
+        let store = scheduled_store();
+        // heights without operations have no undo record
+        assert_eq!(store.get_raw(&schedule_undo_key(2)), Ok(None));
+        assert!(store.get_raw(&schedule_undo_key(3)).unwrap().is_some());
+
+        let mut batch = store.batch();
+        batch.run_scheduled_operations(SCHEDULE_FINALITY_DEPTH + 2).unwrap();
+        batch.commit().unwrap();
+        assert!(store.get_raw(&schedule_undo_key(3)).unwrap().is_some());
+        assert_eq!(store.get_native(&schedule_final_key()), Ok(Some(Json::U64(2))));
+
+        let mut batch = store.batch();
+        batch.run_scheduled_operations(SCHEDULE_FINALITY_DEPTH + 3).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(store.get_raw(&schedule_undo_key(3)), Ok(None));
+        assert!(store.get_raw(&schedule_undo_key(4)).unwrap().is_some());
+        assert_eq!(store.get_native(&schedule_final_key()), Ok(Some(Json::U64(3))));
+
+        // operations at final heights neither run again nor get reverted
+        let mut batch = store.batch();
+        batch.revert_scheduled_operations_from(4).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(store.get_native("order"), Ok(Some(string("expired"))));
+        let mut batch = store.batch();
+        batch.revert_scheduled_operations(3).unwrap();
+        batch.put_raw("order".to_string(), encode_native(&string("open")));
+        batch.run_scheduled_operations(3).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(store.get_native("order"), Ok(Some(string("open"))));
+    }
+
+    #[test]
+    fn out_of_order_test() {
+        let store = scheduled_store();
+        assert_eq!(store.del_block("b3"), Err(StoreError::OutOfOrderRevert { height: 3, above: 4 }));
+        assert_eq!(store.get_native("order"), Ok(Some(string("settled"))));
+        assert_eq!(store.get_block_by_height(3).unwrap().map(|block| block.get_hash().to_string()), Some("b3".to_string()));
+
+        let mut batch = store.batch();
+        batch.del_block("b4").unwrap();
+        batch.del_block("b3").unwrap();
+        batch.commit().unwrap();
+        assert_eq!(store.get_native("order"), Ok(Some(string("open"))));
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! State of the settlement of matched orders, see `dex::settlement`
+//!
+//! Every entry of the tracker is stored as JSON under `bc.settle.state.<entry>`, as JS keeps
+//! `maker.wants.*`, `taker.wants.*` and `settle.tx.*` keys, so applying a block writes only the
+//! entries it changed. The last block applied is stored as `{hash, height}` under
+//! `bc.settle.latest` and the previous values of the entries a block changed under
+//! `bc.settle.undo.<hash>`, which reverts the block when it is removed from the main branch.
+use rustc_serialize::json::{Json, Object};
+
+use dex::settlement::{MarkedWatch, SettlementKey, SettlementTracker, SettlementUndo};
+use protos::core::BcBlock;
+
+use super::codec::encode_native;
+use super::{invalid_value, Batch, KeyValueDb, Reader, Store, StoreError, BLOCKCHAIN};
+
+fn settle_state_prefix() -> String {
+    format!("{}.settle.state.", BLOCKCHAIN)
+}
+
+pub fn settle_state_key(key: &SettlementKey) -> String {
+    format!("{}{}", settle_state_prefix(), key.to_key())
+}
+
+pub fn settle_latest_key() -> String {
+    format!("{}.settle.latest", BLOCKCHAIN)
+}
+
+pub fn settle_undo_key(hash: &str) -> String {
+    format!("{}.settle.undo.{}", BLOCKCHAIN, hash)
+}
+
+fn latest_to_json(hash: &str, height: u64) -> Json {
+    let mut object = Object::new();
+    object.insert("hash".to_string(), Json::String(hash.to_string()));
+    object.insert("height".to_string(), Json::U64(height));
+    Json::Object(object)
+}
+
+fn latest_from_json(json: &Json) -> Option<(String, u64)> {
+    let object = json.as_object()?;
+    Some((object.get("hash")?.as_string()?.to_string(), object.get("height")?.as_u64()?))
+}
+
+fn undo_to_json(undo: &SettlementUndo) -> Json {
+    let entries = undo
+        .entries
+        .iter()
+        .map(|(key, previous)| Json::Array(vec![Json::String(key.to_key()), previous.clone().unwrap_or(Json::Null)]))
+        .collect();
+    let mut object = Object::new();
+    object.insert("hash".to_string(), Json::String(undo.hash.clone()));
+    object.insert("previousHash".to_string(), Json::String(undo.previous_hash.clone()));
+    object.insert("previousHeight".to_string(), Json::U64(undo.previous_height));
+    object.insert("entries".to_string(), Json::Array(entries));
+    Json::Object(object)
+}
+
+fn undo_from_json(json: &Json) -> Option<SettlementUndo> {
+    let object = json.as_object()?;
+    let entries = object
+        .get("entries")?
+        .as_array()?
+        .iter()
+        .map(|entry| match entry.as_array().map(|entry| &entry[..]) {
+            Some([key, previous]) => {
+                let previous = if previous.is_null() { None } else { Some(previous.clone()) };
+                Some((SettlementKey::parse(key.as_string()?)?, previous))
+            }
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    Some(SettlementUndo {
+        hash: object.get("hash")?.as_string()?.to_string(),
+        previous_hash: object.get("previousHash")?.as_string()?.to_string(),
+        previous_height: object.get("previousHeight")?.as_u64()?,
+        entries,
+    })
+}
+
+impl<'a, D: KeyValueDb> Batch<'a, D> {
+    fn put_settlement_entries<'k, I: Iterator<Item = &'k SettlementKey>>(&mut self, tracker: &SettlementTracker, keys: I) {
+        for key in keys {
+            match tracker.get_entry(key) {
+                Some(value) => self.put_raw(settle_state_key(key), encode_native(&value)),
+                None => self.delete(&settle_state_key(key)),
+            }
+        }
+    }
+
+    fn put_settlement_latest(&mut self, tracker: &SettlementTracker) {
+        self.put_raw(settle_latest_key(), encode_native(&latest_to_json(tracker.best_block_hash(), tracker.height())));
+    }
+
+    /// Applies `block` to `tracker` and writes the entries it changed with the undo record
+    pub fn apply_settlement(&mut self, tracker: &mut SettlementTracker, block: &BcBlock) {
+        let undo = tracker.apply_block(block);
+        self.put_settlement_entries(tracker, undo.entries.iter().map(|(key, _)| key));
+        self.put_settlement_latest(tracker);
+        self.put_raw(settle_undo_key(&undo.hash), encode_native(&undo_to_json(&undo)));
+    }
+
+    /// Reverts block `hash` of `tracker` with its stored undo record and writes the entries back
+    ///
+    /// Returns false if no undo record of the block is stored. Fails if the block is not the last
+    /// one applied to the tracker.
+    pub fn revert_settlement(&mut self, tracker: &mut SettlementTracker, hash: &str) -> Result<bool, StoreError> {
+        let key = settle_undo_key(hash);
+        let undo = match self.get_native(&key)? {
+            Some(json) => undo_from_json(&json).ok_or_else(|| invalid_value(&key, "expected a settlement undo record"))?,
+            None => return Ok(false),
+        };
+        tracker.revert_block(&undo).map_err(|err| invalid_value(&key, err))?;
+        self.put_settlement_entries(tracker, undo.entries.iter().map(|(key, _)| key));
+        self.put_settlement_latest(tracker);
+        self.delete(&key);
+        Ok(true)
+    }
+
+    /// Registers `watch` with `tracker` and writes the watches of its address, see
+    /// `SettlementTracker::set_marked_watch`
+    pub fn set_marked_watch(&mut self, tracker: &mut SettlementTracker, watch: MarkedWatch) -> bool {
+        let key = SettlementKey::Watches(watch.bridged_chain.clone(), watch.to_addr.clone());
+        if !tracker.set_marked_watch(watch) {
+            return false;
+        }
+        self.put_settlement_entries(tracker, Some(&key).into_iter());
+        true
+    }
+}
+
+impl<D: KeyValueDb> Store<D> {
+    /// Tracker with the stored entries, empty if no block has been applied
+    pub fn load_settlement(&self) -> Result<SettlementTracker, StoreError> {
+        let latest_key = settle_latest_key();
+        let (hash, height) = match self.get_native(&latest_key)? {
+            Some(json) => latest_from_json(&json).ok_or_else(|| invalid_value(&latest_key, "expected hash and height"))?,
+            None => (String::new(), 0),
+        };
+
+        let prefix = settle_state_prefix();
+        let mut entries = vec![];
+        let mut result = Ok(());
+        self.db().scan(&mut |key, bytes| {
+            if result.is_err() || !key.starts_with(&prefix) {
+                return;
+            }
+            result = match SettlementKey::parse(&key[prefix.len()..]) {
+                Some(entry) => match self.codec().decode_native(bytes) {
+                    Ok(value) => {
+                        entries.push((entry, value));
+                        Ok(())
+                    }
+                    Err(err) => Err(invalid_value(key, err)),
+                },
+                None => Err(invalid_value(key, "expected a settlement entry key")),
+            };
+        })?;
+        result?;
+        SettlementTracker::load(&hash, height, &entries).map_err(|err| invalid_value(&prefix, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use protos::core::{Transaction, TransactionOutput};
+    use store::MemoryDb;
+    use tx::cross_chain::MakerOrder;
+    use utxo::Utxo;
+
+    fn block(height: u64, txs: Vec<Transaction>) -> BcBlock {
+        let mut block = BcBlock::new();
+        block.set_hash(format!("b{}", height));
+        block.set_height(height);
+        block.set_txs(RepeatedField::from_vec(txs));
+        block
+    }
+
+    fn maker_tx(hash: &str) -> Transaction {
+        let script = MakerOrder::new(0, 5, 10, "eth", "btc", "1maker", "0.5", "10", "0xmaker").to_script();
+        let mut output = TransactionOutput::new();
+        output.set_output_script(script.into_bytes());
+        let mut tx = Transaction::new();
+        tx.set_hash(hash.to_string());
+        tx.set_outputs(RepeatedField::from_vec(vec![output]));
+        tx
+    }
+
+    #[test]
+    fn apply_settlement_test() {
+        let store = Store::new(MemoryDb::new());
+        assert_eq!(store.load_settlement(), Ok(SettlementTracker::new()));
+
+        let mut tracker = SettlementTracker::new();
+        let mut batch = store.batch();
+        batch.apply_settlement(&mut tracker, &block(1, vec![maker_tx("m1")]));
+        assert!(batch.set_marked_watch(
+            &mut tracker,
+            MarkedWatch {
+                callback: Utxo::new("m1", 0),
+                bridged_chain: "btc".to_string(),
+                bc_settle_window: 12,
+                to_addr: "1maker".to_string(),
+                bind_from_addr: None,
+            }
+        ));
+        batch.commit().unwrap();
+        let applied = tracker.clone();
+        assert_eq!(store.load_settlement(), Ok(applied.clone()));
+
+        // expires the order and the watch
+        let mut batch = store.batch();
+        batch.apply_settlement(&mut tracker, &block(12, vec![]));
+        batch.commit().unwrap();
+        assert!(!tracker.is_before_settle_height("1maker", "btc", 11));
+        assert_eq!(store.load_settlement(), Ok(tracker.clone()));
+
+        let mut batch = store.batch();
+        assert_eq!(batch.revert_settlement(&mut tracker, "b1").map_err(|_| ()), Err(()));
+        assert_eq!(batch.revert_settlement(&mut tracker, "b12"), Ok(true));
+        assert_eq!(batch.revert_settlement(&mut tracker, "b12"), Ok(false));
+        batch.commit().unwrap();
+        assert_eq!(tracker, applied);
+        assert_eq!(store.load_settlement(), Ok(applied));
+        assert_eq!(store.get_raw(&settle_undo_key("b12")), Ok(None));
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Loaders for the JSON templates in src/utils/templates used by the consensus parity tests
+use num_bigint::BigUint;
+use rustc_serialize::json::Json;
+use protobuf::RepeatedField;
+
+use protos::core::{BcBlock, BlockchainHeader, BlockchainHeaders, Transaction, TransactionInput, TransactionOutput};
+
+pub const BC_BLOCK_93699: &str = include_str!("../../../src/utils/templates/bc.block.93699.json");
+pub const BC_TX_CB: &str = include_str!("../../../src/utils/templates/bc_tx_cb.json");
+
+fn get_str(json: &Json, key: &str) -> String {
+    json.find(key)
+        .and_then(|value| value.as_string())
+        .unwrap_or("")
+        .to_string()
+}
+
+fn get_u64(json: &Json, key: &str) -> u64 {
+    json.find(key)
+        .and_then(|value| value.as_u64())
+        .unwrap_or(0)
+}
+
+pub fn blockchain_header_from_json(json: &Json) -> BlockchainHeader {
+    let mut header = BlockchainHeader::new();
+    header.set_blockchain(get_str(json, "blockchain"));
+    header.set_hash(get_str(json, "hash"));
+    header.set_previous_hash(get_str(json, "previousHash"));
+    header.set_timestamp(get_u64(json, "timestamp"));
+    header.set_height(get_u64(json, "height"));
+    header.set_merkle_root(get_str(json, "merkleRoot"));
+    header.set_blockchain_confirmations_in_parent_count(get_u64(json, "blockchainConfirmationsInParentCount"));
+    header
+}
+
+fn headers_from_json(json: &Json, key: &str) -> RepeatedField<BlockchainHeader> {
+    let headers = json.find(key)
+        .and_then(|value| value.as_array())
+        .map(|list| list.iter().map(blockchain_header_from_json).collect())
+        .unwrap_or_default();
+
+    RepeatedField::from_vec(headers)
+}
+
+pub fn blockchain_headers_from_json(json: &Json) -> BlockchainHeaders {
+    let mut headers = BlockchainHeaders::new();
+    headers.set_btc(headers_from_json(json, "btcList"));
+    headers.set_eth(headers_from_json(json, "ethList"));
+    headers.set_lsk(headers_from_json(json, "lskList"));
+    headers.set_neo(headers_from_json(json, "neoList"));
+    headers.set_wav(headers_from_json(json, "wavList"));
+    headers
+}
+
+/// Builds `BcBlock` from its `toObject()` JSON representation
+pub fn bc_block_from_json(json: &Json) -> BcBlock {
+    let mut block = BcBlock::new();
+    block.set_hash(get_str(json, "hash"));
+    block.set_previous_hash(get_str(json, "previousHash"));
+    block.set_version(get_u64(json, "version"));
+    block.set_schema_version(get_u64(json, "schemaVersion"));
+    block.set_height(get_u64(json, "height"));
+    block.set_miner(get_str(json, "miner"));
+    block.set_difficulty(get_str(json, "difficulty"));
+    block.set_timestamp(get_u64(json, "timestamp"));
+    block.set_merkle_root(get_str(json, "merkleRoot"));
+    block.set_chain_root(get_str(json, "chainRoot"));
+    block.set_distance(get_str(json, "distance"));
+    block.set_total_distance(get_str(json, "totalDistance"));
+    block.set_nonce(get_str(json, "nonce"));
+    block.set_nrg_grant(get_u64(json, "nrgGrant"));
+    block.set_target_hash(get_str(json, "targetHash"));
+    block.set_target_height(get_u64(json, "targetHeight"));
+    block.set_target_miner(get_str(json, "targetMiner"));
+    block.set_target_signature(get_str(json, "targetSignature"));
+    block.set_twn(get_u64(json, "twn"));
+    block.set_emblem_weight(get_u64(json, "emblemWeight"));
+    block.set_emblem_chain_block_hash(get_str(json, "emblemChainBlockHash"));
+    block.set_emblem_chain_fingerprint_root(get_str(json, "emblemChainFingerprintRoot"));
+    block.set_emblem_chain_address(get_str(json, "emblemChainAddress"));
+    block.set_tx_count(get_u64(json, "txCount"));
+    block.set_tx_fee_base(get_u64(json, "txFeeBase"));
+    block.set_tx_distance_sum_limit(get_u64(json, "txDistanceSumLimit"));
+    block.set_blockchain_headers_count(get_u64(json, "blockchainHeadersCount"));
+    block.set_blockchain_fingerprints_root(get_str(json, "blockchainFingerprintsRoot"));
+    if let Some(headers) = json.find("blockchainHeaders") {
+        block.set_blockchain_headers(blockchain_headers_from_json(headers));
+    }
+    block
+}
+
+pub fn load_bc_block(template: &str) -> BcBlock {
+    let json = Json::from_str(template).expect("valid JSON template");
+    bc_block_from_json(&json)
+}
+
+fn get_decimal_u64(json: &Json, key: &str) -> u64 {
+    json.find(key)
+        .and_then(|value| value.as_string())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Builds `Transaction` from the legacy transaction format of bc_tx_cb.json
+///
+/// Previous output of an input becomes its `OutPoint` and the signature its script, output values
+/// are taken as boson amounts.
+pub fn load_bc_tx_cb(template: &str) -> Transaction {
+    let json = Json::from_str(template).expect("valid JSON template");
+
+    let inputs = json.find("inputs").and_then(|value| value.as_array()).cloned().unwrap_or_default();
+    let outputs = json.find("outputs").and_then(|value| value.as_array()).cloned().unwrap_or_default();
+
+    let mut tx = Transaction::new();
+    tx.set_version(get_decimal_u64(&json, "version"));
+    tx.set_nin_count(get_decimal_u64(&json, "ninputs"));
+    tx.set_nout_count(get_decimal_u64(&json, "noutputs"));
+    tx.set_inputs(RepeatedField::from_vec(inputs.iter().map(|input| {
+        let prev = input.find("prev").expect("input with previous output");
+        let signature = get_str(input, "signature");
+
+        let mut tx_input = TransactionInput::new();
+        tx_input.mut_out_point().set_hash(get_str(prev, "txId"));
+        tx_input.mut_out_point().set_index(get_u64(prev, "outputIndex"));
+        tx_input.set_script_length(signature.len() as u32);
+        tx_input.set_input_script(signature.into_bytes());
+        tx_input
+    }).collect()));
+    tx.set_outputs(RepeatedField::from_vec(outputs.iter().map(|output| {
+        let script = get_str(output, "script");
+
+        let mut tx_output = TransactionOutput::new();
+        tx_output.set_value(BigUint::from(get_decimal_u64(output, "value")).to_bytes_be());
+        tx_output.set_script_length(script.len() as u32);
+        tx_output.set_output_script(script.into_bytes());
+        tx_output
+    }).collect()));
+    tx
+}
@@ -0,0 +1,207 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Coinbase transaction of a new block, port of `txCreateCoinbase` from src/core/txUtils.es6
+use std::convert::TryFrom;
+
+use num_bigint::{BigInt, BigUint};
+use protobuf::RepeatedField;
+
+use coin::{biguint_to_internal, MAX_NRG_VALUE};
+use economics::{
+    calc_tx_fee, get_max_distance_with_emblems, get_nrg_grant, get_txs_distance_sum, EconomicsError, COINBASE_MATURITY,
+};
+use protos::core::{Transaction, TransactionOutput};
+use tx::{create_nrg_output_lock_script, tx_hash};
+
+/// Boson in one NRG
+const NRG_IN_BOSON: u64 = 1000000000000000000;
+
+/// NRG granted to the miner of block at `current_block_height`
+///
+/// The grant is capped so that no more than `MAX_NRG_VALUE` gets minted in total.
+fn capped_nrg_grant(
+    current_block_height: u64,
+    block_txs: &[Transaction],
+    emblem_balance: &BigUint,
+    minted_nrg: u64,
+) -> Result<BigUint, EconomicsError> {
+    if minted_nrg >= MAX_NRG_VALUE {
+        return Ok(BigUint::from(0u64));
+    }
+
+    let txs_distance_sum = get_txs_distance_sum(block_txs);
+    let emblems = get_max_distance_with_emblems(emblem_balance);
+    let nrg_grant = get_nrg_grant(emblems.emblem_bonus, &emblems.total_distance, &txs_distance_sum, current_block_height)?;
+
+    let remaining = BigUint::from(MAX_NRG_VALUE - minted_nrg);
+    Ok(if nrg_grant > remaining { remaining } else { nrg_grant })
+}
+
+/// Coinbase transaction of a block at `current_block_height` including `block_txs`, `txCreateCoinbase` in JS
+///
+/// The only output pays the NRG grant and fees of `block_txs` to `miner_address`. `emblem_balance`
+/// is the emblem balance of the miner and `minted_nrg` the NRG minted so far, which JS reads
+/// from the persistence.
+pub fn tx_create_coinbase(
+    current_block_height: u64,
+    block_txs: &[Transaction],
+    miner_address: &str,
+    emblem_balance: &BigUint,
+    minted_nrg: u64,
+) -> Result<Transaction, EconomicsError> {
+    let miner_address = miner_address.to_lowercase();
+    let lock_time = current_block_height
+        .checked_add(COINBASE_MATURITY)
+        .and_then(|lock_time| u32::try_from(lock_time).ok())
+        .ok_or(EconomicsError::LockTimeOverflow { height: current_block_height })?;
+    let nrg_grant = capped_nrg_grant(current_block_height, block_txs, emblem_balance, minted_nrg)?;
+
+    let tx_fees: BigInt = block_txs.iter().map(calc_tx_fee).sum();
+    let grant = (BigInt::from(nrg_grant * BigUint::from(NRG_IN_BOSON)) + &tx_fees)
+        .to_biguint()
+        .ok_or_else(|| EconomicsError::NegativeFees { fees: tx_fees.to_string() })?;
+
+    let output_lock_script = create_nrg_output_lock_script(&miner_address);
+    let mut output = TransactionOutput::new();
+    output.set_value(biguint_to_internal(&grant));
+    output.set_unit(biguint_to_internal(&BigUint::from(1u64)));
+    output.set_script_length(output_lock_script.len() as u32);
+    output.set_output_script(output_lock_script.into_bytes());
+
+    let mut tx = Transaction::new();
+    tx.set_outputs(RepeatedField::from_vec(vec![output]));
+    tx.set_nin_count(0);
+    tx.set_nout_count(1);
+    tx.set_nonce(miner_address);
+    tx.set_overline("0".to_string());
+    tx.set_lock_time(lock_time);
+    tx.set_version(1);
+
+    let hash = tx_hash(&tx);
+    tx.set_hash(hash.to_string());
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::ToHex;
+
+    use protos::core::{MarkedTransaction, OutPoint, TransactionInput};
+    use script::environment::ScriptLookup;
+    use tx::validation::{is_tx_valid, TxValidationOptions, UnspentOutput, UtxoLookup};
+
+    const MINER_ADDRESS: &str = "0x25CC7722A6378E11082F7967C09B98BD26C979D3";
+
+    struct NoUtxos;
+
+    impl UtxoLookup for NoUtxos {
+        fn get_unspent_output(&self, _out_point: &OutPoint) -> Option<UnspentOutput> {
+            None
+        }
+    }
+
+    impl ScriptLookup for NoUtxos {
+        fn get_transaction(&self, _hash: &str) -> Option<(Transaction, u64)> {
+            None
+        }
+
+        fn get_latest_height(&self) -> u64 {
+            0
+        }
+
+        fn get_marked_txs(&self, _from: u64, _to: u64) -> Vec<MarkedTransaction> {
+            vec![]
+        }
+    }
+
+    /// Transaction paying 1 NRG of fees, its distance is 41571918484192
+    fn block_tx(input_value: u64, output_value: u64) -> Transaction {
+        let mut out_point = OutPoint::new();
+        out_point.set_hash("ophash".to_string());
+        out_point.set_index(1);
+        out_point.set_value(biguint_to_internal(&(BigUint::from(input_value) * BigUint::from(NRG_IN_BOSON))));
+        let mut input = TransactionInput::new();
+        input.set_out_point(out_point);
+        let mut output = TransactionOutput::new();
+        output.set_value(biguint_to_internal(&(BigUint::from(output_value) * BigUint::from(NRG_IN_BOSON))));
+
+        let mut tx = Transaction::new();
+        tx.set_nonce("12345minerkey".to_string());
+        tx.set_hash("txhash".to_string());
+        tx.set_inputs(RepeatedField::from_vec(vec![input]));
+        tx.set_outputs(RepeatedField::from_vec(vec![output]));
+        tx
+    }
+
+    #[test]
+    fn tx_create_coinbase_test() {
+        // parity with txCreateCoinbase in JS, 16 NRG grant and 1 NRG fees
+        let txs = vec![block_tx(10, 9)];
+        let coinbase = tx_create_coinbase(1000, &txs, MINER_ADDRESS, &BigUint::from(0u64), 0).unwrap();
+        assert_eq!(coinbase.get_hash(), "cead21a8fd6f1b32e9f4275414d744a1d3b6eaa34479335b3153a3732a865bd0");
+        assert_eq!(coinbase.get_hash(), tx_hash(&coinbase).to_string());
+        assert_eq!(coinbase.get_nonce(), MINER_ADDRESS.to_lowercase());
+        assert_eq!(coinbase.get_lock_time(), 1100);
+
+        let output = &coinbase.get_outputs()[0];
+        assert_eq!(output.get_value().to_hex(), "ebec21ee1da40000");
+        assert_eq!(output.get_unit(), &[1]);
+        assert_eq!(output.get_output_script(), create_nrg_output_lock_script(MINER_ADDRESS).as_bytes());
+
+        let opts = TxValidationOptions { tx_index: Some(0), block_height: Some(1000) };
+        assert_eq!(is_tx_valid(&coinbase, &NoUtxos, &NoUtxos, opts), Ok(()));
+    }
+
+    #[test]
+    fn tx_create_coinbase_emblems_test() {
+        // 46 NRG of emblem bonus doubled as the transactions consumed less than half of the distance
+        let txs = vec![block_tx(10, 9)];
+        let coinbase = tx_create_coinbase(1000, &txs, MINER_ADDRESS, &BigUint::from(10000u64), 0).unwrap();
+        assert_eq!(coinbase.get_hash(), "fbc618d7c348fed6657a63bbcb83ad7e76e071a71621e3f21bb67c86140f9650");
+        assert_eq!(coinbase.get_outputs()[0].get_value().to_hex(), "050aa25f43cf540000");
+
+        // JS throws as the grant divides by the consumed distance
+        assert_eq!(
+            tx_create_coinbase(1000, &[], MINER_ADDRESS, &BigUint::from(10000u64), 0),
+            Err(EconomicsError::NoConsumedDistance)
+        );
+    }
+
+    #[test]
+    fn tx_create_coinbase_minted_test() {
+        // only the remaining 5 NRG can be minted
+        let coinbase = tx_create_coinbase(66520001, &[], MINER_ADDRESS, &BigUint::from(0u64), MAX_NRG_VALUE - 5).unwrap();
+        assert_eq!(coinbase.get_hash(), "559cdbafc03683a16b68e2d2bb65e43414dfbc6917740f0a8eef346b60b94ecc");
+        assert_eq!(coinbase.get_outputs()[0].get_value().to_hex(), "4563918244f40000");
+
+        let txs = vec![block_tx(10, 9)];
+        let coinbase = tx_create_coinbase(1000, &txs, MINER_ADDRESS, &BigUint::from(0u64), MAX_NRG_VALUE).unwrap();
+        assert_eq!(coinbase.get_outputs()[0].get_value().to_hex(), "0de0b6b3a7640000");
+
+        assert_eq!(
+            tx_create_coinbase(1000, &[block_tx(9, 10)], MINER_ADDRESS, &BigUint::from(0u64), MAX_NRG_VALUE),
+            Err(EconomicsError::NegativeFees { fees: "-1000000000000000000".to_string() })
+        );
+    }
+
+    #[test]
+    fn tx_create_coinbase_lock_time_overflow_test() {
+        let txs = vec![block_tx(10, 9)];
+        let height = u64::from(u32::max_value()) - COINBASE_MATURITY;
+        let coinbase = tx_create_coinbase(height, &txs, MINER_ADDRESS, &BigUint::from(0u64), MAX_NRG_VALUE).unwrap();
+        assert_eq!(coinbase.get_lock_time(), u32::max_value());
+
+        assert_eq!(
+            tx_create_coinbase(height + 1, &txs, MINER_ADDRESS, &BigUint::from(0u64), MAX_NRG_VALUE),
+            Err(EconomicsError::LockTimeOverflow { height: height + 1 })
+        );
+        assert_eq!(
+            tx_create_coinbase(u64::max_value(), &txs, MINER_ADDRESS, &BigUint::from(0u64), MAX_NRG_VALUE),
+            Err(EconomicsError::LockTimeOverflow { height: u64::max_value() })
+        );
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Cross chain (DEX) order scripts, port of the maker/taker `ScriptTemplates` and the
+//! `extractInfoFromCrossChainTx*` functions from src/core/txUtils.es6
+//!
+//! Parsing is strict: a script is only accepted when building the order back yields the very
+//! same script, the way `validateMakerCollTakerPairScriptFormat` checks maker scripts in JS.
+use std::error::Error;
+use std::fmt;
+
+use tx::double_hashed_address;
+
+const MAKER_OUTPUT_SCRIPT_LENGTH: usize = 44;
+const TAKER_OUTPUT_SCRIPT_LENGTH: usize = 14;
+const TAKER_INPUT_SCRIPT_LENGTH: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    InvalidTokenCount { expected: usize, found: usize },
+    InvalidNumber(String),
+    NotCanonical,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TemplateError::InvalidTokenCount { expected, found } =>
+                write!(f, "invalid script with {} tokens, expected {}", found, expected),
+            TemplateError::InvalidNumber(ref value) => write!(f, "invalid number '{}'", value),
+            TemplateError::NotCanonical => write!(f, "script does not match its template"),
+        }
+    }
+}
+
+impl Error for TemplateError {
+    fn description(&self) -> &str {
+        "invalid script template"
+    }
+}
+
+fn split_tokens(script: &str, expected: usize) -> Result<Vec<&str>, TemplateError> {
+    let tokens: Vec<&str> = script.split(' ').collect();
+    if tokens.len() != expected {
+        return Err(TemplateError::InvalidTokenCount { expected, found: tokens.len() });
+    }
+    Ok(tokens)
+}
+
+fn parse_number<T: ::std::str::FromStr>(value: &str) -> Result<T, TemplateError> {
+    value.parse().map_err(|_| TemplateError::InvalidNumber(value.to_string()))
+}
+
+/// Checks that `script` is exactly what `to_script` of the parsed value builds
+fn canonical<T, F: Fn(&T) -> String>(script: &str, value: T, to_script: F) -> Result<T, TemplateError> {
+    if to_script(&value) != script {
+        return Err(TemplateError::NotCanonical);
+    }
+    Ok(value)
+}
+
+/// Maker side of a trade, collateralized NRG offered for an asset of another chain
+///
+/// The block heights delimit the trade: takers can pair with the order from `shift_starts_at`,
+/// must deposit until `deposit_ends_at` and settle until `settle_ends_at`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MakerOrder {
+    pub shift_starts_at: u64,
+    pub deposit_ends_at: u64,
+    pub settle_ends_at: u64,
+    pub pays_chain_id: String,
+    pub wants_chain_id: String,
+    pub wants_address: String,
+    pub wants_unit: String,
+    pub pays_unit: String,
+    /// `blake2bl(blake2bl(address))` of the BC address of the maker
+    pub double_hashed_bc_address: String,
+}
+
+impl MakerOrder {
+    /// Order of the maker owning `maker_bc_address`, the address gets hashed
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        shift_starts_at: u64,
+        deposit_ends_at: u64,
+        settle_ends_at: u64,
+        pays_chain_id: &str,
+        wants_chain_id: &str,
+        wants_address: &str,
+        wants_unit: &str,
+        pays_unit: &str,
+        maker_bc_address: &str,
+    ) -> MakerOrder {
+        MakerOrder {
+            shift_starts_at,
+            deposit_ends_at,
+            settle_ends_at,
+            pays_chain_id: pays_chain_id.to_string(),
+            wants_chain_id: wants_chain_id.to_string(),
+            wants_address: wants_address.to_lowercase(),
+            wants_unit: wants_unit.to_string(),
+            pays_unit: pays_unit.to_string(),
+            double_hashed_bc_address: double_hashed_address(maker_bc_address),
+        }
+    }
+
+    /// Order of a maker output script, `extractInfoFromCrossChainTxMakerOutputScript` in JS
+    pub fn parse(script: &str) -> Result<MakerOrder, TemplateError> {
+        let tokens = split_tokens(script, MAKER_OUTPUT_SCRIPT_LENGTH)?;
+        let order = MakerOrder {
+            shift_starts_at: parse_number(tokens[1])?,
+            deposit_ends_at: parse_number(tokens[2])?,
+            settle_ends_at: parse_number(tokens[3])?,
+            pays_chain_id: tokens[20].to_string(),
+            wants_chain_id: tokens[21].to_string(),
+            wants_address: tokens[22].to_string(),
+            wants_unit: tokens[23].to_string(),
+            pays_unit: tokens[24].to_string(),
+            double_hashed_bc_address: tokens[29].to_string(),
+        };
+        canonical(script, order, MakerOrder::to_script)
+    }
+
+    /// Maker output script, `ScriptTemplates.createCrossChainTxMakerOutputScript` in JS
+    pub fn to_script(&self) -> String {
+        let address = self.double_hashed_bc_address.to_lowercase();
+        [
+            format!("OP_MONOID {} {} {} OP_DEPSET", self.shift_starts_at, self.deposit_ends_at, self.settle_ends_at),
+            "OP_0 OP_IFEQ OP_RETURN OP_ENDIFEQ".to_string(),
+            "OP_2 OP_IFEQ OP_TAKERPAIR 2 OP_MINUNITVALUE OP_RETURN OP_ENDIFEQ".to_string(),
+            "OP_3 OP_IFEQ OP_RETURN OP_ENDIFEQ".to_string(),
+            format!(
+                "{} {} {} {} {} OP_MAKERCOLL",
+                self.pays_chain_id,
+                self.wants_chain_id,
+                self.wants_address.to_lowercase(),
+                self.wants_unit,
+                self.pays_unit
+            ),
+            format!("OP_3 OP_IFEQ OP_BLAKE2BL {} OP_EQ OP_CHECKSIGVERIFY OP_ENDIFEQ", address),
+            format!(
+                "OP_2 OP_IFEQ 1 OP_MINUNITVALUE OP_MONAD OP_BLAKE2BL {} OP_EQ OP_CHECKSIGVERIFY OP_ENDMONAD OP_ENDIFEQ",
+                address
+            ),
+        ]
+        .join(" ")
+    }
+}
+
+/// Taker side of a trade, pairs with output `maker_tx_output_index` of the maker transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TakerOrder {
+    pub maker_tx_hash: String,
+    pub maker_tx_output_index: u32,
+    /// `blake2bl(blake2bl(address))` of the BC address of the taker
+    pub double_hashed_bc_address: String,
+}
+
+impl TakerOrder {
+    /// Order of the taker owning `taker_bc_address`, the address gets hashed
+    pub fn new(maker_tx_hash: &str, maker_tx_output_index: u32, taker_bc_address: &str) -> TakerOrder {
+        TakerOrder {
+            maker_tx_hash: maker_tx_hash.to_string(),
+            maker_tx_output_index,
+            double_hashed_bc_address: double_hashed_address(taker_bc_address),
+        }
+    }
+
+    /// Order of a taker output script, `extractInfoFromCrossChainTxTakerOutputScript` in JS
+    pub fn parse(script: &str) -> Result<TakerOrder, TemplateError> {
+        let tokens = split_tokens(script, TAKER_OUTPUT_SCRIPT_LENGTH)?;
+        let order = TakerOrder {
+            maker_tx_hash: tokens[0].to_string(),
+            maker_tx_output_index: parse_number(tokens[1])?,
+            double_hashed_bc_address: tokens[6].to_string(),
+        };
+        canonical(script, order, TakerOrder::to_script)
+    }
+
+    /// Callback part of the taker output script, `ScriptTemplates.createCrossChainTxTakerOutputCallbackScript` in JS
+    pub fn to_callback_script(&self) -> String {
+        format!("{} {} OP_CALLBACK", self.maker_tx_hash, self.maker_tx_output_index)
+    }
+
+    /// Taker output script, `ScriptTemplates.createCrossChainTxTakerOutputScript` in JS
+    ///
+    /// `OP_BLAKE` is not an opcode, JS builds the script this way.
+    pub fn to_script(&self) -> String {
+        let address = &self.double_hashed_bc_address;
+        format!(
+            "{} 4 OP_IFEQ OP_BLAKE {} OP_CHECKSIGVERIFY OP_ENDIFEQ OP_MONAD OP_BLAKE {} OP_CHECKSIG OP_ENDMONAD",
+            self.to_callback_script(),
+            address,
+            address
+        )
+    }
+}
+
+/// Addresses of the taker on the other chains, input script spending the maker output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TakerInput {
+    pub wants_address: String,
+    pub sends_address: String,
+}
+
+impl TakerInput {
+    /// Addresses of a taker input script, `extractInfoFromCrossChainTxTakerInputScript` in JS
+    pub fn parse(script: &str) -> Result<TakerInput, TemplateError> {
+        let tokens = split_tokens(script, TAKER_INPUT_SCRIPT_LENGTH)?;
+        let input = TakerInput { wants_address: tokens[0].to_string(), sends_address: tokens[1].to_string() };
+        canonical(script, input, TakerInput::to_script)
+    }
+
+    /// Taker input script, `ScriptTemplates.createCrossChainTxTakerInputScript` in JS
+    pub fn to_script(&self) -> String {
+        format!("{} {}", self.wants_address, self.sends_address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "0x25CC7722A6378E11082F7967C09B98BD26C979D3";
+    const ADDRESS_HASH: &str = "350c8702addcb6edda4e028e956ec7e0565f1ffed28df6feca6fa0484ddf61f8";
+    const MAKER_TX_HASH: &str = "387a12d284f41d0f7f29f42efd14a232849d4305b6d7b980b448638d8877c129";
+
+    // createCrossChainTxMakerOutputScript(0, 450, 800, 'eth', 'btc', '1BoatSLRHtKNngkdXEeobR76b53LETtpyT', '0.5', '10', ADDRESS) in JS
+    const MAKER_SCRIPT: &str = "OP_MONOID 0 450 800 OP_DEPSET OP_0 OP_IFEQ OP_RETURN OP_ENDIFEQ \
+        OP_2 OP_IFEQ OP_TAKERPAIR 2 OP_MINUNITVALUE OP_RETURN OP_ENDIFEQ OP_3 OP_IFEQ OP_RETURN OP_ENDIFEQ \
+        eth btc 1boatslrhtknngkdxeeobr76b53lettpyt 0.5 10 OP_MAKERCOLL \
+        OP_3 OP_IFEQ OP_BLAKE2BL 350c8702addcb6edda4e028e956ec7e0565f1ffed28df6feca6fa0484ddf61f8 OP_EQ OP_CHECKSIGVERIFY OP_ENDIFEQ \
+        OP_2 OP_IFEQ 1 OP_MINUNITVALUE OP_MONAD OP_BLAKE2BL 350c8702addcb6edda4e028e956ec7e0565f1ffed28df6feca6fa0484ddf61f8 \
+        OP_EQ OP_CHECKSIGVERIFY OP_ENDMONAD OP_ENDIFEQ";
+
+    // createCrossChainTxTakerOutputScript(MAKER_TX_HASH, 1, ADDRESS) in JS
+    const TAKER_SCRIPT: &str = "387a12d284f41d0f7f29f42efd14a232849d4305b6d7b980b448638d8877c129 1 OP_CALLBACK \
+        4 OP_IFEQ OP_BLAKE 350c8702addcb6edda4e028e956ec7e0565f1ffed28df6feca6fa0484ddf61f8 OP_CHECKSIGVERIFY OP_ENDIFEQ \
+        OP_MONAD OP_BLAKE 350c8702addcb6edda4e028e956ec7e0565f1ffed28df6feca6fa0484ddf61f8 OP_CHECKSIG OP_ENDMONAD";
+
+    #[test]
+    fn maker_order_test() {
+        let order = MakerOrder::new(0, 450, 800, "eth", "btc", "1BoatSLRHtKNngkdXEeobR76b53LETtpyT", "0.5", "10", ADDRESS);
+        assert_eq!(order.to_script(), MAKER_SCRIPT);
+
+        let parsed = MakerOrder::parse(MAKER_SCRIPT).unwrap();
+        assert_eq!(parsed, order);
+        assert_eq!(parsed.double_hashed_bc_address, ADDRESS_HASH);
+        assert_eq!(parsed.wants_address, "1boatslrhtknngkdxeeobr76b53lettpyt");
+        assert_eq!(parsed.to_script(), MAKER_SCRIPT);
+    }
+
+    #[test]
+    fn maker_order_errors_test() {
+        assert_eq!(
+            MakerOrder::parse("OP_MONOID 0 450 800 OP_DEPSET"),
+            Err(TemplateError::InvalidTokenCount { expected: 44, found: 5 })
+        );
+        assert_eq!(
+            MakerOrder::parse(&MAKER_SCRIPT.replacen(" 450 ", " 1c2 ", 1)),
+            Err(TemplateError::InvalidNumber("1c2".to_string()))
+        );
+        assert_eq!(MakerOrder::parse(&MAKER_SCRIPT.replacen(" 450 ", " 0450 ", 1)), Err(TemplateError::NotCanonical));
+        assert_eq!(MakerOrder::parse(&MAKER_SCRIPT.replacen("OP_TAKERPAIR", "OP_DROP", 1)), Err(TemplateError::NotCanonical));
+        assert_eq!(MakerOrder::parse(&MAKER_SCRIPT.replacen("1boat", "1Boat", 1)), Err(TemplateError::NotCanonical));
+
+        // both hashes of the maker address have to match
+        let script = MAKER_SCRIPT.replacen(ADDRESS_HASH, MAKER_TX_HASH, 1);
+        assert_eq!(MakerOrder::parse(&script), Err(TemplateError::NotCanonical));
+    }
+
+    #[test]
+    fn taker_order_test() {
+        let order = TakerOrder::new(MAKER_TX_HASH, 1, ADDRESS);
+        assert_eq!(order.to_script(), TAKER_SCRIPT);
+        assert_eq!(order.to_callback_script(), format!("{} 1 OP_CALLBACK", MAKER_TX_HASH));
+        assert_eq!(TakerOrder::parse(TAKER_SCRIPT), Ok(order));
+
+        assert_eq!(
+            TakerOrder::parse(&TAKER_SCRIPT.replacen(" 1 ", " one ", 1)),
+            Err(TemplateError::InvalidNumber("one".to_string()))
+        );
+        assert_eq!(TakerOrder::parse(&TAKER_SCRIPT.replacen(" 4 ", " 3 ", 1)), Err(TemplateError::NotCanonical));
+        assert_eq!(
+            TakerOrder::parse(&format!("{} ", TAKER_SCRIPT)),
+            Err(TemplateError::InvalidTokenCount { expected: 14, found: 15 })
+        );
+    }
+
+    #[test]
+    fn taker_input_test() {
+        let input = TakerInput::parse("1BoatSLRHtKNngkdXEeobR76b53LETtpyT 0xabc").unwrap();
+        assert_eq!(input.wants_address, "1BoatSLRHtKNngkdXEeobR76b53LETtpyT");
+        assert_eq!(input.sends_address, "0xabc");
+        assert_eq!(input.to_script(), "1BoatSLRHtKNngkdXEeobR76b53LETtpyT 0xabc");
+
+        assert_eq!(TakerInput::parse("abc"), Err(TemplateError::InvalidTokenCount { expected: 2, found: 1 }));
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Transaction identification, port of `txHash` and `outPointOutputHash` from src/core/txUtils.es6
+//!
+//! JS joins the `toObject()` values of the messages, bytes fields are therefore hashed in their
+//! base64 form and numbers in decimal.
+pub mod coinbase;
+pub mod cross_chain;
+pub mod validation;
+
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+use coin::{internal_to_human, CoinFrac};
+use protos::core::{OutPoint, Transaction, TransactionInput, TransactionOutput};
+use types::Hash256;
+
+fn input_data(input: &TransactionInput) -> String {
+    let out_point = input.get_out_point();
+    format!(
+        "{}{}{}{}{}",
+        out_point.get_value().to_base64(STANDARD),
+        out_point.get_hash(),
+        out_point.get_index(),
+        input.get_script_length(),
+        input.get_input_script().to_base64(STANDARD)
+    )
+}
+
+fn output_data(output: &TransactionOutput) -> String {
+    format!(
+        "{}{}{}{}",
+        output.get_value().to_base64(STANDARD),
+        output.get_unit().to_base64(STANDARD),
+        output.get_script_length(),
+        output.get_output_script().to_base64(STANDARD)
+    )
+}
+
+fn outputs_data(outputs: &[TransactionOutput]) -> String {
+    outputs.iter().map(output_data).collect()
+}
+
+/// Hash identifying `tx`, blake2bl applied twice over its fields
+pub fn tx_hash(tx: &Transaction) -> Hash256 {
+    let inputs: String = tx.get_inputs().iter().map(input_data).collect();
+    let prehash = Hash256::blake2bl(format!(
+        "{}{}{}{}{}{}{}{}",
+        tx.get_version(),
+        tx.get_nonce(),
+        tx.get_overline(),
+        tx.get_nin_count(),
+        tx.get_nout_count(),
+        tx.get_lock_time(),
+        inputs,
+        outputs_data(tx.get_outputs())
+    ).as_bytes());
+
+    Hash256::blake2bl(prehash.to_hex().as_bytes())
+}
+
+/// Hash of `out_point` together with all `outputs` of the spending transaction
+///
+/// This is the data signed by each input of a transaction (see `generateDataToSignForSig`).
+pub fn out_point_output_hash(out_point: &OutPoint, outputs: &[TransactionOutput]) -> Hash256 {
+    Hash256::blake2bl(format!(
+        "{}{}{}{}",
+        internal_to_human(out_point.get_value(), CoinFrac::Nrg),
+        out_point.get_hash(),
+        out_point.get_index(),
+        outputs_data(outputs)
+    ).as_bytes())
+}
+
+/// `blake2bl(blake2bl(address))` of the lowercased `address`, the form addresses take in output scripts
+pub fn double_hashed_address(address: &str) -> String {
+    let address_hash = Hash256::blake2bl(address.to_lowercase().as_bytes()).to_hex();
+    Hash256::blake2bl(address_hash.as_bytes()).to_hex()
+}
+
+/// Output script locking NRG to `address`, `ScriptTemplates.createNRGOutputLockScript` in JS
+pub fn create_nrg_output_lock_script(address: &str) -> String {
+    format!("OP_BLAKE2BL {} OP_EQUALVERIFY OP_CHECKSIGVERIFY", double_hashed_address(address))
+}
+
+/// `n` empty transactions, `newBlankTxs` in JS
+pub fn new_blank_txs(n: usize) -> Vec<Transaction> {
+    vec![Transaction::new(); n]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigUint;
+
+    use coin::biguint_to_internal;
+    use templates::{load_bc_tx_cb, BC_TX_CB};
+
+    #[test]
+    fn tx_hash_test() {
+        // parity with txHash in JS, the txHash field of the template is from the legacy tx format
+        let tx = load_bc_tx_cb(BC_TX_CB);
+        assert_eq!(tx_hash(&tx).to_string(), "19d0106d87f9e8d72eecc292954198ec12195f297d496b893278757a7dab0857");
+
+        let mut changed = tx.clone();
+        changed.set_lock_time(1);
+        assert_ne!(tx_hash(&changed), tx_hash(&tx));
+    }
+
+    #[test]
+    fn tx_hash_empty_test() {
+        // version, nonce, overline, nin count, nout count and lock time joined
+        let expected = Hash256::blake2bl(Hash256::blake2bl(b"0000").to_hex().as_bytes());
+        assert_eq!(tx_hash(&Transaction::new()), expected);
+    }
+
+    #[test]
+    fn create_nrg_output_lock_script_test() {
+        let script = create_nrg_output_lock_script("0x25CC7722A6378E11082F7967C09B98BD26C979D3");
+        assert_eq!(
+            script,
+            "OP_BLAKE2BL 350c8702addcb6edda4e028e956ec7e0565f1ffed28df6feca6fa0484ddf61f8 OP_EQUALVERIFY OP_CHECKSIGVERIFY"
+        );
+    }
+
+    #[test]
+    fn out_point_output_hash_test() {
+        // parity with outPointOutputHash in JS
+        let tx = load_bc_tx_cb(BC_TX_CB);
+        let mut out_point = tx.get_inputs()[0].get_out_point().clone();
+        out_point.set_value(biguint_to_internal(&BigUint::from(1500000000000000000u64)));
+
+        assert_eq!(
+            out_point_output_hash(&out_point, tx.get_outputs()).to_string(),
+            "aa1c52d22f38ea2193b140eeeacb5b5d568bbd588d98877658d108cbb6414209"
+        );
+    }
+}
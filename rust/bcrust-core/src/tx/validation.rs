@@ -0,0 +1,725 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Transaction validation, port of `isValidTx` from src/primitives/txHandler.es6 and
+//! `validateScripts` from src/core/scriptUtils.es6
+//!
+//! Standard NRG transfer scripts are evaluated natively, inputs spending other outputs are
+//! unlocked by the script interpreter in the environment loaded through `ScriptLookup`.
+//! Referenced outputs are looked up through `UtxoLookup`.
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::str;
+
+use num_bigint::BigUint;
+use rustc_serialize::hex::FromHex;
+
+use coin::internal_to_biguint;
+use crypto::sig::{generate_data_to_sign_for_sig, verify_signature, COMPRESSED_PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use economics::COINBASE_MATURITY;
+use protos::core::{OutPoint, Transaction, TransactionOutput};
+use script::environment::ScriptLookup;
+use script::unlock_input;
+use tx::cross_chain::MakerOrder;
+use types::Hash256;
+
+/// Allows to lock outputs until height of ~ 2.815 * 10^14, see OP_CHECKLOCKTIMEVERIFY
+const MAX_HEIGHT_HEX_LENGTH: usize = 16;
+
+/// Output referenced by an input together with the height it was created at
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnspentOutput {
+    pub output: TransactionOutput,
+    pub height: u64,
+    pub is_coinbase: bool,
+}
+
+/// Source of unspent outputs, `getOutputForInput` of the persistence in JS
+pub trait UtxoLookup {
+    /// Output `out_point` refers to, `None` if it never existed or has already been spent
+    fn get_unspent_output(&self, out_point: &OutPoint) -> Option<UnspentOutput>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TxValidationOptions {
+    /// Position of the transaction in its block, only the first one may be a coinbase
+    pub tx_index: Option<usize>,
+    /// Height of the block including the transaction, enables maturity and lock time checks
+    pub block_height: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxValidationError {
+    InputCountMismatch { expected: u64, found: usize },
+    MissingInputs,
+    OutputCountMismatch { expected: u64, found: usize },
+    MissingOutputs,
+    ScriptLengthMismatch { input: usize, expected: u32, found: usize },
+    NonStandardScript { input: usize },
+    DuplicateOutPoint { input: usize },
+    MissingOutPoint { input: usize, hash: String, index: u64 },
+    ImmatureCoinbase { input: usize, height: u64, mature_at: u64 },
+    LockedOutput { input: usize, height: u64, locked_until: u64 },
+    ScriptFailed { input: usize },
+    OutPointValueMismatch { input: usize, expected: String, found: String },
+    NonPositiveOutput { output: usize },
+    ValueNotConserved { inputs: String, outputs: String },
+    LockTimeNotReached { lock_time: u32, height: u64 },
+}
+
+impl fmt::Display for TxValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TxValidationError::InputCountMismatch { expected, found } =>
+                write!(f, "nin count {} does not match {} inputs", expected, found),
+            TxValidationError::MissingInputs =>
+                write!(f, "only the coinbase transaction may have no inputs"),
+            TxValidationError::OutputCountMismatch { expected, found } =>
+                write!(f, "nout count {} does not match {} outputs", expected, found),
+            TxValidationError::MissingOutputs =>
+                write!(f, "transaction has no outputs"),
+            TxValidationError::ScriptLengthMismatch { input, expected, found } =>
+                write!(f, "input {} script length {} does not match {}", input, found, expected),
+            TxValidationError::NonStandardScript { input } =>
+                write!(f, "input {} script is not standard", input),
+            TxValidationError::DuplicateOutPoint { input } =>
+                write!(f, "input {} spends an outpoint already spent by this transaction", input),
+            TxValidationError::MissingOutPoint { input, ref hash, index } =>
+                write!(f, "input {} references missing or spent output {}.{}", input, hash, index),
+            TxValidationError::ImmatureCoinbase { input, height, mature_at } =>
+                write!(f, "input {} spends coinbase output at height {}, mature at {}", input, height, mature_at),
+            TxValidationError::LockedOutput { input, height, locked_until } =>
+                write!(f, "input {} spends output locked until {} at height {}", input, locked_until, height),
+            TxValidationError::ScriptFailed { input } =>
+                write!(f, "input {} script does not unlock referenced output", input),
+            TxValidationError::OutPointValueMismatch { input, ref expected, ref found } =>
+                write!(f, "input {} outpoint value {} does not match output value {}", input, found, expected),
+            TxValidationError::NonPositiveOutput { output } =>
+                write!(f, "output {} value is not positive", output),
+            TxValidationError::ValueNotConserved { ref inputs, ref outputs } =>
+                write!(f, "outputs value {} exceeds inputs value {}", outputs, inputs),
+            TxValidationError::LockTimeNotReached { lock_time, height } =>
+                write!(f, "lock time {} not reached at height {}", lock_time, height),
+        }
+    }
+}
+
+impl Error for TxValidationError {
+    fn description(&self) -> &str {
+        "transaction validation error"
+    }
+}
+
+fn split_script(script: &[u8]) -> Option<Vec<&str>> {
+    str::from_utf8(script).ok().map(|script| script.split(' ').collect())
+}
+
+fn is_hex(value: &str) -> bool {
+    value.from_hex().is_ok()
+}
+
+/// Checks format of standard NRG transfer input script `<signature> <public key> <address hash>`
+fn is_standard_input_script(parts: &[&str]) -> bool {
+    match *parts {
+        [signature, public_key, _] =>
+            signature.len() == SIGNATURE_LENGTH * 2 && is_hex(signature) &&
+                public_key.len() == COMPRESSED_PUBLIC_KEY_LENGTH * 2 && is_hex(public_key),
+        _ => false,
+    }
+}
+
+/// Checks format of the NRG transfer output script `[<height> OP_CHECKLOCKTIMEVERIFY] OP_DUP <hash opcode> <hash> OP_EQUALVERIFY OP_CHECKSIGVERIFY`
+fn is_lock_script_format(parts: &[&str]) -> bool {
+    let (op, hash) = match *parts {
+        [height, "OP_CHECKLOCKTIMEVERIFY", "OP_DUP", op, hash, "OP_EQUALVERIFY", "OP_CHECKSIGVERIFY"]
+            if height.len() <= MAX_HEIGHT_HEX_LENGTH && is_hex(height) => (op, hash),
+        ["OP_DUP", op, hash, "OP_EQUALVERIFY", "OP_CHECKSIGVERIFY"] => (op, hash),
+        _ => return false,
+    };
+    ["OP_BLAKE2BLC", "OP_BLAKE2BL", "OP_BLAKE2BLS"].contains(&op) && hash.len() == 64 && is_hex(hash)
+}
+
+/// Checks format of an input script, `ScriptTemplates.validateScript` in JS
+///
+/// Accepts taker input scripts of two parts, taker input scripts followed by a maker output
+/// script and the NRG transfer scripts.
+fn is_valid_input_script(parts: &[&str]) -> bool {
+    let marketplace = ["OP_MONOID", "OP_TAKERPAIR", "OP_DEPSET", "OP_MAKERCOLL"];
+    if marketplace.iter().all(|op| parts.iter().any(|part| part.contains(op))) {
+        return parts.len() > 2 && parts[2] == "OP_MONOID" && MakerOrder::parse(&parts[2..].join(" ")).is_ok();
+    }
+
+    match parts.len() {
+        2 => true,
+        3 => is_standard_input_script(parts),
+        _ => is_lock_script_format(parts),
+    }
+}
+
+/// Height the output is locked until by `<height> OP_CHECKLOCKTIMEVERIFY` prefix of `parts`
+///
+/// Returns remaining parts of the script, `None` if the prefix is malformed.
+fn split_lock_height<'a, 'b>(parts: &'a [&'b str]) -> Option<(Option<u64>, &'a [&'b str])> {
+    if parts.len() < 2 || parts[1] != "OP_CHECKLOCKTIMEVERIFY" {
+        return Some((None, parts));
+    }
+
+    let height = parts[0];
+    if height.len() > MAX_HEIGHT_HEX_LENGTH {
+        return None;
+    }
+    u64::from_str_radix(height, 16).ok().map(|height| (Some(height), &parts[2..]))
+}
+
+/// Address hash the standard NRG transfer output script `parts` are locked to
+fn output_address_hash<'a>(parts: &[&'a str]) -> Option<&'a str> {
+    let (_, mut parts) = split_lock_height(parts)?;
+    if parts.first() == Some(&"OP_DUP") {
+        parts = &parts[1..];
+    }
+
+    match *parts {
+        ["OP_BLAKE2BL", hash, "OP_EQUALVERIFY", "OP_CHECKSIGVERIFY"] if hash.len() == 64 && is_hex(hash) => Some(hash),
+        _ => None,
+    }
+}
+
+/// Evaluates `input_script` unlocking `output_script`, `validateScripts` in JS
+///
+/// `data_to_sign` is the hash signed by the input, see `generate_data_to_sign_for_sig`.
+pub fn validate_scripts(input_script: &[u8], output_script: &[u8], data_to_sign: &Hash256) -> bool {
+    let (input_parts, output_parts) = match (split_script(input_script), split_script(output_script)) {
+        (Some(input_parts), Some(output_parts)) => (input_parts, output_parts),
+        _ => return false,
+    };
+
+    if !is_standard_input_script(&input_parts) {
+        return false;
+    }
+
+    let address_hash = match output_address_hash(&output_parts) {
+        Some(address_hash) => address_hash,
+        None => return false,
+    };
+
+    if Hash256::blake2bl(input_parts[2].as_bytes()).to_hex() != address_hash.to_lowercase() {
+        return false;
+    }
+
+    let signature = input_parts[0].from_hex().unwrap_or_default();
+    let public_key = input_parts[1].from_hex().unwrap_or_default();
+    verify_signature(&Hash256::blake2bl(data_to_sign.to_hex().as_bytes()), &signature, &public_key)
+}
+
+/// Checks `tx` against unspent outputs of `utxos`, port of `isValidTx`
+///
+/// Rules are checked in the same order as in JS and the first failing one is returned, value
+/// conservation and lock time which are TODOs in JS are checked last, both do not apply to the
+/// coinbase. Unlike in JS a transaction without inputs is only accepted as the first transaction
+/// of a block. Scripts of inputs which do not spend standard NRG transfer outputs are unlocked in
+/// the environment loaded from `scripts`.
+pub fn is_tx_valid<U: UtxoLookup, L: ScriptLookup>(
+    tx: &Transaction,
+    utxos: &U,
+    scripts: &L,
+    opts: TxValidationOptions,
+) -> Result<(), TxValidationError> {
+    let inputs = tx.get_inputs();
+    if tx.get_nin_count() != inputs.len() as u64 {
+        return Err(TxValidationError::InputCountMismatch { expected: tx.get_nin_count(), found: inputs.len() });
+    }
+    let is_coinbase = inputs.is_empty();
+    if is_coinbase && opts.tx_index != Some(0) {
+        return Err(TxValidationError::MissingInputs);
+    }
+
+    let outputs = tx.get_outputs();
+    if tx.get_nout_count() != outputs.len() as u64 {
+        return Err(TxValidationError::OutputCountMismatch { expected: tx.get_nout_count(), found: outputs.len() });
+    }
+    if outputs.is_empty() {
+        return Err(TxValidationError::MissingOutputs);
+    }
+
+    let mut spent = HashSet::new();
+    let mut inputs_value = BigUint::from(0u64);
+    for (i, input) in inputs.iter().enumerate() {
+        let input_script = input.get_input_script();
+        if input.get_script_length() as usize != input_script.len() {
+            return Err(TxValidationError::ScriptLengthMismatch {
+                input: i,
+                expected: input.get_script_length(),
+                found: input_script.len(),
+            });
+        }
+
+        let input_parts = match split_script(input_script) {
+            Some(parts) if is_valid_input_script(&parts) => parts,
+            _ => return Err(TxValidationError::NonStandardScript { input: i }),
+        };
+
+        let out_point = input.get_out_point();
+        if !spent.insert((out_point.get_hash(), out_point.get_index())) {
+            return Err(TxValidationError::DuplicateOutPoint { input: i });
+        }
+
+        let unspent = utxos.get_unspent_output(out_point).ok_or_else(|| TxValidationError::MissingOutPoint {
+            input: i,
+            hash: out_point.get_hash().to_string(),
+            index: out_point.get_index(),
+        })?;
+
+        if let Some(height) = opts.block_height {
+            let mature_at = unspent.height + COINBASE_MATURITY;
+            if unspent.is_coinbase && height < mature_at {
+                return Err(TxValidationError::ImmatureCoinbase { input: i, height, mature_at });
+            }
+
+            let output_parts = split_script(unspent.output.get_output_script()).unwrap_or_default();
+            if let Some((Some(locked_until), _)) = split_lock_height(&output_parts) {
+                if height < locked_until {
+                    return Err(TxValidationError::LockedOutput { input: i, height, locked_until });
+                }
+            }
+        }
+
+        let output_script = unspent.output.get_output_script();
+        let is_nrg_transfer = is_standard_input_script(&input_parts) &&
+            split_script(output_script).and_then(|parts| output_address_hash(&parts)).is_some();
+        let unlocked = if is_nrg_transfer {
+            validate_scripts(input_script, output_script, &generate_data_to_sign_for_sig(out_point, tx))
+        } else {
+            match (str::from_utf8(input_script), str::from_utf8(output_script)) {
+                (Ok(input_script), Ok(output_script)) =>
+                    unlock_input(scripts, output_script, input_script, input, tx, opts.block_height),
+                _ => false,
+            }
+        };
+        if !unlocked {
+            return Err(TxValidationError::ScriptFailed { input: i });
+        }
+
+        let value = internal_to_biguint(out_point.get_value());
+        let expected = internal_to_biguint(unspent.output.get_value());
+        if value != expected {
+            return Err(TxValidationError::OutPointValueMismatch {
+                input: i,
+                expected: expected.to_string(),
+                found: value.to_string(),
+            });
+        }
+        inputs_value += value;
+    }
+
+    let mut outputs_value = BigUint::from(0u64);
+    for (i, output) in outputs.iter().enumerate() {
+        let value = internal_to_biguint(output.get_value());
+        if value == BigUint::from(0u64) {
+            return Err(TxValidationError::NonPositiveOutput { output: i });
+        }
+        outputs_value += value;
+    }
+
+    if !is_coinbase && outputs_value > inputs_value {
+        return Err(TxValidationError::ValueNotConserved {
+            inputs: inputs_value.to_string(),
+            outputs: outputs_value.to_string(),
+        });
+    }
+
+    // lock time of the coinbase is the height its output matures at
+    if let (false, Some(height)) = (is_coinbase, opts.block_height) {
+        if u64::from(tx.get_lock_time()) > height {
+            return Err(TxValidationError::LockTimeNotReached { lock_time: tx.get_lock_time(), height });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use protobuf::RepeatedField;
+    use rustc_serialize::hex::ToHex;
+
+    use coin::biguint_to_internal;
+    use crypto::sig::{public_key_create, tx_input_signature};
+    use protos::core::{MarkedTransaction, TransactionInput};
+    use tx::create_nrg_output_lock_script;
+
+    const PRIVATE_KEY: &str = "e3b7f1c4a2d5960817f3b2a4c6d8e0f1a3b5c7d9e1f30517a9c2b4d6e8f0a1b2";
+    const ADDRESS: &str = "0x25cc7722a6378e11082f7967c09b98bd26c979d3";
+    const PREVIOUS_TX_HASH: &str = "1f6ab4a2f4bd1e5a0c8a3a3b7cbcb16ae0c9e0e22b6a7b4d53f9f1c9ab5a1d10";
+
+    const MAKER_TX_HASH: &str = "387a12d284f41d0f7f29f42efd14a232849d4305b6d7b980b448638d8877c129";
+    const TAKER_TX_HASH: &str = "9120c8bafedecc2b720e3accdc94f8810e54f7b1734cfc8470dc3cfe7c2f9805";
+
+    struct TestUtxos(HashMap<(String, u64), UnspentOutput>);
+
+    /// Transactions of stored blocks with the height of their block
+    struct TestTxs(HashMap<String, (Transaction, u64)>);
+
+    struct NoTxs;
+
+    impl ScriptLookup for TestTxs {
+        fn get_transaction(&self, hash: &str) -> Option<(Transaction, u64)> {
+            self.0.get(hash).cloned()
+        }
+
+        fn get_latest_height(&self) -> u64 {
+            unreachable!()
+        }
+
+        fn get_marked_txs(&self, _from: u64, _to: u64) -> Vec<MarkedTransaction> {
+            vec![]
+        }
+    }
+
+    impl ScriptLookup for NoTxs {
+        fn get_transaction(&self, _hash: &str) -> Option<(Transaction, u64)> {
+            None
+        }
+
+        fn get_latest_height(&self) -> u64 {
+            unreachable!()
+        }
+
+        fn get_marked_txs(&self, _from: u64, _to: u64) -> Vec<MarkedTransaction> {
+            unreachable!()
+        }
+    }
+
+    impl UtxoLookup for TestUtxos {
+        fn get_unspent_output(&self, out_point: &OutPoint) -> Option<UnspentOutput> {
+            self.0.get(&(out_point.get_hash().to_string(), out_point.get_index())).cloned()
+        }
+    }
+
+    fn nrg(value: u64) -> Vec<u8> {
+        biguint_to_internal(&(BigUint::from(value) * BigUint::from(1000000000000000000u64)))
+    }
+
+    fn address_hash(address: &str) -> String {
+        Hash256::blake2bl(address.as_bytes()).to_hex()
+    }
+
+    /// Output script of `createNRGOutputLockScript`
+    fn output(value: u64, address: &str) -> TransactionOutput {
+        let script = create_nrg_output_lock_script(address);
+        let mut output = TransactionOutput::new();
+        output.set_value(nrg(value));
+        output.set_unit(vec![1]);
+        output.set_script_length(script.len() as u32);
+        output.set_output_script(script.into_bytes());
+        output
+    }
+
+    fn utxos(is_coinbase: bool) -> TestUtxos {
+        let mut utxos = HashMap::new();
+        for index in 0..2 {
+            let unspent = UnspentOutput { output: output(5, ADDRESS), height: 1000, is_coinbase };
+            utxos.insert((PREVIOUS_TX_HASH.to_string(), index), unspent);
+        }
+        TestUtxos(utxos)
+    }
+
+    fn set_input_script(input: &mut TransactionInput, script: &str) {
+        input.set_script_length(script.len() as u32);
+        input.set_input_script(script.as_bytes().to_vec());
+    }
+
+    /// Signs inputs of `tx` as `txCreateNRGTransfer` in JS, outputs have to be set already
+    fn sign_inputs(tx: &mut Transaction) {
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let public_key = public_key_create(&private_key).unwrap().to_hex();
+        let mut inputs = tx.get_inputs().to_vec();
+        for input in inputs.iter_mut() {
+            let signature = tx_input_signature(input.get_out_point(), tx, &private_key).unwrap();
+            set_input_script(input, &format!("{} {} {}", signature.to_hex(), public_key, address_hash(ADDRESS)));
+        }
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+    }
+
+    /// Spends both 5 NRG outputs, sending 7 NRG and 2 NRG of change, 1 NRG is the fee
+    fn valid_tx() -> Transaction {
+        let inputs: Vec<TransactionInput> = (0..2)
+            .map(|index| {
+                let mut out_point = OutPoint::new();
+                out_point.set_value(nrg(5));
+                out_point.set_hash(PREVIOUS_TX_HASH.to_string());
+                out_point.set_index(index);
+                let mut input = TransactionInput::new();
+                input.set_out_point(out_point);
+                input
+            })
+            .collect();
+
+        let mut tx = Transaction::new();
+        tx.set_version(1);
+        tx.set_nonce("nonce".to_string());
+        tx.set_nin_count(2);
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx.set_nout_count(2);
+        tx.set_outputs(RepeatedField::from_vec(vec![output(7, "0xea674fdde714fd979de3edf0f56aa9716b898ec8"), output(2, ADDRESS)]));
+        tx.set_lock_time(1201);
+        sign_inputs(&mut tx);
+        tx
+    }
+
+    fn opts() -> TxValidationOptions {
+        TxValidationOptions { tx_index: Some(1), block_height: Some(1201) }
+    }
+
+    #[test]
+    fn is_tx_valid_test() {
+        let tx = valid_tx();
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Ok(()));
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, TxValidationOptions::default()), Ok(()));
+        assert_eq!(is_tx_valid(&tx, &utxos(true), &NoTxs, opts()), Ok(()));
+    }
+
+    #[test]
+    fn is_tx_valid_structure_test() {
+        let mut tx = valid_tx();
+        tx.set_nin_count(3);
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::InputCountMismatch { expected: 3, found: 2 }));
+
+        let mut tx = valid_tx();
+        tx.set_nout_count(1);
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::OutputCountMismatch { expected: 1, found: 2 }));
+
+        let mut tx = valid_tx();
+        tx.set_nout_count(0);
+        tx.clear_outputs();
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::MissingOutputs));
+
+        let mut coinbase = valid_tx();
+        coinbase.set_nin_count(0);
+        coinbase.clear_inputs();
+        assert_eq!(is_tx_valid(&coinbase, &utxos(false), &NoTxs, opts()), Err(TxValidationError::MissingInputs));
+        let coinbase_opts = TxValidationOptions { tx_index: Some(0), ..opts() };
+        assert_eq!(is_tx_valid(&coinbase, &utxos(false), &NoTxs, coinbase_opts), Ok(()));
+
+        let mut tx = valid_tx();
+        tx.mut_inputs()[1].set_script_length(3);
+        assert_eq!(
+            is_tx_valid(&tx, &utxos(false), &NoTxs, opts()),
+            Err(TxValidationError::ScriptLengthMismatch { input: 1, expected: 3, found: 262 })
+        );
+
+        let mut tx = valid_tx();
+        set_input_script(&mut tx.mut_inputs()[0], "OP_1");
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::NonStandardScript { input: 0 }));
+    }
+
+    #[test]
+    fn is_tx_valid_outpoints_test() {
+        let mut tx = valid_tx();
+        tx.mut_inputs()[1].mut_out_point().set_index(0);
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::DuplicateOutPoint { input: 1 }));
+
+        let mut tx = valid_tx();
+        tx.mut_inputs()[1].mut_out_point().set_index(2);
+        assert_eq!(
+            is_tx_valid(&tx, &utxos(false), &NoTxs, opts()),
+            Err(TxValidationError::MissingOutPoint { input: 1, hash: PREVIOUS_TX_HASH.to_string(), index: 2 })
+        );
+
+        let immature = TxValidationOptions { block_height: Some(1099), ..opts() };
+        assert_eq!(
+            is_tx_valid(&valid_tx(), &utxos(true), &NoTxs, immature),
+            Err(TxValidationError::ImmatureCoinbase { input: 0, height: 1099, mature_at: 1100 })
+        );
+        assert_eq!(
+            is_tx_valid(&valid_tx(), &utxos(false), &NoTxs, immature),
+            Err(TxValidationError::LockTimeNotReached { lock_time: 1201, height: 1099 })
+        );
+
+        // outpoint value is part of the signed data, the input has to be signed again
+        let mut tx = valid_tx();
+        tx.mut_inputs()[0].mut_out_point().set_value(nrg(6));
+        sign_inputs(&mut tx);
+        assert_eq!(
+            is_tx_valid(&tx, &utxos(false), &NoTxs, opts()),
+            Err(TxValidationError::OutPointValueMismatch {
+                input: 0,
+                expected: "5000000000000000000".to_string(),
+                found: "6000000000000000000".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn is_tx_valid_signatures_test() {
+        // outputs are signed by every input
+        let mut tx = valid_tx();
+        tx.mut_outputs()[0].set_value(nrg(6));
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::ScriptFailed { input: 0 }));
+
+        // signature of the first input used for the second one
+        let mut tx = valid_tx();
+        let first_script = String::from_utf8(tx.get_inputs()[0].get_input_script().to_vec()).unwrap();
+        set_input_script(&mut tx.mut_inputs()[1], &first_script);
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::ScriptFailed { input: 1 }));
+
+        // outputs locked to another address
+        let mut other = HashMap::new();
+        for index in 0..2 {
+            let unspent = UnspentOutput { output: output(5, "0xother"), height: 1000, is_coinbase: false };
+            other.insert((PREVIOUS_TX_HASH.to_string(), index), unspent);
+        }
+        assert_eq!(is_tx_valid(&valid_tx(), &TestUtxos(other), &NoTxs, opts()), Err(TxValidationError::ScriptFailed { input: 0 }));
+    }
+
+    #[test]
+    fn is_tx_valid_values_test() {
+        let mut tx = valid_tx();
+        tx.mut_outputs()[1].set_value(Vec::new());
+        sign_inputs(&mut tx);
+        assert_eq!(is_tx_valid(&tx, &utxos(false), &NoTxs, opts()), Err(TxValidationError::NonPositiveOutput { output: 1 }));
+
+        let mut tx = valid_tx();
+        tx.mut_outputs()[1].set_value(nrg(4));
+        sign_inputs(&mut tx);
+        assert_eq!(
+            is_tx_valid(&tx, &utxos(false), &NoTxs, opts()),
+            Err(TxValidationError::ValueNotConserved {
+                inputs: "10000000000000000000".to_string(),
+                outputs: "11000000000000000000".to_string(),
+            })
+        );
+
+        let mut tx = valid_tx();
+        tx.set_lock_time(1202);
+        assert_eq!(
+            is_tx_valid(&tx, &utxos(false), &NoTxs, opts()),
+            Err(TxValidationError::LockTimeNotReached { lock_time: 1202, height: 1201 })
+        );
+    }
+
+    #[test]
+    fn validate_scripts_lock_height_test() {
+        let tx = valid_tx();
+        let input = &tx.get_inputs()[0];
+        let data_to_sign = generate_data_to_sign_for_sig(input.get_out_point(), &tx);
+        let hash = Hash256::blake2bl(address_hash(ADDRESS).as_bytes());
+
+        let locked = format!("4b1 OP_CHECKLOCKTIMEVERIFY OP_DUP OP_BLAKE2BL {} OP_EQUALVERIFY OP_CHECKSIGVERIFY", hash);
+        assert!(validate_scripts(input.get_input_script(), locked.as_bytes(), &data_to_sign));
+        let unsupported = format!("OP_DUP OP_BLAKE2BLS {} OP_EQUALVERIFY OP_CHECKSIGVERIFY", hash);
+        assert!(!validate_scripts(input.get_input_script(), unsupported.as_bytes(), &data_to_sign));
+
+        let mut utxos = utxos(false);
+        for unspent in utxos.0.values_mut() {
+            unspent.output.set_output_script(locked.clone().into_bytes());
+        }
+        assert_eq!(is_tx_valid(&tx, &utxos, &NoTxs, opts()), Ok(()));
+        let early = TxValidationOptions { block_height: Some(1200), ..opts() };
+        assert_eq!(
+            is_tx_valid(&tx, &utxos, &NoTxs, early),
+            Err(TxValidationError::LockedOutput { input: 0, height: 1200, locked_until: 1201 })
+        );
+    }
+
+    fn script_output(value: u64, script: &str) -> TransactionOutput {
+        let mut output = TransactionOutput::new();
+        output.set_value(nrg(value));
+        output.set_unit(vec![1]);
+        output.set_script_length(script.len() as u32);
+        output.set_output_script(script.as_bytes().to_vec());
+        output
+    }
+
+    /// Spends output 0 of 5 NRG of transaction `hash` with a taker input script of two parts
+    fn spending_tx(hash: &str, outputs: Vec<TransactionOutput>) -> Transaction {
+        let mut out_point = OutPoint::new();
+        out_point.set_value(nrg(5));
+        out_point.set_hash(hash.to_string());
+        out_point.set_index(0);
+        let mut input = TransactionInput::new();
+        input.set_out_point(out_point);
+
+        let mut tx = Transaction::new();
+        tx.set_nin_count(1);
+        tx.set_nout_count(outputs.len() as u64);
+        tx.set_outputs(RepeatedField::from_vec(outputs));
+        let private_key = PRIVATE_KEY.from_hex().unwrap();
+        let signature = tx_input_signature(input.get_out_point(), &tx, &private_key).unwrap();
+        set_input_script(&mut input, &format!("{} {}", signature.to_hex(), address_hash(ADDRESS)));
+        tx.set_inputs(RepeatedField::from_vec(vec![input]));
+        tx
+    }
+
+    /// Maker output settled after 20 blocks and the taker output calling back to it
+    fn marketplace() -> (TestUtxos, TestTxs) {
+        let maker_script = format!(
+            "OP_MONOID 0 10 20 OP_DEPSET OP_1 OP_EQUALVERIFY OP_BLAKE2BL {} OP_EQUALVERIFY OP_CHECKSIGNOPUBKEYVERIFY",
+            address_hash(&address_hash(ADDRESS))
+        );
+        let taker_script = format!("{} 0 OP_CALLBACK 1 OP_VERIFY", MAKER_TX_HASH);
+        let mut maker_tx = Transaction::new();
+        maker_tx.set_outputs(RepeatedField::from_vec(vec![script_output(5, &maker_script)]));
+        let mut taker_tx = Transaction::new();
+        taker_tx.set_outputs(RepeatedField::from_vec(vec![script_output(5, &taker_script)]));
+
+        let mut utxos = HashMap::new();
+        let mut txs = HashMap::new();
+        for &(hash, ref tx) in &[(MAKER_TX_HASH, maker_tx), (TAKER_TX_HASH, taker_tx)] {
+            let unspent = UnspentOutput { output: tx.get_outputs()[0].clone(), height: 1000, is_coinbase: false };
+            utxos.insert((hash.to_string(), 0), unspent);
+            txs.insert(hash.to_string(), (tx.clone(), 1000));
+        }
+        (TestUtxos(utxos), TestTxs(txs))
+    }
+
+    #[test]
+    fn is_tx_valid_maker_output_test() {
+        let (utxos, txs) = marketplace();
+        let callback = format!("{} 0 OP_CALLBACK 1 OP_VERIFY", MAKER_TX_HASH);
+        let tx = spending_tx(MAKER_TX_HASH, vec![script_output(5, &callback)]);
+        assert_eq!(is_tx_valid(&tx, &utxos, &txs, opts()), Ok(()));
+
+        // the outputs calling back have to pay the value of the maker output
+        let tx = spending_tx(MAKER_TX_HASH, vec![script_output(4, &callback), script_output(1, "OP_DROP")]);
+        assert_eq!(is_tx_valid(&tx, &utxos, &txs, opts()), Err(TxValidationError::ScriptFailed { input: 0 }));
+        // before the settlement height
+        let early = TxValidationOptions { block_height: Some(1019), ..opts() };
+        let tx = spending_tx(MAKER_TX_HASH, vec![script_output(5, &callback)]);
+        assert_eq!(is_tx_valid(&tx, &utxos, &txs, early), Err(TxValidationError::ScriptFailed { input: 0 }));
+        // the stored transaction is needed to evaluate OP_MONOID
+        assert_eq!(is_tx_valid(&tx, &utxos, &NoTxs, opts()), Err(TxValidationError::ScriptFailed { input: 0 }));
+    }
+
+    #[test]
+    fn is_tx_valid_taker_output_test() {
+        let (utxos, txs) = marketplace();
+        let callback = format!("{} 0 OP_CALLBACK 1 OP_VERIFY", MAKER_TX_HASH);
+        let tx = spending_tx(TAKER_TX_HASH, vec![script_output(5, &callback)]);
+        assert_eq!(is_tx_valid(&tx, &utxos, &txs, opts()), Ok(()));
+
+        let tx = spending_tx(TAKER_TX_HASH, vec![script_output(5, "1 OP_VERIFY")]);
+        assert_eq!(is_tx_valid(&tx, &utxos, &txs, opts()), Err(TxValidationError::ScriptFailed { input: 0 }));
+    }
+
+    #[test]
+    fn is_tx_valid_cross_chain_template_test() {
+        // the JS maker template does not lex, its inputs are evaluated and fail instead of being non-standard
+        let maker = MakerOrder::new(0, 450, 800, "eth", "btc", "1BoatSLRHtKNngkdXEeobR76b53LETtpyT", "0.5", "10", ADDRESS);
+        let mut utxos = HashMap::new();
+        let unspent = UnspentOutput { output: script_output(5, &maker.to_script()), height: 1000, is_coinbase: false };
+        utxos.insert((MAKER_TX_HASH.to_string(), 0), unspent);
+        let tx = spending_tx(MAKER_TX_HASH, vec![script_output(5, "1 OP_VERIFY")]);
+        assert_eq!(is_tx_valid(&tx, &TestUtxos(utxos), &NoTxs, opts()), Err(TxValidationError::ScriptFailed { input: 0 }));
+
+        let taker_input = format!("ab cd {}", maker.to_script());
+        assert!(is_valid_input_script(&taker_input.split(' ').collect::<Vec<_>>()));
+        assert!(!is_valid_input_script(&format!("ab cd OP_DROP {}", maker.to_script()).split(' ').collect::<Vec<_>>()));
+        assert!(is_valid_input_script(&["ab", "cd"]));
+    }
+}
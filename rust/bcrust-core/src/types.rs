@@ -0,0 +1,269 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Typed hashes, difficulties and distances
+//!
+//! Protobuf messages carry these values as strings, convert them using `FromStr` / `Display`
+//! when reading from or writing to the messages and use the typed values everywhere else.
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::ops::BitXor;
+use std::str::FromStr;
+
+use blake2_rfc::blake2b::{blake2b};
+use num_bigint::{BigInt, BigUint};
+use rustc_serialize::hex::{FromHex, ToHex};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    InvalidHex(String),
+    InvalidLength(usize),
+    InvalidDecimal(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::InvalidHex(ref value) => write!(f, "invalid hex string '{}'", value),
+            ParseError::InvalidLength(len) => write!(f, "invalid hash length {}, expected {} bytes", len, Hash256::LENGTH),
+            ParseError::InvalidDecimal(ref value) => write!(f, "invalid decimal number '{}'", value),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseError::InvalidHex(_) => "invalid hex string",
+            ParseError::InvalidLength(_) => "invalid hash length",
+            ParseError::InvalidDecimal(_) => "invalid decimal number",
+        }
+    }
+}
+
+/// 256 bit hash, always formatted as lower case hex
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Hash256([u8; 32]);
+
+impl Hash256 {
+    pub const LENGTH: usize = 32;
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Hash256 {
+        Hash256(bytes)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Hash256, ParseError> {
+        if bytes.len() != Hash256::LENGTH {
+            return Err(ParseError::InvalidLength(bytes.len()));
+        }
+
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(bytes);
+        Ok(Hash256(hash))
+    }
+
+    /// Hash of `data` computed as in `blake2bl` from src/utils/crypto.es6 (last 32 bytes of blake2b-512)
+    pub fn blake2bl(data: &[u8]) -> Hash256 {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&blake2b(64, &[], data).as_bytes()[32..64]);
+        Hash256(hash)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.to_hex()
+    }
+
+    /// Unsigned big endian integer value of the hash, `new BN(hash, 16)` in JS
+    pub fn to_biguint(&self) -> BigUint {
+        BigUint::from_bytes_be(&self.0)
+    }
+}
+
+impl FromStr for Hash256 {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Hash256, ParseError> {
+        if s.len() != Hash256::LENGTH * 2 {
+            return Err(ParseError::InvalidLength(s.len() / 2));
+        }
+
+        let bytes = s.from_hex().map_err(|_| ParseError::InvalidHex(s.to_string()))?;
+        Hash256::from_slice(&bytes)
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hash256({})", self.to_hex())
+    }
+}
+
+impl BitXor for Hash256 {
+    type Output = Hash256;
+
+    fn bitxor(self, rhs: Hash256) -> Hash256 {
+        let mut hash = self.0;
+        for (a, b) in hash.iter_mut().zip(rhs.0.iter()) {
+            *a ^= *b;
+        }
+        Hash256(hash)
+    }
+}
+
+impl<'a> BitXor<&'a Hash256> for &'a Hash256 {
+    type Output = Hash256;
+
+    fn bitxor(self, rhs: &Hash256) -> Hash256 {
+        *self ^ *rhs
+    }
+}
+
+fn parse_decimal(s: &str) -> Result<BigUint, ParseError> {
+    // BigUint::from_str also accepts sign and separators, consensus values are plain digits only
+    if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(ParseError::InvalidDecimal(s.to_string()));
+    }
+
+    BigUint::from_str(s).map_err(|_| ParseError::InvalidDecimal(s.to_string()))
+}
+
+macro_rules! decimal_newtype {
+    ($name:ident) => {
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+        pub struct $name(BigUint);
+
+        impl $name {
+            pub fn as_biguint(&self) -> &BigUint {
+                &self.0
+            }
+
+            pub fn to_bigint(&self) -> BigInt {
+                BigInt::from(self.0.clone())
+            }
+
+            /// Converts signed intermediate result back, `None` if `value` is negative
+            pub fn from_bigint(value: &BigInt) -> Option<$name> {
+                value.to_biguint().map($name)
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(value: u64) -> $name {
+                $name(BigUint::from(value))
+            }
+        }
+
+        impl From<BigUint> for $name {
+            fn from(value: BigUint) -> $name {
+                $name(value)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseError;
+
+            fn from_str(s: &str) -> Result<$name, ParseError> {
+                parse_decimal(s).map($name)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    }
+}
+
+decimal_newtype!(Difficulty);
+decimal_newtype!(Distance);
+
+impl PartialEq<Difficulty> for Distance {
+    fn eq(&self, other: &Difficulty) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl PartialOrd<Difficulty> for Distance {
+    fn partial_cmp(&self, other: &Difficulty) -> Option<Ordering> {
+        Some(self.0.cmp(&other.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_A: &str = "9b80fc5cba6238801d745ca139ec639924d27ed004c22609d6d9409f1221b8ce";
+    const HASH_B: &str = "781ff33f4d7d36b3f599d8125fd74ed37e2a1564ddc3f06fb22e1b0bf668a4f7";
+    const HASH_A_XOR_B: &str = "e39f0f63f71f0e33e8ed84b3663b2d4a5af86bb4d901d66664f75b94e4491c39";
+
+    #[test]
+    fn hash256_parse_test() {
+        let hash: Hash256 = HASH_A.parse().unwrap();
+        assert_eq!(hash.to_string(), HASH_A);
+
+        let upper: Hash256 = HASH_A.to_uppercase().parse().unwrap();
+        assert_eq!(upper, hash);
+        assert_eq!(upper.to_string(), HASH_A);
+
+        assert_eq!("abcd".parse::<Hash256>(), Err(ParseError::InvalidLength(2)));
+        assert!(HASH_A.replace("9b", "zz").parse::<Hash256>().is_err());
+    }
+
+    #[test]
+    fn hash256_xor_test() {
+        let a: Hash256 = HASH_A.parse().unwrap();
+        let b: Hash256 = HASH_B.parse().unwrap();
+
+        assert_eq!((a ^ b).to_string(), HASH_A_XOR_B);
+        assert_eq!(a ^ Hash256::default(), a);
+    }
+
+    #[test]
+    fn hash256_blake2bl_test() {
+        assert_eq!(
+            Hash256::blake2bl(b"").to_string(),
+            "d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce"
+        );
+    }
+
+    #[test]
+    fn difficulty_parse_test() {
+        let difficulty: Difficulty = "290112262029015".parse().unwrap();
+        assert_eq!(difficulty, Difficulty::from(290112262029015));
+        assert_eq!(difficulty.to_string(), "290112262029015");
+
+        assert!("".parse::<Difficulty>().is_err());
+        assert!("+1".parse::<Difficulty>().is_err());
+        assert!("-1".parse::<Difficulty>().is_err());
+        assert!("1_000".parse::<Difficulty>().is_err());
+    }
+
+    #[test]
+    fn difficulty_from_bigint_test() {
+        assert_eq!(Difficulty::from_bigint(&BigInt::from(42)), Some(Difficulty::from(42)));
+        assert_eq!(Difficulty::from_bigint(&BigInt::from(-42)), None);
+    }
+
+    #[test]
+    fn distance_difficulty_cmp_test() {
+        let distance: Distance = "28675559808772362000".parse().unwrap();
+        assert!(distance > Difficulty::from(311513444441702));
+        assert!(Distance::from(5) < Difficulty::from(6));
+        assert!(Distance::from(6) == Difficulty::from(6));
+    }
+}
@@ -0,0 +1,916 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Set of unspent transaction outputs, port of src/primitives/utxoPool.es6 and the undo coins of
+//! src/coins
+//!
+//! Blocks are applied and reverted as a whole. Applying a block returns the `BlockUndo` record
+//! which restores the outputs it spent when the block gets disconnected in a reorg. The set is
+//! persisted with one key per unspent output, `utxo.<hash>.<index>`, and its best block under
+//! `utxo.latest`, so a block only writes the outputs it changes. Both are JSON in the `DbValue`
+//! envelope of `store::codec`. Undo records are encoded to bytes to be persisted next to the
+//! blocks.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str;
+
+use num_bigint::BigUint;
+use protobuf::{self, Message};
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{Json, Object};
+
+use coin::internal_to_biguint;
+use economics::COINBASE_MATURITY;
+use protos::core::{BcBlock, OutPoint, TransactionOutput};
+use script::environment::ScriptLookup;
+use store::codec::{encode_native, Codec};
+use store::WriteOp;
+use tx::cross_chain::{MakerOrder, TakerOrder};
+use tx::double_hashed_address;
+use tx::validation::{UnspentOutput, UtxoLookup};
+use types::Hash256;
+
+/// Reference to an output of a transaction, `UTXO` in JS
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Utxo {
+    pub tx_hash: String,
+    pub index: u64,
+}
+
+impl Utxo {
+    pub fn new(tx_hash: &str, index: u64) -> Utxo {
+        Utxo { tx_hash: tx_hash.to_string(), index }
+    }
+
+    pub fn from_out_point(out_point: &OutPoint) -> Utxo {
+        Utxo::new(out_point.get_hash(), out_point.get_index())
+    }
+}
+
+impl fmt::Display for Utxo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.tx_hash, self.index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtxoError {
+    NotConnected { best_block_hash: String, previous_hash: String },
+    MissingOutput(Utxo),
+    DuplicateOutput(Utxo),
+    UndoMismatch { best_block_hash: String, undo_hash: String },
+    MissingTransaction(String),
+    InvalidTradeScript(Utxo),
+    InvalidEncoding,
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UtxoError::NotConnected { ref best_block_hash, ref previous_hash } =>
+                write!(f, "block with previous hash {} does not follow best block {}", previous_hash, best_block_hash),
+            UtxoError::MissingOutput(ref utxo) => write!(f, "output {} is missing or spent", utxo),
+            UtxoError::DuplicateOutput(ref utxo) => write!(f, "output {} already exists", utxo),
+            UtxoError::UndoMismatch { ref best_block_hash, ref undo_hash } =>
+                write!(f, "undo record of block {} does not revert best block {}", undo_hash, best_block_hash),
+            UtxoError::MissingTransaction(ref hash) => write!(f, "transaction {} is missing", hash),
+            UtxoError::InvalidTradeScript(ref utxo) => write!(f, "output {} has an invalid trade script", utxo),
+            UtxoError::InvalidEncoding => write!(f, "invalid encoding"),
+        }
+    }
+}
+
+impl Error for UtxoError {
+    fn description(&self) -> &str {
+        "utxo set error"
+    }
+}
+
+/// Changes of the set done by a block, `UndoCoins` in JS
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockUndo {
+    pub hash: String,
+    /// Best block hash of the set before the block was applied
+    pub previous_hash: String,
+    /// Height of the set before the block was applied
+    pub previous_height: u64,
+    /// Outputs created by the block, in order of creation
+    pub created: Vec<Utxo>,
+    /// Outputs spent by the block, in order of spending
+    pub spent: Vec<(Utxo, UnspentOutput)>,
+}
+
+impl BlockUndo {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, UtxoError> {
+        let mut buf = Vec::new();
+        write_bytes(&mut buf, self.hash.as_bytes());
+        write_bytes(&mut buf, self.previous_hash.as_bytes());
+        write_u64(&mut buf, self.previous_height);
+        write_u64(&mut buf, self.created.len() as u64);
+        for utxo in &self.created {
+            write_utxo(&mut buf, utxo);
+        }
+        write_u64(&mut buf, self.spent.len() as u64);
+        for (utxo, unspent) in &self.spent {
+            write_utxo(&mut buf, utxo);
+            write_unspent_output(&mut buf, unspent)?;
+        }
+        Ok(buf)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockUndo, UtxoError> {
+        let mut reader = Reader { bytes, position: 0 };
+        let hash = reader.read_string()?;
+        let previous_hash = reader.read_string()?;
+        let previous_height = reader.read_u64()?;
+        let created = (0..reader.read_u64()?).map(|_| reader.read_utxo()).collect::<Result<_, _>>()?;
+        let spent = (0..reader.read_u64()?)
+            .map(|_| Ok((reader.read_utxo()?, reader.read_unspent_output()?)))
+            .collect::<Result<_, _>>()?;
+        reader.finish()?;
+        Ok(BlockUndo { hash, previous_hash, previous_height, created, spent })
+    }
+}
+
+/// NRG balance of an address, see `getBalanceData` in src/bc/wallet.es6
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Balance {
+    /// Spendable outputs
+    pub confirmed: BigUint,
+    /// Coinbase outputs which are not mature yet
+    pub unconfirmed: BigUint,
+    /// Maker outputs of trades which are not settled yet
+    pub collateralized: BigUint,
+}
+
+/// Whether `script` refers to `address` in plain, hashed or double hashed form, `hasAddressInScript` in JS
+fn has_address_in_script(script: &str, address: &str) -> bool {
+    let hash = Hash256::blake2bl(address.as_bytes()).to_hex();
+    script.contains(address) || script.contains(&hash) || script.contains(&double_hashed_address(address))
+}
+
+/// Settlement of trades on the bridged chains, `getTxSettleInfo` of the `UnsettledTxManager` in JS
+pub trait SettleLookup {
+    /// If the maker or taker output `utxo` has been settled
+    fn is_settled(&self, utxo: &Utxo) -> bool;
+}
+
+const UTXO_KEY_PREFIX: &str = "utxo";
+
+pub fn utxo_key(utxo: &Utxo) -> String {
+    format!("{}.{}", UTXO_KEY_PREFIX, utxo)
+}
+
+pub fn utxo_latest_key() -> String {
+    format!("{}.latest", UTXO_KEY_PREFIX)
+}
+
+/// Output script of output `index` of transaction `hash`, `getTransactionByHash` in JS
+fn output_script<L: ScriptLookup>(txs: &L, hash: &str, index: u64) -> Result<String, UtxoError> {
+    let (tx, _) = txs.get_transaction(hash).ok_or_else(|| UtxoError::MissingTransaction(hash.to_string()))?;
+    let output = tx.get_outputs().get(index as usize).ok_or_else(|| UtxoError::MissingOutput(Utxo::new(hash, index)))?;
+    str::from_utf8(output.get_output_script())
+        .map(|script| script.to_string())
+        .map_err(|_| UtxoError::InvalidTradeScript(Utxo::new(hash, index)))
+}
+
+/// Follows the callbacks of `script`, the output script of `utxo`, to the maker output script
+/// with its order
+///
+/// JS reads the parent outpoint from the first two words of a script ending with `OP_CALLBACK`.
+fn resolve_maker_order<L: ScriptLookup>(utxo: &Utxo, script: String, txs: &L) -> Result<(String, MakerOrder), UtxoError> {
+    let mut utxo = utxo.clone();
+    let mut script = script;
+    while script.ends_with("OP_CALLBACK") {
+        let parent = {
+            let mut words = script.split(' ');
+            match (words.next(), words.next().and_then(|index| index.parse().ok())) {
+                (Some(hash), Some(index)) => Utxo::new(hash, index),
+                _ => return Err(UtxoError::InvalidTradeScript(utxo)),
+            }
+        };
+        script = output_script(txs, &parent.tx_hash, parent.index)?;
+        utxo = parent;
+    }
+    match MakerOrder::parse(&script) {
+        Ok(order) => Ok((script, order)),
+        Err(_) => Err(UtxoError::InvalidTradeScript(utxo)),
+    }
+}
+
+/// Unspent outputs with the best block they are unspent at, `UTXOPool` in JS
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UtxoSet {
+    outputs: HashMap<Utxo, UnspentOutput>,
+    best_block_hash: String,
+    height: u64,
+}
+
+impl UtxoSet {
+    /// Empty set, the first applied block is not checked to follow any other block
+    pub fn new() -> UtxoSet {
+        UtxoSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    /// Hash of the last applied block, empty before any block was applied
+    pub fn best_block_hash(&self) -> &str {
+        &self.best_block_hash
+    }
+
+    /// Height of the last applied block
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn get(&self, utxo: &Utxo) -> Option<&UnspentOutput> {
+        self.outputs.get(utxo)
+    }
+
+    pub fn contains(&self, utxo: &Utxo) -> bool {
+        self.outputs.contains_key(utxo)
+    }
+
+    /// Spends inputs and adds outputs of all transactions of `block`
+    ///
+    /// Transactions are applied in order so an output can be spent later in the same block. The
+    /// set is left untouched if the block does not apply.
+    pub fn apply_block(&mut self, block: &BcBlock) -> Result<BlockUndo, UtxoError> {
+        if !self.best_block_hash.is_empty() && block.get_previous_hash() != self.best_block_hash {
+            return Err(UtxoError::NotConnected {
+                best_block_hash: self.best_block_hash.clone(),
+                previous_hash: block.get_previous_hash().to_string(),
+            });
+        }
+
+        let mut undo = BlockUndo {
+            hash: block.get_hash().to_string(),
+            previous_hash: self.best_block_hash.clone(),
+            previous_height: self.height,
+            created: Vec::new(),
+            spent: Vec::new(),
+        };
+        if let Err(err) = self.apply_txs(block, &mut undo) {
+            self.undo_changes(&undo);
+            return Err(err);
+        }
+
+        self.best_block_hash = block.get_hash().to_string();
+        self.height = block.get_height();
+        Ok(undo)
+    }
+
+    fn apply_txs(&mut self, block: &BcBlock, undo: &mut BlockUndo) -> Result<(), UtxoError> {
+        for tx in block.get_txs() {
+            for input in tx.get_inputs() {
+                let utxo = Utxo::from_out_point(input.get_out_point());
+                match self.outputs.remove(&utxo) {
+                    Some(unspent) => undo.spent.push((utxo, unspent)),
+                    None => return Err(UtxoError::MissingOutput(utxo)),
+                }
+            }
+
+            let is_coinbase = tx.get_inputs().is_empty();
+            for (index, output) in tx.get_outputs().iter().enumerate() {
+                let utxo = Utxo::new(tx.get_hash(), index as u64);
+                if self.outputs.contains_key(&utxo) {
+                    return Err(UtxoError::DuplicateOutput(utxo));
+                }
+                let unspent = UnspentOutput { output: output.clone(), height: block.get_height(), is_coinbase };
+                self.outputs.insert(utxo.clone(), unspent);
+                undo.created.push(utxo);
+            }
+        }
+        Ok(())
+    }
+
+    /// Disconnects the best block using the `undo` record returned when it was applied
+    pub fn revert_block(&mut self, undo: &BlockUndo) -> Result<(), UtxoError> {
+        if undo.hash != self.best_block_hash {
+            return Err(UtxoError::UndoMismatch {
+                best_block_hash: self.best_block_hash.clone(),
+                undo_hash: undo.hash.clone(),
+            });
+        }
+
+        self.undo_changes(undo);
+        self.best_block_hash = undo.previous_hash.clone();
+        self.height = undo.previous_height;
+        Ok(())
+    }
+
+    /// Restores spent outputs first as outputs spent in the same block are also in `created`
+    fn undo_changes(&mut self, undo: &BlockUndo) {
+        for (utxo, unspent) in undo.spent.iter().rev() {
+            self.outputs.insert(utxo.clone(), unspent.clone());
+        }
+        for utxo in &undo.created {
+            self.outputs.remove(utxo);
+        }
+    }
+
+    /// Value of all unspent outputs
+    pub fn total_value(&self) -> BigUint {
+        self.outputs.values().map(|unspent| internal_to_biguint(unspent.output.get_value())).sum()
+    }
+
+    /// Balance of `address` at the best block, `getBalanceData` in JS
+    ///
+    /// Maker outputs and the remaining collateral calling back to them are collateralized until
+    /// the settlement of the order ends. A taker output is split between maker and taker by which
+    /// side settled, `getAddressRelatedOutPoints` in JS. Outputs claimed by pending transactions
+    /// are not known to the set and counted as well. Trade outputs whose order can not be resolved
+    /// are logged and skipped.
+    pub fn balance<L: ScriptLookup, S: SettleLookup>(&self, address: &str, txs: &L, settlement: &S) -> Balance {
+        let address = address.to_lowercase();
+        let mut balance = Balance::default();
+        for (utxo, unspent) in &self.outputs {
+            let script = match str::from_utf8(unspent.output.get_output_script()) {
+                Ok(script) => script,
+                Err(_) => continue,
+            };
+            let value = internal_to_biguint(unspent.output.get_value());
+
+            if script.starts_with("OP_MONOID") || script.ends_with("OP_CALLBACK") {
+                // only callbacks have to be followed to the maker script holding the address
+                if !script.ends_with("OP_CALLBACK") && !has_address_in_script(script, &address) {
+                    continue;
+                }
+                let (maker_script, order) = match resolve_maker_order(utxo, script.to_string(), txs) {
+                    Ok(resolved) => resolved,
+                    Err(err) => {
+                        warn!("balance: skipping output {}, {}", utxo, err);
+                        continue;
+                    }
+                };
+                if !has_address_in_script(&maker_script, &address) {
+                    continue;
+                }
+                if self.height < unspent.height.saturating_add(order.settle_ends_at) {
+                    balance.collateralized += value;
+                } else {
+                    balance.confirmed += value;
+                }
+            } else if script.contains("OP_MONAD") && script.contains("OP_CALLBACK") {
+                let resolved = TakerOrder::parse(script)
+                    .map_err(|_| UtxoError::InvalidTradeScript(utxo.clone()))
+                    .and_then(|taker| {
+                        let maker_utxo = Utxo::new(&taker.maker_tx_hash, u64::from(taker.maker_tx_output_index));
+                        let maker_script = output_script(txs, &maker_utxo.tx_hash, maker_utxo.index)?;
+                        Ok((resolve_maker_order(&maker_utxo, maker_script, txs)?, maker_utxo))
+                    });
+                let ((maker_script, order), maker_utxo) = match resolved {
+                    Ok(resolved) => resolved,
+                    Err(err) => {
+                        warn!("balance: skipping output {}, {}", utxo, err);
+                        continue;
+                    }
+                };
+                let is_before_settle_ends = self.height < unspent.height.saturating_add(order.settle_ends_at);
+
+                let maker_settles = settlement.is_settled(&maker_utxo);
+                let taker_settles = settlement.is_settled(utxo);
+                let (settles, other_settles) = if has_address_in_script(&maker_script, &address) {
+                    (maker_settles, taker_settles)
+                } else if has_address_in_script(script, &address) {
+                    (taker_settles, maker_settles)
+                } else {
+                    continue;
+                };
+
+                // both settled, or neither and the settlement ended, splits the value
+                if settles && other_settles || !settles && !other_settles && !is_before_settle_ends {
+                    balance.confirmed += value / BigUint::from(2u32);
+                } else if settles && !is_before_settle_ends {
+                    balance.confirmed += value;
+                }
+            } else if !has_address_in_script(script, &address) {
+                continue;
+            } else if unspent.is_coinbase && self.height < unspent.height + COINBASE_MATURITY {
+                balance.unconfirmed += value;
+            } else {
+                balance.confirmed += value;
+            }
+        }
+        balance
+    }
+
+    /// Writes storing the whole set, outputs are sorted to get the same writes for the same set
+    pub fn write_ops(&self) -> Result<Vec<WriteOp>, UtxoError> {
+        let mut outputs: Vec<_> = self.outputs.iter().collect();
+        outputs.sort_by(|a, b| a.0.cmp(b.0));
+        let mut ops = vec![self.latest_write_op()];
+        for (utxo, unspent) in outputs {
+            ops.push(WriteOp::Put(utxo_key(utxo), encode_native(&unspent_output_to_json(unspent)?)));
+        }
+        Ok(ops)
+    }
+
+    /// Writes storing the changes of the block of `undo` applied to a stored set
+    pub fn apply_write_ops(&self, undo: &BlockUndo) -> Result<Vec<WriteOp>, UtxoError> {
+        let mut ops = vec![self.latest_write_op()];
+        for (utxo, _) in &undo.spent {
+            ops.push(WriteOp::Delete(utxo_key(utxo)));
+        }
+        // outputs spent in the same block are in `created` as well
+        for utxo in &undo.created {
+            if let Some(unspent) = self.outputs.get(utxo) {
+                ops.push(WriteOp::Put(utxo_key(utxo), encode_native(&unspent_output_to_json(unspent)?)));
+            }
+        }
+        Ok(ops)
+    }
+
+    /// Writes storing the revert of the block of `undo` from a stored set
+    pub fn revert_write_ops(&self, undo: &BlockUndo) -> Result<Vec<WriteOp>, UtxoError> {
+        let mut ops = vec![self.latest_write_op()];
+        for (utxo, unspent) in &undo.spent {
+            ops.push(WriteOp::Put(utxo_key(utxo), encode_native(&unspent_output_to_json(unspent)?)));
+        }
+        // deleted last as outputs spent in the same block are in `created` as well
+        for utxo in &undo.created {
+            ops.push(WriteOp::Delete(utxo_key(utxo)));
+        }
+        Ok(ops)
+    }
+
+    fn latest_write_op(&self) -> WriteOp {
+        let mut object = Object::new();
+        object.insert("hash".to_string(), Json::String(self.best_block_hash.clone()));
+        object.insert("height".to_string(), Json::U64(self.height));
+        WriteOp::Put(utxo_latest_key(), encode_native(&Json::Object(object)))
+    }
+
+    /// Reads the set from the stored `entries` with `codec`, entries of other keys are skipped
+    pub fn from_entries<'a, I: IntoIterator<Item = (&'a str, &'a [u8])>>(codec: &Codec, entries: I) -> Result<UtxoSet, UtxoError> {
+        let mut utxos = UtxoSet::new();
+        let latest_key = utxo_latest_key();
+        let prefix = format!("{}.", UTXO_KEY_PREFIX);
+        for (key, value) in entries {
+            if key == latest_key {
+                let json = codec.decode_native(value).map_err(|_| UtxoError::InvalidEncoding)?;
+                let (hash, height) = latest_from_json(&json).ok_or(UtxoError::InvalidEncoding)?;
+                utxos.best_block_hash = hash;
+                utxos.height = height;
+            } else if key.starts_with(&prefix) {
+                let utxo = utxo_from_key(&key[prefix.len()..]).ok_or(UtxoError::InvalidEncoding)?;
+                let json = codec.decode_native(value).map_err(|_| UtxoError::InvalidEncoding)?;
+                let unspent = unspent_output_from_json(&json).ok_or(UtxoError::InvalidEncoding)?;
+                utxos.outputs.insert(utxo, unspent);
+            }
+        }
+        Ok(utxos)
+    }
+}
+
+impl UtxoLookup for UtxoSet {
+    fn get_unspent_output(&self, out_point: &OutPoint) -> Option<UnspentOutput> {
+        self.get(&Utxo::from_out_point(out_point)).cloned()
+    }
+}
+
+fn write_u64(buf: &mut Vec<u8>, value: u64) {
+    for i in (0..8).rev() {
+        buf.push((value >> (i * 8)) as u8);
+    }
+}
+
+/// Length prefixed `bytes`
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_u64(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_utxo(buf: &mut Vec<u8>, utxo: &Utxo) {
+    write_bytes(buf, utxo.tx_hash.as_bytes());
+    write_u64(buf, utxo.index);
+}
+
+fn write_unspent_output(buf: &mut Vec<u8>, unspent: &UnspentOutput) -> Result<(), UtxoError> {
+    let output = unspent.output.write_to_bytes().map_err(|_| UtxoError::InvalidEncoding)?;
+    write_u64(buf, unspent.height);
+    buf.push(unspent.is_coinbase as u8);
+    write_bytes(buf, &output);
+    Ok(())
+}
+
+/// Outpoint of the part of a `utxo.<hash>.<index>` key after the prefix
+fn utxo_from_key(key: &str) -> Option<Utxo> {
+    let mut parts = key.rsplitn(2, '.');
+    let index = parts.next()?.parse().ok()?;
+    Some(Utxo::new(parts.next()?, index))
+}
+
+/// Outpoint of a `utxo.<hash>.<index>` key, `None` for keys of other values
+pub fn utxo_of_key(key: &str) -> Option<Utxo> {
+    utxo_from_key(key.strip_prefix(UTXO_KEY_PREFIX)?.strip_prefix('.')?)
+}
+
+/// Best block of the value stored under `utxo.latest`
+pub fn latest_from_json(json: &Json) -> Option<(String, u64)> {
+    let object = json.as_object()?;
+    Some((object.get("hash")?.as_string()?.to_string(), object.get("height")?.as_u64()?))
+}
+
+/// `{height, isCoinbase, output}` with the serialized output in hex
+fn unspent_output_to_json(unspent: &UnspentOutput) -> Result<Json, UtxoError> {
+    let output = unspent.output.write_to_bytes().map_err(|_| UtxoError::InvalidEncoding)?;
+    let mut object = Object::new();
+    object.insert("height".to_string(), Json::U64(unspent.height));
+    object.insert("isCoinbase".to_string(), Json::Boolean(unspent.is_coinbase));
+    object.insert("output".to_string(), Json::String(output.to_hex()));
+    Ok(Json::Object(object))
+}
+
+/// Unspent output stored under a `utxo.<hash>.<index>` key
+pub fn unspent_output_from_json(json: &Json) -> Option<UnspentOutput> {
+    let object = json.as_object()?;
+    let output = object.get("output")?.as_string()?.from_hex().ok()?;
+    Some(UnspentOutput {
+        output: protobuf::parse_from_bytes(&output).ok()?,
+        height: object.get("height")?.as_u64()?,
+        is_coinbase: object.get("isCoinbase")?.as_boolean()?,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, len: usize) -> Result<&'a [u8], UtxoError> {
+        if self.bytes.len() - self.position < len {
+            return Err(UtxoError::InvalidEncoding);
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, UtxoError> {
+        Ok(self.read(8)?.iter().fold(0, |value, &b| (value << 8) | u64::from(b)))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], UtxoError> {
+        let len = self.read_u64()?;
+        if len > (self.bytes.len() - self.position) as u64 {
+            return Err(UtxoError::InvalidEncoding);
+        }
+        self.read(len as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String, UtxoError> {
+        str::from_utf8(self.read_bytes()?).map(|s| s.to_string()).map_err(|_| UtxoError::InvalidEncoding)
+    }
+
+    fn read_utxo(&mut self) -> Result<Utxo, UtxoError> {
+        Ok(Utxo { tx_hash: self.read_string()?, index: self.read_u64()? })
+    }
+
+    fn read_unspent_output(&mut self) -> Result<UnspentOutput, UtxoError> {
+        let height = self.read_u64()?;
+        let is_coinbase = match self.read(1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(UtxoError::InvalidEncoding),
+        };
+        let output: TransactionOutput =
+            protobuf::parse_from_bytes(self.read_bytes()?).map_err(|_| UtxoError::InvalidEncoding)?;
+        Ok(UnspentOutput { output, height, is_coinbase })
+    }
+
+    fn finish(&self) -> Result<(), UtxoError> {
+        if self.position != self.bytes.len() {
+            return Err(UtxoError::InvalidEncoding);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+
+    use coin::biguint_to_internal;
+    use protos::core::{MarkedTransaction, Transaction, TransactionInput};
+    use store::{KeyValueDb, MemoryDb};
+    use tx::create_nrg_output_lock_script;
+
+    const ADDRESS: &str = "0x25cc7722a6378e11082f7967c09b98bd26c979d3";
+    const OTHER_ADDRESS: &str = "0xea674fdde714fd979de3edf0f56aa9716b898ec8";
+
+    fn nrg(value: u64) -> Vec<u8> {
+        biguint_to_internal(&(BigUint::from(value) * BigUint::from(1000000000000000000u64)))
+    }
+
+    fn output(value: u64, script: &str) -> TransactionOutput {
+        let mut output = TransactionOutput::new();
+        output.set_value(nrg(value));
+        output.set_unit(vec![1]);
+        output.set_script_length(script.len() as u32);
+        output.set_output_script(script.as_bytes().to_vec());
+        output
+    }
+
+    fn tx(hash: &str, spends: &[(&str, u64)], outputs: Vec<TransactionOutput>) -> Transaction {
+        let inputs: Vec<TransactionInput> = spends
+            .iter()
+            .map(|&(hash, index)| {
+                let mut out_point = OutPoint::new();
+                out_point.set_hash(hash.to_string());
+                out_point.set_index(index);
+                let mut input = TransactionInput::new();
+                input.set_out_point(out_point);
+                input
+            })
+            .collect();
+
+        let mut tx = Transaction::new();
+        tx.set_hash(hash.to_string());
+        tx.set_nin_count(inputs.len() as u64);
+        tx.set_inputs(RepeatedField::from_vec(inputs));
+        tx.set_nout_count(outputs.len() as u64);
+        tx.set_outputs(RepeatedField::from_vec(outputs));
+        tx
+    }
+
+    fn block(hash: &str, previous_hash: &str, height: u64, txs: Vec<Transaction>) -> BcBlock {
+        let mut block = BcBlock::new();
+        block.set_hash(hash.to_string());
+        block.set_previous_hash(previous_hash.to_string());
+        block.set_height(height);
+        block.set_txs(RepeatedField::from_vec(txs));
+        block
+    }
+
+    /// Coinbase paying `ADDRESS` and a transfer spending an output of the genesis block
+    fn blocks() -> (BcBlock, BcBlock) {
+        let nrg_script = create_nrg_output_lock_script(ADDRESS);
+        let genesis = block("b1", "b0", 1, vec![tx("t1", &[], vec![output(10, &nrg_script), output(5, &nrg_script)])]);
+        let next = block(
+            "b2",
+            "b1",
+            2,
+            vec![
+                tx("t2", &[], vec![output(16, &create_nrg_output_lock_script(OTHER_ADDRESS))]),
+                tx("t3", &[("t1", 0)], vec![output(4, &nrg_script), output(6, &create_nrg_output_lock_script(OTHER_ADDRESS))]),
+                tx("t4", &[("t3", 1)], vec![output(6, &create_nrg_output_lock_script(OTHER_ADDRESS))]),
+            ],
+        );
+        (genesis, next)
+    }
+
+    #[test]
+    fn apply_revert_block_test() {
+        let (genesis, next) = blocks();
+        let mut utxos = UtxoSet::new();
+        utxos.apply_block(&genesis).unwrap();
+        let after_genesis = utxos.clone();
+        assert_eq!(utxos.len(), 2);
+
+        let undo = utxos.apply_block(&next).unwrap();
+        assert_eq!((utxos.best_block_hash(), utxos.height()), ("b2", 2));
+        assert_eq!(utxos.len(), 4);
+        assert!(!utxos.contains(&Utxo::new("t1", 0)));
+        assert!(!utxos.contains(&Utxo::new("t3", 1)));
+        assert!(utxos.get(&Utxo::new("t2", 0)).unwrap().is_coinbase);
+        assert!(!utxos.get(&Utxo::new("t4", 0)).unwrap().is_coinbase);
+
+        let mut out_point = OutPoint::new();
+        out_point.set_hash("t3".to_string());
+        assert_eq!(utxos.get_unspent_output(&out_point).map(|unspent| unspent.height), Some(2));
+
+        assert_eq!(
+            utxos.revert_block(&BlockUndo { hash: "b1".to_string(), ..undo.clone() }),
+            Err(UtxoError::UndoMismatch { best_block_hash: "b2".to_string(), undo_hash: "b1".to_string() })
+        );
+        utxos.revert_block(&undo).unwrap();
+        assert_eq!(utxos, after_genesis);
+    }
+
+    #[test]
+    fn apply_block_errors_test() {
+        let (genesis, next) = blocks();
+        let mut utxos = UtxoSet::new();
+        utxos.apply_block(&genesis).unwrap();
+        let after_genesis = utxos.clone();
+
+        assert_eq!(
+            utxos.apply_block(&block("b3", "b2", 3, vec![])),
+            Err(UtxoError::NotConnected { best_block_hash: "b1".to_string(), previous_hash: "b2".to_string() })
+        );
+
+        let mut invalid = next.clone();
+        invalid.mut_txs().push(tx("t5", &[("t1", 0)], vec![]));
+        assert_eq!(utxos.apply_block(&invalid), Err(UtxoError::MissingOutput(Utxo::new("t1", 0))));
+        assert_eq!(utxos, after_genesis);
+
+        let mut invalid = next;
+        invalid.mut_txs().push(tx("t2", &[("t1", 1)], vec![output(1, "OP_RETURN")]));
+        assert_eq!(utxos.apply_block(&invalid), Err(UtxoError::DuplicateOutput(Utxo::new("t2", 0))));
+        assert_eq!(utxos, after_genesis);
+    }
+
+    /// Transactions of the applied blocks and the settled trade outputs
+    #[derive(Default)]
+    struct TestLookup {
+        txs: HashMap<String, (Transaction, u64)>,
+        settled: Vec<Utxo>,
+    }
+
+    impl TestLookup {
+        fn apply_block(&mut self, utxos: &mut UtxoSet, block: &BcBlock) -> BlockUndo {
+            for tx in block.get_txs() {
+                self.txs.insert(tx.get_hash().to_string(), (tx.clone(), block.get_height()));
+            }
+            utxos.apply_block(block).unwrap()
+        }
+    }
+
+    impl ScriptLookup for TestLookup {
+        fn get_transaction(&self, hash: &str) -> Option<(Transaction, u64)> {
+            self.txs.get(hash).cloned()
+        }
+
+        fn get_latest_height(&self) -> u64 {
+            0
+        }
+
+        fn get_marked_txs(&self, _from: u64, _to: u64) -> Vec<MarkedTransaction> {
+            vec![]
+        }
+    }
+
+    impl SettleLookup for TestLookup {
+        fn is_settled(&self, utxo: &Utxo) -> bool {
+            self.settled.contains(utxo)
+        }
+    }
+
+    fn skip_to(lookup: &mut TestLookup, utxos: &mut UtxoSet, height: u64) {
+        while utxos.height() < height {
+            let next = utxos.height() + 1;
+            let previous_hash = utxos.best_block_hash().to_string();
+            lookup.apply_block(utxos, &block(&format!("b{}", next), &previous_hash, next, vec![]));
+        }
+    }
+
+    #[test]
+    fn balance_test() {
+        let (genesis, next) = blocks();
+        let mut lookup = TestLookup::default();
+        let mut utxos = UtxoSet::new();
+        lookup.apply_block(&mut utxos, &genesis);
+        let undo = lookup.apply_block(&mut utxos, &next);
+
+        let maker_script = MakerOrder::new(2, 4, 6, "btc", "eth", "0xwantsaddress", "1", "2", ADDRESS).to_script();
+        let maker_block = block("b3", "b2", 3, vec![tx("t5", &[("t1", 1)], vec![output(3, &maker_script)])]);
+        let maker_undo = lookup.apply_block(&mut utxos, &maker_block);
+
+        let balance = utxos.balance(&ADDRESS.to_uppercase().replace("0X", "0x"), &lookup, &lookup);
+        assert_eq!(balance.confirmed, internal_to_biguint(&nrg(4)));
+        assert_eq!(balance.unconfirmed, BigUint::from(0u32));
+        assert_eq!(balance.collateralized, internal_to_biguint(&nrg(3)));
+
+        let other = utxos.balance(OTHER_ADDRESS, &lookup, &lookup);
+        assert_eq!(other.confirmed, internal_to_biguint(&nrg(6)));
+        assert_eq!(other.unconfirmed, internal_to_biguint(&nrg(16)));
+        assert_eq!(other.collateralized, BigUint::from(0u32));
+
+        utxos.revert_block(&maker_undo).unwrap();
+        utxos.revert_block(&undo).unwrap();
+        assert_eq!(utxos.balance(ADDRESS, &lookup, &lookup).unconfirmed, internal_to_biguint(&nrg(15)));
+        skip_to(&mut lookup, &mut utxos, COINBASE_MATURITY + 1);
+        assert_eq!(utxos.balance(ADDRESS, &lookup, &lookup).confirmed, internal_to_biguint(&nrg(15)));
+    }
+
+    #[test]
+    fn trade_balance_test() {
+        let (genesis, next) = blocks();
+        let mut lookup = TestLookup::default();
+        let mut utxos = UtxoSet::new();
+        lookup.apply_block(&mut utxos, &genesis);
+        lookup.apply_block(&mut utxos, &next);
+
+        // maker of ADDRESS matched by a taker of OTHER_ADDRESS, the remaining collateral calls back
+        let maker_script = MakerOrder::new(2, 4, 6, "btc", "eth", "0xwantsaddress", "1", "2", ADDRESS).to_script();
+        lookup.apply_block(&mut utxos, &block("b3", "b2", 3, vec![tx("m1", &[("t1", 1)], vec![output(5, &maker_script)])]));
+        let taker_script = TakerOrder::new("m1", 0, OTHER_ADDRESS).to_script();
+        let taker_tx = tx("t6", &[("m1", 0)], vec![output(8, &taker_script), output(2, "m1 0 OP_CALLBACK")]);
+        lookup.apply_block(&mut utxos, &block("b4", "b3", 4, vec![taker_tx]));
+
+        let confirmed = |utxos: &UtxoSet, lookup: &TestLookup, address: &str| {
+            utxos.balance(address, lookup, lookup).confirmed
+        };
+        let balance = utxos.balance(ADDRESS, &lookup, &lookup);
+        assert_eq!(balance.confirmed, internal_to_biguint(&nrg(4)));
+        assert_eq!(balance.collateralized, internal_to_biguint(&nrg(2)));
+        assert_eq!(utxos.balance(OTHER_ADDRESS, &lookup, &lookup).collateralized, BigUint::from(0u32));
+
+        // a side which settled gets nothing before the settlement ends unless both settled
+        lookup.settled = vec![Utxo::new("t6", 0)];
+        assert_eq!(confirmed(&utxos, &lookup, OTHER_ADDRESS), internal_to_biguint(&nrg(6)));
+        lookup.settled.push(Utxo::new("m1", 0));
+        assert_eq!(confirmed(&utxos, &lookup, ADDRESS), internal_to_biguint(&nrg(8)));
+        assert_eq!(confirmed(&utxos, &lookup, OTHER_ADDRESS), internal_to_biguint(&nrg(10)));
+
+        skip_to(&mut lookup, &mut utxos, 10);
+        let balance = utxos.balance(ADDRESS, &lookup, &lookup);
+        assert_eq!(balance.confirmed, internal_to_biguint(&nrg(10)));
+        assert_eq!(balance.collateralized, BigUint::from(0u32));
+
+        // only the taker settled, the taker gets all of it
+        lookup.settled = vec![Utxo::new("t6", 0)];
+        assert_eq!(confirmed(&utxos, &lookup, ADDRESS), internal_to_biguint(&nrg(6)));
+        assert_eq!(confirmed(&utxos, &lookup, OTHER_ADDRESS), internal_to_biguint(&nrg(14)));
+
+        // nobody settled, both get half
+        lookup.settled = vec![];
+        assert_eq!(confirmed(&utxos, &lookup, ADDRESS), internal_to_biguint(&nrg(10)));
+        assert_eq!(confirmed(&utxos, &lookup, OTHER_ADDRESS), internal_to_biguint(&nrg(10)));
+
+        // trade outputs of unknown makers are skipped, the other outputs are still counted
+        lookup.txs.remove("m1");
+        let balance = utxos.balance(ADDRESS, &lookup, &lookup);
+        assert_eq!(balance.confirmed, internal_to_biguint(&nrg(4)));
+        assert_eq!(balance.collateralized, BigUint::from(0u32));
+        let mut lookup = TestLookup::default();
+        let mut utxos = UtxoSet::new();
+        lookup.apply_block(&mut utxos, &genesis);
+        let invalid = block("b2", "b1", 2, vec![tx("t7", &[], vec![output(1, "t1 0 OP_CALLBACK")])]);
+        lookup.apply_block(&mut utxos, &invalid);
+        let balance = utxos.balance(ADDRESS, &lookup, &lookup);
+        assert_eq!(balance.unconfirmed, internal_to_biguint(&nrg(15)));
+        assert_eq!(balance.collateralized, BigUint::from(0u32));
+    }
+
+    #[test]
+    fn write_ops_test() {
+        let (genesis, next) = blocks();
+        let mut utxos = UtxoSet::new();
+        utxos.apply_block(&genesis).unwrap();
+        let db = MemoryDb::new();
+        db.write(utxos.write_ops().unwrap()).unwrap();
+
+        let stored = |db: &MemoryDb| {
+            let mut entries = Vec::new();
+            db.scan(&mut |key, value| entries.push((key.to_string(), value.to_vec()))).unwrap();
+            UtxoSet::from_entries(&Codec::new(), entries.iter().map(|(key, value)| (&key[..], &value[..])))
+        };
+        assert_eq!(stored(&db), Ok(utxos.clone()));
+
+        let undo = utxos.apply_block(&next).unwrap();
+        let ops = utxos.apply_write_ops(&undo).unwrap();
+        assert!(ops.contains(&WriteOp::Delete("utxo.t1.0".to_string())));
+        assert!(!ops.iter().any(|op| match *op {
+            WriteOp::Put(ref key, _) => key == "utxo.t3.1",
+            WriteOp::Delete(_) => false,
+        }));
+        db.write(ops).unwrap();
+        assert_eq!(stored(&db), Ok(utxos.clone()));
+
+        utxos.revert_block(&undo).unwrap();
+        db.write(utxos.revert_write_ops(&undo).unwrap()).unwrap();
+        assert_eq!(stored(&db), Ok(utxos.clone()));
+
+        // values are stored in the DbValue envelope like the other keys
+        let codec = Codec::new();
+        let latest = db.get(&utxo_latest_key()).unwrap().unwrap();
+        assert_eq!(codec.decode_native(&latest).unwrap().find("height"), Some(&Json::U64(1)));
+        let unspent = db.get(&utxo_key(&Utxo::new("t1", 0))).unwrap().unwrap();
+        assert_eq!(codec.decode_native(&unspent).ok().and_then(|json| unspent_output_from_json(&json)), utxos.get(&Utxo::new("t1", 0)).cloned());
+
+        db.write(vec![WriteOp::Put("utxo.t1".to_string(), encode_native(&Json::Null))]).unwrap();
+        assert_eq!(stored(&db), Err(UtxoError::InvalidEncoding));
+        db.write(vec![WriteOp::Delete("utxo.t1".to_string()), WriteOp::Put("utxo.t1.0".to_string(), vec![1, 2])]).unwrap();
+        assert_eq!(stored(&db), Err(UtxoError::InvalidEncoding));
+    }
+
+    #[test]
+    fn encoding_test() {
+        let (genesis, next) = blocks();
+        let mut utxos = UtxoSet::new();
+        utxos.apply_block(&genesis).unwrap();
+        let undo = utxos.apply_block(&next).unwrap();
+
+        let bytes = undo.to_bytes().unwrap();
+        assert_eq!(BlockUndo::from_bytes(&bytes), Ok(undo.clone()));
+        assert_eq!(BlockUndo::from_bytes(&bytes[..bytes.len() - 1]), Err(UtxoError::InvalidEncoding));
+
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(BlockUndo::from_bytes(&bytes), Err(UtxoError::InvalidEncoding));
+    }
+}
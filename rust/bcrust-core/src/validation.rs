@@ -0,0 +1,1052 @@
+// Copyright (c) 2017-present, Block Collider developers, All rights reserved.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Native port of the block checks from src/bc/validation.es6
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use futures::Future;
+use futures::future::join_all;
+use futures_cpupool::CpuPool;
+use num_bigint::BigInt;
+use num_cpus;
+
+use data::{BLOCKCHAINS, GENESIS_BLOCKCHAIN_FINGERPRINTS_ROOT, GENESIS_CHILD_BLOCKCHAIN_COUNT, GENESIS_HEIGHT};
+use funcs::distance;
+use mining::primitives::{
+    blockchain_headers_to_lists,
+    create_merkle_root,
+    get_chain_root,
+    get_children_block_hashes,
+    get_diff,
+    get_exp_factor_diff,
+    get_new_block_count,
+    get_newest_header,
+    prepare_work,
+    MINIMUM_DIFFICULTY
+};
+use mining::traits::RoveredBlockLike;
+use protos::core::{BcBlock, BlockchainHeader};
+use types::{Difficulty, Distance, Hash256};
+
+/// Blocks below this height are accepted without validation (soft opening of the network)
+pub const SOFT_OPENING_HEIGHT: u64 = 151500;
+
+/// Max length of validated string and repeated fields
+pub const MAX_FIELD_LENGTH: usize = 128;
+
+const CHILD_AGE_HIGH_RANGE_LIMIT: u64 = 59 * 1000;
+const CHILD_AGE_LOW_RANGE_LIMIT: u64 = 29 * 1000;
+
+/// Dark fiber bound (in seconds) of each child chain, see src/utils/templates/blockchain_fingerprints.json
+const DF_BOUNDS: [(&str, u64); 5] = [
+    ("btc", 0),
+    ("eth", 0),
+    ("lsk", 0),
+    ("neo", 180),
+    ("wav", 300)
+];
+
+pub fn df_bound(blockchain: &str) -> Option<u64> {
+    DF_BOUNDS
+        .iter()
+        .find(|&&(name, _)| name == blockchain)
+        .map(|&(_, bound)| bound)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationOptions {
+    /// Also check merkle root which requires transactions to be present in the block
+    pub full_block: bool,
+}
+
+impl Default for ValidationOptions {
+    fn default() -> ValidationOptions {
+        ValidationOptions { full_block: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    MissingBlockchainHeaders,
+    BlockchainCountMismatch { expected: usize, found: usize },
+    UnorderedChildHeaders { blockchain: &'static str },
+    InvalidChainRoot { expected: Hash256, found: String },
+    FieldLengthUnbounded { field: &'static str, length: usize },
+    InvalidMerkleRoot { expected: Hash256, found: String },
+    MissingNewestChildHeader,
+    UnknownBlockchain(String),
+    ChildAgeAboveUpperLimit { timestamp: u64, limit: u64 },
+    ChildAgeBelowLowerLimit { timestamp: u64, limit: u64 },
+    MalformedField { field: &'static str, value: String },
+    FieldOverflow { field: &'static str, value: u64 },
+    DistanceBelowDifficulty { distance: String, difficulty: String },
+    InvalidDistance { expected: String, found: String },
+    InvalidPreviousHash { expected: String, found: String },
+    InvalidHeight { expected: u64, found: u64 },
+    InvalidTotalDistance { expected: String, found: String },
+    InvalidDifficulty { expected: Difficulty, found: String },
+    MissingChildHeaders,
+    UnlinkedChildHeader { blockchain: String, hash: String },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::MissingBlockchainHeaders =>
+                write!(f, "missing blockchain headers in block"),
+            ValidationError::BlockchainCountMismatch { expected, found } =>
+                write!(f, "expected headers of {} blockchains, found {}", expected, found),
+            ValidationError::UnorderedChildHeaders { blockchain } =>
+                write!(f, "{} headers are not ordered by height", blockchain),
+            ValidationError::InvalidChainRoot { ref expected, ref found } =>
+                write!(f, "chain root {} does not match expected {}", found, expected),
+            ValidationError::FieldLengthUnbounded { field, length } =>
+                write!(f, "field {} has length {} over {}", field, length, MAX_FIELD_LENGTH),
+            ValidationError::InvalidMerkleRoot { ref expected, ref found } =>
+                write!(f, "merkle root {} does not match expected {}", found, expected),
+            ValidationError::MissingNewestChildHeader =>
+                write!(f, "no upper limit child header found"),
+            ValidationError::UnknownBlockchain(ref blockchain) =>
+                write!(f, "unknown blockchain '{}'", blockchain),
+            ValidationError::ChildAgeAboveUpperLimit { timestamp, limit } =>
+                write!(f, "block timestamp {} is above child age upper limit {}", timestamp, limit),
+            ValidationError::ChildAgeBelowLowerLimit { timestamp, limit } =>
+                write!(f, "block timestamp {} is below child age lower limit {}", timestamp, limit),
+            ValidationError::MalformedField { field, ref value } =>
+                write!(f, "field {} has malformed value '{}'", field, value),
+            ValidationError::FieldOverflow { field, value } =>
+                write!(f, "field {} has value {} out of range", field, value),
+            ValidationError::DistanceBelowDifficulty { ref distance, ref difficulty } =>
+                write!(f, "distance {} is not above difficulty {}", distance, difficulty),
+            ValidationError::InvalidDistance { ref expected, ref found } =>
+                write!(f, "distance {} does not match expected {}", found, expected),
+            ValidationError::InvalidPreviousHash { ref expected, ref found } =>
+                write!(f, "previous hash {} does not match parent hash {}", found, expected),
+            ValidationError::InvalidHeight { expected, found } =>
+                write!(f, "height {} does not follow parent, expected {}", found, expected),
+            ValidationError::InvalidTotalDistance { ref expected, ref found } =>
+                write!(f, "total distance {} does not match expected {}", found, expected),
+            ValidationError::InvalidDifficulty { ref expected, ref found } =>
+                write!(f, "difficulty {} does not match expected {}", found, expected),
+            ValidationError::MissingChildHeaders =>
+                write!(f, "child or parent block has no headers of a blockchain"),
+            ValidationError::UnlinkedChildHeader { ref blockchain, ref hash } =>
+                write!(f, "{} header {} does not follow its predecessor", blockchain, hash),
+        }
+    }
+}
+
+impl Error for ValidationError {
+    fn description(&self) -> &str {
+        "invalid block"
+    }
+}
+
+/// Failed check of a block sequence, `index` is position of the offending block in the sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceError {
+    pub index: usize,
+    pub error: ValidationError,
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block {} of sequence is invalid: {}", self.index, self.error)
+    }
+}
+
+impl Error for SequenceError {
+    fn description(&self) -> &str {
+        "invalid block sequence"
+    }
+}
+
+/// Failure of a `validate_batch` worker, `index` is the first block of the chunk it validated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchError {
+    pub index: usize,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "validation of blocks from {} of batch failed", self.index)
+    }
+}
+
+impl Error for BatchError {
+    fn description(&self) -> &str {
+        "block validation task failed"
+    }
+}
+
+/// Checks structure of `block`, port of `isValidBlock`
+///
+/// Rules are checked in the same order as in JS and the first failing one is returned.
+pub fn is_valid_block(block: &BcBlock, opts: ValidationOptions) -> Result<(), ValidationError> {
+    // blocks may pass before the soft opening limit
+    if block.get_height() < SOFT_OPENING_HEIGHT {
+        return Ok(());
+    }
+
+    number_of_blockchains_needed_matches_child_block(block)?;
+    if_more_than_one_header_per_blockchain_are_they_ordered(block)?;
+    is_chain_root_correctly_calculated(block)?;
+    is_field_length_bounded(block)?;
+    if opts.full_block {
+        is_merkle_root_correctly_calculated(block)?;
+    }
+    is_valid_child_age(block)?;
+    is_distance_above_difficulty(block)?;
+    is_distance_correctly_calculated(block)
+}
+
+/// Checks that block timestamp is in range given by its newest child header and dark fiber bound
+pub fn is_valid_child_age(block: &BcBlock) -> Result<(), ValidationError> {
+    let newest_header = get_newest_header(block).ok_or(ValidationError::MissingNewestChildHeader)?;
+    let bound = df_bound(newest_header.get_blockchain())
+        .ok_or_else(|| ValidationError::UnknownBlockchain(newest_header.get_blockchain().to_string()))?;
+
+    let block_timestamp = block.get_timestamp().checked_mul(1000)
+        .ok_or(ValidationError::FieldOverflow { field: "timestamp", value: block.get_timestamp() })?;
+    let header_overflow = ValidationError::FieldOverflow { field: "blockchainHeaders.timestamp", value: newest_header.get_timestamp() };
+    let newest_header_timestamp = newest_header.get_timestamp().checked_add(bound * 1000).ok_or_else(|| header_overflow.clone())?;
+    let upper_limit = newest_header_timestamp.checked_add(CHILD_AGE_HIGH_RANGE_LIMIT).ok_or(header_overflow)?;
+    // JS works with signed numbers, no block timestamp is below a negative limit
+    let lower_limit = newest_header_timestamp.saturating_sub(CHILD_AGE_LOW_RANGE_LIMIT);
+
+    if block_timestamp > upper_limit {
+        return Err(ValidationError::ChildAgeAboveUpperLimit { timestamp: block_timestamp, limit: upper_limit });
+    }
+
+    if block_timestamp < lower_limit {
+        return Err(ValidationError::ChildAgeBelowLowerLimit { timestamp: block_timestamp, limit: lower_limit });
+    }
+
+    Ok(())
+}
+
+/// Checks that `blocks`, ordered from the oldest to the newest, form a chain, port of `validateBlockSequence`
+///
+/// Unlike JS, which only logs them, failures of `validate_sequence_total_distance` and
+/// `validate_sequence_difficulty` reject the sequence as well.
+pub fn validate_block_sequence(blocks: &[BcBlock]) -> Result<(), SequenceError> {
+    for (index, pair) in blocks.windows(2).enumerate() {
+        validate_sequence_pair(&pair[0], &pair[1])
+            .map_err(|error| SequenceError { index: index + 1, error })?;
+    }
+
+    Ok(())
+}
+
+/// Validates `blocks`, ordered from the oldest to the newest, returning one result per block
+///
+/// `is_valid_block` of each block runs on `pool`, the sequence checks of
+/// `validate_block_sequence` run afterwards serially for the blocks which passed it.
+pub fn validate_batch(pool: &CpuPool, blocks: Arc<[BcBlock]>, opts: ValidationOptions) -> Result<Vec<Result<(), ValidationError>>, BatchError> {
+    if blocks.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let chunk_size = cmp::max(1, blocks.len() / num_cpus::get());
+
+    let tasks: Vec<_> = (0..blocks.len())
+        .step_by(chunk_size)
+        .map(|start| {
+            let blocks = blocks.clone();
+            let end = cmp::min(start + chunk_size, blocks.len());
+            pool.spawn_fn(move || {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    blocks[start..end].iter().map(|block| is_valid_block(block, opts)).collect::<Vec<_>>()
+                })).map_err(|_| BatchError { index: start })
+            })
+        })
+        .collect();
+
+    let mut results: Vec<Result<(), ValidationError>> = join_all(tasks)
+        .wait()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    for (index, pair) in blocks.windows(2).enumerate() {
+        let result = &mut results[index + 1];
+        if result.is_ok() {
+            *result = validate_sequence_pair(&pair[0], &pair[1]);
+        }
+    }
+
+    Ok(results)
+}
+
+fn validate_sequence_pair(previous_block: &BcBlock, new_block: &BcBlock) -> Result<(), ValidationError> {
+    validate_sequence_link(previous_block, new_block)?;
+    validate_sequence_total_distance(previous_block, new_block)?;
+    validate_sequence_difficulty(previous_block, new_block)
+}
+
+fn validate_sequence_link(previous_block: &BcBlock, new_block: &BcBlock) -> Result<(), ValidationError> {
+    if new_block.get_previous_hash() != previous_block.get_hash() {
+        return Err(ValidationError::InvalidPreviousHash {
+            expected: previous_block.get_hash().to_string(),
+            found: new_block.get_previous_hash().to_string()
+        });
+    }
+
+    let expected_height = previous_block.get_height().checked_add(1)
+        .ok_or(ValidationError::FieldOverflow { field: "height", value: previous_block.get_height() })?;
+    if new_block.get_height() != expected_height {
+        return Err(ValidationError::InvalidHeight {
+            expected: expected_height,
+            found: new_block.get_height()
+        });
+    }
+
+    Ok(())
+}
+
+/// `BN.divRound` by 8, rounds half up for positive and truncates for negative values
+fn div_round_8(value: BigInt) -> BigInt {
+    if value < BigInt::from(0) {
+        return value / 8;
+    }
+
+    (value + 4) / 8
+}
+
+/// Checks that total distance of `new_block` is parent total distance plus chain weight and difficulty
+pub fn validate_sequence_total_distance(previous_block: &BcBlock, new_block: &BcBlock) -> Result<(), ValidationError> {
+    // parents without difficulty (e.g. genesis) are not checked, same as in JS
+    if previous_block.get_difficulty().is_empty() {
+        return Ok(());
+    }
+
+    let previous_difficulty: Difficulty = parse_field("difficulty", previous_block.get_difficulty())?;
+    if previous_difficulty == Difficulty::default() {
+        return Ok(());
+    }
+
+    let previous_distance: Distance = parse_field("distance", previous_block.get_distance())?;
+    let previous_total_distance: Distance = parse_field("total_distance", previous_block.get_total_distance())?;
+    let difficulty: Difficulty = parse_field("difficulty", new_block.get_difficulty())?;
+
+    let chain_weight = div_round_8(previous_distance.to_bigint() - previous_difficulty.to_bigint());
+    let expected = (previous_total_distance.to_bigint() + chain_weight + difficulty.to_bigint()).to_string();
+    if new_block.get_total_distance() != expected {
+        return Err(ValidationError::InvalidTotalDistance {
+            expected,
+            found: new_block.get_total_distance().to_string()
+        });
+    }
+
+    Ok(())
+}
+
+/// Checks that difficulty of `new_block` is the one calculated from its parent
+///
+/// As in JS the stale cost is computed from the newest child header of `new_block`, not of its parent.
+pub fn validate_sequence_difficulty(previous_block: &BcBlock, new_block: &BcBlock) -> Result<(), ValidationError> {
+    // new BN('') is zero in JS
+    let previous_difficulty = match previous_block.get_difficulty() {
+        "" => Difficulty::default(),
+        difficulty => parse_field("difficulty", difficulty)?
+    };
+    let newest_header = get_newest_header(new_block).ok_or(ValidationError::MissingNewestChildHeader)?;
+    let new_block_count = get_new_block_count(previous_block.get_blockchain_headers(), new_block.get_blockchain_headers());
+
+    let pre_exp_diff = get_diff(
+        new_block.get_timestamp(),
+        previous_block.get_timestamp(),
+        &previous_difficulty,
+        MINIMUM_DIFFICULTY,
+        new_block_count,
+        &newest_header
+    );
+    let expected = get_exp_factor_diff(pre_exp_diff, previous_block.get_height());
+
+    if new_block.get_difficulty() != expected.to_string() {
+        return Err(ValidationError::InvalidDifficulty { expected, found: new_block.get_difficulty().to_string() });
+    }
+
+    Ok(())
+}
+
+fn headers_are_linked<T: RoveredBlockLike>(previous: &T, next: &T) -> bool {
+    next.get_previous_hash() == previous.get_hash() && next.get_height() > previous.get_height()
+}
+
+/// Checks that headers of one child chain continue the headers of the same chain in the parent block
+///
+/// Port of `blockchainHeadersAreChain`, the first child header may also be the last parent header
+/// (no new block was rovered). The link checks which are logged only in JS reject the headers here,
+/// including the one for child headers, and heights have to increase along the links.
+pub fn blockchain_headers_are_chain<T: RoveredBlockLike>(child_headers: &[T], parent_headers: &[T]) -> Result<(), ValidationError> {
+    let (first_child_header, last_parent_header) = match (child_headers.first(), parent_headers.last()) {
+        (Some(child), Some(parent)) => (child, parent),
+        _ => return Err(ValidationError::MissingChildHeaders)
+    };
+
+    let unlinked = |header: &T| ValidationError::UnlinkedChildHeader {
+        blockchain: header.get_blockchain().to_string(),
+        hash: header.get_hash().to_string()
+    };
+
+    if first_child_header.get_hash() != last_parent_header.get_hash() &&
+        !headers_are_linked(last_parent_header, first_child_header) {
+        return Err(unlinked(first_child_header));
+    }
+
+    for headers in [parent_headers, child_headers].iter() {
+        if let Some(pair) = headers.windows(2).find(|pair| !headers_are_linked(&pair[0], &pair[1])) {
+            return Err(unlinked(&pair[1]));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_child_headers_sequence(child_block: &BcBlock, parent_block: &BcBlock) -> Result<(), ValidationError> {
+    let child_lists = blockchain_headers_to_lists(child_block.get_blockchain_headers());
+    let parent_lists = blockchain_headers_to_lists(parent_block.get_blockchain_headers());
+
+    for (child_headers, parent_headers) in child_lists.iter().zip(parent_lists.iter()) {
+        blockchain_headers_are_chain(child_headers, parent_headers)?;
+    }
+
+    Ok(())
+}
+
+/// Checks child chain headers of `blocks`, ordered from the oldest to the newest, port of `validateRoveredSequences`
+///
+/// Every block following the first one must also have a valid child age. Pairs whose parent is
+/// the genesis block are skipped as genesis carries no child headers.
+pub fn validate_rovered_sequences(blocks: &[BcBlock]) -> Result<(), SequenceError> {
+    for (index, pair) in blocks.windows(2).enumerate() {
+        let (parent_block, child_block) = (&pair[0], &pair[1]);
+        if parent_block.get_height() == GENESIS_HEIGHT {
+            continue;
+        }
+
+        validate_child_headers_sequence(child_block, parent_block)
+            .and_then(|_| is_valid_child_age(child_block))
+            .map_err(|error| SequenceError { index: index + 1, error })?;
+    }
+
+    Ok(())
+}
+
+/// Highest header of each child chain, port of `childrenHighestBlock`
+///
+/// Of equally high headers the last one is returned. JS returns a placeholder `{ height: 1 }` for
+/// a block without child headers, here the result is empty instead (see `children_height_sum`).
+pub fn children_highest_block(block: &BcBlock) -> Vec<BlockchainHeader> {
+    blockchain_headers_to_lists(block.get_blockchain_headers())
+        .iter()
+        .filter_map(|headers| headers.iter().max_by_key(|header| header.get_height()))
+        .cloned()
+        .collect()
+}
+
+/// Sum of heights of the highest child chain headers, port of `childrenHeightSum`
+pub fn children_height_sum(block: &BcBlock) -> u64 {
+    let highest = children_highest_block(block);
+    if highest.is_empty() {
+        // placeholder header of JS childrenHighestBlock
+        return 1;
+    }
+
+    highest.iter().map(|header| header.get_height()).sum()
+}
+
+fn number_of_blockchains_needed_matches_child_block(block: &BcBlock) -> Result<(), ValidationError> {
+    if !block.has_blockchain_headers() {
+        return Err(ValidationError::MissingBlockchainHeaders);
+    }
+
+    let found = blockchain_headers_to_lists(block.get_blockchain_headers())
+        .iter()
+        .filter(|headers| !headers.is_empty())
+        .count();
+
+    if found != GENESIS_CHILD_BLOCKCHAIN_COUNT {
+        return Err(ValidationError::BlockchainCountMismatch { expected: GENESIS_CHILD_BLOCKCHAIN_COUNT, found });
+    }
+
+    Ok(())
+}
+
+fn if_more_than_one_header_per_blockchain_are_they_ordered(block: &BcBlock) -> Result<(), ValidationError> {
+    let lists = blockchain_headers_to_lists(block.get_blockchain_headers());
+    for (&blockchain, headers) in BLOCKCHAINS.iter().zip(lists.iter()) {
+        let ordered = headers
+            .windows(2)
+            .all(|pair| pair[0].get_height() < pair[1].get_height());
+
+        if !ordered {
+            return Err(ValidationError::UnorderedChildHeaders { blockchain });
+        }
+    }
+
+    Ok(())
+}
+
+fn is_chain_root_correctly_calculated(block: &BcBlock) -> Result<(), ValidationError> {
+    let expected = get_chain_root(block.get_blockchain_headers());
+    if block.get_chain_root() != expected.to_hex() {
+        return Err(ValidationError::InvalidChainRoot { expected, found: block.get_chain_root().to_string() });
+    }
+
+    Ok(())
+}
+
+fn is_field_length_bounded(block: &BcBlock) -> Result<(), ValidationError> {
+    // JS reduces over all fields of toObject() but overwrites the result with every field, so only
+    // the last one (blockchainFingerprintsRoot) decides. Checking more would reject blocks JS accepts.
+    let length = block.get_blockchain_fingerprints_root().chars().count();
+    if length > MAX_FIELD_LENGTH {
+        return Err(ValidationError::FieldLengthUnbounded { field: "blockchain_fingerprints_root", length });
+    }
+
+    Ok(())
+}
+
+fn is_merkle_root_correctly_calculated(block: &BcBlock) -> Result<(), ValidationError> {
+    // JS miner (prepareNewBlock) uses transaction hashes, validation.es6 stringifies whole
+    // Transaction messages which only matches for blocks without transactions
+    let mut items: Vec<String> = get_children_block_hashes(block.get_blockchain_headers())
+        .iter()
+        .map(|hash| hash.to_hex())
+        .collect();
+    items.extend(block.get_txs().iter().map(|tx| tx.get_hash().to_string()));
+    items.push(block.get_difficulty().to_string());
+    items.push(block.get_miner().to_string());
+    items.push(block.get_height().to_string());
+    items.push(block.get_version().to_string());
+    items.push(block.get_schema_version().to_string());
+    items.push(block.get_nrg_grant().to_string());
+    items.push(GENESIS_BLOCKCHAIN_FINGERPRINTS_ROOT.to_string());
+
+    // list always contains the block fields so the merkle root exists
+    let expected = create_merkle_root(&items).unwrap_or_default();
+    if block.get_merkle_root() != expected.to_hex() {
+        return Err(ValidationError::InvalidMerkleRoot { expected, found: block.get_merkle_root().to_string() });
+    }
+
+    Ok(())
+}
+
+fn parse_field<T: ::std::str::FromStr>(field: &'static str, value: &str) -> Result<T, ValidationError> {
+    value.parse().map_err(|_| ValidationError::MalformedField { field, value: value.to_string() })
+}
+
+fn is_distance_above_difficulty(block: &BcBlock) -> Result<(), ValidationError> {
+    // NOTE: This is the difficulty for THIS block and not for the parent
+    let distance: Distance = parse_field("distance", block.get_distance())?;
+    let difficulty: Difficulty = parse_field("difficulty", block.get_difficulty())?;
+
+    if distance <= difficulty {
+        return Err(ValidationError::DistanceBelowDifficulty {
+            distance: distance.to_string(),
+            difficulty: difficulty.to_string()
+        });
+    }
+
+    Ok(())
+}
+
+fn is_distance_correctly_calculated(block: &BcBlock) -> Result<(), ValidationError> {
+    let previous_hash: Hash256 = parse_field("previous_hash", block.get_previous_hash())?;
+    let work = prepare_work(&previous_hash, block.get_blockchain_headers());
+
+    let nonce_hash = Hash256::blake2bl(block.get_nonce().as_bytes());
+    let payload = format!("{}{}{}{}", block.get_miner(), block.get_merkle_root(), nonce_hash, block.get_timestamp());
+    let result = Hash256::blake2bl(payload.as_bytes());
+
+    let expected = distance(work.to_hex().as_bytes(), result.to_hex().as_bytes()).to_string();
+    if block.get_distance() != expected {
+        return Err(ValidationError::InvalidDistance { expected, found: block.get_distance().to_string() });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+    use protos::core::{BlockchainHeader, BlockchainHeaders, Transaction};
+    use templates::{load_bc_block, BC_BLOCK_93699};
+
+    fn header(blockchain: &str, timestamp: u64, height: u64) -> BlockchainHeader {
+        let mut header = BlockchainHeader::new();
+        header.set_blockchain(blockchain.to_string());
+        header.set_hash(format!("{}_hash", blockchain));
+        header.set_previous_hash(format!("{}_prev", blockchain));
+        header.set_merkle_root(format!("{}_merkle", blockchain));
+        header.set_timestamp(timestamp);
+        header.set_height(height);
+        header
+    }
+
+    /// Block with values computed by the JS implementation
+    fn valid_block() -> BcBlock {
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(vec![header("btc", 1540000000000, 100)]));
+        headers.set_eth(RepeatedField::from_vec(vec![header("eth", 1540000010000, 100)]));
+        headers.set_lsk(RepeatedField::from_vec(vec![header("lsk", 1540000020000, 100)]));
+        headers.set_neo(RepeatedField::from_vec(vec![header("neo", 1540000030000, 100)]));
+        headers.set_wav(RepeatedField::from_vec(vec![header("wav", 1540000005000, 100)]));
+
+        let mut block = BcBlock::new();
+        block.set_previous_hash("9b80fc5cba6238801d745ca139ec639924d27ed004c22609d6d9409f1221b8ce".to_string());
+        block.set_height(200000);
+        block.set_miner("0x028d3af888e08aa8380e5866b6ed068bd60e7b19".to_string());
+        block.set_difficulty("100000000000000".to_string());
+        block.set_version(1);
+        block.set_schema_version(1);
+        block.set_nrg_grant(1600000000);
+        block.set_nonce("0.12345".to_string());
+        block.set_timestamp(1540000200);
+        block.set_chain_root("781163953dcac266615aa6622c1e4d6fc32339bade17b97b4951874b78292853".to_string());
+        block.set_merkle_root("b231e88be734d1ed3a76377798818242273df3570603a99e12d3dd5e162c8bd1".to_string());
+        block.set_distance("179276075259062".to_string());
+        block.set_blockchain_fingerprints_root(GENESIS_BLOCKCHAIN_FINGERPRINTS_ROOT.to_string());
+        block.set_blockchain_headers(headers);
+        block
+    }
+
+    #[test]
+    fn is_valid_block_test() {
+        assert_eq!(is_valid_block(&valid_block(), ValidationOptions::default()), Ok(()));
+    }
+
+    #[test]
+    fn is_valid_block_soft_opening_test() {
+        let block = load_bc_block(BC_BLOCK_93699);
+        assert_eq!(is_valid_block(&block, ValidationOptions::default()), Ok(()));
+
+        let mut block = BcBlock::new();
+        block.set_height(SOFT_OPENING_HEIGHT - 1);
+        assert_eq!(is_valid_block(&block, ValidationOptions::default()), Ok(()));
+    }
+
+    #[test]
+    fn blockchain_count_test() {
+        let mut block = valid_block();
+        block.clear_blockchain_headers();
+        assert_eq!(is_valid_block(&block, ValidationOptions::default()), Err(ValidationError::MissingBlockchainHeaders));
+
+        let mut block = valid_block();
+        block.mut_blockchain_headers().clear_wav();
+        assert_eq!(
+            is_valid_block(&block, ValidationOptions::default()),
+            Err(ValidationError::BlockchainCountMismatch { expected: 5, found: 4 })
+        );
+    }
+
+    #[test]
+    fn unordered_child_headers_test() {
+        let mut block = valid_block();
+        block.mut_blockchain_headers().mut_eth().push(header("eth", 1540000011000, 99));
+        assert_eq!(
+            is_valid_block(&block, ValidationOptions::default()),
+            Err(ValidationError::UnorderedChildHeaders { blockchain: "eth" })
+        );
+    }
+
+    #[test]
+    fn invalid_chain_root_test() {
+        let mut block = valid_block();
+        block.mut_blockchain_headers().mut_btc()[0].set_hash("btc_other_hash".to_string());
+        match is_valid_block(&block, ValidationOptions::default()) {
+            Err(ValidationError::InvalidChainRoot { .. }) => {},
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn field_length_test() {
+        let mut block = valid_block();
+        block.set_blockchain_fingerprints_root("f".repeat(129));
+        assert_eq!(
+            is_valid_block(&block, ValidationOptions::default()),
+            Err(ValidationError::FieldLengthUnbounded { field: "blockchain_fingerprints_root", length: 129 })
+        );
+    }
+
+    #[test]
+    fn invalid_merkle_root_test() {
+        let mut block = valid_block();
+        let mut tx = Transaction::new();
+        tx.set_hash("tx_hash_1".to_string());
+        block.mut_txs().push(tx);
+
+        let expected: Hash256 = "18c25a53c2743cff972453877311e202e347c257fe9e328c0336019237bbeeec".parse().unwrap();
+        assert_eq!(
+            is_valid_block(&block, ValidationOptions::default()),
+            Err(ValidationError::InvalidMerkleRoot { expected, found: block.get_merkle_root().to_string() })
+        );
+
+        // merkle root is not checked for blocks without full data, the distance still is
+        match is_valid_block(&block, ValidationOptions { full_block: false }) {
+            Ok(()) => {},
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn child_age_test() {
+        // newest header is neo (1540000030000) with dark fiber bound of 180s
+        let mut block = valid_block();
+        block.set_timestamp(1540000270);
+        assert_eq!(
+            is_valid_child_age(&block),
+            Err(ValidationError::ChildAgeAboveUpperLimit { timestamp: 1540000270000, limit: 1540000269000 })
+        );
+
+        block.set_timestamp(1540000180);
+        assert_eq!(
+            is_valid_child_age(&block),
+            Err(ValidationError::ChildAgeBelowLowerLimit { timestamp: 1540000180000, limit: 1540000181000 })
+        );
+
+        block.set_timestamp(1540000181);
+        assert_eq!(is_valid_child_age(&block), Ok(()));
+
+        assert_eq!(is_valid_child_age(&BcBlock::new()), Err(ValidationError::MissingNewestChildHeader));
+
+        block.set_timestamp(u64::max_value() / 10);
+        assert_eq!(
+            is_valid_child_age(&block),
+            Err(ValidationError::FieldOverflow { field: "timestamp", value: u64::max_value() / 10 })
+        );
+
+        block.set_timestamp(1540000181);
+        for header in block.mut_blockchain_headers().mut_neo().iter_mut() {
+            header.set_timestamp(u64::max_value() - 1000);
+        }
+        assert_eq!(
+            is_valid_child_age(&block),
+            Err(ValidationError::FieldOverflow { field: "blockchainHeaders.timestamp", value: u64::max_value() - 1000 })
+        );
+    }
+
+    #[test]
+    fn distance_above_difficulty_test() {
+        let mut block = valid_block();
+        block.set_difficulty("179276075259062".to_string());
+        block.set_merkle_root("".to_string());
+        assert_eq!(
+            is_valid_block(&block, ValidationOptions { full_block: false }),
+            Err(ValidationError::DistanceBelowDifficulty {
+                distance: "179276075259062".to_string(),
+                difficulty: "179276075259062".to_string()
+            })
+        );
+
+        block.set_difficulty("".to_string());
+        assert_eq!(
+            is_valid_block(&block, ValidationOptions { full_block: false }),
+            Err(ValidationError::MalformedField { field: "difficulty", value: "".to_string() })
+        );
+    }
+
+    fn sequence_block(height: u64, timestamp: u64, difficulty: &str, distance: &str, total_distance: &str, child_timestamp: u64) -> BcBlock {
+        let mut btc = header("btc", child_timestamp, 100 + height);
+        btc.set_hash(format!("btc_hash_{}", height));
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(RepeatedField::from_vec(vec![btc]));
+
+        let mut block = BcBlock::new();
+        block.set_hash(format!("bc_hash_{}", height));
+        block.set_previous_hash(format!("bc_hash_{}", height - 1));
+        block.set_height(height);
+        block.set_timestamp(timestamp);
+        block.set_difficulty(difficulty.to_string());
+        block.set_distance(distance.to_string());
+        block.set_total_distance(total_distance.to_string());
+        block.set_blockchain_headers(headers);
+        block
+    }
+
+    /// Sequence with difficulties and total distances computed by the JS implementation
+    fn valid_sequence() -> Vec<BcBlock> {
+        vec![
+            sequence_block(200000, 1540000200, "300000000000000", "400000000000004", "1000000000000000", 1540000190000),
+            sequence_block(200001, 1540000210, "299631901840491", "350000000000003", "1312131901840492", 1540000205000),
+            sequence_block(200002, 1540000230, "298528962324516", "310000000000000", "1616956876434947", 1540000222000),
+        ]
+    }
+
+    #[test]
+    fn validate_block_sequence_test() {
+        assert_eq!(validate_block_sequence(&valid_sequence()), Ok(()));
+        assert_eq!(validate_block_sequence(&valid_sequence()[..1]), Ok(()));
+        assert_eq!(validate_block_sequence(&[]), Ok(()));
+    }
+
+    #[test]
+    fn validate_block_sequence_link_test() {
+        let mut blocks = valid_sequence();
+        blocks[2].set_previous_hash("bc_hash_other".to_string());
+        assert_eq!(
+            validate_block_sequence(&blocks),
+            Err(SequenceError {
+                index: 2,
+                error: ValidationError::InvalidPreviousHash {
+                    expected: "bc_hash_200001".to_string(),
+                    found: "bc_hash_other".to_string()
+                }
+            })
+        );
+
+        let mut blocks = valid_sequence();
+        blocks[1].set_height(200002);
+        assert_eq!(
+            validate_block_sequence(&blocks),
+            Err(SequenceError { index: 1, error: ValidationError::InvalidHeight { expected: 200001, found: 200002 } })
+        );
+
+        let mut blocks = valid_sequence();
+        blocks[0].set_height(u64::max_value());
+        assert_eq!(
+            validate_block_sequence(&blocks),
+            Err(SequenceError { index: 1, error: ValidationError::FieldOverflow { field: "height", value: u64::max_value() } })
+        );
+
+        let mut blocks = valid_sequence();
+        blocks.swap(1, 2);
+        assert_eq!(validate_block_sequence(&blocks).unwrap_err().index, 1);
+    }
+
+    #[test]
+    fn validate_block_sequence_total_distance_test() {
+        let mut blocks = valid_sequence();
+        blocks[2].set_total_distance("1616956876434946".to_string());
+        assert_eq!(
+            validate_block_sequence(&blocks),
+            Err(SequenceError {
+                index: 2,
+                error: ValidationError::InvalidTotalDistance {
+                    expected: "1616956876434947".to_string(),
+                    found: "1616956876434946".to_string()
+                }
+            })
+        );
+
+        // parent without difficulty is not checked
+        let mut blocks = valid_sequence();
+        blocks[0].set_difficulty("".to_string());
+        assert_eq!(validate_sequence_total_distance(&blocks[0], &blocks[1]), Ok(()));
+    }
+
+    #[test]
+    fn validate_block_sequence_difficulty_test() {
+        let mut blocks = valid_sequence();
+        blocks[1].set_timestamp(1540000220);
+        assert_eq!(
+            validate_block_sequence(&blocks),
+            Err(SequenceError {
+                index: 1,
+                error: ValidationError::InvalidDifficulty {
+                    expected: Difficulty::from(298895705521473),
+                    found: "299631901840491".to_string()
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn validate_sequence_difficulty_template_test() {
+        // successor of bc.block.93699 with a new lsk header 14s later, its difficulty is the one
+        // getNewBlockCount, getDiff and getExpFactorDiff in JS compute and validateSequenceDifficulty accepts
+        let previous = load_bc_block(BC_BLOCK_93699);
+        let mut lsk = header("lsk", 1534304280000, 6778047);
+        lsk.set_hash("7123498152376541234".to_string());
+        lsk.set_previous_hash("5966913534974813203".to_string());
+        let mut next = previous.clone();
+        next.set_height(93700);
+        next.set_timestamp(1534304289);
+        next.mut_blockchain_headers().set_lsk(RepeatedField::from_vec(vec![lsk]));
+        next.set_difficulty("311131219356498".to_string());
+        assert_eq!(validate_sequence_difficulty(&previous, &next), Ok(()));
+
+        next.set_difficulty("311131219356499".to_string());
+        assert_eq!(
+            validate_sequence_difficulty(&previous, &next),
+            Err(ValidationError::InvalidDifficulty {
+                expected: Difficulty::from(311131219356498),
+                found: "311131219356499".to_string()
+            })
+        );
+    }
+
+    fn rovered_block(height: u64, timestamp: u64, child_heights: [(u64, u64); 5], child_timestamp: u64) -> BcBlock {
+        let lists: Vec<RepeatedField<BlockchainHeader>> = BLOCKCHAINS
+            .iter()
+            .zip(child_heights.iter())
+            .map(|(blockchain, &(first, last))| {
+                RepeatedField::from_vec((first..last + 1).map(|child_height| {
+                    let mut child = header(blockchain, child_timestamp, child_height);
+                    child.set_hash(format!("{}_{}", blockchain, child_height));
+                    child.set_previous_hash(format!("{}_{}", blockchain, child_height - 1));
+                    child
+                }).collect())
+            })
+            .collect();
+
+        let mut headers = BlockchainHeaders::new();
+        headers.set_btc(lists[0].clone());
+        headers.set_eth(lists[1].clone());
+        headers.set_lsk(lists[2].clone());
+        headers.set_neo(lists[3].clone());
+        headers.set_wav(lists[4].clone());
+
+        let mut block = BcBlock::new();
+        block.set_height(height);
+        block.set_timestamp(timestamp);
+        block.set_blockchain_headers(headers);
+        block
+    }
+
+    fn rovered_sequence() -> Vec<BcBlock> {
+        vec![
+            rovered_block(200000, 1540000010, [(10, 10), (20, 20), (30, 30), (40, 40), (50, 50)], 1540000000000),
+            rovered_block(200001, 1540000030, [(11, 11), (20, 20), (31, 32), (40, 40), (51, 52)], 1540000020000),
+        ]
+    }
+
+    /// `valid_sequence` moved below the soft opening height where only the sequence rules apply
+    fn soft_opening_sequence() -> Vec<BcBlock> {
+        let mut blocks = valid_sequence();
+        for block in blocks.iter_mut() {
+            let height = block.get_height() - 100000;
+            block.set_height(height);
+        }
+        blocks
+    }
+
+    #[test]
+    fn validate_batch_test() {
+        let pool = CpuPool::new(2);
+        let opts = ValidationOptions::default();
+        assert_eq!(validate_batch(&pool, soft_opening_sequence().into(), opts), Ok(vec![Ok(()), Ok(()), Ok(())]));
+        assert_eq!(validate_batch(&pool, Vec::new().into(), opts), Ok(vec![]));
+
+        let mut blocks = soft_opening_sequence();
+        blocks[1].set_total_distance("0".to_string());
+        assert_eq!(
+            validate_batch(&pool, blocks.into(), opts),
+            Ok(vec![
+                Ok(()),
+                Err(ValidationError::InvalidTotalDistance {
+                    expected: "1312131901840492".to_string(),
+                    found: "0".to_string()
+                }),
+                // total distance of the next block is checked against the invalid one
+                Err(ValidationError::InvalidTotalDistance {
+                    expected: "304824974594455".to_string(),
+                    found: "1616956876434947".to_string()
+                })
+            ])
+        );
+
+        // per block rules are reported before the sequence ones
+        let mut invalid_block = valid_block();
+        invalid_block.set_chain_root(String::new());
+        let results = validate_batch(&pool, vec![valid_block(), invalid_block].into(), opts).unwrap();
+        assert_eq!(results[0], Ok(()));
+        match results[1] {
+            Err(ValidationError::InvalidChainRoot { .. }) => {},
+            ref res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn validate_rovered_sequences_test() {
+        assert_eq!(validate_rovered_sequences(&rovered_sequence()), Ok(()));
+
+        let mut blocks = rovered_sequence();
+        blocks[1].mut_blockchain_headers().mut_lsk()[0].set_previous_hash("lsk_29".to_string());
+        assert_eq!(
+            validate_rovered_sequences(&blocks),
+            Err(SequenceError {
+                index: 1,
+                error: ValidationError::UnlinkedChildHeader { blockchain: "lsk".to_string(), hash: "lsk_31".to_string() }
+            })
+        );
+
+        let mut blocks = rovered_sequence();
+        blocks[1].set_timestamp(1540000080);
+        assert_eq!(
+            validate_rovered_sequences(&blocks),
+            Err(SequenceError {
+                index: 1,
+                error: ValidationError::ChildAgeAboveUpperLimit { timestamp: 1540000080000, limit: 1540000079000 }
+            })
+        );
+
+        // genesis has no child headers
+        let mut blocks = rovered_sequence();
+        blocks[0] = BcBlock::new();
+        blocks[0].set_height(GENESIS_HEIGHT);
+        assert_eq!(validate_rovered_sequences(&blocks), Ok(()));
+    }
+
+    #[test]
+    fn blockchain_headers_are_chain_test() {
+        let parent = rovered_block(200000, 1540000010, [(10, 11), (20, 20), (30, 30), (40, 40), (50, 50)], 1540000000000);
+        let child = rovered_block(200001, 1540000030, [(12, 13), (20, 20), (31, 32), (40, 40), (51, 52)], 1540000020000);
+        let parent_btc = parent.get_blockchain_headers().get_btc();
+        let child_btc = child.get_blockchain_headers().get_btc();
+
+        assert_eq!(blockchain_headers_are_chain(child_btc, parent_btc), Ok(()));
+        // the same header in both blocks
+        assert_eq!(blockchain_headers_are_chain(&parent_btc[1..], parent_btc), Ok(()));
+        assert_eq!(blockchain_headers_are_chain(child_btc, &[]), Err(ValidationError::MissingChildHeaders));
+        assert_eq!(blockchain_headers_are_chain(&[], parent_btc), Err(ValidationError::MissingChildHeaders));
+
+        let mut unordered = child_btc.to_vec();
+        unordered[1].set_height(12);
+        assert_eq!(
+            blockchain_headers_are_chain(&unordered, parent_btc),
+            Err(ValidationError::UnlinkedChildHeader { blockchain: "btc".to_string(), hash: "btc_13".to_string() })
+        );
+
+        let mut unlinked_parent = parent_btc.to_vec();
+        unlinked_parent[1].set_previous_hash("btc_9".to_string());
+        assert_eq!(
+            blockchain_headers_are_chain(child_btc, &unlinked_parent),
+            Err(ValidationError::UnlinkedChildHeader { blockchain: "btc".to_string(), hash: "btc_11".to_string() })
+        );
+    }
+
+    #[test]
+    fn children_height_sum_test() {
+        assert_eq!(children_height_sum(&valid_block()), 500);
+        // parity with childrenHeightSum(bc.block.93699) in JS
+        assert_eq!(children_height_sum(&load_bc_block(BC_BLOCK_93699)), 17212636);
+        assert_eq!(children_height_sum(&BcBlock::new()), 1);
+
+        let block = rovered_block(200000, 1540000010, [(10, 11), (20, 20), (30, 30), (40, 40), (50, 50)], 1540000000000);
+        let highest = children_highest_block(&block);
+        assert_eq!(highest.len(), 5);
+        assert_eq!(highest[0].get_hash(), "btc_11");
+        assert!(children_highest_block(&BcBlock::new()).is_empty());
+    }
+
+    #[test]
+    fn invalid_distance_test() {
+        let mut block = valid_block();
+        block.set_nonce("0.54321".to_string());
+        match is_valid_block(&block, ValidationOptions::default()) {
+            Err(ValidationError::InvalidDistance { ref found, .. }) => assert_eq!(found, "179276075259062"),
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+}